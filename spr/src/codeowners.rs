@@ -0,0 +1,209 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Finding the code owners (as listed in a `CODEOWNERS` file) of the files
+//! changed by a commit, so they can be requested as reviewers.
+
+use git2::Oid;
+use lazy_regex::Regex;
+
+use crate::{error::Result, git::Git};
+
+/// The places GitHub looks for the CODEOWNERS file, in order
+pub const CODEOWNERS_PATHS: &[&str] =
+    &[".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"];
+
+#[derive(Debug)]
+pub struct CodeOwners {
+    rules: Vec<(Regex, Vec<String>)>,
+}
+
+impl CodeOwners {
+    /// Parse the contents of a CODEOWNERS file. Lines with patterns we can't
+    /// make sense of are skipped, as GitHub does.
+    pub fn parse(text: &str) -> Self {
+        let rules = text
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| {
+                let mut words = line.split_whitespace();
+                let pattern = pattern_to_regex(words.next()?)?;
+                Some((pattern, words.map(String::from).collect()))
+            })
+            .collect();
+
+        Self { rules }
+    }
+
+    /// Read the CODEOWNERS file from the tree of the given commit. Returns
+    /// `None` if there is none.
+    pub fn load(git: &Git, commit_oid: Oid) -> Result<Option<Self>> {
+        let repo = git.repo();
+        let tree = repo.find_commit(commit_oid)?.tree()?;
+        for path in CODEOWNERS_PATHS {
+            if let Ok(entry) = tree.get_path(std::path::Path::new(path)) {
+                let blob = repo.find_blob(entry.id())?;
+                return Ok(Some(Self::parse(&String::from_utf8_lossy(
+                    blob.content(),
+                ))));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The owners of the given path. The last matching rule wins, and a rule
+    /// without owners means the path has none.
+    pub fn owners_of(&self, path: &str) -> &[String] {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(pattern, _)| pattern.is_match(path))
+            .map(|(_, owners)| &owners[..])
+            .unwrap_or_default()
+    }
+
+    /// The owners of all the given paths, without duplicates
+    pub fn owners_of_paths<'a, I>(&self, paths: I) -> Vec<String>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut result = Vec::<String>::new();
+        for path in paths {
+            for owner in self.owners_of(path) {
+                if !result.contains(owner) {
+                    result.push(owner.clone());
+                }
+            }
+        }
+
+        result
+    }
+}
+
+/// Translate a CODEOWNERS owner into the form used in the Reviewers section:
+/// `@user` becomes `user`, and `@org/team` becomes `#team` if the team
+/// belongs to the given organization. Owners given by email address can't
+/// be requested as reviewers, so they are dropped.
+pub fn owner_to_reviewer(owner: &str, organization: &str) -> Option<String> {
+    let name = owner.strip_prefix('@')?;
+    match name.split_once('/') {
+        Some((org, team)) if org.eq_ignore_ascii_case(organization) => {
+            Some(format!("#{team}"))
+        }
+        Some(_) => None,
+        None => Some(name.to_string()),
+    }
+}
+
+/// Translate a CODEOWNERS pattern (which follows the rules of gitignore
+/// patterns, mostly) into a regex matching the paths it applies to
+fn pattern_to_regex(pattern: &str) -> Option<Regex> {
+    let (pattern, directory_only) = match pattern.strip_suffix('/') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    // A pattern with a slash anywhere but at the end is relative to the root
+    // of the repository, otherwise it matches at any depth
+    let (pattern, anchored) = match pattern.strip_prefix('/') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, pattern.contains('/')),
+    };
+    if pattern.is_empty() {
+        return None;
+    }
+
+    let mut regex = String::from(if anchored { "^" } else { "^(.*/)?" });
+    let mut rest = pattern;
+    while let Some(c) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("**/") {
+            regex.push_str("(.*/)?");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("**") {
+            regex.push_str(".*");
+            rest = after;
+        } else {
+            match c {
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                c => {
+                    if "\\.+*?()|[]{}^$".contains(c) {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    // A pattern matching a directory applies to everything in it
+    regex.push_str(if directory_only { "/.*$" } else { "(/.*)?$" });
+
+    Regex::new(&regex).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_owners_of_changed_paths() {
+        let codeowners = CodeOwners::parse(
+            "# Default owners\n\
+             *                   @acme/core\n\
+             *.md                @docs-person # Documentation\n\
+             /src/frontend/      @alice @acme/web\n\
+             src/**/api.rs       @bob\n\
+             /src/frontend/vendor/\n\
+             build/              @other-org/builders dev@example.com\n",
+        );
+
+        assert_eq!(codeowners.owners_of("Cargo.toml"), ["@acme/core"]);
+        assert_eq!(codeowners.owners_of("docs/intro.md"), ["@docs-person"]);
+        assert_eq!(
+            codeowners.owners_of("src/frontend/app/main.ts"),
+            ["@alice", "@acme/web"]
+        );
+        assert_eq!(codeowners.owners_of("src/backend/v1/api.rs"), ["@bob"]);
+        assert_eq!(codeowners.owners_of("src/api.rs"), ["@bob"]);
+        // No owners for vendored code
+        assert!(codeowners
+            .owners_of("src/frontend/vendor/lib.js")
+            .is_empty());
+        // Unanchored directory patterns match at any depth
+        assert_eq!(
+            codeowners.owners_of("tools/build/run.sh"),
+            ["@other-org/builders", "dev@example.com"]
+        );
+
+        let owners = codeowners.owners_of_paths([
+            "src/frontend/index.ts",
+            "README.md",
+            "src/frontend/style.css",
+            "tools/build/run.sh",
+        ]);
+        assert_eq!(
+            owners,
+            vec![
+                "@alice",
+                "@acme/web",
+                "@docs-person",
+                "@other-org/builders",
+                "dev@example.com"
+            ]
+        );
+        assert_eq!(
+            owners
+                .iter()
+                .filter_map(|owner| owner_to_reviewer(owner, "ACME"))
+                .collect::<Vec<_>>(),
+            vec!["alice", "#web", "docs-person"]
+        );
+    }
+}
@@ -55,13 +55,14 @@ pub async fn amend(
     let mut failure = false;
 
     for commit in slice.iter_mut() {
-        write_commit_title(commit)?;
+        write_commit_title(commit, None)?;
         let pull_request = pull_requests.pop().flatten();
         if let Some(pull_request) = pull_request {
             let pull_request = pull_request.await??;
             commit.message = pull_request.sections;
         }
-        failure = validate_commit_message(&commit.message, config).is_err()
+        failure = validate_commit_message(&commit.message, config, false)
+            .is_err()
             || failure;
     }
     git.rewrite_commit_messages(slice, None)?;
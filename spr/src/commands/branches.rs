@@ -0,0 +1,199 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use serde::Serialize;
+
+use crate::{
+    error::Result,
+    github::PullRequest,
+    output::{output, write_paged},
+};
+
+#[derive(Debug, clap::Parser)]
+pub struct BranchesOptions {
+    /// Print the branches as JSON
+    #[clap(long)]
+    json: bool,
+
+    /// Show the output through a pager ($SPR_PAGER or $PAGER, defaulting to
+    /// less) if it is written to a terminal. Setting $SPR_PAGER enables this
+    /// by default
+    #[clap(long)]
+    pager: bool,
+}
+
+/// A branch on GitHub that spr manages for a commit of the stack
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ManagedBranch {
+    name: String,
+    kind: BranchKind,
+    /// The Pull Request the branch belongs to
+    pull_request: u64,
+    /// The local commit of that Pull Request
+    commit: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum BranchKind {
+    /// The head branch of the Pull Request
+    Head,
+    /// The intermediate base branch spr created for the Pull Request
+    Base,
+}
+
+pub async fn branches(
+    opts: BranchesOptions,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let mut prepared_commits = git.get_prepared_commits(config, Some(gh))?;
+
+    let mut pull_requests = Vec::new();
+    for prepared_commit in prepared_commits.iter_mut() {
+        if let Some(pull_request) = prepared_commit.pull_request().await? {
+            pull_requests.push((prepared_commit.oid, pull_request));
+        }
+    }
+    let branches = managed_branches(config, &pull_requests);
+
+    if opts.json {
+        write_paged(
+            &format!("{}\n", serde_json::to_string_pretty(&branches)?),
+            opts.pager,
+        )
+    } else if branches.is_empty() {
+        output("👋", "No Pull Requests on this branch - nothing to list")
+    } else {
+        write_paged(&format_branches(&branches), opts.pager)
+    }
+}
+
+/// The branches spr manages for the given Pull Requests (with the local
+/// commits they belong to), from the bottom of the stack to the top. A base
+/// branch is only listed if it is under the branch prefix, i.e. created by
+/// spr, and not the head branch of another Pull Request of the stack.
+fn managed_branches(
+    config: &crate::config::Config,
+    pull_requests: &[(git2::Oid, PullRequest)],
+) -> Vec<ManagedBranch> {
+    let heads: Vec<&str> = pull_requests
+        .iter()
+        .map(|(_, pr)| pr.head.branch_name())
+        .collect();
+
+    let mut result = Vec::new();
+    for (oid, pr) in pull_requests {
+        let branch = |name: &str, kind| ManagedBranch {
+            name: name.to_string(),
+            kind,
+            pull_request: pr.number,
+            commit: oid.to_string(),
+        };
+        result.push(branch(pr.head.branch_name(), BranchKind::Head));
+
+        let base = pr.base.branch_name();
+        if base.starts_with(&config.branch_prefix) && !heads.contains(&base) {
+            result.push(branch(base, BranchKind::Base));
+        }
+    }
+    result
+}
+
+fn format_branches(branches: &[ManagedBranch]) -> String {
+    let mut text = String::new();
+    for branch in branches {
+        text.push_str(&format!(
+            "{} {}\n",
+            branch.name,
+            console::style(format!(
+                "({} of #{})",
+                match branch.kind {
+                    BranchKind::Head => "head",
+                    BranchKind::Base => "base",
+                },
+                branch.pull_request
+            ))
+            .dim(),
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    use crate::{
+        config::test_utils::config_factory,
+        github::{test_utils::pull_request_factory, PullRequestState},
+    };
+
+    #[test]
+    fn test_managed_branches_json() {
+        let config = config_factory();
+        let oid = |n: u8| git2::Oid::from_bytes(&[n; 20]).unwrap();
+        let pull_request_factory = |number, head, base| PullRequest {
+            number,
+            head: config.new_github_branch(head),
+            base: config.new_github_branch(base),
+            ..pull_request_factory(PullRequestState::Open)
+        };
+        let pull_requests = vec![
+            // Based on master
+            (oid(1), pull_request_factory(1, "spr/foo/first", "master")),
+            // With a base branch of its own
+            (
+                oid(2),
+                pull_request_factory(
+                    2,
+                    "spr/foo/second",
+                    "spr/foo/master.second",
+                ),
+            ),
+            // Stacked onto the first Pull Request
+            (
+                oid(3),
+                pull_request_factory(3, "spr/foo/third", "spr/foo/first"),
+            ),
+        ];
+
+        assert_eq!(
+            serde_json::to_value(managed_branches(&config, &pull_requests))
+                .unwrap(),
+            serde_json::json!([
+                {
+                    "name": "spr/foo/first",
+                    "kind": "head",
+                    "pullRequest": 1,
+                    "commit": "0101010101010101010101010101010101010101",
+                },
+                {
+                    "name": "spr/foo/second",
+                    "kind": "head",
+                    "pullRequest": 2,
+                    "commit": "0202020202020202020202020202020202020202",
+                },
+                {
+                    "name": "spr/foo/master.second",
+                    "kind": "base",
+                    "pullRequest": 2,
+                    "commit": "0202020202020202020202020202020202020202",
+                },
+                {
+                    "name": "spr/foo/third",
+                    "kind": "head",
+                    "pullRequest": 3,
+                    "commit": "0303030303030303030303030303030303030303",
+                },
+            ])
+        );
+    }
+}
@@ -50,7 +50,7 @@ pub async fn close(
             break;
         }
 
-        write_commit_title(prepared_commit)?;
+        write_commit_title(prepared_commit, None)?;
 
         // The further implementation of the close command is in a separate function.
         // This makes it easier to run the code to update the local commit message
@@ -0,0 +1,146 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use crate::{
+    error::{Error, Result},
+    github::{PullRequest, PullRequestUpdate},
+    output::{output, write_commit_title},
+};
+
+#[derive(Debug, clap::Parser)]
+pub struct DescribeOptions {
+    /// File with the new description of the Pull Request (Markdown), or '-'
+    /// to read it from standard input
+    #[clap(long, value_name = "PATH")]
+    from_file: String,
+}
+
+pub async fn describe(
+    opts: DescribeOptions,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let prepared_commits = git.get_prepared_commits(config, None)?;
+
+    let prepared_commit = match prepared_commits.last() {
+        Some(prepared_commit) => prepared_commit,
+        None => {
+            output("👋", "Branch is empty - nothing to do. Good bye!")?;
+            return Ok(());
+        }
+    };
+    write_commit_title(prepared_commit, None)?;
+
+    let number = prepared_commit.pull_request_number.ok_or_else(|| {
+        Error::new(
+            "This commit does not refer to a Pull Request yet - run `spr \
+             diff` to create one",
+        )
+    })?;
+
+    let body = read_description(&opts.from_file)?;
+    let pull_request = gh.clone().get_pull_request(number).await?;
+
+    let updates = description_update(&pull_request, body);
+    if updates.is_empty() {
+        output("👌", &format!("#{number} has this description already"))?;
+    } else {
+        gh.update_pull_request(number, updates).await?;
+        output("✍", &format!("Updated the description of #{number}"))?;
+    }
+
+    Ok(())
+}
+
+/// Read the description from the given file, or from standard input if the
+/// path is `-`
+fn read_description(path: &str) -> Result<String> {
+    let result = if path == "-" {
+        let mut text = String::new();
+        std::io::Read::read_to_string(&mut std::io::stdin(), &mut text)
+            .map(|_| text)
+    } else {
+        std::fs::read_to_string(path)
+    };
+
+    result.map_err(|error| {
+        Error::new(format!(
+            "Could not read the description from {path}: {error}"
+        ))
+    })
+}
+
+/// The update replacing the description of the Pull Request with the given
+/// one. The title and everything else stays as it is.
+fn description_update(
+    pull_request: &PullRequest,
+    body: String,
+) -> PullRequestUpdate {
+    let body = format!("{}\n", body.trim_end());
+    PullRequestUpdate {
+        body: (pull_request.body.as_ref() != Some(&body)).then_some(body),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    use crate::github::{GitHubBranch, PullRequestState};
+
+    #[test]
+    fn test_describe_only_changes_body() {
+        let branch =
+            |name| GitHubBranch::new_from_branch_name(name, "origin", "master");
+        let pull_request = PullRequest {
+            number: 7,
+            state: PullRequestState::Open,
+            is_draft: false,
+            title: "Add feature".into(),
+            body: Some("Short summary\n".into()),
+            sections: Default::default(),
+            base: branch("master"),
+            head: branch("spr/foo/add-feature"),
+            base_oid: git2::Oid::zero(),
+            head_oid: git2::Oid::zero(),
+            merge_commit: None,
+            reviewers: Default::default(),
+            requested_reviewers: Vec::new(),
+            review_status: None,
+        };
+
+        let updates = description_update(
+            &pull_request,
+            "# Add feature\n\nA long description.\n\n".into(),
+        );
+        assert_eq!(updates.title, None);
+        assert_eq!(updates.base, None);
+        assert_eq!(updates.state, None);
+        assert_eq!(
+            updates.body.as_deref(),
+            Some("# Add feature\n\nA long description.\n")
+        );
+
+        // Nothing to do if the description is the same
+        assert!(description_update(&pull_request, "Short summary".into())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_read_description_from_file() {
+        let path = std::env::temp_dir()
+            .join(format!("spr-test-describe-{}.md", std::process::id()));
+        std::fs::write(&path, "Body\n").unwrap();
+        assert_eq!(read_description(path.to_str().unwrap()).unwrap(), "Body\n");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(read_description(path.to_str().unwrap()).is_err());
+    }
+}
@@ -6,22 +6,33 @@
  */
 
 use crate::{
-    error::{add_error, Error, Result, ResultExt},
+    codeowners::{owner_to_reviewer, CodeOwners},
+    config::BaseBranchStrategy,
+    error::{add_error, Error, ErrorKind, Result, ResultExt},
     git::{CommitOption, PreparedCommit},
     github::{
-        GitHub, PullRequestRequestReviewers, PullRequestState,
-        PullRequestUpdate,
+        CheckStatus, GitHub, GitHubBranch, PullRequestChecks,
+        PullRequestRequestReviewers, PullRequestState, PullRequestUpdate,
+        ReviewStatus, UserWithName,
     },
-    message::{validate_commit_message, MessageSection},
-    output::{output, write_commit_title},
+    message::{
+        build_github_body, parse_dependency_references,
+        validate_commit_message, with_diffstat, with_spr_footer,
+        MessageSection, MessageSectionsMap,
+    },
+    output::{
+        output, write_commit_separator, write_commit_title, PullRequestBadge,
+    },
+    plan::{Plan, PlanOptions, PlannedAction, PlannedCommit},
+    state::DiffState,
     utils::{
-        parse_name_list, remove_all_parens,
-        run_command_with_live_output,
+        open_in_browser, parse_name_list, remove_all_parens,
+        run_command_with_live_output, slugify,
     },
 };
 use git2::Oid;
 use indoc::formatdoc;
-use inquire::{MultiSelect, Select};
+use inquire::{Confirm, MultiSelect, Select};
 
 const MAIN_SPECIAL_COMMIT_INDEX: isize = -1;
 const UNKNOWN_PR_SPECIAL_COMMIT_INDEX: isize = -2;
@@ -33,20 +44,109 @@ pub struct DiffOptions {
     #[clap(long, short = 'a')]
     all: bool,
 
+    /// Create/update pull requests for the commits in the given revision
+    /// range (e.g. `HEAD~3..HEAD`, or a single commit) without an interactive
+    /// selection
+    #[clap(long, value_name = "RANGE", conflicts_with = "all")]
+    commits: Option<String>,
+
     /// Update the pull request title and description on GitHub from the local
     /// commit message
     #[clap(long)]
     update_message: bool,
 
+    /// Use this title for the Pull Request on GitHub (when creating or
+    /// updating it) instead of the title of the commit message, which is
+    /// left unchanged
+    #[clap(
+        long,
+        value_name = "TEXT",
+        conflicts_with_all = &["all", "commits"]
+    )]
+    title: Option<String>,
+
+    /// Use exactly this name for the head branch of a new Pull Request,
+    /// instead of one derived from the commit title. It must not exist on
+    /// GitHub yet
+    #[clap(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = &["all", "commits"]
+    )]
+    branch_name: Option<String>,
+
     /// Submit any new Pull Request as a draft
     #[clap(long)]
     draft: bool,
 
+    /// When creating a draft Pull Request, post this text as its first
+    /// comment (e.g. why it is not ready yet). The placeholder {pr} is
+    /// replaced with the pull request number. Ignored for Pull Requests that
+    /// are not created as drafts
+    #[clap(long, value_name = "TEXT")]
+    draft_comment: Option<String>,
+
+    /// After pushing, wait for the checks the base branch requires, then
+    /// convert the Pull Request to a draft if one of them fails, or mark it
+    /// as ready for review if all of them pass. A draft state requested with
+    /// --draft or the draft/ready keyword takes priority.
+    #[clap(long)]
+    draft_if_failing: bool,
+
+    /// Dismiss approving reviews of existing Pull Requests after significant
+    /// updates (a rebase, or changes to many files), so that reviewers take
+    /// another look. Note that branch protection on GitHub can be configured
+    /// to do this automatically on every push
+    #[clap(long)]
+    dismiss_stale_reviews: bool,
+
+    /// If HEAD is detached, create a branch of this name at HEAD and check it
+    /// out before doing anything else
+    #[clap(long, value_name = "NAME")]
+    branch: Option<String>,
+
+    /// Work on the commits of the given local branch instead of HEAD,
+    /// without checking it out. The branch is updated in place
+    #[clap(long, value_name = "NAME", conflicts_with = "branch")]
+    on_branch: Option<String>,
+
+    /// Post this text as a comment on each Pull Request that gets updated
+    /// (e.g. to reply to review feedback). The placeholder {pr} is replaced
+    /// with the pull request number
+    #[clap(long, value_name = "TEXT")]
+    comment: Option<String>,
+
+    /// Post the --comment even on Pull Requests that need no update
+    #[clap(long, requires = "comment")]
+    comment_always: bool,
+
     /// Message to be used for commits updating existing pull requests (e.g.
     /// 'rebase' or 'review comments')
     #[clap(long, short = 'm')]
     message: Option<String>,
 
+    /// Template for generating the message for commits updating existing
+    /// pull requests, instead of prompting for one. The placeholders {pr},
+    /// {title} and {reason} are replaced with the pull request number, the
+    /// commit title and 'rebase' or 'amend', depending on whether the commit
+    /// was rebased. Example: --message-template '{reason}: {title}'
+    #[clap(long, conflicts_with = "message")]
+    message_template: Option<String>,
+
+    /// File with a template for the description of new Pull Requests, used
+    /// instead of the sections of the commit message. The placeholders
+    /// {title}, {summary}, {test_plan}, {pr} and {url} are replaced with the
+    /// commit title, summary and test plan, and the number and URL of the
+    /// Pull Request
+    #[clap(long, value_name = "PATH")]
+    template_file: Option<std::path::PathBuf>,
+
+    /// Message to be used, instead of prompting for one, when a pull request
+    /// is only rebased, without any change to its diff (default from
+    /// spr.rebaseMessage). Other updates still prompt for a message
+    #[clap(long, conflicts_with = "message")]
+    rebase_message: Option<String>,
+
     /// Submit this commit and do not cherry-pick it onto any GitHub branch.
     /// An intermediate branch for the parent commit will be created as the
     /// base branch for the PR. Note: Once a PR is created with this option,
@@ -55,799 +155,6430 @@ pub struct DiffOptions {
     #[clap(long)]
     no_cherry_pick: bool,
 
+    /// When a new Pull Request is submitted without cherry-picking, but the
+    /// parent of the commit is a commit on master (so its tree only differs
+    /// from master because master moved on), base the Pull Request on that
+    /// master commit and target master directly, instead of creating an
+    /// intermediate base branch
+    #[clap(long)]
+    prefer_master_base: bool,
+
+    /// When updating a Pull Request that has an intermediate base branch,
+    /// and only the changes of the commit itself changed, keep using that
+    /// base branch as it is rather than creating a new base
+    #[clap(long)]
+    prefer_existing_base: bool,
+
+    /// Base the Pull Request on the given commit on master, instead of the
+    /// current master commit. The commit must be reachable from master.
+    #[clap(long, value_name = "COMMIT", conflicts_with = "base")]
+    master_base: Option<String>,
+
+    /// After creating or updating the Pull Request, print how many more
+    /// approvals it needs to be merged, according to the branch protection
+    /// of the master branch
+    #[clap(long)]
+    show_required_approvals: bool,
+
+    /// If the master branch spr is configured with is not the default
+    /// branch of the repository on GitHub (e.g. after a rename from master
+    /// to main), create new Pull Requests against the default branch
+    /// instead. Without this, spr only warns about the mismatch
+    #[clap(long)]
+    use_default_branch: bool,
+
     /// PR base branch name. Use this to cherry-pick a PR on top of another
     /// PR branch instead of on top of the master branch. This avoids
     /// creating an intermediate base branch for stacked PRs.
     /// Example: spr diff --base <branch-name>. A special HEAD can be
     /// used to indicate that a parent commit should be used as the base.
-    /// For example: spr diff --base HEAD^1
+    /// For example: spr diff --base HEAD^1. Other revisions that refer to a
+    /// branch on GitHub, such as origin/main or @{upstream}, are accepted too
     #[clap(long, short = 'b')]
     base: Option<String>,
 
+    /// When creating a Pull Request on top of another Pull Request, copy
+    /// the labels and reviewers of that Pull Request
+    #[clap(long)]
+    inherit_from_base: bool,
+
+    /// Check that the Pull Requests listed in the Depends-on section of the
+    /// commit message exist (only those of this repository can be checked)
+    #[clap(long)]
+    check_dependencies: bool,
+
     /// Add --no-verify for git push to GitHub. This is useful when you
     /// have a pre-push hook that you want to skip.
     /// For example: spr diff --no-verify
     #[clap(long, short = 'n')]
     no_verify: bool,
+
+    /// Check the reviewers in the commit message against GitHub, but do not
+    /// replace them with the normalised list (including full names) in the
+    /// local commit message
+    #[clap(long)]
+    no_reviewers_writeback: bool,
+
+    /// When updating a Pull Request, remove requested reviewers that are no
+    /// longer listed in the Reviewers section of the commit message (or
+    /// through an alias in it). Reviewers who have already submitted a
+    /// review, code owners of the changed files and reviewers for the size of
+    /// the change are left alone
+    #[clap(long)]
+    prune_reviewers: bool,
+
+    /// How to update the intermediate base branch of a Pull Request that is
+    /// not based on master: 'merge' adds commits on top of the previous base
+    /// (the default, unless configured with git config
+    /// spr.baseBranchStrategy), 'rebase' replaces it with a single commit on
+    /// master
+    #[clap(long, value_enum, value_name = "STRATEGY")]
+    base_branch_strategy: Option<BaseBranchStrategy>,
+
+    /// When a Pull Request against master needs updating because the commit
+    /// was rebased, replace its branch with a single new commit on the new
+    /// master commit (force-pushed, unless someone else pushed to the branch
+    /// in the meantime), instead of merging master into it. The
+    /// branch stays linear, but GitHub can no longer show reviewers what
+    /// changed since their last review
+    #[clap(long)]
+    no_master_merge: bool,
+
+    /// Add a Signed-off-by trailer for the committer to the commits pushed
+    /// to GitHub (the default if configured with git config spr.signoff)
+    #[clap(long)]
+    signoff: bool,
+
+    /// Put the diffstat of the change at the top of the Pull Request
+    /// description, and keep it up to date (the default if configured with
+    /// git config spr.diffstat)
+    #[clap(long)]
+    diffstat: bool,
+
+    /// Follow the first parent of merge commits in the branch, treating
+    /// each merge commit like a single commit with all the changes it merges
+    /// in, instead of refusing to work on the branch (the default if
+    /// configured with git config spr.firstParent)
+    #[clap(long)]
+    first_parent: bool,
+
+    /// Refuse to create a Pull Request without any reviewers to request (the
+    /// default if configured with git config spr.requireReviewers)
+    #[clap(long)]
+    reviewers_required: bool,
+
+    /// When creating a Pull Request, also request reviews from the code
+    /// owners (as listed in the repository's CODEOWNERS file) of the changed
+    /// files: 'suggest' lets you pick from them, 'auto' requests them all
+    #[clap(long, value_enum, value_name = "MODE")]
+    codeowners: Option<CodeOwnersMode>,
+
+    /// Skip commits whose title starts with 'fixup!', 'squash!' or 'amend!'
+    /// instead of creating Pull Requests for them
+    #[clap(long)]
+    skip_fixups: bool,
+
+    /// Only create Pull Requests for commits that don't have one yet, and
+    /// leave commits that are linked to a Pull Request alone
+    #[clap(long)]
+    new_only: bool,
+
+    /// Open the Pull Request in the browser when done. With `--all`, only
+    /// the top Pull Request is opened, unless `--open=all` is given
+    #[clap(
+        long,
+        value_enum,
+        value_name = "WHICH",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "top"
+    )]
+    open: Option<OpenMode>,
+
+    /// Keep running and update the Pull Request for the HEAD commit whenever
+    /// HEAD changes (e.g. after amending the commit), until interrupted with
+    /// Ctrl-C
+    #[clap(long, conflicts_with_all = &["all", "commits"])]
+    watch: bool,
+
+    /// Create or update a single Pull Request for the whole stack of local
+    /// commits, against the master branch, instead of one Pull Request per
+    /// commit. The title and description are taken from the top commit
+    #[clap(long, conflicts_with_all = &["all", "commits", "base", "no-cherry-pick"])]
+    squash_stack: bool,
+
+    /// Retarget existing Pull Requests that are stacked on the branch of a
+    /// Pull Request that is not in the stack any more (e.g. because its
+    /// commit was dropped) onto the nearest Pull Request below them, or
+    /// master. Only applies to branches created by spr (under the branch
+    /// prefix)
+    #[clap(long)]
+    retarget_children: bool,
+
+    /// Only print what would be done for the commits (the HEAD commit, or
+    /// all commits with --all), without changing anything
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Create the commits for the Pull Request branches (and base branches)
+    /// as local branches only, without pushing anything or talking to GitHub,
+    /// so they can be inspected with the usual Git tools. With --all, all
+    /// commits are prepared, without prompting
+    #[clap(long, conflicts_with_all = &["dry-run", "watch", "squash-stack"])]
+    no_push: bool,
+
+    /// Exit with code 6 if none of the Pull Requests needed updating, so
+    /// that scripts can tell when nothing changed
+    #[clap(long)]
+    no_op_exit_code: bool,
+
+    /// When working on several commits, carry on with the next commit after
+    /// one fails, and report all failures at the end
+    #[clap(long)]
+    keep_going: bool,
+
+    /// Fail on commits without any changes (as a rebase can leave behind)
+    /// instead of skipping them with a warning
+    #[clap(long)]
+    fail_on_empty: bool,
+
+    /// When a commit has no Pull Request section, but there is an open Pull
+    /// Request on the branch spr would create for it (e.g. because the
+    /// section got lost in a rebase), and it changes some of the same files,
+    /// link the commit to that Pull Request. With git config spr.reattach,
+    /// spr looks for such Pull Requests on every run, but asks first
+    #[clap(long)]
+    reattach: bool,
+
+    /// Show what is going to be done and ask for confirmation before
+    /// changing anything (the default if configured with git config
+    /// spr.confirm)
+    #[clap(long)]
+    confirm: bool,
+
+    /// Don't ask for confirmation, even if configured with spr.confirm
+    #[clap(long, short = 'y', conflicts_with = "confirm")]
+    assume_yes: bool,
+
+    /// Print which of the cases for updating a Pull Request applies to each
+    /// commit, and why. Useful to understand (and report) unexpected base
+    /// branches
+    #[clap(long)]
+    explain: bool,
+
+    /// Output format for --dry-run
+    #[clap(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value = "text",
+        requires = "dry-run"
+    )]
+    output: OutputFormat,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum CodeOwnersMode {
+    /// Pick the code owners to request reviews from
+    Suggest,
+    /// Request reviews from all code owners
+    Auto,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OpenMode {
+    /// Open the Pull Request of the top-most selected commit
+    Top,
+    /// Open the Pull Requests of all selected commits
+    All,
+}
+
+/// How often `--watch` checks whether HEAD has changed
+const WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 pub async fn diff(
     opts: DiffOptions,
     git: &crate::git::Git,
     gh: &mut crate::github::GitHub,
     config: &crate::config::Config,
 ) -> Result<()> {
-    // Abort right here if the local Git repository is not clean
-    git.check_no_uncommitted_changes()?;
-
-    let mut result = Ok(());
-
-    // Look up the commits on the local branch
-    let mut prepared_commits = git.get_prepared_commits(config, Some(gh))?;
-    let length = prepared_commits.len();
+    let branch_git;
+    let git = if let Some(branch) = &opts.on_branch {
+        branch_git = git.with_branch(branch)?;
+        &branch_git
+    } else {
+        git
+    };
 
-    // The parent of the first commit in the list is the commit on master that
-    // the local branch is based on
-    let master_base_oid = if let Some(first_commit) = prepared_commits.get(0) {
-        first_commit.parent_oid
+    let flags_config;
+    let config = if (opts.signoff && !config.signoff)
+        || (opts.diffstat && !config.diffstat)
+        || (opts.first_parent && !config.first_parent)
+        || (opts.reviewers_required && !config.require_reviewers)
+    {
+        flags_config = crate::config::Config {
+            signoff: config.signoff || opts.signoff,
+            diffstat: config.diffstat || opts.diffstat,
+            first_parent: config.first_parent || opts.first_parent,
+            require_reviewers: config.require_reviewers
+                || opts.reviewers_required,
+            ..config.clone()
+        };
+        &flags_config
     } else {
-        output("👋", "Branch is empty - nothing to do. Good bye!")?;
-        return result;
+        config
     };
 
-    let mut message_on_prompt = "".to_string();
+    if opts.dry_run {
+        return diff_dry_run(&opts, git, gh, config).await;
+    }
 
-    let selected_indexes = if opts.all {
-        let options = prepared_commits
-            .iter()
-            .enumerate()
-            .map(|(i, commit)| {
-                let title = commit
-                    .message
-                    .get(&MessageSection::Title)
-                    .map(|t| &t[..])
-                    .unwrap_or("(untitled)");
-                CommitOption {
-                    message: format!(
-                        "PR #{} - {}",
-                        commit
-                            .pull_request_number
-                            .map(|n| n.to_string())
-                            .unwrap_or_else(|| "??????".to_string()),
-                        title
-                    ),
-                    index: i as isize,
-                }
-            })
-            .rev()
-            .collect::<Vec<CommitOption>>();
+    if opts.no_push {
+        let _lock = git.lock()?;
+        check_before_diff(&opts, git, config)?;
+        return diff_no_push(&opts, git, config);
+    }
 
-        let ans =
-            MultiSelect::new("Select commits to create/update PR:", options)
-                .prompt()?;
+    if opts.watch {
+        return diff_watch(&opts, git, gh, config).await;
+    }
 
-        ans.iter().map(|x| x.index as usize).rev().collect()
-    } else {
-        vec![length - 1]
-    };
+    let _lock = git.lock()?;
+    diff_once(&opts, git, gh, config).await
+}
 
-    // selected_indexes is sorted from lower commits to higher commits
-    for &index in &selected_indexes {
-        if result.is_err() {
-            break;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiffOutcome {
+    Succeeded,
+    /// The Pull Request was up to date already
+    UpToDate,
+    Failed,
+    /// Not attempted, because an earlier commit failed
+    Skipped,
+    /// Not submitted, because the commit has no changes
+    Empty,
+}
+
+/// What `diff_selected` does for each commit
+trait DiffStep {
+    /// Create or update the Pull Request of the commit at the given index
+    async fn diff(&mut self, index: usize) -> Result<DiffOutcome>;
+
+    /// Called for every commit that did not fail
+    fn completed(&mut self, index: usize) -> Result<()>;
+}
+
+/// Run the step for each of the selected commits (given by index, from the
+/// bottom of the stack up), and return the outcome for each. Errors are
+/// collected in `result`; after the first one, the remaining commits are
+/// skipped, unless `keep_going` is set.
+async fn diff_selected<S: DiffStep>(
+    step: &mut S,
+    selected_indexes: &[usize],
+    keep_going: bool,
+    result: &mut Result<()>,
+) -> Result<Vec<(usize, DiffOutcome)>> {
+    let mut outcomes = Vec::new();
+
+    for (position, &index) in selected_indexes.iter().enumerate() {
+        if stop_after_failure(result, keep_going) {
+            outcomes.push((index, DiffOutcome::Skipped));
+            continue;
         }
 
-        // The further implementation of the diff command is in a separate function.
-        // This makes it easier to run the code to update the local commit message
-        // with all the changes that the implementation makes at the end, even if
-        // the implementation encounters an error or exits early.
-        result = diff_impl(
-            &opts,
-            &mut message_on_prompt,
-            git,
-            gh,
-            config,
-            &mut prepared_commits,
-            master_base_oid,
+        if selected_indexes.len() > 1 {
+            write_commit_separator(position + 1, selected_indexes.len())?;
+        }
+
+        let outcome = record_result(result, step.diff(index).await);
+        outcomes.push((index, outcome));
+
+        if outcome != DiffOutcome::Failed {
+            step.completed(index)?;
+        }
+    }
+
+    Ok(outcomes)
+}
+
+/// The step of `diff_once`: `diff_impl` for the commit, and then noting its
+/// Pull Request, for opening it in the browser and for resuming the run
+struct DiffOnceStep<'a> {
+    opts: &'a DiffOptions,
+    message_on_prompt: &'a mut String,
+    git: &'a crate::git::Git,
+    gh: &'a mut crate::github::GitHub,
+    config: &'a crate::config::Config,
+    prepared_commits: &'a mut [PreparedCommit],
+    master_base_oid: Oid,
+    selected_indexes: &'a [usize],
+    pull_request_urls: &'a mut Vec<String>,
+    state: &'a mut Option<DiffState>,
+}
+
+impl DiffStep for DiffOnceStep<'_> {
+    async fn diff(&mut self, index: usize) -> Result<DiffOutcome> {
+        // The further implementation of the diff command is in a separate
+        // function. This makes it easier to run the code to update the local
+        // commit message with all the changes that the implementation makes
+        // at the end, even if the implementation encounters an error or
+        // exits early.
+        diff_impl(
+            self.opts,
+            self.message_on_prompt,
+            self.git,
+            self.gh,
+            self.config,
+            self.prepared_commits,
+            self.master_base_oid,
             index,
-            &selected_indexes,
+            self.selected_indexes,
         )
-        .await;
+        .await
     }
 
-    // This updates the commit message in the local Git repository (if it was
-    // changed by the implementation)
-    add_error(
-        &mut result,
-        git.rewrite_commit_messages(prepared_commits.as_mut_slice(), None),
-    );
+    fn completed(&mut self, index: usize) -> Result<()> {
+        let url = self.prepared_commits[index]
+            .message
+            .get(&MessageSection::PullRequest)
+            .cloned();
+        if let Some(url) = &url {
+            self.pull_request_urls.push(url.clone());
+        }
+        if let Some(state) = self.state {
+            state.complete(index, url);
+            state.save(self.git)?;
+        }
+        Ok(())
+    }
+}
 
-    result
+/// Whether the remaining commits are to be skipped, given the result so far.
+/// Normally the first failure stops the run; with `keep_going`, it doesn't.
+fn stop_after_failure(result: &Result<()>, keep_going: bool) -> bool {
+    result.is_err() && !keep_going
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn diff_impl(
+/// Add the result of `diff` for one commit to the overall result, collecting
+/// the error messages of all failed commits, and return its outcome
+fn record_result(
+    result: &mut Result<()>,
+    commit_result: Result<DiffOutcome>,
+) -> DiffOutcome {
+    add_error(result, commit_result).unwrap_or(DiffOutcome::Failed)
+}
+
+/// Split the selected commits (given by index) into the ones that don't have
+/// a Pull Request yet, and the ones that are linked to one already
+fn split_linked_commits(
+    prepared_commits: &[PreparedCommit],
+    selected_indexes: Vec<usize>,
+) -> (Vec<usize>, Vec<usize>) {
+    selected_indexes.into_iter().partition(|&index| {
+        prepared_commits[index].pull_request_number.is_none()
+    })
+}
+
+/// One line (with icon) per commit, describing the outcome of `diff` for it
+fn diff_summary(
+    config: &crate::config::Config,
+    prepared_commits: &[PreparedCommit],
+    outcomes: &[(usize, DiffOutcome)],
+) -> Vec<(&'static str, String)> {
+    outcomes
+        .iter()
+        .map(|&(index, outcome)| {
+            let commit = &prepared_commits[index];
+            let title = commit
+                .message
+                .get(&MessageSection::Title)
+                .map(|t| &t[..])
+                .unwrap_or("(untitled)");
+            let pull_request = commit
+                .message
+                .get(&MessageSection::PullRequest)
+                .and_then(|text| config.parse_pull_request_field(text))
+                .map(|number| format!(" (#{number})"))
+                .unwrap_or_default();
+            let (icon, status) = match outcome {
+                DiffOutcome::Succeeded => ("✅", "succeeded"),
+                DiffOutcome::UpToDate => ("👌", "up to date"),
+                DiffOutcome::Failed => ("❌", "failed"),
+                DiffOutcome::Skipped => ("⏭️", "skipped"),
+                DiffOutcome::Empty => ("🫙", "skipped (no changes)"),
+            };
+            (
+                icon,
+                format!(
+                    "{} {}{}: {}",
+                    commit.short_id, title, pull_request, status
+                ),
+            )
+        })
+        .collect()
+}
+
+async fn diff_dry_run(
     opts: &DiffOptions,
-    message_on_prompt: &mut String,
     git: &crate::git::Git,
     gh: &mut crate::github::GitHub,
     config: &crate::config::Config,
-    prepared_commits: &mut [PreparedCommit],
-    master_base_oid: Oid,
-    index: usize,
-    selected_indexes: &[usize],
 ) -> Result<()> {
-    write_commit_title(prepared_commits.get_mut(index).unwrap())?;
+    let mut prepared_commits = git.get_prepared_commits(config, Some(gh))?;
+    let mut plan =
+        compute_plan(git, config, &mut prepared_commits, &opts.into()).await?;
+    if let Some(range) = &opts.commits {
+        let indexes = git.select_commit_range(&prepared_commits, range)?;
+        plan.commits = indexes
+            .into_iter()
+            .map(|index| plan.commits[index].clone())
+            .collect();
+    } else if !opts.all {
+        plan.commits.drain(..plan.commits.len().saturating_sub(1));
+    }
 
-    let pull_request = if let Some(task) =
-        &mut prepared_commits.get_mut(index).unwrap().pull_request_task
-    {
-        Some(task.await??)
-    } else {
-        None
+    write_plan(&plan, opts.output)
+}
+
+/// Write the plan to the terminal, where the rest of the output goes, both
+/// for `--dry-run` and before asking to go ahead with it
+fn write_plan(plan: &crate::plan::Plan, format: OutputFormat) -> Result<()> {
+    let text = match format {
+        OutputFormat::Json => crate::plan::render_json(plan)?,
+        OutputFormat::Text => crate::plan::render_text(plan),
     };
+    console::Term::stdout().write_line(&text)?;
+    Ok(())
+}
 
-    let (base_ref, base_pull_request_number) = if let Some(base) = &opts.base {
-        let diff = parse_parent_or_zero(base);
-        if diff == 0 {
-            let base_pull_request_number_result =
-                gh.get_open_pull_request_number_for_head(base.clone()).await;
-            (
-                config.new_github_branch(base),
-                base_pull_request_number_result.ok(),
-            )
-        } else {
-            let base_index = index as isize - diff;
-            if base_index < 0 {
-                (config.master_ref.clone(), None)
-            } else if base_index >= index as isize {
-                return Err(Error::new("Invalid base".to_string()));
-            } else {
-                let pull_request =
-                    get_pull_request_for_index(prepared_commits, base_index)
-                        .await?;
-                (pull_request.head, Some(pull_request.number))
-            }
+impl From<&DiffOptions> for PlanOptions {
+    fn from(opts: &DiffOptions) -> Self {
+        PlanOptions {
+            base: opts.base.clone(),
+            master_base: opts.master_base.clone(),
+            branch_name: opts.branch_name.clone(),
+            no_cherry_pick: opts.no_cherry_pick,
+            prefer_master_base: opts.prefer_master_base,
+            prefer_existing_base: opts.prefer_existing_base,
         }
-    } else if let Some(pull_request) = &pull_request {
-        (pull_request.base.clone(), None)
-    } else if index == 0 {
-        (config.master_ref.clone(), None)
-    } else {
-        let mut options: Vec<CommitOption> = Vec::new();
+    }
+}
 
-        for i in (0..index).rev() {
-            let commit = prepared_commits.get(i).unwrap();
-            let title = commit
-                .message
-                .get(&MessageSection::Title)
-                .map(|t| &t[..])
-                .unwrap_or("(untitled)");
-            options.push(
-                if let Some(pull_request_number) = commit.pull_request_number {
-                    CommitOption {
-                        message: format!(
-                            "PR #{} - {}",
-                            pull_request_number, title
-                        ),
-                        index: i as isize,
-                    }
-                } else {
-                    CommitOption {
-                        message: format!("PR #{} - {}", "?????", title),
-                        index: UNKNOWN_PR_SPECIAL_COMMIT_INDEX,
-                    }
-                },
-            );
-        }
-        options.push(CommitOption {
-            message: config.master_ref.branch_name().to_string(),
-            index: MAIN_SPECIAL_COMMIT_INDEX,
-        });
+/// What `spr diff` with the given options would do for each commit of the
+/// stack. This makes the decisions of `diff_impl`, with the same functions,
+/// but does not prompt: a new Pull Request goes onto master unless a base is
+/// given, which is what the prompt preselects. Nothing is changed locally or
+/// on GitHub. This awaits the Pull Request tasks of the prepared commits.
+pub async fn compute_plan(
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+    prepared_commits: &mut [PreparedCommit],
+    options: &PlanOptions,
+) -> Result<Plan> {
+    let master_base_oid = match prepared_commits.first() {
+        Some(commit) => commit.parent_oid,
+        None => return Ok(Plan { commits: vec![] }),
+    };
 
-        let ans = Select::new("Select a base:", options)
-            .with_starting_cursor(index)
-            .prompt();
+    let mut commits: Vec<PlannedCommit> = Vec::new();
+    for (index, prepared_commit) in prepared_commits.iter_mut().enumerate() {
+        let pull_request = prepared_commit.pull_request().await?;
+        let title = prepared_commit
+            .message
+            .get(&MessageSection::Title)
+            .cloned()
+            .unwrap_or_default();
 
-        match ans {
-            Ok(choice) => match choice.index {
-                MAIN_SPECIAL_COMMIT_INDEX => (config.master_ref.clone(), None),
-                UNKNOWN_PR_SPECIAL_COMMIT_INDEX => {
-                    return Err(Error::new(
-                        "Your selection obviously has no PR created yet"
-                            .to_string(),
-                    ));
-                }
-                choice_index => {
-                    let pull_request = get_pull_request_for_index(
-                        prepared_commits,
-                        choice_index,
+        let requested_base =
+            requested_base(options.base.as_ref(), &prepared_commit.message);
+        let mut tag_base_oid = None;
+        let base_ref = match &requested_base {
+            Some(base) if parse_cross_repo_reference(base).is_none() => {
+                let diff = parse_parent_or_zero(base);
+                let base_index = index as isize - diff;
+                if diff == 0 {
+                    let (branch_name, tag_oid) =
+                        resolve_base_branch(git, config, base)?;
+                    tag_base_oid = tag_oid;
+                    config.new_github_branch(&branch_name)
+                } else if base_index < 0 {
+                    config.master_ref.clone()
+                } else if base_index >= index as isize {
+                    return Err(Error::new("Invalid base".to_string()));
+                } else {
+                    // The Pull Request of a commit below, which is created
+                    // first if it does not exist yet
+                    config.new_github_branch(
+                        &commits[base_index as usize].head_branch,
                     )
-                    .await?;
-                    (pull_request.head, Some(pull_request.number))
                 }
-            },
-            Err(_) => {
-                return Err(Error::new(
-                    "Aborted as per user request".to_string(),
-                ));
             }
+            // A Pull Request in another repository is only recorded as a
+            // dependency, the Pull Request targets master
+            Some(_) => config.master_ref.clone(),
+            None => pull_request.as_ref().map_or_else(
+                || config.master_ref.clone(),
+                |pr| pr.base.clone(),
+            ),
+        };
+
+        let current_master_oid = git
+            .resolve_reference(base_ref.local())
+            .unwrap_or(master_base_oid);
+        let master_base_oid = match (&options.master_base, tag_base_oid) {
+            (Some(revision), _) => {
+                pinned_master_base(git, revision, current_master_oid)?
+            }
+            (None, Some(oid)) => oid,
+            (None, None) => current_master_oid,
+        };
+
+        let (action, base_branch) = planned_action(
+            git,
+            config,
+            options,
+            prepared_commit,
+            pull_request.as_ref(),
+            requested_base.is_some(),
+            &base_ref,
+            current_master_oid,
+            master_base_oid,
+            &title,
+        )?;
+        let head_branch = match &pull_request {
+            Some(pr) => pr.head.clone(),
+            None => {
+                let branch = new_pull_request_branch(
+                    config,
+                    &*git.get_all_ref_names()?,
+                    options.branch_name.as_deref(),
+                    &title,
+                )?;
+                git.add_ref_name(branch.local());
+                branch
+            }
+        };
+
+        commits.push(PlannedCommit {
+            commit: prepared_commit.oid.to_string(),
+            title,
+            action,
+            pull_request: pull_request.map(|pr| pr.number),
+            base_branch: base_branch.branch_name().to_string(),
+            head_branch: head_branch.branch_name().to_string(),
+        });
+    }
+
+    // The branch names picked for the plan are not taken
+    git.forget_ref_names();
+
+    Ok(Plan { commits })
+}
+
+/// What `diff_impl` does for a commit on the given base, and the branch the
+/// Pull Request ends up based on: the base, or an intermediate base branch.
+#[allow(clippy::too_many_arguments)]
+fn planned_action(
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+    options: &PlanOptions,
+    prepared_commit: &PreparedCommit,
+    pull_request: Option<&crate::github::PullRequest>,
+    explicit_base: bool,
+    base_ref: &GitHubBranch,
+    current_master_oid: Oid,
+    master_base_oid: Oid,
+    title: &str,
+) -> Result<(PlannedAction, GitHubBranch)> {
+    let (new_head_tree, new_base_tree, no_cherry_pick) = match new_trees(
+        git,
+        prepared_commit.oid,
+        prepared_commit.parent_oid,
+        master_base_oid,
+        base_ref.branch_name(),
+        options.no_cherry_pick,
+        config.auto_no_cherry_pick,
+    ) {
+        Ok(trees) => trees,
+        Err(error) if error.kind() == ErrorKind::Conflict => {
+            return Ok((PlannedAction::Conflict, base_ref.clone()));
+        }
+        Err(error) => return Err(error),
+    };
+
+    let (action, update_case, base_branch) = match pull_request {
+        None => {
+            let master_base_oid = new_pull_request_master_base(
+                git,
+                options.prefer_master_base,
+                no_cherry_pick,
+                prepared_commit.parent_oid,
+                master_base_oid,
+            )?;
+            let update_case = choose_update_case(
+                git.get_tree_oid_for_commit(master_base_oid)? == new_base_tree,
+                false,
+                false,
+                no_cherry_pick,
+            );
+            (PlannedAction::Create, update_case, None)
+        }
+        Some(pr) => {
+            let pr_head_tree = git.get_tree_oid_for_commit(pr.head_oid)?;
+            let pr_base_oid =
+                git.repo().merge_base(pr.head_oid, pr.base_oid)?;
+            let pr_base_tree = git.get_tree_oid_for_commit(pr_base_oid)?;
+            let pr_master_base =
+                git.repo().merge_base(pr.head_oid, current_master_oid)?;
+            let needs_merging_master = pr_master_base != master_base_oid;
+
+            if !needs_merging_master
+                && pr_head_tree == new_head_tree
+                && pr_base_tree == new_base_tree
+            {
+                return Ok((PlannedAction::NoOp, pr.base.clone()));
+            }
+
+            let base_branch = existing_base_branch(
+                config,
+                &pr.base,
+                explicit_base,
+                no_cherry_pick,
+            )
+            .or_else(|| {
+                reusable_base_branch(
+                    config,
+                    &pr.base,
+                    options.prefer_existing_base,
+                    pr_base_tree == new_base_tree,
+                    needs_merging_master,
+                )
+            });
+            let update_case = choose_update_case(
+                pr_base_tree == new_base_tree,
+                needs_merging_master,
+                base_branch.is_some(),
+                no_cherry_pick,
+            );
+            let action = match update_case {
+                UpdateCase::Unchanged => PlannedAction::Update,
+                UpdateCase::MergeMaster => PlannedAction::MergeMaster,
+                UpdateCase::BaseBranch => PlannedAction::UpdateBase,
+            };
+            (action, update_case, base_branch)
+        }
+    };
+
+    // As in `base_branch_update`, a new intermediate base branch gets a name
+    // derived from the title
+    let base_branch = match (update_case, base_branch) {
+        (UpdateCase::MergeMaster, _) => None,
+        (UpdateCase::BaseBranch, None) => {
+            let branch = config.new_github_branch(
+                &config.get_base_branch_name(&*git.get_all_ref_names()?, title),
+            );
+            git.add_ref_name(branch.local());
+            Some(branch)
+        }
+        (_, base_branch) => base_branch,
+    };
+
+    Ok((action, base_branch.unwrap_or_else(|| base_ref.clone())))
+}
+
+/// `spr diff --no-push`: create the commits a new Pull Request would get, and
+/// point local branches of the same names at them. Nothing is pushed, and
+/// GitHub is not asked about anything, so commits that link to a Pull
+/// Request already are skipped: the branch of that Pull Request is unknown.
+fn diff_no_push(
+    opts: &DiffOptions,
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+) -> Result<()> {
+    // The branch names picked in an earlier run are not taken
+    git.forget_ref_names();
+
+    let mut prepared_commits = git.get_prepared_commits(config, None)?;
+    let master_base_oid = match prepared_commits.first() {
+        Some(commit) => commit.parent_oid,
+        None => {
+            output("👋", "Branch is empty - nothing to do. Good bye!")?;
+            return Ok(());
+        }
+    };
+
+    let selected_indexes = if let Some(range) = &opts.commits {
+        git.select_commit_range(&prepared_commits, range)?
+    } else if opts.all {
+        (0..prepared_commits.len()).collect()
+    } else {
+        vec![prepared_commits.len() - 1]
+    };
+
+    // The Pull Request branches created so far, with their commits, for the
+    // commits above to be stacked on
+    let mut created = std::collections::HashMap::new();
+
+    for index in selected_indexes {
+        let prepared_commit = &prepared_commits[index];
+        write_commit_title(prepared_commit, None)?;
+        if let Some(number) = prepared_commit.pull_request_number {
+            output(
+                "⏭️",
+                &format!(
+                    "Linked to #{number} already - skipping, as its branch \
+                     is only known to GitHub"
+                ),
+            )?;
+            continue;
+        }
+
+        let (base_ref, base_oid) = no_push_base(
+            opts,
+            git,
+            config,
+            &prepared_commits,
+            index,
+            &created,
+            master_base_oid,
+        )?;
+        let (head_branch, pr_commit, base_branch) =
+            create_local_pull_request_branches(
+                opts,
+                git,
+                config,
+                prepared_commit,
+                &base_ref,
+                base_oid,
+            )?;
+        if !base_ref.is_master_branch() {
+            output("🪜", &format!("Based on {}", base_ref.branch_name()))?;
+        }
+        output("🌿", &format!("Local branch {}", head_branch.branch_name()))?;
+        if let Some(base_branch) = base_branch {
+            output(
+                "🌿",
+                &format!("Local base branch {}", base_branch.branch_name()),
+            )?;
+        }
+        created.insert(index, (head_branch, pr_commit));
+    }
+
+    git.rewrite_commit_messages(prepared_commits.as_mut_slice(), None)
+}
+
+/// The base of the Pull Request for the commit at the given index, and the
+/// commit to build on, for `spr diff --no-push`. As in `diff_impl`, that is
+/// master, the base given with --base (or in the commit message), or with a
+/// relative --base (e.g. `HEAD^`) the Pull Request of a commit below, which
+/// must be one that this run has created a local branch for.
+fn no_push_base(
+    opts: &DiffOptions,
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+    prepared_commits: &[PreparedCommit],
+    index: usize,
+    created: &std::collections::HashMap<usize, (GitHubBranch, Oid)>,
+    master_base_oid: Oid,
+) -> Result<(GitHubBranch, Oid)> {
+    let base = match requested_base(
+        opts.base.as_ref(),
+        &prepared_commits[index].message,
+    ) {
+        // A Pull Request in another repository is only recorded as a
+        // dependency, the Pull Request targets master
+        Some(base) if parse_cross_repo_reference(&base).is_none() => base,
+        _ => return Ok((config.master_ref.clone(), master_base_oid)),
+    };
+
+    let diff = parse_parent_or_zero(&base);
+    if diff == 0 {
+        let (branch_name, tag_oid) = resolve_base_branch(git, config, &base)?;
+        if tag_oid.is_some() {
+            output(
+                "🏷️",
+                &format!(
+                    "Basing on tag {base}, targeting branch {branch_name}"
+                ),
+            )?;
+        }
+        let base_ref = config.new_github_branch(&branch_name);
+        let oid = match tag_oid {
+            Some(oid) => oid,
+            None => git
+                .resolve_reference(base_ref.local())
+                .unwrap_or(master_base_oid),
+        };
+        return Ok((base_ref, oid));
+    }
+
+    let base_index = index as isize - diff;
+    if base_index < 0 {
+        Ok((config.master_ref.clone(), master_base_oid))
+    } else if base_index >= index as isize {
+        Err(Error::new("Invalid base".to_string()))
+    } else {
+        created.get(&(base_index as usize)).cloned().ok_or_else(|| {
+            Error::new(format!(
+                "Commit {} has no local Pull Request branch to base this one \
+                 on - include it in the selection",
+                prepared_commits[base_index as usize].short_id
+            ))
+        })
+    }
+}
+
+/// Create the commit of the Pull Request branch for a new Pull Request on
+/// the given base (and of the base branch, if it needs one), the way
+/// `diff_impl` does, and point local branches at them. Returns the Pull
+/// Request branch with its commit, and the base branch if there is one.
+fn create_local_pull_request_branches(
+    opts: &DiffOptions,
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+    prepared_commit: &PreparedCommit,
+    base_ref: &GitHubBranch,
+    master_base_oid: Oid,
+) -> Result<(GitHubBranch, Oid, Option<GitHubBranch>)> {
+    let title = prepared_commit
+        .message
+        .get(&MessageSection::Title)
+        .map(|t| &t[..])
+        .unwrap_or("");
+    let (new_head_tree, new_base_tree, no_cherry_pick) = new_trees(
+        git,
+        prepared_commit.oid,
+        prepared_commit.parent_oid,
+        master_base_oid,
+        base_ref.branch_name(),
+        opts.no_cherry_pick,
+        config.auto_no_cherry_pick,
+    )?;
+    let master_base_oid = new_pull_request_master_base(
+        git,
+        opts.prefer_master_base,
+        no_cherry_pick,
+        prepared_commit.parent_oid,
+        master_base_oid,
+    )?;
+
+    let head_branch = new_pull_request_branch(
+        config,
+        &*git.get_all_ref_names()?,
+        opts.branch_name.as_deref(),
+        title,
+    )?;
+    git.add_ref_name(head_branch.local());
+
+    // A new Pull Request has no base branch yet, and nothing to merge in
+    let update_case = choose_update_case(
+        git.get_tree_oid_for_commit(master_base_oid)? == new_base_tree,
+        false,
+        false,
+        no_cherry_pick,
+    );
+    let (pr_base_parent, base_branch) = base_branch_update(
+        git,
+        config,
+        prepared_commit.oid,
+        update_case,
+        None,
+        master_base_oid,
+        base_branch_commit_parents(
+            opts.base_branch_strategy
+                .unwrap_or(config.base_branch_strategy),
+            master_base_oid,
+            master_base_oid,
+            false,
+        ),
+        new_base_tree,
+        false,
+        title,
+    )?;
+    let pr_commit = create_pull_request_commit(
+        git,
+        config,
+        prepared_commit.oid,
+        None,
+        new_head_tree,
+        &pull_request_commit_parents(master_base_oid, pr_base_parent, false),
+    )?;
+
+    set_local_branch(git, head_branch.branch_name(), pr_commit)?;
+    if let (Some(branch), Some(oid)) = (&base_branch, pr_base_parent) {
+        set_local_branch(git, branch.branch_name(), oid)?;
+    }
+
+    Ok((head_branch, pr_commit, base_branch))
+}
+
+const NO_PUSH_REFLOG_MESSAGE: &str = "spr diff --no-push";
+
+/// Point the local branch of the given name at the given commit. A branch
+/// that exists already is only moved if `spr diff --no-push` put it where it
+/// is, so that running that again updates its branches, but leaves any
+/// other branch alone.
+fn set_local_branch(git: &crate::git::Git, name: &str, oid: Oid) -> Result<()> {
+    let repo = git.repo();
+    let ref_name = format!("refs/heads/{name}");
+    let current_oid = match repo.find_reference(&ref_name) {
+        Ok(reference) => reference.target(),
+        Err(error) if error.code() == git2::ErrorCode::NotFound => {
+            repo.reference(&ref_name, oid, false, NO_PUSH_REFLOG_MESSAGE)?;
+            return Ok(());
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    let created_by_us = repo
+        .reflog(&ref_name)?
+        .get(0)
+        .and_then(|entry| entry.message().map(String::from))
+        .is_some_and(|message| message == NO_PUSH_REFLOG_MESSAGE);
+    match current_oid {
+        Some(current_oid) if created_by_us => {
+            repo.reference_matching(
+                &ref_name,
+                oid,
+                true,
+                current_oid,
+                NO_PUSH_REFLOG_MESSAGE,
+            )?;
+            Ok(())
+        }
+        _ => Err(Error::new(format!(
+            "The local branch {name} exists already, and was not created by \
+             spr diff --no-push - please rename or delete it"
+        ))),
+    }
+}
+
+/// Run `diff_once` every time HEAD changes, until the user presses Ctrl-C.
+/// Each run takes the lock on its own, so that other spr commands can be run
+/// while we are waiting.
+async fn diff_watch(
+    opts: &DiffOptions,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    output("👀", "Watching HEAD for changes. Press Ctrl-C to stop.")?;
+
+    // Listen for Ctrl-C once, for the whole watch. The handler is installed
+    // on the first poll, from when on Ctrl-C no longer kills the process (and
+    // with it a run holding the lock). Pressed during a run, that run
+    // completes and the watch ends after it.
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    if futures::poll!(&mut ctrl_c).is_ready() {
+        return Ok(());
+    }
+
+    let mut last_head = None;
+    loop {
+        let head = git.head()?;
+        if last_head != Some(head) {
+            let result = async {
+                let _lock = git.lock()?;
+                diff_once(opts, git, gh, config).await
+            }
+            .await;
+
+            // An error should not end the watch, the user may well fix the
+            // problem and amend the commit again.
+            if let Err(error) = result {
+                for message in error.messages() {
+                    output("🛑", message)?;
+                }
+            }
+
+            // The run may have rewritten the commit message and with it HEAD,
+            // so only now take note of the commit we have seen.
+            last_head = Some(git.head()?);
+            output("👀", "Waiting for HEAD to change...")?;
+        }
+
+        if !wait_for_next_check(ctrl_c.as_mut(), WATCH_INTERVAL).await {
+            output("👋", "Stopped watching. Good bye!")?;
+            return Ok(());
+        }
+    }
+}
+
+/// Wait for the given interval before HEAD is checked again. Returns false
+/// if `stop` completes first, or has completed while it was not polled.
+async fn wait_for_next_check<S: std::future::Future>(
+    stop: std::pin::Pin<&mut S>,
+    interval: std::time::Duration,
+) -> bool {
+    tokio::select! {
+        _ = stop => false,
+        _ = tokio::time::sleep(interval) => true,
+    }
+}
+
+/// The checks before a run changes anything, locally or on GitHub
+fn check_before_diff(
+    opts: &DiffOptions,
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+) -> Result<()> {
+    // Abort right here if the local Git repository is not clean. Neither
+    // that nor a detached HEAD matters when working on another branch.
+    if opts.on_branch.is_none() {
+        git.check_no_uncommitted_changes()?;
+    }
+
+    git.check_master_ref(config)?;
+
+    // A detached HEAD would leave it unclear where the updated commits go
+    if opts.on_branch.is_none()
+        && git.check_head_is_attached(opts.branch.as_deref()).context(
+            "Alternatively, run `spr diff --branch <name>` to create a branch \
+             at HEAD"
+                .to_string(),
+        )?
+    {
+        output(
+            "🌱",
+            &format!(
+                "Created and checked out branch '{}'",
+                opts.branch.as_deref().unwrap_or_default()
+            ),
+        )?;
+    }
+
+    // Before anything is requested from GitHub
+    git.check_stack_depth(config)
+}
+
+async fn diff_once(
+    opts: &DiffOptions,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    // References may have changed since the last run (in watch mode)
+    git.forget_ref_names();
+
+    check_before_diff(opts, git, config)?;
+
+    let mut result = Ok(());
+
+    // Look up the commits on the local branch
+    let mut prepared_commits = git.get_prepared_commits(config, Some(gh))?;
+
+    // New Pull Requests should not target a stale master branch
+    let default_branch_config;
+    let config = if prepared_commits
+        .iter()
+        .any(|commit| commit.pull_request_number.is_none())
+    {
+        match check_default_branch(opts, gh, config).await? {
+            Some(corrected) => {
+                default_branch_config = corrected;
+                git.check_master_ref(&default_branch_config)?;
+                prepared_commits =
+                    git.get_prepared_commits(&default_branch_config, Some(gh))?;
+                &default_branch_config
+            }
+            None => config,
         }
+    } else {
+        config
+    };
+    let length = prepared_commits.len();
+
+    // The parent of the first commit in the list is the commit on master that
+    // the local branch is based on
+    let master_base_oid = if let Some(first_commit) = prepared_commits.first() {
+        first_commit.parent_oid
+    } else {
+        output("👋", "Branch is empty - nothing to do. Good bye!")?;
+        return result;
     };
 
-    let local_commit = prepared_commits.get_mut(index).unwrap();
+    // Squashing the stack creates or updates the one Pull Request of the top
+    // commit
+    if opts.squash_stack {
+        confirm_selection(
+            opts,
+            git,
+            config,
+            &mut prepared_commits,
+            &[length - 1],
+            ask_to_confirm,
+        )
+        .await?;
+        return diff_squash_stack(
+            opts,
+            git,
+            &*gh,
+            config,
+            &mut prepared_commits,
+            master_base_oid,
+        )
+        .await;
+    }
+
+    let mut message_on_prompt = "".to_string();
+    let mut pull_request_urls = Vec::new();
+
+    let range_indexes = opts
+        .commits
+        .as_deref()
+        .map(|range| git.select_commit_range(&prepared_commits, range))
+        .transpose()?;
+
+    if opts.skip_fixups {
+        let candidates = if let Some(indexes) = &range_indexes {
+            indexes.clone()
+        } else if opts.all {
+            (0..length).collect()
+        } else {
+            vec![length - 1]
+        };
+        for commit in candidates
+            .into_iter()
+            .map(|index| &prepared_commits[index])
+            .filter(|commit| commit.is_fixup())
+        {
+            output(
+                "⏭️",
+                &format!("Skipping fixup commit {}", commit.short_id),
+            )?;
+        }
+    }
+
+    // Offer to pick up an interrupted `--all` run where it stopped
+    let head = git.head()?;
+    let mut resume_state = None;
+    if opts.all {
+        if let Some(state) = resumable_state(git, head, length)? {
+            if Confirm::new(&format!(
+                "Resume the interrupted run ({} of {} commits done)?",
+                state.completed.len(),
+                state.selected.len()
+            ))
+            .with_default(true)
+            .prompt()
+            .with_kind(ErrorKind::Aborted)?
+            {
+                restore_completed_commits(
+                    gh,
+                    config,
+                    &mut prepared_commits,
+                    &state,
+                );
+                resume_state = Some(state);
+            } else {
+                DiffState::clear(git)?;
+            }
+        }
+    }
+
+    let selected_indexes = if let Some(state) = &resume_state {
+        state.remaining()
+    } else if opts.all {
+        let options = prepared_commits
+            .iter()
+            .enumerate()
+            .filter(|(_, commit)| !(opts.skip_fixups && commit.is_fixup()))
+            .filter(|(_, commit)| {
+                !(opts.new_only && commit.pull_request_number.is_some())
+            })
+            .map(|(i, commit)| {
+                let title = commit
+                    .message
+                    .get(&MessageSection::Title)
+                    .map(|t| &t[..])
+                    .unwrap_or("(untitled)");
+                CommitOption {
+                    message: format!(
+                        "PR #{} - {}",
+                        commit
+                            .pull_request_number
+                            .map(|n| n.to_string())
+                            .unwrap_or_else(|| "??????".to_string()),
+                        title
+                    ),
+                    index: i as isize,
+                }
+            })
+            .rev()
+            .collect::<Vec<CommitOption>>();
+
+        let ans =
+            MultiSelect::new("Select commits to create/update PR:", options)
+                .prompt()
+                .with_kind(ErrorKind::Aborted)?;
+
+        ans.iter().map(|x| x.index as usize).rev().collect()
+    } else if let Some(indexes) = range_indexes {
+        indexes
+            .into_iter()
+            .filter(|&index| {
+                !(opts.skip_fixups && prepared_commits[index].is_fixup())
+            })
+            .collect()
+    } else if opts.skip_fixups && prepared_commits[length - 1].is_fixup() {
+        vec![]
+    } else {
+        vec![length - 1]
+    };
+
+    let selected_indexes = if opts.new_only && resume_state.is_none() {
+        let (selected_indexes, linked) =
+            split_linked_commits(&prepared_commits, selected_indexes);
+        for index in linked {
+            output(
+                "⏭️",
+                &format!(
+                    "Skipping {}, which is linked to a Pull Request already",
+                    prepared_commits[index].short_id
+                ),
+            )?;
+        }
+        selected_indexes
+    } else {
+        selected_indexes
+    };
+
+    // GitHub rejects Pull Requests whose bases form a cycle, which could
+    // happen after reordering commits that were stacked with `--base`
+    if let Some(cycle) = find_base_cycle(
+        &planned_base_branches(opts, &mut prepared_commits, &selected_indexes)
+            .await,
+    ) {
+        return Err(Error::new(format!(
+            "The base branches of the Pull Requests would form a cycle: {}",
+            cycle.join(" -> ")
+        )));
+    }
+
+    confirm_selection(
+        opts,
+        git,
+        config,
+        &mut prepared_commits,
+        &selected_indexes,
+        ask_to_confirm,
+    )
+    .await?;
+
+    let mut state = opts.all.then(|| {
+        resume_state.unwrap_or_else(|| {
+            DiffState::new(head, length, selected_indexes.clone())
+        })
+    });
+
+    let outcomes = diff_selected(
+        &mut DiffOnceStep {
+            opts,
+            message_on_prompt: &mut message_on_prompt,
+            git,
+            gh,
+            config,
+            prepared_commits: &mut prepared_commits,
+            master_base_oid,
+            selected_indexes: &selected_indexes,
+            pull_request_urls: &mut pull_request_urls,
+            state: &mut state,
+        },
+        &selected_indexes,
+        opts.keep_going,
+        &mut result,
+    )
+    .await?;
+
+    // This updates the commit message in the local Git repository (if it was
+    // changed by the implementation)
+    add_error(
+        &mut result,
+        git.rewrite_commit_messages(prepared_commits.as_mut_slice(), None),
+    );
+
+    // Rewriting the commit messages moved HEAD, so the state has to refer to
+    // the new one. After full success, there is nothing left to resume.
+    if let Some(mut state) = state {
+        if result.is_ok() {
+            DiffState::clear(git)?;
+        } else {
+            state.head = git.head()?.to_string();
+            state.save(git)?;
+            output(
+                "💾",
+                "Run `spr diff --all` again to resume where this run stopped",
+            )?;
+        }
+    }
+
+    // When working on several commits, sum up what happened to each of them,
+    // as an error would otherwise hide how far we got
+    if outcomes.len() > 1 {
+        output("📋", "Summary:")?;
+        for (icon, line) in diff_summary(config, &prepared_commits, &outcomes) {
+            output(icon, &line)?;
+        }
+    }
+
+    match opts.open {
+        Some(OpenMode::All) => {
+            for url in &pull_request_urls {
+                open_in_browser(url)?;
+            }
+        }
+        Some(OpenMode::Top) => {
+            if let Some(url) = pull_request_urls.last() {
+                open_in_browser(url)?;
+            }
+        }
+        None => (),
+    }
+
+    if result.is_ok() && opts.no_op_exit_code && all_up_to_date(&outcomes) {
+        return Err(Error::empty().with_kind(ErrorKind::UpToDate));
+    }
+
+    result
+}
+
+/// Whether there were commits to update, but all their Pull Requests were up
+/// to date already (or the commits had no changes to submit)
+fn all_up_to_date(outcomes: &[(usize, DiffOutcome)]) -> bool {
+    !outcomes.is_empty()
+        && outcomes.iter().all(|(_, outcome)| {
+            matches!(outcome, DiffOutcome::UpToDate | DiffOutcome::Empty)
+        })
+}
+
+/// The trees the Pull Request branch and its base branch should have, and
+/// whether the commit is submitted without cherry-picking it. Normally that
+/// is the commit cherry-picked onto master, and master. With
+/// `no_cherry_pick`, or if the cherry-pick conflicts and
+/// `fall_back_on_conflict` is set, it is the commit and its parent as they
+/// are. A conflict is an error of kind `Conflict` otherwise.
+fn new_trees(
+    git: &crate::git::Git,
+    commit_oid: Oid,
+    parent_oid: Oid,
+    master_base_oid: Oid,
+    master_name: &str,
+    no_cherry_pick: bool,
+    fall_back_on_conflict: bool,
+) -> Result<(Oid, Oid, bool)> {
+    if !no_cherry_pick {
+        // Cherry-pick the current commit onto master
+        let index = git.cherrypick(commit_oid, master_base_oid)?;
+
+        if !index.has_conflicts() {
+            // This is the tree we are getting from cherrypicking the local
+            // commit on master.
+            let cherry_pick_tree = git.write_index(index)?;
+            let master_tree = git.get_tree_oid_for_commit(master_base_oid)?;
+
+            return Ok((cherry_pick_tree, master_tree, false));
+        }
+        if !fall_back_on_conflict {
+            return Err(Error::new(formatdoc!(
+                "This commit cannot be cherry-picked on {master_name}.",
+            ))
+            .with_kind(ErrorKind::Conflict));
+        }
+    }
+
+    // Without cherry-picking, these are the trees of the commit and its
+    // parent.
+    let head_tree = git.get_tree_oid_for_commit(commit_oid)?;
+    let base_tree = git.get_tree_oid_for_commit(parent_oid)?;
+
+    Ok((head_tree, base_tree, true))
+}
+
+/// The branch on GitHub a base given by name refers to. The base may be the
+/// name of a branch on GitHub, or any revision that refers to one (e.g.
+/// `origin/main` or `@{upstream}`). For a tag, this is a branch that contains
+/// the tagged commit, together with that commit to build on.
+fn resolve_base_branch(
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+    base: &str,
+) -> Result<(String, Option<Oid>)> {
+    let remote_branch = format!("refs/remotes/{}/{}", config.remote_name, base);
+    if git.resolve_reference(&remote_branch).is_ok() {
+        return Ok((base.to_string(), None));
+    }
+    if let Some((oid, branch_name)) = git.resolve_tag_base(config, base)? {
+        // GitHub Pull Requests target branches, not tags, so we target a
+        // branch that contains the tagged commit, but build on the tagged
+        // commit itself.
+        return Ok((branch_name, Some(oid)));
+    }
+    Ok((
+        git.resolve_github_branch_name(config, base)?
+            .unwrap_or_else(|| base.to_string()),
+        None,
+    ))
+}
+
+/// The master commit a new Pull Request is based on. That is normally the
+/// current master commit. With `prefer_master_base`, a commit submitted
+/// without cherry-picking whose parent is an older commit on master is based
+/// on that parent instead: the parent tree then matches the base tree, so no
+/// intermediate base branch is created and the Pull Request targets master.
+fn new_pull_request_master_base(
+    git: &crate::git::Git,
+    prefer_master_base: bool,
+    no_cherry_pick: bool,
+    parent_oid: Oid,
+    master_base_oid: Oid,
+) -> Result<Oid> {
+    if prefer_master_base
+        && no_cherry_pick
+        && git
+            .repo()
+            .graph_descendant_of(master_base_oid, parent_oid)?
+    {
+        Ok(parent_oid)
+    } else {
+        Ok(master_base_oid)
+    }
+}
+
+/// The commit given with `--master-base`, which must be reachable from the
+/// current master commit
+fn pinned_master_base(
+    git: &crate::git::Git,
+    revision: &str,
+    master_oid: Oid,
+) -> Result<Oid> {
+    let oid = git.resolve_revision(revision).map_err(|_| {
+        Error::new(format!("Could not resolve --master-base {revision}"))
+    })?;
+    if oid == master_oid || git.repo().graph_descendant_of(master_oid, oid)? {
+        Ok(oid)
+    } else {
+        Err(Error::new(format!(
+            "--master-base {revision} is not reachable from master"
+        )))
+    }
+}
+
+/// Compare the configured master branch with the default branch of the
+/// repository on GitHub. On a mismatch, warn, or with `--use-default-branch`
+/// return a copy of the config that has the default branch as master.
+async fn check_default_branch(
+    opts: &DiffOptions,
+    gh: &crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<Option<crate::config::Config>> {
+    let default_branch = gh.get_default_branch().await?;
+    let branch = match default_branch_mismatch(config, default_branch) {
+        Some(branch) => branch,
+        None => return Ok(None),
+    };
+    let master = config.master_ref.branch_name();
+
+    if !opts.use_default_branch {
+        output(
+            "⚠️",
+            &format!(
+                "New Pull Requests target '{master}', but the default branch \
+                 of the repository is '{branch}'. Run with \
+                 --use-default-branch to target '{branch}' instead, or set \
+                 `git config spr.githubMasterBranch {branch}`"
+            ),
+        )?;
+        return Ok(None);
+    }
+
+    output(
+        "🔀",
+        &format!(
+            "Using the default branch '{branch}' instead of '{master}' for \
+             new Pull Requests"
+        ),
+    )?;
+    let mut config = config.clone();
+    config.master_ref = GitHubBranch::new_from_branch_name(
+        &branch,
+        &config.remote_name,
+        &branch,
+    );
+    Ok(Some(config))
+}
+
+/// The default branch of the repository, if it is not the master branch spr
+/// is configured with
+fn default_branch_mismatch(
+    config: &crate::config::Config,
+    default_branch: Option<String>,
+) -> Option<String> {
+    default_branch.filter(|branch| branch != config.master_ref.branch_name())
+}
+
+/// The parents of a new commit for the Pull Request branch. The first parent
+/// is the current head commit of the Pull Request (the master base commit if
+/// the Pull Request does not exist yet). If we prepared a commit that needs
+/// merging into the Pull Request branch (`pr_base_parent`), that is a parent
+/// as well. With `replace_pr_branch`, it is the only parent, so the new
+/// commit is not a merge but replaces the Pull Request branch.
+fn pull_request_commit_parents(
+    pr_head_oid: Oid,
+    pr_base_parent: Option<Oid>,
+    replace_pr_branch: bool,
+) -> Vec<Oid> {
+    match pr_base_parent {
+        Some(oid) if replace_pr_branch => vec![oid],
+        // No duplicates in the parents
+        Some(oid) if oid != pr_head_oid => vec![pr_head_oid, oid],
+        _ => vec![pr_head_oid],
+    }
+}
+
+/// The parents of a new commit for an intermediate base branch. The merge
+/// strategy builds on the current base commit (the top of an existing base
+/// branch, or a commit on master), merging in the master commit if we were
+/// rebased. The rebase strategy starts afresh from the master commit.
+fn base_branch_commit_parents(
+    strategy: BaseBranchStrategy,
+    pr_base_oid: Oid,
+    master_base_oid: Oid,
+    needs_merging_master: bool,
+) -> Vec<Oid> {
+    match strategy {
+        BaseBranchStrategy::Merge => {
+            let mut parents = vec![pr_base_oid];
+            // No duplicates in `parents`
+            if needs_merging_master && pr_base_oid != master_base_oid {
+                parents.push(master_base_oid);
+            }
+            parents
+        }
+        BaseBranchStrategy::Rebase => vec![master_base_oid],
+    }
+}
+
+/// The message of a new commit for an intermediate base branch. Whether a
+/// base branch needs a new commit is decided by comparing trees only, so
+/// changing `base_branch_skip_ci` doesn't cause new commits by itself.
+fn base_branch_commit_message(
+    config: &crate::config::Config,
+    updating: bool,
+) -> String {
+    let mut message = format!(
+        "[𝘀𝗽𝗿] {}\n\nCreated using spr {}",
+        if updating {
+            "changes introduced through rebase".to_string()
+        } else {
+            format!(
+                "changes to {} this commit is based on",
+                config.master_ref.branch_name()
+            )
+        },
+        env!("CARGO_PKG_VERSION"),
+    );
+    if config.base_branch_skip_ci {
+        message.push_str("\n\n[skip ci]");
+    }
+
+    message
+}
+
+/// Create a new commit for the intermediate base branch. Its author is that
+/// of the local commit (unless spr.derivedCommitAuthor is set), like for the
+/// commit on the Pull Request branch, rather than whoever authored the parent
+/// commit. Together with spr.signoff, that keeps DCO checks happy with every
+/// commit of the Pull Request.
+fn create_base_branch_commit(
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+    local_commit_oid: Oid,
+    tree_oid: Oid,
+    parent_oids: &[Oid],
+    updating: bool,
+) -> Result<Oid> {
+    git.create_derived_commit(
+        local_commit_oid,
+        &git.derived_commit_message(
+            config,
+            base_branch_commit_message(config, updating),
+        )?,
+        tree_oid,
+        parent_oids,
+        config.derived_commit_author.as_ref(),
+        config.derived_commit_committer.as_ref(),
+    )
+}
+
+/// The commit to merge into the Pull Request branch, and the base branch, for
+/// the given update case (see the comment in `diff_impl`). In case 2, that is
+/// the master commit. In case 3, this creates the new commit for the base
+/// branch (with the given parents), and picks a name for the base branch if
+/// there is none yet.
+#[allow(clippy::too_many_arguments)]
+fn base_branch_update(
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+    local_commit_oid: Oid,
+    update_case: UpdateCase,
+    base_branch: Option<GitHubBranch>,
+    master_base_oid: Oid,
+    base_branch_parents: Vec<Oid>,
+    new_base_tree: Oid,
+    updating: bool,
+    title: &str,
+) -> Result<(Option<Oid>, Option<GitHubBranch>)> {
+    match update_case {
+        // Case 1
+        UpdateCase::Unchanged => Ok((None, base_branch)),
+        // Case 2
+        UpdateCase::MergeMaster => Ok((Some(master_base_oid), None)),
+        // Case 3
+        UpdateCase::BaseBranch => {
+            let new_base_branch_commit = create_base_branch_commit(
+                git,
+                config,
+                local_commit_oid,
+                new_base_tree,
+                &base_branch_parents[..],
+                updating,
+            )?;
+
+            // If `base_branch` is `None` (which means a base branch does not
+            // exist yet), then make a `GitHubBranch` with a new name for a
+            // base branch
+            let base_branch = match base_branch {
+                Some(base_branch) => base_branch,
+                None => {
+                    let branch =
+                        config.new_github_branch(&config.get_base_branch_name(
+                            &*git.get_all_ref_names()?,
+                            title,
+                        ));
+                    git.add_ref_name(branch.local());
+                    branch
+                }
+            };
+
+            Ok((Some(new_base_branch_commit), Some(base_branch)))
+        }
+    }
+}
+
+/// Create a new commit for the Pull Request branch, with the given message
+/// (for an update) or as the initial version. Like the commit for the base
+/// branch, it is authored like the local commit.
+fn create_pull_request_commit(
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+    local_commit_oid: Oid,
+    message: Option<&str>,
+    tree_oid: Oid,
+    parent_oids: &[Oid],
+) -> Result<Oid> {
+    git.create_derived_commit(
+        local_commit_oid,
+        &git.derived_commit_message(
+            config,
+            with_spr_footer(message.unwrap_or("[𝘀𝗽𝗿] initial version")),
+        )?,
+        tree_oid,
+        parent_oids,
+        config.derived_commit_author.as_ref(),
+        config.derived_commit_committer.as_ref(),
+    )
+}
+
+/// The progress of an interrupted `--all` run, if it still applies to the
+/// branch at `head` with `length` commits. Progress that doesn't is discarded.
+fn resumable_state(
+    git: &crate::git::Git,
+    head: git2::Oid,
+    length: usize,
+) -> Result<Option<DiffState>> {
+    let state = match DiffState::load(git)? {
+        Some(state) => state,
+        None => return Ok(None),
+    };
+    if state.is_valid_for(head, length) {
+        return Ok(Some(state));
+    }
+
+    output(
+        "🗑️",
+        "Discarding the progress of an interrupted run, as the branch has \
+         changed since",
+    )?;
+    DiffState::clear(git)?;
+    Ok(None)
+}
+
+/// Put the Pull Requests created by an interrupted run back into the messages
+/// of the completed commits. If the run was killed, they never made it into
+/// the local commits.
+fn restore_completed_commits(
+    gh: &crate::github::GitHub,
+    config: &crate::config::Config,
+    prepared_commits: &mut [PreparedCommit],
+    state: &DiffState,
+) {
+    for completed in &state.completed {
+        let (commit, url) = match (
+            prepared_commits.get_mut(completed.index),
+            &completed.pull_request_url,
+        ) {
+            (Some(commit), Some(url)) => (commit, url),
+            _ => continue,
+        };
+        if commit.pull_request_number.is_some() {
+            continue;
+        }
+        commit
+            .message
+            .insert(MessageSection::PullRequest, url.clone());
+        if let Some(number) = config.parse_pull_request_field(url) {
+            commit.pull_request_number = Some(number);
+            // Later commits may be stacked on this one's Pull Request
+            commit.pull_request_task =
+                Some(tokio::spawn(gh.clone().get_pull_request(number)));
+        }
+    }
+}
+
+/// The `--squash-stack` flow: all commits between master and HEAD go into a
+/// single Pull Request against master. No base branches are needed, as the
+/// first commit of the stack is based on master directly.
+async fn diff_squash_stack(
+    opts: &DiffOptions,
+    git: &crate::git::Git,
+    gh: &crate::github::GitHub,
+    config: &crate::config::Config,
+    prepared_commits: &mut [PreparedCommit],
+    master_base_oid: Oid,
+) -> Result<()> {
+    let mut result = diff_squash_stack_impl(
+        opts,
+        git,
+        gh,
+        &mut GitHubPublisher { opts, gh, config },
+        config,
+        prepared_commits,
+        master_base_oid,
+    )
+    .await;
+
+    add_error(
+        &mut result,
+        git.rewrite_commit_messages(prepared_commits, None),
+    );
+
+    if result.is_ok() && opts.open.is_some() {
+        if let Some(url) = prepared_commits
+            .last()
+            .and_then(|commit| commit.message.get(&MessageSection::PullRequest))
+        {
+            open_in_browser(url)?;
+        }
+    }
+
+    result
+}
+
+async fn diff_squash_stack_impl<P: Publisher>(
+    opts: &DiffOptions,
+    git: &crate::git::Git,
+    gh: &crate::github::GitHub,
+    publisher: &mut P,
+    config: &crate::config::Config,
+    prepared_commits: &mut [PreparedCommit],
+    master_base_oid: Oid,
+) -> Result<()> {
+    let commit_oids = prepared_commits
+        .iter()
+        .map(|commit| commit.oid)
+        .collect::<Vec<_>>();
+    let top_commit = prepared_commits.last_mut().unwrap();
+    let pull_request = if let Some(task) = &mut top_commit.pull_request_task {
+        Some(task.await??)
+    } else {
+        None
+    };
+
+    write_commit_title(
+        top_commit,
+        Some(PullRequestBadge::new(pull_request.as_ref())),
+    )?;
+    output(
+        "🥞",
+        &format!(
+            "Squashing {} commit(s) into one Pull Request",
+            commit_oids.len()
+        ),
+    )?;
+
+    let message = &mut top_commit.message;
+    if pull_request.is_none() || opts.update_message {
+        let is_draft = pull_request
+            .as_ref()
+            .map_or(opts.draft, |pull_request| pull_request.is_draft);
+        validate_commit_message(message, config, is_draft)?;
+    }
+
+    if let Some(ref pull_request) = pull_request {
+        if pull_request.state == PullRequestState::Closed {
+            return Err(Error::new(formatdoc!(
+                "Pull request is closed. If you want to open a new one, \
+                 remove the 'Pull Request' section from the commit message."
+            )));
+        }
+    }
+
+    let top_oid = *commit_oids.last().unwrap();
+    let new_head_tree = git.get_tree_oid_for_commit(top_oid)?;
+
+    // The parents of the new commit: the current head of an existing Pull
+    // Request, plus the master commit if the stack was rebased.
+    let parents = match &pull_request {
+        Some(pr) => {
+            let pr_master_base =
+                git.repo().merge_base(pr.head_oid, master_base_oid)?;
+            if pr_master_base == master_base_oid
+                && git.get_tree_oid_for_commit(pr.head_oid)? == new_head_tree
+            {
+                output("✅", "No update necessary")?;
+                return Ok(());
+            }
+            if pr_master_base == master_base_oid {
+                vec![pr.head_oid]
+            } else {
+                vec![pr.head_oid, master_base_oid]
+            }
+        }
+        None => vec![master_base_oid],
+    };
+
+    let title = message
+        .get(&MessageSection::Title)
+        .map(|t| &t[..])
+        .unwrap_or("");
+    let pull_request_branch = match &pull_request {
+        Some(pr) if opts.branch_name.is_some() => {
+            return Err(Error::new(format!(
+                "Pull Request #{} exists already, so the name of its branch \
+                 can't be changed with --branch-name",
+                pr.number
+            )));
+        }
+        Some(pr) => pr.head.clone(),
+        None => {
+            let branch = new_pull_request_branch(
+                config,
+                &*git.get_all_ref_names()?,
+                opts.branch_name.as_deref(),
+                title,
+            )?;
+            git.add_ref_name(branch.local());
+            branch
+        }
+    };
+
+    let commit_message = if pull_request.is_some() {
+        opts.message
+            .clone()
+            .unwrap_or_else(|| "[𝘀𝗽𝗿] updated stack".into())
+    } else {
+        "[𝘀𝗽𝗿] initial version".to_string()
+    };
+    // The tree of the top commit has the changes of the whole stack
+    let squashed_commit = git.create_derived_commit(
+        top_oid,
+        &git.derived_commit_message(config, with_spr_footer(&commit_message))?,
+        new_head_tree,
+        &parents,
+        config.derived_commit_author.as_ref(),
+        config.derived_commit_committer.as_ref(),
+    )?;
+
+    let mut cmd = push_command(opts);
+    cmd.arg("--").arg(&config.remote_name).arg(format!(
+        "{}:{}",
+        squashed_commit,
+        pull_request_branch.on_github()
+    ));
+
+    let diffstat = diffstat_for(
+        git,
+        config,
+        git.get_tree_oid_for_commit(master_base_oid)?,
+        new_head_tree,
+    )?;
+
+    if let Some(pull_request) = &pull_request {
+        publisher.push(&mut cmd).await?;
+
+        let mut pull_request_updates: PullRequestUpdate = Default::default();
+        pull_request_updates.update_title_and_description(
+            pull_request,
+            opts.update_message.then_some(&*message),
+            opts.title.as_deref(),
+            diffstat.as_deref(),
+        );
+        if !pull_request.base.is_master_branch() {
+            pull_request_updates.base =
+                Some(config.master_ref.branch_name().to_string());
+        }
+        if !pull_request_updates.is_empty() {
+            gh.update_pull_request(pull_request.number, pull_request_updates)
+                .await?;
+        }
+        output(
+            "✅",
+            &format!("Updated Pull Request #{}", pull_request.number),
+        )?;
+        post_comment(gh, opts, pull_request.number).await?;
+    } else {
+        let pull_request_number = publish_pull_request(
+            publisher,
+            opts,
+            config,
+            &mut cmd,
+            message,
+            config.master_ref.branch_name().to_string(),
+            pull_request_branch.branch_name().to_string(),
+            diffstat.as_deref(),
+        )
+        .await?;
+        post_draft_comment(gh, opts, pull_request_number).await?;
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn diff_impl(
+    opts: &DiffOptions,
+    message_on_prompt: &mut String,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+    prepared_commits: &mut [PreparedCommit],
+    master_base_oid: Oid,
+    index: usize,
+    selected_indexes: &[usize],
+) -> Result<DiffOutcome> {
+    let mut pull_request = prepared_commits[index].pull_request().await?;
+
+    // Rather than creating a duplicate, pick up the Pull Request the commit
+    // has lost the link to
+    if pull_request.is_none()
+        && opts.branch_name.is_none()
+        && (opts.reattach || config.reattach)
+    {
+        if let Some(number) =
+            find_orphaned_pull_request(git, gh, config, prepared_commits, index)
+                .await?
+        {
+            let reattach = opts.reattach
+                || Confirm::new(&format!(
+                    "Pull Request #{number} is on the branch spr would create \
+                     for this commit, but the commit does not link to it. \
+                     Link the commit to #{number}?"
+                ))
+                .with_default(true)
+                .prompt()
+                .with_kind(ErrorKind::Aborted)?;
+            if reattach {
+                let prepared_commit = &mut prepared_commits[index];
+                restore_pull_request_link(config, prepared_commit, number);
+                prepared_commit.pull_request_task =
+                    Some(tokio::spawn(gh.clone().get_pull_request(number)));
+                pull_request = prepared_commit.pull_request().await?;
+                output("🔗", &format!("Reattached to Pull Request #{number}"))?;
+            }
+        }
+    }
+
+    write_commit_title(
+        &prepared_commits[index],
+        Some(PullRequestBadge::new(pull_request.as_ref())),
+    )?;
+
+    // GitHub refuses to create a Pull Request without changes, and an update
+    // would make an existing one empty
+    if git.is_empty_commit(prepared_commits[index].oid)? {
+        if opts.fail_on_empty {
+            return Err(Error::new(format!(
+                "Commit {} has no changes - drop it, e.g. with `git rebase \
+                 -i`, before running spr diff",
+                prepared_commits[index].short_id
+            )));
+        }
+        output("🫙", "This commit has no changes - skipping it")?;
+        return Ok(DiffOutcome::Empty);
+    }
+
+    let requested_base =
+        requested_base(opts.base.as_ref(), &prepared_commits[index].message);
+
+    // If the base is given as a tag, this is the commit it points to
+    let mut tag_base_oid = None;
+
+    // A Pull Request in another repository can't be the base on GitHub, so
+    // it is only recorded as a dependency in the description
+    let cross_repo_base = requested_base
+        .as_deref()
+        .and_then(parse_cross_repo_reference);
+
+    let mut retargeted = false;
+    let (base_ref, base_pull_request_number) = if let (Some(base), None) =
+        (&requested_base, &cross_repo_base)
+    {
+        let diff = parse_parent_or_zero(base);
+        if diff == 0 {
+            let (branch_name, tag_oid) =
+                resolve_base_branch(git, config, base)?;
+            if tag_oid.is_some() {
+                output(
+                    "🏷️",
+                    &format!(
+                        "Basing on tag {base}, targeting branch {branch_name}"
+                    ),
+                )?;
+            }
+            tag_base_oid = tag_oid;
+            let base_pull_request_number_result = gh
+                .get_open_pull_request_number_for_head(branch_name.clone())
+                .await;
+            (
+                config.new_github_branch(&branch_name),
+                base_pull_request_number_result.ok(),
+            )
+        } else {
+            let base_index = index as isize - diff;
+            if base_index < 0 {
+                (config.master_ref.clone(), None)
+            } else if base_index >= index as isize {
+                return Err(Error::new("Invalid base".to_string()));
+            } else {
+                let pull_request = get_pull_request_for_index(
+                    config,
+                    prepared_commits,
+                    base_index,
+                )
+                .await?;
+                (pull_request.head, Some(pull_request.number))
+            }
+        }
+    } else if let Some(reference) = &cross_repo_base {
+        if reference.owner.eq_ignore_ascii_case(&config.owner)
+            && reference.repo.eq_ignore_ascii_case(&config.repo)
+        {
+            return Err(Error::new(format!(
+                "{reference} is a Pull Request in this repository - give its \
+                 branch as the base instead"
+            )));
+        }
+        let title = gh
+            .get_pull_request_title_in(
+                &reference.owner,
+                &reference.repo,
+                reference.number,
+            )
+            .await
+            .reword(format!(
+                "Could not find the base Pull Request {reference}"
+            ))?;
+        output(
+            "🔗",
+            &format!(
+                "{reference} ({title}) is in another repository, so it can't \
+                 be the base branch on GitHub. This Pull Request targets {} \
+                 and lists {reference} as a dependency in its description.",
+                config.master_ref.branch_name()
+            ),
+        )?;
+        (config.master_ref.clone(), None)
+    } else if let Some(pull_request) = &pull_request {
+        let retarget = if opts.retarget_children {
+            let stack_heads =
+                stack_head_branches(&mut prepared_commits[..index]).await;
+            retarget_base(config, &pull_request.base, &stack_heads)
+        } else {
+            None
+        };
+        match retarget {
+            Some((base_ref, base_pull_request_number)) => {
+                output(
+                    "🪢",
+                    &format!(
+                        "{} is not in the stack any more, retargeting onto {}",
+                        pull_request.base.branch_name(),
+                        base_ref.branch_name()
+                    ),
+                )?;
+                retargeted = true;
+                if base_pull_request_number.is_none() {
+                    prepared_commits[index]
+                        .message
+                        .remove(&MessageSection::BasePR);
+                }
+                (base_ref, base_pull_request_number)
+            }
+            None => (pull_request.base.clone(), None),
+        }
+    } else if index == 0 {
+        (config.master_ref.clone(), None)
+    } else {
+        let mut options: Vec<CommitOption> = Vec::new();
+
+        for i in (0..index).rev() {
+            let commit = prepared_commits.get(i).unwrap();
+            let title = commit
+                .message
+                .get(&MessageSection::Title)
+                .map(|t| &t[..])
+                .unwrap_or("(untitled)");
+            options.push(
+                if let Some(pull_request_number) = commit.pull_request_number {
+                    CommitOption {
+                        message: format!(
+                            "PR #{} - {}",
+                            pull_request_number, title
+                        ),
+                        index: i as isize,
+                    }
+                } else {
+                    CommitOption {
+                        message: format!("PR #{} - {}", "?????", title),
+                        index: UNKNOWN_PR_SPECIAL_COMMIT_INDEX,
+                    }
+                },
+            );
+        }
+        options.push(CommitOption {
+            message: config.master_ref.branch_name().to_string(),
+            index: MAIN_SPECIAL_COMMIT_INDEX,
+        });
+
+        let ans = Select::new("Select a base:", options)
+            .with_starting_cursor(index)
+            .prompt();
+
+        match ans {
+            Ok(choice) => match choice.index {
+                MAIN_SPECIAL_COMMIT_INDEX => (config.master_ref.clone(), None),
+                UNKNOWN_PR_SPECIAL_COMMIT_INDEX => {
+                    return Err(Error::new(
+                        "Your selection obviously has no PR created yet"
+                            .to_string(),
+                    ));
+                }
+                choice_index => {
+                    let pull_request = get_pull_request_for_index(
+                        config,
+                        prepared_commits,
+                        choice_index,
+                    )
+                    .await?;
+                    (pull_request.head, Some(pull_request.number))
+                }
+            },
+            Err(_) => {
+                return Err(Error::new(
+                    "Aborted as per user request".to_string(),
+                )
+                .with_kind(ErrorKind::Aborted));
+            }
+        }
+    };
+
+    let local_commit = prepared_commits.get_mut(index).unwrap();
+
+    // Record the base given on the command line in the commit message, so
+    // that the next run picks the same one
+    if let Some(base) = &opts.base {
+        local_commit.message.insert(
+            MessageSection::Base,
+            if tag_base_oid.is_some() || cross_repo_base.is_some() {
+                base.clone()
+            } else {
+                base_ref.branch_name().to_string()
+            },
+        );
+    }
+
+    // Update master_base_oid if base if provided
+    let master_base_oid = match tag_base_oid {
+        Some(oid) => oid,
+        None => git
+            .resolve_reference(base_ref.local())
+            .unwrap_or(master_base_oid),
+    };
+
+    // Pin the master commit the Pull Request is based on, if requested
+    let master_base_oid = match &opts.master_base {
+        Some(revision) => {
+            if base_ref.branch_name() != config.master_ref.branch_name() {
+                return Err(Error::new(format!(
+                    "--master-base can't be used for a Pull Request that \
+                     targets {} rather than {}",
+                    base_ref.branch_name(),
+                    config.master_ref.branch_name()
+                )));
+            }
+            pinned_master_base(git, revision, master_base_oid)?
+        }
+        None => master_base_oid,
+    };
+
+    // Parsed commit message of the local commit
+    let message = &mut local_commit.message;
+
+    // If the user has provided a base PR, add a "Depends On" line in PR body
+    if let Some(base_pull_request_number) = base_pull_request_number {
+        message.insert(
+            MessageSection::BasePR,
+            format!(
+                "\n- #{} (powered by [spr-enhanced](https://go/spr-enhanced))",
+                base_pull_request_number
+            ),
+        );
+    } else if let Some(reference) = &cross_repo_base {
+        message.insert(
+            MessageSection::BasePR,
+            format!(
+                "\n- {reference} (powered by [spr-enhanced](https://go/spr-enhanced))"
+            ),
+        );
+    }
+
+    // Determine the trees the Pull Request branch and the base branch should
+    // have when we're done here.
+    let (new_head_tree, new_base_tree, no_cherry_pick) = new_trees(
+        git,
+        local_commit.oid,
+        local_commit.parent_oid,
+        master_base_oid,
+        base_ref.branch_name(),
+        opts.no_cherry_pick,
+        config.auto_no_cherry_pick,
+    )?;
+    if no_cherry_pick && !opts.no_cherry_pick {
+        output(
+            "⚠️",
+            &format!(
+                "This commit cannot be cherry-picked on {}, so it is \
+                 submitted as with --no-cherry-pick. Future updates of this \
+                 Pull Request need --no-cherry-pick as well.",
+                base_ref.branch_name(),
+            ),
+        )?;
+    }
+
+    let master_base_oid = if local_commit.pull_request_number.is_none() {
+        let oid = new_pull_request_master_base(
+            git,
+            opts.prefer_master_base,
+            no_cherry_pick,
+            local_commit.parent_oid,
+            master_base_oid,
+        )?;
+        if oid != master_base_oid {
+            output(
+                "🎯",
+                &format!(
+                    "The parent commit is on {}, so no base branch is needed",
+                    base_ref.branch_name(),
+                ),
+            )?;
+        }
+        oid
+    } else {
+        master_base_oid
+    };
+
+    if let Some(number) = local_commit.pull_request_number {
+        output(
+            "#️⃣ ",
+            &format!(
+                "Pull Request #{}: {}",
+                number,
+                config.pull_request_url(number)
+            ),
+        )?;
+    }
+
+    if local_commit.pull_request_number.is_none() || opts.update_message {
+        // The draft state the Pull Request has, or will be created with
+        let is_draft = pull_request
+            .as_ref()
+            .map_or(opts.draft, |pull_request| pull_request.is_draft);
+        validate_commit_message(message, config, is_draft)?;
+
+        if opts.check_dependencies {
+            check_dependencies(gh, config, message).await?;
+        }
+    }
+
+    if let Some(ref pull_request) = pull_request {
+        if pull_request.state == PullRequestState::Closed {
+            return Err(Error::new(formatdoc!(
+                "Pull request is closed. If you want to open a new one, \
+                 remove the 'Pull Request' section from the commit message."
+            )));
+        }
+
+        if !opts.update_message {
+            let mut pull_request_updates: PullRequestUpdate =
+                Default::default();
+            pull_request_updates.update_message(pull_request, message);
+        }
+    }
+
+    // Parse "Reviewers" section, if this is a new Pull Request
+    let mut requested_reviewers = PullRequestRequestReviewers::default();
+    let mut inherited_labels = Vec::new();
+
+    if local_commit.pull_request_number.is_none() {
+        requested_reviewers =
+            check_reviewers(&*gh, opts, config, message).await?;
+
+        if !config.size_reviewers.is_empty() {
+            add_size_reviewers(
+                git,
+                config,
+                local_commit.oid,
+                &mut requested_reviewers,
+            )
+            .await?;
+        }
+
+        if let Some(mode) = opts.codeowners {
+            add_code_owner_reviewers(
+                git,
+                gh,
+                config,
+                local_commit.oid,
+                mode,
+                &mut requested_reviewers,
+            )
+            .await?;
+        }
+
+        if let (true, Some(number)) =
+            (opts.inherit_from_base, base_pull_request_number)
+        {
+            let base_pull_request = gh.clone().get_pull_request(number).await?;
+            let me = GitHub::get_current_user_login().await?;
+            inherit_reviewers(
+                &base_pull_request,
+                &me,
+                &mut requested_reviewers,
+            );
+            inherited_labels = gh.get_labels(number).await?;
+            output(
+                "🧬",
+                &format!("Copying the labels and reviewers of #{number}"),
+            )?;
+        }
+
+        check_reviewers_required(config, &requested_reviewers)?;
+    }
+
+    // Get the name of the existing Pull Request branch, or constuct one if
+    // there is none yet.
+
+    let title = message
+        .get(&MessageSection::Title)
+        .map(|t| &t[..])
+        .unwrap_or("");
+
+    let pull_request_branch = match &pull_request {
+        Some(pr) if opts.branch_name.is_some() => {
+            return Err(Error::new(format!(
+                "Pull Request #{} exists already, so the name of its branch \
+                 can't be changed with --branch-name",
+                pr.number
+            )));
+        }
+        Some(pr) => pr.head.clone(),
+        None => {
+            let branch = new_pull_request_branch(
+                config,
+                &*git.get_all_ref_names()?,
+                opts.branch_name.as_deref(),
+                title,
+            )?;
+            git.add_ref_name(branch.local());
+            branch
+        }
+    };
+
+    // Get the tree ids of the current head of the Pull Request, as well as the
+    // base, and the commit id of the master commit this PR is currently based
+    // on.
+    // If there is no pre-existing Pull Request, we fill in the equivalent
+    // values.
+    let (pr_head_oid, pr_head_tree, pr_base_oid, pr_base_tree, pr_master_base) =
+        if let Some(pr) = &pull_request {
+            let pr_head_tree = git.get_tree_oid_for_commit(pr.head_oid)?;
+
+            let current_master_oid = git.resolve_reference(base_ref.local())?;
+            let pr_base_oid =
+                git.repo().merge_base(pr.head_oid, pr.base_oid)?;
+            let pr_base_tree = git.get_tree_oid_for_commit(pr_base_oid)?;
+
+            let pr_master_base =
+                git.repo().merge_base(pr.head_oid, current_master_oid)?;
+
+            // If master was force-pushed, the merge base computed above is
+            // not the master commit this Pull Request was built on, and
+            // updating it will merge in surprising changes.
+            if base_ref.is_master_branch() {
+                if let Some(lost_base) =
+                    git.find_lost_master_base(pr.head_oid, current_master_oid)?
+                {
+                    output(
+                        "⚠️",
+                        &formatdoc!(
+                            "This Pull Request is based on commit {lost_base}, \
+                             which is no longer on {master}. Was {master} \
+                             force-pushed? The update may contain unexpected \
+                             changes - consider rebasing your commit on \
+                             current {master} and checking the Pull Request \
+                             carefully.",
+                            master = base_ref.branch_name(),
+                        ),
+                    )?;
+                }
+            }
+
+            (
+                pr.head_oid,
+                pr_head_tree,
+                pr_base_oid,
+                pr_base_tree,
+                pr_master_base,
+            )
+        } else {
+            let master_base_tree =
+                git.get_tree_oid_for_commit(master_base_oid)?;
+            (
+                master_base_oid,
+                master_base_tree,
+                master_base_oid,
+                master_base_tree,
+                master_base_oid,
+            )
+        };
+    let needs_merging_master = pr_master_base != master_base_oid;
+
+    // Check if there is a intermediate base branch on GitHub already. That's the case when
+    // there is an existing Pull Request, and its base is not the master branch or other PR's branch.
+    let base_branch = pull_request.as_ref().and_then(|pr| {
+        existing_base_branch(
+            config,
+            &pr.base,
+            requested_base.is_some() || retargeted,
+            no_cherry_pick,
+        )
+        .or_else(|| {
+            reusable_base_branch(
+                config,
+                &pr.base,
+                opts.prefer_existing_base && !retargeted,
+                pr_base_tree == new_base_tree,
+                needs_merging_master,
+            )
+        })
+    });
+
+    if opts.explain {
+        let is_no_op = pull_request.is_some()
+            && !needs_merging_master
+            && pr_head_tree == new_head_tree
+            && pr_base_tree == new_base_tree;
+        output(
+            "🔎",
+            &explain_update_case(
+                pr_base_tree == new_base_tree,
+                needs_merging_master,
+                base_branch.as_ref().map(|branch| branch.branch_name()),
+                no_cherry_pick,
+                is_no_op,
+            ),
+        )?;
+    }
+
+    // At this point we can check if we can exit early because no update to the
+    // existing Pull Request is necessary
+    if let Some(ref pull_request) = pull_request {
+        // So there is an existing Pull Request...
+        if !needs_merging_master
+            && pr_head_tree == new_head_tree
+            && pr_base_tree == new_base_tree
+        {
+            // ...and it does not need a rebase, and the trees of both Pull
+            // Request branch and base are all the right ones.
+            output("✅", "No update necessary")?;
+            let mut outcome = DiffOutcome::UpToDate;
+
+            if opts.comment_always {
+                post_comment(gh, opts, pull_request.number).await?;
+            }
+
+            if opts.update_message || opts.title.is_some() || config.diffstat {
+                // However, the user requested to update the commit message
+                // (or the title, or the diffstat) on GitHub
+
+                let mut pull_request_updates: PullRequestUpdate =
+                    Default::default();
+                pull_request_updates.update_title_and_description(
+                    pull_request,
+                    opts.update_message.then_some(&*message),
+                    opts.title.as_deref(),
+                    diffstat_for(git, config, new_base_tree, new_head_tree)?
+                        .as_deref(),
+                );
+
+                if !pull_request_updates.is_empty() {
+                    // ...and there are actual changes to the message
+                    gh.update_pull_request(
+                        pull_request.number,
+                        pull_request_updates,
+                    )
+                    .await?;
+                    output("✍", "Updated commit message on GitHub")?;
+                    outcome = DiffOutcome::Succeeded;
+                }
+            }
+
+            return Ok(outcome);
+        }
+    }
+
+    // We are going to construct `pr_base_parent: Option<Oid>`.
+    // The value will be the commit we have to merge into the new Pull Request
+    // commit to reflect changes in the parent of the local commit (by rebasing
+    // or changing commits between master and this one, although technically
+    // that's also rebasing).
+    // If it's `None`, then we will not merge anything into the new Pull Request
+    // commit.
+    // If we are updating an existing PR, then there are three cases here:
+    // (1) the parent tree of this commit is unchanged and we do not need to
+    //     merge in master, which means that the local commit was amended, but
+    //     not rebased. We don't need to merge anything into the Pull Request
+    //     branch.
+    // (2) the parent tree has changed, but the parent of the local commit is on
+    //     master (or we are cherry-picking) and we are not already using a base
+    //     branch: in this case we can merge the master commit we are based on
+    //     into the PR branch, without going via a base branch. Thus, we don't
+    //     introduce a base branch here and the PR continues to target the
+    //     master branch.
+    // (3) the parent tree has changed, and we need to use a base branch (either
+    //     because one was already created earlier, or we find that we are not
+    //     directly based on master now): we need to construct a new commit for
+    //     the base branch. That new commit's tree is always that of that local
+    //     commit's parent (thus making sure that the difference between base
+    //     branch and pull request branch are exactly the changes made by the
+    //     local commit, thus the changes we want to have reviewed). The new
+    //     commit may have one or two parents. The previous base is always a
+    //     parent (that's either the current commit on an existing base branch,
+    //     or the previous master commit the PR was based on if there isn't a
+    //     base branch already). In addition, if the master commit this commit
+    //     is based on has changed, (i.e. the local commit got rebased on newer
+    //     master in the meantime) then we have to merge in that master commit,
+    //     which will be the second parent.
+    // If we are creating a new pull request then `pr_base_tree` (the current
+    // base of the PR) was set above to be the tree of the master commit the
+    // local commit is based one, whereas `new_base_tree` is the tree of the
+    // parent of the local commit. So if the local commit for this new PR is on
+    // master, those two are the same (and we want to apply case 1). If the
+    // commit is not directly based on master, we have to create this new PR
+    // with a base branch, so that is case 3. With `--prefer-master-base`, a
+    // parent that is an older master commit was used as `master_base_oid`
+    // above, which makes this case 1 without a base branch.
+
+    let base_branch_strategy = opts
+        .base_branch_strategy
+        .unwrap_or(config.base_branch_strategy);
+
+    let update_case = choose_update_case(
+        pr_base_tree == new_base_tree,
+        needs_merging_master,
+        base_branch.is_some(),
+        no_cherry_pick,
+    );
+    let (pr_base_parent, base_branch) = base_branch_update(
+        git,
+        config,
+        local_commit.oid,
+        update_case,
+        base_branch,
+        master_base_oid,
+        base_branch_commit_parents(
+            base_branch_strategy,
+            pr_base_oid,
+            master_base_oid,
+            needs_merging_master,
+        ),
+        new_base_tree,
+        pull_request.is_some(),
+        title,
+    )?;
+
+    let was_draft = pull_request.as_ref().map(|pr| pr.is_draft);
+    let mut github_commit_message = opts.message.clone();
+    if let (Some(pull_request), Some(template), None) = (
+        &pull_request,
+        &opts.message_template,
+        &github_commit_message,
+    ) {
+        github_commit_message = Some(render_message_template(
+            template,
+            pull_request.number,
+            title,
+            needs_merging_master,
+        ));
+    }
+    if let (Some(_), Some(rebase_message), None) = (
+        &pull_request,
+        opts.rebase_message
+            .as_ref()
+            .or(config.rebase_message.as_ref()),
+        &github_commit_message,
+    ) {
+        if needs_merging_master
+            && git.same_changes(
+                pr_base_tree,
+                pr_head_tree,
+                new_base_tree,
+                new_head_tree,
+            )?
+        {
+            output("🔁", "Only rebased, the changes are the same")?;
+            github_commit_message = Some(rebase_message.clone());
+        }
+    }
+    if pull_request.is_some() && github_commit_message.is_none() {
+        let input = {
+            let message_on_prompt = message_on_prompt.clone();
+
+            tokio::task::spawn_blocking(move || {
+                dialoguer::Input::<String>::new()
+                    .with_prompt("Message (leave empty to abort)")
+                    .with_initial_text(message_on_prompt)
+                    .allow_empty(true)
+                    .interact_text()
+            })
+            .await??
+        };
+
+        if input.is_empty() {
+            return Err(Error::new("Aborted as per user request".to_string())
+                .with_kind(ErrorKind::Aborted));
+        }
+
+        *message_on_prompt = input.clone();
+        github_commit_message = Some(input);
+    }
+
+    // Before the ready keyword takes the Pull Request out of draft, the
+    // message must pass the checks for one that is ready for review
+    if was_draft == Some(true)
+        && draft_state_from_keyword(config, github_commit_message.as_deref())
+            == Some(false)
+    {
+        validate_commit_message(message, config, false)?;
+    }
+
+    // Construct the new commit for the Pull Request branch. With
+    // --no-master-merge, a rebased commit (case 2) starts afresh on the master
+    // commit, and the Pull Request branch gets force-pushed.
+    let replace_pr_branch = opts.no_master_merge
+        && pull_request.is_some()
+        && update_case == UpdateCase::MergeMaster;
+    let pr_commit_parents = pull_request_commit_parents(
+        pr_head_oid,
+        pr_base_parent,
+        replace_pr_branch,
+    );
+
+    // Create the new commit
+    let pr_commit = create_pull_request_commit(
+        git,
+        config,
+        local_commit.oid,
+        github_commit_message.as_deref(),
+        new_head_tree,
+        &pr_commit_parents[..],
+    )?;
+
+    let mut cmd = push_command(opts);
+    if replace_pr_branch {
+        // Only replace the commit the Pull Request branch had when we looked
+        cmd.arg(force_with_lease(&pull_request_branch, pr_head_oid));
+    }
+
+    cmd.arg("--").arg(&config.remote_name).arg(format!(
+        "{}:{}",
+        pr_commit,
+        pull_request_branch.on_github()
+    ));
+
+    let pull_request_number = if let Some(pull_request) = &pull_request {
+        // We are updating an existing Pull Request
+
+        if needs_merging_master {
+            output(
+                "⚾",
+                &format!(
+                    "Commit was rebased - updating Pull Request #{}",
+                    pull_request.number
+                ),
+            )?;
+        } else {
+            output(
+                "🔁",
+                &format!(
+                    "Commit was changed - updating Pull Request #{}",
+                    pull_request.number
+                ),
+            )?;
+        }
+
+        if config.warn_shared_commits {
+            let dependents = git.branches_based_on(
+                config,
+                pull_request.head_oid,
+                pull_request.head.branch_name(),
+            )?;
+            if !dependents.is_empty() {
+                output(
+                    "⚠️",
+                    &format!(
+                        "Other branches are based on this Pull Request's \
+                         branch and will need to be updated too: {}",
+                        dependents.join(", ")
+                    ),
+                )?;
+            }
+        }
+
+        // Things we want to update in the Pull Request on GitHub
+        let mut pull_request_updates: PullRequestUpdate = Default::default();
+        pull_request_updates.update_title_and_description(
+            pull_request,
+            opts.update_message.then_some(&*message),
+            opts.title.as_deref(),
+            diffstat_for(git, config, new_base_tree, new_head_tree)?.as_deref(),
+        );
+
+        if let Some(base_branch) = base_branch {
+            // We are using a base branch.
+
+            if let Some(base_branch_commit) = pr_base_parent {
+                // ...and we prepared a new commit for it, so we need to push an
+                // update of the base branch. With the rebase strategy, that
+                // update is not a fast-forward.
+                cmd.arg(format!(
+                    "{}{}:{}",
+                    if base_branch_strategy == BaseBranchStrategy::Rebase {
+                        "+"
+                    } else {
+                        ""
+                    },
+                    base_branch_commit,
+                    base_branch.on_github()
+                ));
+            }
+
+            // Push the new commit onto the Pull Request branch (and also the
+            // new base commit, if we added that to cmd above).
+            push_to_github(&mut cmd, config).await?;
+
+            // If the Pull Request's base is not set to the base branch yet,
+            // change that now.
+            if pull_request.base.branch_name() != base_branch.branch_name() {
+                pull_request_updates.base =
+                    Some(base_branch.branch_name().to_string());
+            }
+        } else {
+            if (requested_base.is_some() || retargeted)
+                && pull_request.base.branch_name() != base_ref.branch_name()
+            {
+                pull_request_updates.base =
+                    Some(base_ref.branch_name().to_string());
+            }
+
+            // The Pull Request is against the master branch. In that case we
+            // only need to push the update to the Pull Request branch.
+            push_to_github(&mut cmd, config).await?;
+        }
+
+        if !pull_request_updates.is_empty() {
+            gh.update_pull_request(pull_request.number, pull_request_updates)
+                .await?;
+        }
+
+        post_comment(gh, opts, pull_request.number).await?;
+
+        if opts.dismiss_stale_reviews
+            && is_significant_update(
+                needs_merging_master,
+                git.count_changed_files(pr_head_tree, new_head_tree)?,
+            )
+        {
+            let dismissed = gh
+                .dismiss_reviews(
+                    pull_request.number,
+                    "Dismissed by spr: this Pull Request was substantially \
+                     updated and needs another look.",
+                )
+                .await?;
+            if dismissed > 0 {
+                output(
+                    "🙈",
+                    &format!("Dismissed {dismissed} stale approval(s)"),
+                )?;
+            }
+        }
+
+        if opts.prune_reviewers {
+            let listed =
+                expected_reviewers(git, config, message, local_commit.oid)?;
+            let pruned = reviewers_to_prune(&listed, pull_request);
+            if !pruned.reviewers.is_empty() || !pruned.team_reviewers.is_empty()
+            {
+                let names = pruned
+                    .reviewers
+                    .iter()
+                    .cloned()
+                    .chain(
+                        pruned.team_reviewers.iter().map(|t| format!("#{t}")),
+                    )
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                gh.remove_requested_reviewers(pull_request.number, pruned)
+                    .await?;
+                output("✂️", &format!("Removed requested reviewers: {names}"))?;
+            }
+        }
+
+        pull_request.number
+    } else {
+        // We are creating a new Pull Request.
+
+        // If there's a base branch, add it to the push
+        if let (Some(base_branch), Some(base_branch_commit)) =
+            (&base_branch, pr_base_parent)
+        {
+            cmd.arg(format!(
+                "{}:{}",
+                base_branch_commit,
+                base_branch.on_github()
+            ));
+        }
+        // Push the pull request branch and the base branch if present, then
+        // create the Pull Request
+        let pull_request_number = publish_pull_request(
+            &mut GitHubPublisher { opts, gh, config },
+            opts,
+            config,
+            &mut cmd,
+            message,
+            base_branch
+                .as_ref()
+                .unwrap_or(&base_ref)
+                .branch_name()
+                .to_string(),
+            pull_request_branch.branch_name().to_string(),
+            diffstat_for(git, config, new_base_tree, new_head_tree)?.as_deref(),
+        )
+        .await?;
+
+        // If current commit is not the last selected commit, update pull request number and task
+        // so that it can be used as a base PR for the subsequent commits.
+        if Some(&index) != selected_indexes.last() {
+            local_commit.pull_request_number = Some(pull_request_number);
+            local_commit.pull_request_task = Some(tokio::spawn(
+                gh.clone().get_pull_request(pull_request_number),
+            ));
+        }
+
+        let result = gh
+            .request_reviewers(pull_request_number, requested_reviewers)
+            .await;
+        match result {
+            Ok(()) => (),
+            Err(error) => {
+                output("⚠️", "Requesting reviewers failed")?;
+                for message in error.messages() {
+                    output("  ", message)?;
+                }
+            }
+        }
+
+        // Like the reviewers, a failure here must not stop us from recording
+        // the new Pull Request in the commit message
+        if !inherited_labels.is_empty() {
+            if let Err(error) =
+                gh.add_labels(pull_request_number, &inherited_labels).await
+            {
+                output("⚠️", "Adding labels failed")?;
+                for message in error.messages() {
+                    output("  ", message)?;
+                }
+            }
+        }
+
+        post_draft_comment(gh, opts, pull_request_number).await?;
+
+        if let Some(project) = message.get(&MessageSection::Project) {
+            if let Err(error) =
+                gh.add_to_project(pull_request_number, project).await
+            {
+                output("⚠️", "Adding the Pull Request to the project failed")?;
+                for message in error.messages() {
+                    output("  ", message)?;
+                }
+            }
+        }
+
+        if let Some(attachments) = message.get(&MessageSection::Attachments) {
+            upload_attachments(gh, pull_request_number, attachments).await?;
+        }
+
+        pull_request_number
+    };
+
+    // An update message that is just a keyword changes the draft state, too
+    if let Some(draft) =
+        draft_state_from_keyword(config, github_commit_message.as_deref())
+    {
+        if was_draft == Some(!draft) {
+            let id = gh.get_pull_request_id(pull_request_number).await?;
+            gh.set_pull_request_draft(&id, draft).await?;
+            if draft {
+                output(
+                    "📝",
+                    &format!("Converted #{pull_request_number} to draft"),
+                )?;
+            } else {
+                output(
+                    "✅",
+                    &format!("Marked #{pull_request_number} ready for review"),
+                )?;
+            }
+        }
+    }
+
+    if opts.draft_if_failing
+        && !opts.draft
+        && draft_state_from_keyword(config, github_commit_message.as_deref())
+            .is_none()
+    {
+        update_draft_state_from_checks(gh, pull_request_number, pr_commit)
+            .await?;
+    }
+
+    if opts.show_required_approvals {
+        show_required_approvals(gh, config, pull_request.as_ref()).await?;
+    }
+
+    Ok(DiffOutcome::Succeeded)
+}
+
+/// How often `--draft-if-failing` looks at pending required checks, and for
+/// how long (ten minutes)
+const CHECKS_POLL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(15);
+const CHECKS_POLL_ATTEMPTS: usize = 40;
+
+/// Convert the Pull Request to a draft if one of the required checks on the
+/// pushed commit fails, or mark it as ready for review once all of them pass
+async fn update_draft_state_from_checks(
+    gh: &crate::github::GitHub,
+    number: u64,
+    head_oid: Oid,
+) -> Result<()> {
+    let checks = wait_for_required_checks(
+        || gh.get_pull_request_checks(number, head_oid),
+        number,
+        CHECKS_POLL_INTERVAL,
+        CHECKS_POLL_ATTEMPTS,
+    )
+    .await?;
+    let status = checks.required_status();
+
+    match draft_state_change(status, checks.is_draft) {
+        Some(true) => {
+            output(
+                "📝",
+                &format!(
+                    "A required check is failing - converting #{number} to \
+                     draft"
+                ),
+            )?;
+            gh.set_pull_request_draft(&checks.id, true).await?;
+        }
+        Some(false) => {
+            output(
+                "✅",
+                &format!(
+                    "All required checks pass - marking #{number} ready for \
+                     review"
+                ),
+            )?;
+            gh.set_pull_request_draft(&checks.id, false).await?;
+        }
+        None if status == Some(CheckStatus::Pending) => {
+            output(
+                "⏳",
+                &format!(
+                    "The required checks of #{number} are still pending - \
+                     leaving its draft state as it is"
+                ),
+            )?;
+        }
+        None => (),
+    }
+
+    Ok(())
+}
+
+/// Fetch the checks until none of the required ones is pending any more, or
+/// the attempts are used up
+async fn wait_for_required_checks<F, Fut>(
+    mut fetch: F,
+    number: u64,
+    interval: std::time::Duration,
+    attempts: usize,
+) -> Result<PullRequestChecks>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<PullRequestChecks>>,
+{
+    let mut checks = fetch().await?;
+    for attempt in 1..attempts {
+        if checks.required_status() != Some(CheckStatus::Pending) {
+            break;
+        }
+        if attempt == 1 {
+            output(
+                "⏳",
+                &format!("Waiting for the required checks of #{number}"),
+            )?;
+        }
+        tokio::time::sleep(interval).await;
+        checks = fetch().await?;
+    }
+
+    Ok(checks)
+}
+
+/// The draft state a Pull Request should change to, given the state of its
+/// required checks, or `None` to leave it as it is
+fn draft_state_change(
+    status: Option<CheckStatus>,
+    is_draft: bool,
+) -> Option<bool> {
+    match status {
+        Some(CheckStatus::Failure) if !is_draft => Some(true),
+        Some(CheckStatus::Success) if is_draft => Some(false),
+        _ => None,
+    }
+}
+
+/// Print how many more approvals the Pull Request (none for a new one) needs
+/// to land on master. Reading the branch protection needs admin access, so a
+/// failure is only a warning.
+async fn show_required_approvals(
+    gh: &crate::github::GitHub,
+    config: &crate::config::Config,
+    pull_request: Option<&crate::github::PullRequest>,
+) -> Result<()> {
+    // Stacked Pull Requests are based on another branch for review, but
+    // what counts is the protection of the branch they land on
+    let base = config.master_ref.branch_name();
+    match gh.get_branch_protection(base).await {
+        Ok(protection) => {
+            let required = protection
+                .map(|protection| protection.required_approvals())
+                .unwrap_or(0);
+            let approved = pull_request.map_or(0, |pull_request| {
+                pull_request
+                    .reviewers
+                    .values()
+                    .filter(|status| **status == ReviewStatus::Approved)
+                    .count() as u64
+            });
+            output("🧮", &approvals_note(base, required, approved))
+        }
+        Err(error) => {
+            output(
+                "⚠️",
+                &format!(
+                    "Could not read the branch protection of {base} - \
+                     required approvals are unknown"
+                ),
+            )?;
+            for message in error.messages() {
+                output("  ", message)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn approvals_note(base: &str, required: u64, approved: u64) -> String {
+    if required == 0 {
+        format!("{base} does not require approvals")
+    } else if approved >= required {
+        format!("Approved ({approved} of {required} required approvals)")
+    } else {
+        format!(
+            "{} more approval(s) needed ({approved} of {required} required)",
+            required - approved
+        )
+    }
+}
+
+/// The draft state requested by an update message consisting of just the
+/// ready or draft keyword, if keywords are enabled (spr.messageKeywords)
+fn draft_state_from_keyword(
+    config: &crate::config::Config,
+    message: Option<&str>,
+) -> Option<bool> {
+    let message = message?.trim();
+    if !config.message_keywords {
+        None
+    } else if message.eq_ignore_ascii_case(&config.ready_keyword) {
+        Some(false)
+    } else if message.eq_ignore_ascii_case(&config.draft_keyword) {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+/// With spr.requireReviewers, a new Pull Request must have someone to review
+/// it
+fn check_reviewers_required(
+    config: &crate::config::Config,
+    requested_reviewers: &PullRequestRequestReviewers,
+) -> Result<()> {
+    if config.require_reviewers
+        && requested_reviewers.reviewers.is_empty()
+        && requested_reviewers.team_reviewers.is_empty()
+    {
+        Err(Error::new(
+            "This commit has no reviewers, but new Pull Requests need at \
+             least one. Add a 'Reviewers:' section to the commit message.",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Add the reviewers of the given (base) Pull Request - requested ones as
+/// well as those who reviewed it already - to the reviewers to request,
+/// except for the given user (the author of the new Pull Request)
+fn inherit_reviewers(
+    base_pull_request: &crate::github::PullRequest,
+    me: &str,
+    requested_reviewers: &mut PullRequestRequestReviewers,
+) {
+    let mut reviewed = base_pull_request.reviewers.keys().collect::<Vec<_>>();
+    reviewed.sort();
+    for reviewer in base_pull_request.requested_reviewers.iter().chain(reviewed)
+    {
+        let (list, name) = match reviewer.strip_prefix('#') {
+            Some(slug) => (&mut requested_reviewers.team_reviewers, slug),
+            None if reviewer.eq_ignore_ascii_case(me) => continue,
+            None => (&mut requested_reviewers.reviewers, &reviewer[..]),
+        };
+        if !list.iter().any(|r| r.eq_ignore_ascii_case(name)) {
+            list.push(name.to_string());
+        }
+    }
+}
+
+/// Add the reviewers configured for the size of the given commit (see
+/// `size_reviewers`) to the reviewers to request, except for the user spr
+/// acts as and those requested already
+async fn add_size_reviewers(
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+    commit_oid: Oid,
+    requested_reviewers: &mut PullRequestRequestReviewers,
+) -> Result<()> {
+    let changed_lines = git.count_changed_lines(commit_oid)?;
+    let reviewers = config.reviewers_for_size(changed_lines)?;
+    if reviewers.is_empty() {
+        return Ok(());
+    }
+    let me = GitHub::get_current_user_login().await?;
+
+    let mut added = Vec::new();
+    for reviewer in reviewers {
+        let (list, name) = match reviewer.strip_prefix('#') {
+            Some(slug) => (&mut requested_reviewers.team_reviewers, slug),
+            None if reviewer.eq_ignore_ascii_case(&me) => continue,
+            None => (&mut requested_reviewers.reviewers, &reviewer[..]),
+        };
+        if !list.iter().any(|r| r.eq_ignore_ascii_case(name)) {
+            list.push(name.to_string());
+            added.push(reviewer.clone());
+        }
+    }
+    if !added.is_empty() {
+        output(
+            "👀",
+            &format!(
+                "Requesting reviews from {} for {} changed lines",
+                added.join(", "),
+                changed_lines
+            ),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Check that the Pull Requests of this repository listed in the Depends-on
+/// section of the message exist. References to other repositories are
+/// skipped, as spr's token may well not have access to them.
+async fn check_dependencies(
+    gh: &GitHub,
+    config: &crate::config::Config,
+    message: &MessageSectionsMap,
+) -> Result<()> {
+    let references = message
+        .get(&MessageSection::DependsOn)
+        .map(|text| parse_dependency_references(text))
+        .unwrap_or_default();
+    for reference in references {
+        match config.parse_pull_request_field(&reference) {
+            Some(number) => {
+                if !gh.pull_request_exists(number).await? {
+                    return Err(Error::new(format!(
+                        "Depends-on section refers to Pull Request \
+                         {reference}, which does not exist"
+                    )));
+                }
+            }
+            None => output(
+                "🤷",
+                &format!(
+                    "Not checking dependency {reference} in another repository"
+                ),
+            )?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Add the code owners of the files changed by the given commit to the
+/// reviewers to request, except for the user spr acts as (who will be the
+/// author of the Pull Request) and those requested already. Owners that
+/// GitHub doesn't know are skipped with a warning, as a CODEOWNERS file may
+/// well be out of date.
+async fn add_code_owner_reviewers(
+    git: &crate::git::Git,
+    gh: &GitHub,
+    config: &crate::config::Config,
+    commit_oid: Oid,
+    mode: CodeOwnersMode,
+    requested_reviewers: &mut PullRequestRequestReviewers,
+) -> Result<()> {
+    let codeowners = match CodeOwners::load(git, commit_oid)? {
+        Some(codeowners) => codeowners,
+        None => {
+            output("🤷", "There is no CODEOWNERS file")?;
+            return Ok(());
+        }
+    };
+    let changed_paths = git.changed_paths(commit_oid)?;
+    let me = GitHub::get_current_user_login().await?;
+
+    let candidates = codeowners
+        .owners_of_paths(changed_paths.iter().map(|path| &path[..]))
+        .iter()
+        .filter_map(|owner| owner_to_reviewer(owner, &config.owner))
+        .filter(|reviewer| match reviewer.strip_prefix('#') {
+            Some(slug) => !requested_reviewers
+                .team_reviewers
+                .iter()
+                .any(|team| team.eq_ignore_ascii_case(slug)),
+            None => {
+                !reviewer.eq_ignore_ascii_case(&me)
+                    && !requested_reviewers
+                        .reviewers
+                        .iter()
+                        .any(|user| user.eq_ignore_ascii_case(reviewer))
+            }
+        })
+        .collect::<Vec<_>>();
+    if candidates.is_empty() {
+        return Ok(());
+    }
+
+    let chosen = match mode {
+        CodeOwnersMode::Auto => candidates,
+        CodeOwnersMode::Suggest => {
+            let all = (0..candidates.len()).collect::<Vec<_>>();
+            MultiSelect::new(
+                "Request reviews from these code owners:",
+                candidates,
+            )
+            .with_default(&all)
+            .prompt()
+            .with_kind(ErrorKind::Aborted)?
+        }
+    };
+
+    for reviewer in chosen {
+        if let Some(slug) = reviewer.strip_prefix('#') {
+            match gh
+                .get_github_team((&config.owner).into(), slug.into())
+                .await
+            {
+                Ok(team) => requested_reviewers.team_reviewers.push(team.slug),
+                Err(_) => output(
+                    "⚠️",
+                    &format!("Skipping unknown code owner team '{reviewer}'"),
+                )?,
+            }
+        } else {
+            match gh.get_github_user(reviewer.clone()).await {
+                Ok(user) => requested_reviewers.reviewers.push(user.login),
+                Err(_) => output(
+                    "⚠️",
+                    &format!("Skipping unknown code owner '{reviewer}'"),
+                )?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The base branch of every Pull Request of the stack (by the name of its
+/// head branch) after this run: Pull Requests of selected commits are
+/// restacked if `--base` is given, all others keep their current base.
+/// Commits without a Pull Request are left out, as no other Pull Request can
+/// be based on them yet.
+async fn planned_base_branches(
+    opts: &DiffOptions,
+    prepared_commits: &mut [PreparedCommit],
+    selected_indexes: &[usize],
+) -> std::collections::BTreeMap<String, String> {
+    // Failing to load a Pull Request is reported when (and if) the commit
+    // is handled
+    let mut pull_requests = Vec::new();
+    for commit in prepared_commits.iter_mut() {
+        pull_requests.push(commit.pull_request().await.ok().flatten());
+    }
+
+    let mut bases = std::collections::BTreeMap::new();
+    for (index, pull_request) in pull_requests.iter().enumerate() {
+        let pull_request = match pull_request {
+            Some(pull_request) => pull_request,
+            None => continue,
+        };
+        let base = match &opts.base {
+            Some(base) if selected_indexes.contains(&index) => {
+                let diff = parse_parent_or_zero(base);
+                if diff == 0 {
+                    base.clone()
+                } else {
+                    match (index as isize - diff)
+                        .try_into()
+                        .ok()
+                        .and_then(|i: usize| pull_requests.get(i))
+                    {
+                        Some(Some(base)) => base.head.branch_name().to_string(),
+                        // Based on master, or a commit without Pull Request,
+                        // which diff will refuse anyway
+                        _ => continue,
+                    }
+                }
+            }
+            _ => pull_request.base.branch_name().to_string(),
+        };
+        bases.insert(pull_request.head.branch_name().to_string(), base);
+    }
+
+    bases
+}
+
+/// Follow the base branches from every branch, and return the first cycle
+/// found, starting and ending with the same branch
+fn find_base_cycle(
+    bases: &std::collections::BTreeMap<String, String>,
+) -> Option<Vec<String>> {
+    for start in bases.keys() {
+        let mut chain = vec![start.clone()];
+        let mut current = start;
+        while let Some(base) = bases.get(current) {
+            if let Some(position) = chain.iter().position(|b| b == base) {
+                let mut cycle = chain.split_off(position);
+                cycle.push(base.clone());
+                return Some(cycle);
+            }
+            chain.push(base.clone());
+            current = base;
+        }
+    }
+
+    None
+}
+
+/// With --confirm (or spr.confirm), show the plan for the selected commits
+/// and ask (with the given function) whether to go ahead. This comes before
+/// anything is pushed or changed on GitHub.
+async fn confirm_selection<F>(
+    opts: &DiffOptions,
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+    prepared_commits: &mut [PreparedCommit],
+    selected_indexes: &[usize],
+    ask: F,
+) -> Result<()>
+where
+    F: FnOnce(&str) -> Result<bool>,
+{
+    if !(opts.confirm || config.confirm)
+        || opts.assume_yes
+        || selected_indexes.is_empty()
+    {
+        return Ok(());
+    }
+
+    let mut plan =
+        compute_plan(git, config, prepared_commits, &opts.into()).await?;
+    plan.commits = selected_indexes
+        .iter()
+        .map(|&index| plan.commits[index].clone())
+        .collect();
+    confirm_plan(&plan, ask)
+}
+
+fn ask_to_confirm(prompt: &str) -> Result<bool> {
+    Confirm::new(prompt)
+        .with_default(false)
+        .prompt()
+        .with_kind(ErrorKind::Aborted)
+}
+
+/// Show the plan and ask (with the given function) whether to go ahead.
+/// Declining is an error, so that nothing gets changed.
+fn confirm_plan<F>(plan: &crate::plan::Plan, ask: F) -> Result<()>
+where
+    F: FnOnce(&str) -> Result<bool>,
+{
+    output("📋", "About to do the following:")?;
+    write_plan(plan, OutputFormat::Text)?;
+
+    if ask("Go ahead?")? {
+        Ok(())
+    } else {
+        Err(Error::new("Aborted as per user request")
+            .with_kind(ErrorKind::Aborted))
+    }
+}
+
+/// The three cases of updating a Pull Request, as described in `diff_impl`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UpdateCase {
+    /// Case 1: nothing needs merging into the Pull Request branch
+    Unchanged,
+    /// Case 2: the master commit is merged into the Pull Request branch
+    MergeMaster,
+    /// Case 3: a new commit is created on the intermediate base branch
+    BaseBranch,
+}
+
+fn choose_update_case(
+    base_tree_unchanged: bool,
+    needs_merging_master: bool,
+    has_base_branch: bool,
+    no_cherry_pick: bool,
+) -> UpdateCase {
+    if base_tree_unchanged && !needs_merging_master {
+        UpdateCase::Unchanged
+    } else if !has_base_branch && !no_cherry_pick {
+        UpdateCase::MergeMaster
+    } else {
+        UpdateCase::BaseBranch
+    }
+}
+
+/// The `--explain` output: which update case applies, why, and the values
+/// the decision is based on
+fn explain_update_case(
+    base_tree_unchanged: bool,
+    needs_merging_master: bool,
+    base_branch: Option<&str>,
+    no_cherry_pick: bool,
+    is_no_op: bool,
+) -> String {
+    let case = choose_update_case(
+        base_tree_unchanged,
+        needs_merging_master,
+        base_branch.is_some(),
+        no_cherry_pick,
+    );
+    let summary = match case {
+        UpdateCase::Unchanged => {
+            "Case 1: the parent tree is unchanged and master does not need \
+             merging in, so nothing is merged into the Pull Request branch"
+                .to_string()
+        }
+        UpdateCase::MergeMaster => {
+            "Case 2: the parent tree changed or master needs merging in, and \
+             there is no base branch, so the master commit is merged into the \
+             Pull Request branch"
+                .to_string()
+        }
+        UpdateCase::BaseBranch => format!(
+            "Case 3: the parent tree changed or master needs merging in, and \
+             {}, so a new commit is created on the base branch",
+            if base_branch.is_some() {
+                "a base branch exists already"
+            } else {
+                "--no-cherry-pick is given"
+            }
+        ),
+    };
+
+    let mut explanation = formatdoc!(
+        "
+        {summary}
+          needs_merging_master: {needs_merging_master}
+          pr_base_tree == new_base_tree: {base_tree_unchanged}
+          existing base branch: {base_branch}
+          no_cherry_pick: {no_cherry_pick}",
+        base_branch = base_branch.unwrap_or("none"),
+    );
+    if is_no_op {
+        explanation.push_str("\nThe Pull Request is up to date, though");
+    }
+
+    explanation
+}
+
+/// The intermediate base branch an existing Pull Request with the given base
+/// uses, if any. A Pull Request that was stacked onto another Pull Request
+/// (with `--base`) targets that Pull Request's branch, and keeps doing so on
+/// updates without `--base`: that branch must not be treated as an
+/// intermediate base branch of ours.
+fn existing_base_branch(
+    config: &crate::config::Config,
+    pr_base: &crate::github::GitHubBranch,
+    explicit_base: bool,
+    no_cherry_pick: bool,
+) -> Option<crate::github::GitHubBranch> {
+    let stacked_on_pull_request = explicit_base
+        || (!pr_base.is_master_branch()
+            && !config.is_base_branch_name(pr_base.branch_name()));
+
+    if pr_base.is_master_branch() || stacked_on_pull_request || !no_cherry_pick
+    {
+        None
+    } else {
+        Some(pr_base.clone())
+    }
+}
+
+/// With `--prefer-existing-base`, the intermediate base branch of an
+/// existing Pull Request is reused as it is if only the head of the Pull
+/// Request changed: the base tree is the same, and master needs no merging
+/// in. That makes it case 1, so the base branch is not pushed again.
+fn reusable_base_branch(
+    config: &crate::config::Config,
+    pr_base: &crate::github::GitHubBranch,
+    prefer_existing_base: bool,
+    base_tree_unchanged: bool,
+    needs_merging_master: bool,
+) -> Option<crate::github::GitHubBranch> {
+    (prefer_existing_base
+        && base_tree_unchanged
+        && !needs_merging_master
+        && !pr_base.is_master_branch()
+        && config.is_base_branch_name(pr_base.branch_name()))
+    .then(|| pr_base.clone())
+}
+
+/// Post the `--comment` text, if given, on the given Pull Request
+async fn post_comment(
+    gh: &crate::github::GitHub,
+    opts: &DiffOptions,
+    number: u64,
+) -> Result<()> {
+    if let Some(comment) = &opts.comment {
+        gh.create_comment(number, &render_comment(comment, number))
+            .await?;
+        output("💬", &format!("Commented on #{number}"))?;
+    }
+
+    Ok(())
+}
+
+fn render_comment(template: &str, number: u64) -> String {
+    template.replace("{pr}", &number.to_string())
+}
+
+/// Post the --draft-comment on a Pull Request that was just created. Like
+/// the reviewers, a failure here must not stop us from recording the new
+/// Pull Request in the commit message.
+async fn post_draft_comment(
+    gh: &crate::github::GitHub,
+    opts: &DiffOptions,
+    number: u64,
+) -> Result<()> {
+    let comment = match draft_comment(
+        opts.draft,
+        opts.draft_comment.as_deref(),
+        number,
+    ) {
+        Some(comment) => comment,
+        None => return Ok(()),
+    };
+
+    match gh.create_comment(number, &comment).await {
+        Ok(()) => output("💬", &format!("Commented on draft #{number}")),
+        Err(error) => {
+            output("⚠️", "Posting the draft comment failed")?;
+            for message in error.messages() {
+                output("  ", message)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The first comment for a new Pull Request: the --draft-comment, but only if
+/// the Pull Request was created as a draft
+fn draft_comment(
+    draft: bool,
+    template: Option<&str>,
+    number: u64,
+) -> Option<String> {
+    template
+        .filter(|_| draft)
+        .map(|template| render_comment(template, number))
+}
+
+/// Upload the files listed in the `Attachments` section of the commit message
+/// (separated by commas or newlines) to a new Pull Request. This is best
+/// effort: failures only cause a warning.
+async fn upload_attachments(
+    gh: &crate::github::GitHub,
+    number: u64,
+    attachments: &str,
+) -> Result<()> {
+    let paths = attachments
+        .split([',', '\n'])
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        return Ok(());
+    }
+
+    #[cfg(feature = "attachments")]
+    if let Err(error) = gh.upload_and_comment(number, &paths).await {
+        output("⚠️", "Uploading attachments failed")?;
+        for message in error.messages() {
+            output("  ", message)?;
+        }
+    }
+
+    #[cfg(not(feature = "attachments"))]
+    {
+        let _ = (gh, number);
+        output(
+            "⚠️",
+            "Ignoring the Attachments section: spr was built without the \
+             'attachments' feature",
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Number of changed files from which on an update to a Pull Request counts as
+/// significant for `--dismiss-stale-reviews`
+const SIGNIFICANT_UPDATE_CHANGED_FILES: usize = 10;
+
+/// Whether an update to a Pull Request is significant enough to make existing
+/// approvals stale: that is the case if it was rebased onto a new master
+/// commit, or if it changes many files.
+fn is_significant_update(
+    needs_merging_master: bool,
+    changed_files: usize,
+) -> bool {
+    needs_merging_master || changed_files >= SIGNIFICANT_UPDATE_CHANGED_FILES
+}
+
+async fn get_pull_request_for_index(
+    config: &crate::config::Config,
+    prepared_commits: &mut [PreparedCommit],
+    choice_index: isize,
+) -> Result<crate::github::PullRequest> {
+    let pull_request = prepared_commits[choice_index as usize]
+        .pull_request()
+        .await?;
+    match pull_request {
+        Some(pull_request) => {
+            check_base_pull_request_is_open(config, &pull_request)?;
+            Ok(pull_request)
+        }
+        None => Err(Error::new("Could not find a PR for the base".to_string())),
+    }
+}
+
+/// The head branch for a new Pull Request: the one given with
+/// `--branch-name`, which must not exist yet, or else one derived from the
+/// title that doesn't exist yet
+fn new_pull_request_branch(
+    config: &crate::config::Config,
+    existing_ref_names: &std::collections::HashSet<String>,
+    branch_name: Option<&str>,
+    title: &str,
+) -> Result<crate::github::GitHubBranch> {
+    let branch_name = match branch_name {
+        Some(name) => {
+            if !git2::Branch::name_is_valid(name)? {
+                return Err(Error::new(format!(
+                    "'{name}' is not a valid branch name"
+                )));
+            }
+            let branch = config.new_github_branch(name);
+            if existing_ref_names.contains(branch.local()) {
+                return Err(Error::new(format!(
+                    "The branch '{name}' exists already"
+                )));
+            }
+            return Ok(branch);
+        }
+        None => config.get_new_branch_name(existing_ref_names, title),
+    };
+
+    Ok(config.new_github_branch(&branch_name))
+}
+
+/// The branch spr would give a new Pull Request with the given title, if that
+/// exists on GitHub already. (When it does, spr would pick another name.)
+fn orphaned_branch(
+    config: &crate::config::Config,
+    existing_ref_names: &std::collections::HashSet<String>,
+    title: &str,
+) -> Option<crate::github::GitHubBranch> {
+    let branch = config.new_github_branch(&format!(
+        "{}{}",
+        config.branch_prefix,
+        slugify(title)
+    ));
+    existing_ref_names
+        .contains(branch.local())
+        .then_some(branch)
+}
+
+/// Whether a Pull Request that changes the given files can be the one of a
+/// commit that changes the given files: the two have some file in common
+fn related_changes(
+    commit_paths: &[String],
+    pull_request_paths: &[String],
+) -> bool {
+    commit_paths
+        .iter()
+        .any(|path| pull_request_paths.contains(path))
+}
+
+/// The open Pull Request on the branch spr would create for the commit at
+/// the given index, unless another commit of the stack links to it, or the
+/// branch changes none of the files the commit changes. This is the Pull
+/// Request of a commit that lost its Pull Request section.
+async fn find_orphaned_pull_request(
+    git: &crate::git::Git,
+    gh: &GitHub,
+    config: &crate::config::Config,
+    prepared_commits: &[PreparedCommit],
+    index: usize,
+) -> Result<Option<u64>> {
+    let title = prepared_commits[index]
+        .message
+        .get(&MessageSection::Title)
+        .map(|t| &t[..])
+        .unwrap_or("");
+    let branch =
+        match orphaned_branch(config, &*git.get_all_ref_names()?, title) {
+            Some(branch) => branch,
+            None => return Ok(None),
+        };
+
+    // The branch may just happen to have the name, for a commit with the same
+    // title
+    let branch_head = git.resolve_reference(branch.local())?;
+    let master = git.resolve_reference(config.master_ref.local())?;
+    let branch_base = git.repo().merge_base(branch_head, master)?;
+    let branch_paths = git.changed_paths_between(
+        git.get_tree_oid_for_commit(branch_base)?,
+        git.get_tree_oid_for_commit(branch_head)?,
+    )?;
+    if !related_changes(
+        &git.changed_paths(prepared_commits[index].oid)?,
+        &branch_paths,
+    ) {
+        return Ok(None);
+    }
+
+    let number = match gh
+        .get_open_pull_request_number_for_head(branch.branch_name().into())
+        .await
+    {
+        Ok(number) => number,
+        Err(error) if error.kind() == ErrorKind::GitHub => return Err(error),
+        // No open Pull Request on that branch
+        Err(_) => return Ok(None),
+    };
+    if prepared_commits
+        .iter()
+        .any(|commit| commit.pull_request_number == Some(number))
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(number))
+}
+
+/// Link the commit to the Pull Request with the given number again
+fn restore_pull_request_link(
+    config: &crate::config::Config,
+    prepared_commit: &mut PreparedCommit,
+    number: u64,
+) {
+    prepared_commit.pull_request_number = Some(number);
+    prepared_commit
+        .message
+        .insert(MessageSection::PullRequest, config.pull_request_url(number));
+}
+
+/// The head branches and numbers of the (open) Pull Requests of the given
+/// commits, in the same order. Commits without a Pull Request, or whose Pull
+/// Request can't be fetched, are skipped.
+async fn stack_head_branches(
+    prepared_commits: &mut [PreparedCommit],
+) -> Vec<(crate::github::GitHubBranch, u64)> {
+    let mut heads = Vec::new();
+    for prepared_commit in prepared_commits.iter_mut() {
+        if let Ok(Some(pull_request)) = prepared_commit.pull_request().await {
+            if pull_request.state == PullRequestState::Open {
+                heads.push((pull_request.head, pull_request.number));
+            }
+        }
+    }
+
+    heads
+}
+
+/// If the given base of an existing Pull Request is the branch of a Pull
+/// Request created by spr that is not among the given Pull Requests of the
+/// commits below it (any more), the base to retarget it to: the branch of
+/// the nearest Pull Request below it (with its number), or master.
+fn retarget_base(
+    config: &crate::config::Config,
+    pr_base: &crate::github::GitHubBranch,
+    stack_heads: &[(crate::github::GitHubBranch, u64)],
+) -> Option<(crate::github::GitHubBranch, Option<u64>)> {
+    let name = pr_base.branch_name();
+    if pr_base.is_master_branch()
+        || !name.starts_with(&config.branch_prefix)
+        || config.is_base_branch_name(name)
+        || stack_heads
+            .iter()
+            .any(|(head, _)| head.branch_name() == name)
+    {
+        return None;
+    }
+
+    Some(match stack_heads.last() {
+        Some((head, number)) => (head.clone(), Some(*number)),
+        None => (config.master_ref.clone(), None),
+    })
+}
+
+/// Stacking onto a closed Pull Request does not work: its branch may have
+/// been deleted already, and even if not, it will never be merged.
+fn check_base_pull_request_is_open(
+    config: &crate::config::Config,
+    pull_request: &crate::github::PullRequest,
+) -> Result<()> {
+    if pull_request.state == PullRequestState::Closed {
+        return Err(Error::new(formatdoc!(
+            "Pull Request #{number} selected as the base is closed or \
+             merged. Please select {master} or another open Pull Request \
+             as the base.",
+            number = pull_request.number,
+            master = config.master_ref.branch_name(),
+        )));
+    }
+
+    Ok(())
+}
+
+/// Looks up the reviewers listed in commit messages
+trait ReviewerLookup {
+    async fn user(&self, login: String) -> Result<UserWithName>;
+
+    /// The slug of the given team of the owner
+    async fn team(&self, owner: &str, slug: &str) -> Result<String>;
+}
+
+impl ReviewerLookup for GitHub {
+    async fn user(&self, login: String) -> Result<UserWithName> {
+        self.get_github_user(login).await
+    }
+
+    async fn team(&self, owner: &str, slug: &str) -> Result<String> {
+        let team = self.get_github_team(owner.into(), slug.into()).await?;
+        Ok(team.slug)
+    }
+}
+
+/// The reviewers to request for a new Pull Request, from the Reviewers
+/// section of the commit message. Unknown users and teams are an error.
+/// Unless `--no-reviewers-writeback` is given, the section is replaced with
+/// the reviewers as checked, which includes the names of users.
+async fn check_reviewers<L: ReviewerLookup>(
+    lookup: &L,
+    opts: &DiffOptions,
+    config: &crate::config::Config,
+    message: &mut MessageSectionsMap,
+) -> Result<PullRequestRequestReviewers> {
+    let mut requested_reviewers = PullRequestRequestReviewers::default();
+    let reviewers = match message.get(&MessageSection::Reviewers) {
+        Some(reviewers) => {
+            config.expand_reviewer_aliases(parse_name_list(reviewers))?
+        }
+        None => return Ok(requested_reviewers),
+    };
+    let mut checked_reviewers = Vec::new();
+
+    for reviewer in reviewers {
+        // Teams are indicated with a leading #
+        if let Some(slug) = reviewer.strip_prefix('#') {
+            if let Ok(team) = lookup.team(&config.owner, slug).await {
+                requested_reviewers.team_reviewers.push(team);
+
+                checked_reviewers.push(reviewer);
+            } else {
+                return Err(Error::new(format!(
+                    "Reviewers field contains unknown team '{}'",
+                    reviewer
+                )));
+            }
+        } else if let Ok(user) = lookup.user(reviewer.clone()).await {
+            requested_reviewers.reviewers.push(user.login);
+            if let Some(name) = user.name {
+                checked_reviewers.push(format!(
+                    "{} ({})",
+                    reviewer.clone(),
+                    remove_all_parens(&name)
+                ));
+            } else {
+                checked_reviewers.push(reviewer);
+            }
+        } else {
+            return Err(Error::new(format!(
+                "Reviewers field contains unknown user '{}'",
+                reviewer
+            )));
+        }
+    }
+
+    if !opts.no_reviewers_writeback {
+        message.insert(MessageSection::Reviewers, checked_reviewers.join(", "));
+    }
+
+    Ok(requested_reviewers)
+}
+
+/// The reviewers a Pull Request is expected to have requested: the ones in
+/// the Reviewers section of the message (with aliases expanded), the code
+/// owners of the changed files, and the reviewers for the size of the change,
+/// which spr (or GitHub, for code owners) may have requested
+fn expected_reviewers(
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+    message: &MessageSectionsMap,
+    commit_oid: Oid,
+) -> Result<Vec<String>> {
+    let mut expected = match message.get(&MessageSection::Reviewers) {
+        Some(text) => config.expand_reviewer_aliases(parse_name_list(text))?,
+        None => Vec::new(),
+    };
+
+    if let Some(codeowners) = CodeOwners::load(git, commit_oid)? {
+        let changed_paths = git.changed_paths(commit_oid)?;
+        expected.extend(
+            codeowners
+                .owners_of_paths(changed_paths.iter().map(|path| &path[..]))
+                .iter()
+                .filter_map(|owner| owner_to_reviewer(owner, &config.owner)),
+        );
+    }
+
+    if !config.size_reviewers.is_empty() {
+        expected.extend(
+            config.reviewers_for_size(git.count_changed_lines(commit_oid)?)?,
+        );
+    }
+
+    Ok(expected)
+}
+
+/// The requested reviewers of the Pull Request that are not in the given list
+/// of reviewers from the commit message. GitHub stops listing a reviewer as
+/// requested once they submit a review, and we never remove those either.
+fn reviewers_to_prune(
+    listed: &[String],
+    pull_request: &crate::github::PullRequest,
+) -> PullRequestRequestReviewers {
+    let is_listed = |name: &str| {
+        listed
+            .iter()
+            .any(|listed| listed.eq_ignore_ascii_case(name))
+    };
+    let mut pruned = PullRequestRequestReviewers::default();
+
+    for requested in &pull_request.requested_reviewers {
+        if is_listed(requested) {
+            continue;
+        }
+        if let Some(slug) = requested.strip_prefix('#') {
+            pruned.team_reviewers.push(slug.to_string());
+        } else if !pull_request.reviewers.contains_key(requested) {
+            pruned.reviewers.push(requested.clone());
+        }
+    }
+
+    pruned
+}
+
+/// The base to use for the commit: the one given with `--base`, or else the
+/// one in the Base section of the commit message
+fn requested_base(
+    cli_base: Option<&String>,
+    message: &MessageSectionsMap,
+) -> Option<String> {
+    cli_base.cloned().or_else(|| {
+        message
+            .get(&MessageSection::Base)
+            .map(|base| base.trim().to_string())
+            .filter(|base| !base.is_empty())
+    })
+}
+
+/// The commit message as it should appear on GitHub, with the title replaced
+/// if the user gave one with `--title`. The local message is not changed.
+fn with_title_override(
+    message: &MessageSectionsMap,
+    title: Option<&str>,
+) -> MessageSectionsMap {
+    let mut message = message.clone();
+    if let Some(title) = title {
+        message.insert(MessageSection::Title, title.to_string());
+    }
+    message
+}
+
+fn render_message_template(
+    template: &str,
+    pull_request_number: u64,
+    title: &str,
+    needs_merging_master: bool,
+) -> String {
+    template
+        .replace("{pr}", &pull_request_number.to_string())
+        .replace("{title}", title)
+        .replace(
+            "{reason}",
+            if needs_merging_master {
+                "rebase"
+            } else {
+                "amend"
+            },
+        )
+}
+
+/// Render a template for the description of a Pull Request: the
+/// placeholders {title}, {summary} and {test_plan} are filled from the commit
+/// message, {pr} and {url} from the Pull Request, if it exists yet. Unknown
+/// placeholders render empty; their names are returned, so that the caller
+/// can warn about them.
+fn render_body_template(
+    template: &str,
+    message: &MessageSectionsMap,
+    pull_request: Option<(u64, &str)>,
+) -> (String, Vec<String>) {
+    let mut unknown = Vec::new();
+    let body = lazy_regex::regex!(r#"\{(\w+)\}"#)
+        .replace_all(template, |caps: &lazy_regex::Captures| {
+            let section = |section| message.get(&section).cloned();
+            let value = match &caps[1] {
+                "title" => section(MessageSection::Title),
+                "summary" => section(MessageSection::Summary),
+                "test_plan" => section(MessageSection::TestPlan),
+                "pr" => pull_request.map(|(number, _)| number.to_string()),
+                "url" => pull_request.map(|(_, url)| url.to_string()),
+                name => {
+                    unknown.push(name.to_string());
+                    None
+                }
+            };
+            value.unwrap_or_default()
+        })
+        .into_owned();
+
+    (body, unknown)
+}
+
+/// The diffstat for the Pull Request description, if configured
+fn diffstat_for(
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+    base_tree: Oid,
+    head_tree: Oid,
+) -> Result<Option<String>> {
+    if config.diffstat {
+        git.diffstat(base_tree, head_tree).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Create a Pull Request for the given message. Its description is built
+/// from the message sections, or from the template file, if one is given,
+/// with the diffstat on top if one is given.
+async fn create_pull_request(
+    opts: &DiffOptions,
+    gh: &GitHub,
+    config: &crate::config::Config,
+    message: &MessageSectionsMap,
+    base_ref_name: String,
+    head_ref_name: String,
+    diffstat: Option<&str>,
+) -> Result<u64> {
+    let add_diffstat = |body: String| match diffstat {
+        Some(diffstat) => with_diffstat(&body, diffstat),
+        None => body,
+    };
+
+    let template = match &opts.template_file {
+        Some(path) => Some(std::fs::read_to_string(path).map_err(|error| {
+            Error::new(format!(
+                "Could not read template file {}: {error}",
+                path.display()
+            ))
+        })?),
+        None => None,
+    };
+    let template = match template {
+        Some(template) => template,
+        None => {
+            return gh
+                .create_pull_request(
+                    message,
+                    add_diffstat(build_github_body(message)),
+                    base_ref_name,
+                    head_ref_name,
+                    opts.draft,
+                )
+                .await;
+        }
+    };
+
+    let (body, unknown) = render_body_template(&template, message, None);
+    let body = add_diffstat(body);
+    for name in unknown {
+        output(
+            "⚠️",
+            &format!("Unknown placeholder {{{name}}} in the template file"),
+        )?;
+    }
+    let number = gh
+        .create_pull_request(
+            message,
+            body.clone(),
+            base_ref_name,
+            head_ref_name,
+            opts.draft,
+        )
+        .await?;
+
+    // The number of the Pull Request is only known now
+    let url = config.pull_request_url(number);
+    let (final_body, _) =
+        render_body_template(&template, message, Some((number, &url)));
+    let final_body = add_diffstat(final_body);
+    if final_body != body {
+        gh.update_pull_request(
+            number,
+            PullRequestUpdate {
+                body: Some(final_body),
+                ..Default::default()
+            },
+        )
+        .await?;
+    }
+
+    Ok(number)
+}
+
+/// Pushes the branches of Pull Requests and creates new Pull Requests
+trait Publisher {
+    async fn push(&mut self, cmd: &mut tokio::process::Command) -> Result<()>;
+
+    async fn create_pull_request(
+        &mut self,
+        message: &MessageSectionsMap,
+        base_ref_name: String,
+        head_ref_name: String,
+        diffstat: Option<&str>,
+    ) -> Result<u64>;
+}
+
+struct GitHubPublisher<'a> {
+    opts: &'a DiffOptions,
+    gh: &'a GitHub,
+    config: &'a crate::config::Config,
+}
+
+impl Publisher for GitHubPublisher<'_> {
+    async fn push(&mut self, cmd: &mut tokio::process::Command) -> Result<()> {
+        push_to_github(cmd, self.config).await
+    }
+
+    async fn create_pull_request(
+        &mut self,
+        message: &MessageSectionsMap,
+        base_ref_name: String,
+        head_ref_name: String,
+        diffstat: Option<&str>,
+    ) -> Result<u64> {
+        create_pull_request(
+            self.opts,
+            self.gh,
+            self.config,
+            message,
+            base_ref_name,
+            head_ref_name,
+            diffstat,
+        )
+        .await
+    }
+}
+
+/// Push the branches of a new Pull Request with the given command, then
+/// create the Pull Request and link the commit message to it
+#[allow(clippy::too_many_arguments)]
+async fn publish_pull_request<P: Publisher>(
+    publisher: &mut P,
+    opts: &DiffOptions,
+    config: &crate::config::Config,
+    cmd: &mut tokio::process::Command,
+    message: &mut MessageSectionsMap,
+    base_ref_name: String,
+    head_ref_name: String,
+    diffstat: Option<&str>,
+) -> Result<u64> {
+    publisher.push(cmd).await?;
+
+    let pull_request_number = publisher
+        .create_pull_request(
+            &with_title_override(message, opts.title.as_deref()),
+            base_ref_name,
+            head_ref_name,
+            diffstat,
+        )
+        .await?;
+    let pull_request_url = config.pull_request_url(pull_request_number);
+    output(
+        "✨",
+        &format!(
+            "Created new Pull Request #{}: {}",
+            pull_request_number, &pull_request_url,
+        ),
+    )?;
+    message.insert(MessageSection::PullRequest, pull_request_url);
+
+    Ok(pull_request_number)
+}
+
+/// The `git push` command for the branches of Pull Requests, without the
+/// remote and the refspecs
+fn push_command(opts: &DiffOptions) -> tokio::process::Command {
+    let mut cmd = tokio::process::Command::new("git");
+    cmd.arg("push").arg("--atomic");
+    if opts.no_verify {
+        cmd.arg("--no-verify");
+    }
+    // Git only reports progress if its stderr is a terminal, but
+    // push_to_github pipes it
+    if console::Term::stderr().is_term() {
+        cmd.arg("--progress");
+    }
+    cmd
+}
+
+async fn push_to_github(
+    cmd: &mut tokio::process::Command,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let (remote, refspecs) = push_targets(cmd);
+    check_push_targets(config, &refspecs)?;
+    if config.verbose {
+        output("📤", &format!("Pushing to {remote}:"))?;
+        for refspec in &refspecs {
+            output("  ", refspec)?;
+        }
+    }
+
+    run_command_with_live_output(cmd, |stderr| {
+        explain_push_rejection(stderr, config)
+    })
+    .await
+    .reword("git push failed".to_string())
+}
+
+/// The option that lets `git push` replace the given branch on GitHub, but
+/// only if it is still at the expected commit
+fn force_with_lease(branch: &GitHubBranch, expected: Oid) -> String {
+    format!("--force-with-lease={}:{}", branch.on_github(), expected)
+}
+
+/// The remote and the refspecs of a `git push` command, which follow the
+/// `--`
+fn push_targets(cmd: &tokio::process::Command) -> (&str, Vec<&str>) {
+    let mut targets = cmd
+        .as_std()
+        .get_args()
+        .skip_while(|arg| *arg != "--")
+        .skip(1)
+        .filter_map(|arg| arg.to_str());
+    let remote = targets.next().unwrap_or_default();
+    (remote, targets.collect())
+}
+
+/// Branch names that are the default branch of so many repositories that spr
+/// never pushes to them, whatever the configured master branch
+const DEFAULT_BRANCH_NAMES: &[&str] = &["main", "master"];
+
+/// Refuse to push to the master branch, or any other likely default branch.
+/// spr only ever pushes to its own branches, so this can only be the result
+/// of a misconfiguration (e.g. an empty branch prefix and a commit titled
+/// "main"), and must not clobber master.
+fn check_push_targets(
+    config: &crate::config::Config,
+    refspecs: &[&str],
+) -> Result<()> {
+    for refspec in refspecs {
+        let target = refspec.rsplit(':').next().unwrap_or(refspec);
+        let branch_name = target.strip_prefix("refs/heads/").unwrap_or(target);
+        if branch_name == config.master_ref.branch_name()
+            || DEFAULT_BRANCH_NAMES.contains(&branch_name)
+        {
+            return Err(Error::new(format!(
+                "Refusing to push to '{branch_name}', which is (or looks \
+                 like) the default branch of the repository. Please check \
+                 the branch prefix (spr.branchPrefix is '{}').",
+                config.branch_prefix
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Explain the common reasons for GitHub to reject a push, as the output of
+/// `git push` is rather cryptic about them:
+/// - branches covered by branch protection rules can't be pushed to.
+/// - a push that is not a fast-forward is rejected: somebody else pushed to
+///   the Pull Request branch since spr fetched it.
+fn explain_push_rejection(
+    stderr: &str,
+    config: &crate::config::Config,
+) -> Option<String> {
+    let protected = lazy_regex::regex!(
+        r#"(?i)GH006|GH013|protected branch|repository rule violations|creations being restricted"#
+    );
+    let non_fast_forward = lazy_regex::regex!(
+        r#"! \[rejected\] +\S+ -> (\S+) \((non-fast-forward|fetch first)\)"#
+    );
+
+    if protected.is_match(stderr) {
+        Some(formatdoc!(
+            "GitHub rejected the push because of branch protection rules. \
+             Branches created by spr are named '{prefix}...' - please \
+             configure a branch prefix that is not protected (`git config \
+             spr.branchPrefix <prefix>`).",
+            prefix = config.branch_prefix,
+        ))
+    } else {
+        non_fast_forward.captures(stderr).map(|captures| {
+            formatdoc!(
+                "GitHub rejected the push because the branch '{branch}' has \
+                 commits that spr did not know about: somebody else pushed \
+                 to it since it was fetched. Check those commits on GitHub \
+                 (and take over what you need into the local commit), then \
+                 run `spr diff` again, which builds on top of them.",
+                branch = &captures[1],
+            )
+        })
+    }
+}
+
+/// A reference to a Pull Request in another repository
+#[derive(Debug, PartialEq, Eq)]
+struct CrossRepoReference {
+    owner: String,
+    repo: String,
+    number: u64,
+}
+
+impl std::fmt::Display for CrossRepoReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}#{}", self.owner, self.repo, self.number)
+    }
+}
+
+/// Parse a base given as `owner/repo#number` (optionally with a leading `#`)
+fn parse_cross_repo_reference(base: &str) -> Option<CrossRepoReference> {
+    let base = base.trim();
+    let (path, number) =
+        base.strip_prefix('#').unwrap_or(base).split_once('#')?;
+    let (owner, repo) = path.split_once('/')?;
+    let valid_name = |name: &str| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    };
+    if !valid_name(owner) || !valid_name(repo) {
+        return None;
+    }
+    Some(CrossRepoReference {
+        owner: owner.to_string(),
+        repo: repo.to_string(),
+        number: number.parse().ok()?,
+    })
+}
+
+fn parse_parent_or_zero(s: &str) -> isize {
+    if s == "HEAD~" || s == "HEAD^" {
+        1
+    } else if s.starts_with("HEAD^") || s.starts_with("HEAD^") {
+        s[5..].parse::<isize>().unwrap_or_default()
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    use crate::{
+        config::test_utils::config_factory,
+        git::test_utils::{
+            commit, commit_files, commit_files_as, init_test_repo,
+        },
+        github::test_utils::pull_request_factory,
+    };
+
+    #[test]
+    fn test_resumable_state() {
+        let git = init_test_repo("resumable-state");
+        let root = commit(&git, "root", &[]);
+        let first = commit(&git, "first", &[root]);
+        let second = commit(&git, "second", &[first]);
+        let third = commit(&git, "third", &[second]);
+        assert_eq!(resumable_state(&git, third, 3).unwrap(), None);
+
+        // The first commit was done when the run was interrupted
+        let mut state = DiffState::new(third, 3, vec![0, 1, 2]);
+        state.complete(0, Some("https://github.com/acme/codez/pull/1".into()));
+        state.save(&git).unwrap();
+
+        let resumed = resumable_state(&git, third, 3).unwrap().unwrap();
+        assert_eq!(resumed, state);
+        assert_eq!(resumed.remaining(), vec![1, 2]);
+
+        // A new commit on top invalidates the progress, which is then gone
+        let fourth = commit(&git, "fourth", &[third]);
+        assert_eq!(resumable_state(&git, fourth, 4).unwrap(), None);
+        assert_eq!(DiffState::load(&git).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_restore_completed_commits() {
+        use crate::github::test_utils::offline_github;
+
+        let git = init_test_repo("restore-completed");
+        let (gh, _) = offline_github(&git);
+        let config = config_factory();
+        let commit = || PreparedCommit {
+            oid: Oid::zero(),
+            short_id: "aaaaaaa".into(),
+            parent_oid: Oid::zero(),
+            message: Default::default(),
+            pull_request_number: None,
+            pull_request_task: None,
+        };
+        let urls = [
+            "https://github.com/acme/codez/pull/1",
+            "https://github.com/acme/codez/pull/2/",
+            "https://github.com/acme/codez/pull/3#issuecomment-42",
+            "https://github.com/acme/codez/pull/4?w=1",
+            "https://github.com/other/codez/pull/5",
+        ];
+        let mut prepared_commits: Vec<_> =
+            urls.iter().map(|_| commit()).collect();
+        let mut state =
+            DiffState::new(Oid::zero(), urls.len(), vec![0, 1, 2, 3, 4]);
+        for (index, url) in urls.iter().enumerate() {
+            state.complete(index, Some(url.to_string()));
+        }
+
+        restore_completed_commits(&gh, &config, &mut prepared_commits, &state);
+
+        assert_eq!(
+            prepared_commits
+                .iter()
+                .map(|commit| commit.pull_request_number)
+                .collect::<Vec<_>>(),
+            vec![Some(1), Some(2), Some(3), Some(4), None]
+        );
+        assert_eq!(
+            prepared_commits[2]
+                .message
+                .get(&MessageSection::PullRequest),
+            Some(&urls[2].to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_squash_stack_creates_one_pull_request() {
+        use clap::Parser;
+
+        /// Records the pushes and the Pull Requests created
+        #[derive(Default)]
+        struct Recorder {
+            pushed: Vec<Vec<String>>,
+            created: Vec<(String, String)>,
+        }
+        impl Publisher for Recorder {
+            async fn push(
+                &mut self,
+                cmd: &mut tokio::process::Command,
+            ) -> Result<()> {
+                let (_, refspecs) = push_targets(cmd);
+                self.pushed
+                    .push(refspecs.into_iter().map(String::from).collect());
+                Ok(())
+            }
+            async fn create_pull_request(
+                &mut self,
+                _message: &MessageSectionsMap,
+                base_ref_name: String,
+                head_ref_name: String,
+                _diffstat: Option<&str>,
+            ) -> Result<u64> {
+                self.created.push((base_ref_name, head_ref_name));
+                Ok(7)
+            }
+        }
+
+        let git = init_test_repo("squash-stack");
+        let master = commit_files(&git, &[("m", "m")], "Master", &[]);
+        let first =
+            commit_files(&git, &[("m", "m"), ("a", "a")], "First", &[master]);
+        let second = commit_files(
+            &git,
+            &[("m", "m"), ("a", "a"), ("b", "b")],
+            "Second",
+            &[first],
+        );
+        {
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/master", master, true, "test")
+                .unwrap();
+            repo.set_head_detached(second).unwrap();
+        }
+        let mut config = config_factory();
+        config.require_test_plan = false;
+        let gh =
+            GitHub::new(config.clone(), (*git).clone(), Default::default());
+        let opts = DiffOptions::parse_from(["diff", "--squash-stack"]);
+        let mut prepared_commits =
+            git.get_prepared_commits(&config, None).unwrap();
+
+        let mut recorder = Recorder::default();
+        diff_squash_stack_impl(
+            &opts,
+            &git,
+            &gh,
+            &mut recorder,
+            &config,
+            &mut prepared_commits,
+            master,
+        )
+        .await
+        .unwrap();
+
+        // One branch and one Pull Request against master, for the whole stack
+        assert_eq!(recorder.pushed.len(), 1);
+        assert_eq!(recorder.pushed[0].len(), 1);
+        assert_eq!(recorder.created.len(), 1);
+        let (base, head) = &recorder.created[0];
+        assert_eq!(base, "master");
+        assert!(recorder.pushed[0][0].ends_with(&format!("refs/heads/{head}")));
+
+        // Its commit has the changes of both commits on top of master
+        let squashed =
+            Oid::from_str(recorder.pushed[0][0].split(':').next().unwrap())
+                .unwrap();
+        {
+            let repo = git.repo();
+            let squashed = repo.find_commit(squashed).unwrap();
+            assert_eq!(squashed.parent_ids().collect::<Vec<_>>(), vec![master]);
+            assert_eq!(
+                squashed.tree_id(),
+                repo.find_commit(second).unwrap().tree_id()
+            );
+        }
+
+        // Only the top commit links to the Pull Request
+        assert_eq!(
+            prepared_commits[1]
+                .message
+                .get(&MessageSection::PullRequest),
+            Some(&"https://github.com/acme/codez/pull/7".to_string())
+        );
+        assert_eq!(
+            prepared_commits[0]
+                .message
+                .get(&MessageSection::PullRequest),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_stack_depth() {
+        use crate::github::test_utils::{offline_github, sent_requests};
+        use clap::Parser;
+
+        // Three commits, linked to Pull Requests that spr would look up
+        let git = init_test_repo("diff-max-stack-depth");
+        let root = commit(&git, "root", &[]);
+        let mut head = root;
+        for number in 1..=3 {
+            head = commit(
+                &git,
+                &format!(
+                    "Commit {number}\n\nPull Request: \
+                     https://github.com/acme/codez/pull/{number}"
+                ),
+                &[head],
+            );
+        }
+        {
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/master", root, true, "test")
+                .unwrap();
+            repo.reference("refs/heads/master", head, true, "test")
+                .unwrap();
+        }
+        let (mut gh, listener) = offline_github(&git);
+        let mut config = config_factory();
+        config.max_stack_depth = 2;
+
+        let error = diff(
+            DiffOptions::parse_from(["diff", "--all"]),
+            &git,
+            &mut gh,
+            &config,
+        )
+        .await
+        .unwrap_err();
+        assert!(error.messages()[0].contains("maximum of 2"));
+        // Give lookups that were started in the background time to connect
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(sent_requests(&listener), 0);
+
+        // The lookups that the limit prevented do get through otherwise
+        config.max_stack_depth = 0;
+        git.get_prepared_commits(&config, Some(&gh)).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(sent_requests(&listener) > 0);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_next_check() {
+        let interval = std::time::Duration::from_millis(10);
+        let (sender, stop) = tokio::sync::oneshot::channel::<()>();
+        tokio::pin!(stop);
+
+        assert!(wait_for_next_check(stop.as_mut(), interval).await);
+        assert!(wait_for_next_check(stop.as_mut(), interval).await);
+
+        // A stop request while nobody waits (i.e. during a run) is not lost
+        sender.send(()).unwrap();
+        assert!(!wait_for_next_check(stop.as_mut(), interval).await);
+    }
+
+    #[test]
+    fn test_new_pull_request_branch() {
+        let config = config_factory();
+        let existing = ["refs/remotes/origin/spr/foo/add-feature"]
+            .map(String::from)
+            .into_iter()
+            .collect();
+        let branch_name = |name: Option<&str>| {
+            new_pull_request_branch(&config, &existing, name, "Add feature")
+                .map(|branch| branch.branch_name().to_string())
+        };
+
+        // The given name is used verbatim
+        assert_eq!(
+            branch_name(Some("integration/JIRA-123_Add_Feature")).unwrap(),
+            "integration/JIRA-123_Add_Feature"
+        );
+        assert!(branch_name(Some("spr/foo/add-feature")).is_err());
+        assert!(branch_name(Some("bad..name")).is_err());
+        // Without a name, it is derived from the title
+        assert_eq!(branch_name(None).unwrap(), "spr/foo/add-feature-1");
+    }
+
+    #[test]
+    fn test_reattach_orphaned_branch() {
+        let config = config_factory();
+        let existing = [
+            "refs/remotes/origin/spr/foo/add-feature",
+            "refs/remotes/origin/master",
+        ]
+        .map(String::from)
+        .into_iter()
+        .collect();
+
+        // The commit lost its Pull Request section, but the branch spr gave
+        // its Pull Request is still there
+        assert_eq!(
+            orphaned_branch(&config, &existing, "Add feature")
+                .unwrap()
+                .branch_name(),
+            "spr/foo/add-feature"
+        );
+        assert!(orphaned_branch(&config, &existing, "Fix bug").is_none());
+
+        let mut prepared_commit = PreparedCommit {
+            oid: Oid::zero(),
+            short_id: "aaaaaaa".into(),
+            parent_oid: Oid::zero(),
+            message: crate::message::parse_message(
+                "Add feature\n\nSummary",
+                MessageSection::Title,
+            ),
+            pull_request_number: None,
+            pull_request_task: None,
+        };
+        restore_pull_request_link(&config, &mut prepared_commit, 42);
+        assert_eq!(prepared_commit.pull_request_number, Some(42));
+        assert_eq!(
+            crate::message::build_commit_message(&prepared_commit.message),
+            "Add feature\n\nSummary\n\n\
+             Pull Request: https://github.com/acme/codez/pull/42\n"
+        );
+
+        // Only a branch with changes to the same files is the commit's
+        let paths = |paths: &[&str]| {
+            paths
+                .iter()
+                .map(|path| path.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert!(related_changes(
+            &paths(&["src/feature.rs"]),
+            &paths(&["src/feature.rs", "src/lib.rs"])
+        ));
+        assert!(!related_changes(
+            &paths(&["src/feature.rs"]),
+            &paths(&["docs/unrelated.md"])
+        ));
+    }
+
+    #[test]
+    fn test_retarget_base_after_removing_middle_commit() {
+        let config = config_factory();
+        let branch = |name: &str| config.new_github_branch(name);
+        let retarget =
+            |base: &str, stack_heads: &[(crate::github::GitHubBranch, u64)]| {
+                retarget_base(&config, &branch(base), stack_heads).map(
+                    |(base, number)| (base.branch_name().to_string(), number),
+                )
+            };
+
+        // The stack was first, middle, top, with each Pull Request stacked on
+        // the one below. The middle commit has been dropped.
+        let stack_heads = vec![(branch("spr/foo/first"), 1)];
+        assert_eq!(
+            retarget("spr/foo/middle", &stack_heads),
+            Some(("spr/foo/first".into(), Some(1)))
+        );
+        // Having dropped the first commit as well, top goes onto master
+        assert_eq!(
+            retarget("spr/foo/middle", &[]),
+            Some(("master".into(), None))
+        );
+
+        // Bases that are fine as they are
+        assert_eq!(retarget("spr/foo/first", &stack_heads), None);
+        assert_eq!(retarget("master", &stack_heads), None);
+        assert_eq!(retarget("spr/foo/master.middle", &stack_heads), None);
+        // Someone else's branch, which --base was used to stack onto
+        assert_eq!(retarget("feature/other", &stack_heads), None);
+    }
+
+    #[test]
+    fn test_inherit_reviewers() {
+        let mut base_pull_request =
+            pull_request_factory(PullRequestState::Open);
+        base_pull_request.requested_reviewers =
+            vec!["alice".into(), "#web".into(), "me".into()];
+        base_pull_request.reviewers = [
+            ("carol".to_string(), crate::github::ReviewStatus::Approved),
+            ("bob".to_string(), crate::github::ReviewStatus::Rejected),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut requested_reviewers = PullRequestRequestReviewers {
+            reviewers: vec!["Alice".into(), "dave".into()],
+            team_reviewers: vec![],
+        };
+        inherit_reviewers(&base_pull_request, "Me", &mut requested_reviewers);
+        assert_eq!(
+            requested_reviewers,
+            PullRequestRequestReviewers {
+                reviewers: vec![
+                    "Alice".into(),
+                    "dave".into(),
+                    "bob".into(),
+                    "carol".into()
+                ],
+                team_reviewers: vec!["web".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_new_trees_falls_back_on_conflict() {
+        let git = init_test_repo("new-trees");
+        let commit = |content: &str, parents: &[Oid]| {
+            commit_files(&git, &[("file.txt", content)], content, parents)
+        };
+        let master = commit("master", &[]);
+        // The commit changes a line that its parent (not on master) changed
+        let parent = commit("parent", &[master]);
+        let local = commit("local", &[parent]);
+        let tree = |oid| git.get_tree_oid_for_commit(oid).unwrap();
+
+        let error =
+            new_trees(&git, local, parent, master, "main", false, false)
+                .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Conflict);
+        assert_eq!(
+            error.messages(),
+            &vec!["This commit cannot be cherry-picked on main.".to_string()]
+        );
+
+        assert_eq!(
+            new_trees(&git, local, parent, master, "main", false, true)
+                .unwrap(),
+            (tree(local), tree(parent), true)
+        );
+
+        // Without a conflict, the commit is cherry-picked as usual
+        let (head_tree, base_tree, no_cherry_pick) =
+            new_trees(&git, parent, master, master, "main", false, true)
+                .unwrap();
+        assert_eq!((head_tree, base_tree), (tree(parent), tree(master)));
+        assert!(!no_cherry_pick);
+    }
+
+    #[test]
+    fn test_prefer_master_base() {
+        let git = init_test_repo("master-base");
+        let commit = |name: &str, content: &str, parents: &[Oid]| {
+            commit_files(&git, &[(name, content)], content, parents)
+        };
+        let old_master = commit("file.txt", "old", &[]);
+        let master = commit("file.txt", "new", &[old_master]);
+        let off_master = commit("other.txt", "other", &[old_master]);
+        let tree = |oid| git.get_tree_oid_for_commit(oid).unwrap();
+        let case = |prefer_master_base, parent| {
+            let base = new_pull_request_master_base(
+                &git,
+                prefer_master_base,
+                true,
+                parent,
+                master,
+            )
+            .unwrap();
+            (
+                base,
+                choose_update_case(
+                    tree(base) == tree(parent),
+                    false,
+                    false,
+                    true,
+                ),
+            )
+        };
+
+        // The parent of the commit is on master, but master moved on since
+        assert_eq!(case(false, old_master), (master, UpdateCase::BaseBranch));
+        assert_eq!(case(true, old_master), (old_master, UpdateCase::Unchanged));
+
+        // A parent that is not on master still needs a base branch
+        assert_eq!(case(true, off_master), (master, UpdateCase::BaseBranch));
+
+        // Cherry-picked commits are based on master anyway
+        assert_eq!(
+            new_pull_request_master_base(&git, true, false, old_master, master)
+                .unwrap(),
+            master
+        );
+    }
+
+    #[test]
+    fn test_default_branch_mismatch() {
+        let config = config_factory();
+        assert_eq!(
+            default_branch_mismatch(&config, Some("master".into())),
+            None
+        );
+        assert_eq!(
+            default_branch_mismatch(&config, Some("main".into())),
+            Some("main".into())
+        );
+        // Repositories without any branch have no default branch
+        assert_eq!(default_branch_mismatch(&config, None), None);
+    }
+
+    #[test]
+    fn test_pull_request_commit_parents() {
+        let oid = |n: u8| Oid::from_bytes(&[n; 20]).unwrap();
+        let (pr_head, master) = (oid(1), oid(2));
+
+        // Rebased: master gets merged into the Pull Request branch...
+        assert_eq!(
+            pull_request_commit_parents(pr_head, Some(master), false),
+            vec![pr_head, master]
+        );
+        // ...unless the branch is replaced, which keeps it linear
+        assert_eq!(
+            pull_request_commit_parents(pr_head, Some(master), true),
+            vec![master]
+        );
+
+        // Amended only
+        assert_eq!(
+            pull_request_commit_parents(pr_head, None, false),
+            vec![pr_head]
+        );
+        assert_eq!(
+            pull_request_commit_parents(pr_head, None, true),
+            vec![pr_head]
+        );
+        // New Pull Request
+        assert_eq!(
+            pull_request_commit_parents(master, Some(master), false),
+            vec![master]
+        );
+    }
+
+    #[test]
+    fn test_base_from_message_section() {
+        let message = crate::message::parse_message(
+            "Title\n\nSummary\n\nBase: spr/foo/parent\n",
+            MessageSection::Title,
+        );
+        assert_eq!(
+            requested_base(None, &message),
+            Some("spr/foo/parent".to_string())
+        );
+        // --base overrides the section
+        assert_eq!(
+            requested_base(Some(&"HEAD^2".to_string()), &message),
+            Some("HEAD^2".to_string())
+        );
+
+        let message = crate::message::parse_message(
+            "Title\n\nBase:\n",
+            MessageSection::Title,
+        );
+        assert_eq!(requested_base(None, &message), None);
+        let message = crate::message::parse_message(
+            "Title\n\nSummary\n",
+            MessageSection::Title,
+        );
+        assert_eq!(requested_base(None, &message), None);
+
+        // The section stays in the local message, but not on GitHub
+        let message = crate::message::parse_message(
+            "Title\n\nSummary\n\nBase: HEAD^1\n",
+            MessageSection::Title,
+        );
+        assert_eq!(
+            crate::message::build_commit_message(&message),
+            "Title\n\nSummary\n\nBase: HEAD^1\n"
+        );
+        assert_eq!(build_github_body(&message), "Summary\n");
+    }
+
+    #[test]
+    fn test_base_branch_commit_message() {
+        let mut config = config_factory();
+        let version = env!("CARGO_PKG_VERSION");
+
+        assert_eq!(
+            base_branch_commit_message(&config, false),
+            format!(
+                "[𝘀𝗽𝗿] changes to master this commit is based on\n\n\
+                 Created using spr {version}\n\n[skip ci]"
+            )
+        );
+
+        config.base_branch_skip_ci = false;
+        assert_eq!(
+            base_branch_commit_message(&config, true),
+            format!(
+                "[𝘀𝗽𝗿] changes introduced through rebase\n\n\
+                 Created using spr {version}"
+            )
+        );
+    }
+
+    #[test]
+    fn test_base_branch_commit_parents() {
+        let base =
+            Oid::from_str("1111111111111111111111111111111111111111").unwrap();
+        let master =
+            Oid::from_str("2222222222222222222222222222222222222222").unwrap();
+
+        // Merge: on top of the previous base, with master merged in when
+        // rebased
+        assert_eq!(
+            base_branch_commit_parents(
+                BaseBranchStrategy::Merge,
+                base,
+                master,
+                false
+            ),
+            vec![base]
+        );
+        assert_eq!(
+            base_branch_commit_parents(
+                BaseBranchStrategy::Merge,
+                base,
+                master,
+                true
+            ),
+            vec![base, master]
+        );
+        assert_eq!(
+            base_branch_commit_parents(
+                BaseBranchStrategy::Merge,
+                master,
+                master,
+                true
+            ),
+            vec![master]
+        );
+
+        // Rebase: a single linear commit on master
+        assert_eq!(
+            base_branch_commit_parents(
+                BaseBranchStrategy::Rebase,
+                base,
+                master,
+                false
+            ),
+            vec![master]
+        );
+        assert_eq!(
+            base_branch_commit_parents(
+                BaseBranchStrategy::Rebase,
+                base,
+                master,
+                true
+            ),
+            vec![master]
+        );
+    }
+
+    #[test]
+    fn test_title_override() {
+        let message = crate::message::parse_message(
+            "Fix the thing\n\nDetails",
+            MessageSection::Title,
+        );
+        let pull_request = pull_request_factory(PullRequestState::Open);
+
+        let remote = with_title_override(&message, Some("Hotfix"));
+        assert_eq!(remote[&MessageSection::Title], "Hotfix");
+        assert_eq!(remote[&MessageSection::Summary], "Details");
+        assert_eq!(message[&MessageSection::Title], "Fix the thing");
+        assert_eq!(with_title_override(&message, None), message);
+
+        let mut updates = PullRequestUpdate::default();
+        updates.update_message(&pull_request, &message);
+        updates.update_title(&pull_request, "Hotfix");
+        assert_eq!(updates.title.as_deref(), Some("Hotfix"));
+        assert_eq!(message[&MessageSection::Title], "Fix the thing");
+
+        // The Pull Request already has the title
+        let mut updates = PullRequestUpdate::default();
+        updates.update_title(&pull_request, &pull_request.title);
+        assert!(updates.is_empty());
+    }
+
+    #[test]
+    fn test_reviewers_to_prune() {
+        let mut pull_request = pull_request_factory(PullRequestState::Open);
+        pull_request.requested_reviewers =
+            vec!["alice".into(), "Bob".into(), "carol".into(), "#team".into()];
+        // Carol has reviewed in the meantime
+        pull_request
+            .reviewers
+            .insert("carol".into(), crate::github::ReviewStatus::Approved);
+
+        assert_eq!(
+            reviewers_to_prune(&["bob".into()], &pull_request),
+            PullRequestRequestReviewers {
+                reviewers: vec!["alice".into()],
+                team_reviewers: vec!["team".into()],
+            }
+        );
+        assert_eq!(
+            reviewers_to_prune(
+                &["alice".into(), "bob".into(), "#team".into()],
+                &pull_request
+            ),
+            PullRequestRequestReviewers::default()
+        );
+    }
+
+    #[test]
+    fn test_expected_reviewers() {
+        let git = init_test_repo("expected-reviewers");
+        let master = commit_files(&git, &[("m", "m")], "Master", &[]);
+        let commit = commit_files(
+            &git,
+            &[("m", "m"), ("CODEOWNERS", "* @acme/owners\nsrc/ @dave\n")],
+            "Add code owners",
+            &[master],
+        );
+        let mut config = config_factory();
+        config
+            .reviewer_aliases
+            .insert("backend".into(), "alice, bob".into());
+        config.size_reviewers.insert(0, "erin".into());
+        let message = crate::message::parse_message(
+            "Title\n\nReviewers: @backend, carol (Carol C)",
+            MessageSection::Title,
+        );
+
+        let expected =
+            expected_reviewers(&git, &config, &message, commit).unwrap();
+        assert_eq!(expected, vec!["alice", "bob", "carol", "#owners", "erin"]);
+
+        // Only frank is neither listed (through the alias) nor a code owner
+        // nor a reviewer for the size of the change
+        let mut pull_request = pull_request_factory(PullRequestState::Open);
+        pull_request.requested_reviewers = vec![
+            "alice".into(),
+            "bob".into(),
+            "#owners".into(),
+            "erin".into(),
+            "frank".into(),
+        ];
+        assert_eq!(
+            reviewers_to_prune(&expected, &pull_request),
+            PullRequestRequestReviewers {
+                reviewers: vec!["frank".into()],
+                team_reviewers: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_base_pull_request_open() {
+        let config = config_factory();
+        let pull_request = pull_request_factory(PullRequestState::Open);
 
-    // Update master_base_oid if base if provided
-    let master_base_oid = git
-        .resolve_reference(base_ref.local())
-        .unwrap_or(master_base_oid);
+        assert!(check_base_pull_request_is_open(&config, &pull_request).is_ok());
+    }
 
-    // Parsed commit message of the local commit
-    let message = &mut local_commit.message;
+    #[test]
+    fn test_base_pull_request_closed() {
+        let config = config_factory();
+        let pull_request = pull_request_factory(PullRequestState::Closed);
 
-    // If the user has provided a base PR, add a "Depends On" line in PR body
-    if let Some(base_pull_request_number) = base_pull_request_number {
-        message.insert(
-            MessageSection::BasePR,
-            format!(
-                "\n- #{} (powered by [spr-enhanced](https://go/spr-enhanced))",
-                base_pull_request_number
-            ),
-        );
+        let error = check_base_pull_request_is_open(&config, &pull_request)
+            .unwrap_err();
+        assert!(error.to_string().contains("#42"));
+        assert!(error.to_string().contains("master"));
     }
 
-    // Determine the trees the Pull Request branch and the base branch should
-    // have when we're done here.
-    let (new_head_tree, new_base_tree) = if opts.no_cherry_pick {
-        // If the user tells us not to cherry-pick, these should be the trees
-        // of the current commit and its parent.
-        let head_tree = git.get_tree_oid_for_commit(local_commit.oid)?;
-        let base_tree = git.get_tree_oid_for_commit(local_commit.parent_oid)?;
+    #[test]
+    fn test_push_targets_of_base_branch_update() {
+        let config = config_factory();
+        let pr_commit = Oid::from_bytes(&[1; 20]).unwrap();
+        let base_commit = Oid::from_bytes(&[2; 20]).unwrap();
+        let pull_request_branch = config.new_github_branch("spr/foo/title");
+        let base_branch = config.new_github_branch("spr/foo/master.title");
 
-        (head_tree, base_tree)
-    } else {
-        // Cherry-pick the current commit onto master
-        let index = git.cherrypick(local_commit.oid, master_base_oid)?;
+        // The push of case 3, as `diff_impl` builds it: the Pull Request
+        // branch and the new commit on the base branch
+        let mut cmd = tokio::process::Command::new("git");
+        cmd.arg("push").arg("--atomic");
+        cmd.arg("--").arg(&config.remote_name).arg(format!(
+            "{}:{}",
+            pr_commit,
+            pull_request_branch.on_github()
+        ));
+        cmd.arg(format!("{}:{}", base_commit, base_branch.on_github()));
 
-        if index.has_conflicts() {
-            return Err(Error::new(formatdoc!(
-                "This commit cannot be cherry-picked on {master}.",
-                master = base_ref.branch_name(),
-            )));
-        }
+        assert_eq!(
+            push_targets(&cmd),
+            (
+                "origin",
+                vec![
+                    "0101010101010101010101010101010101010101:\
+                     refs/heads/spr/foo/title",
+                    "0202020202020202020202020202020202020202:\
+                     refs/heads/spr/foo/master.title",
+                ]
+            )
+        );
+    }
 
-        // This is the tree we are getting from cherrypicking the local commit
-        // on master.
-        let cherry_pick_tree = git.write_index(index)?;
-        let master_tree = git.get_tree_oid_for_commit(master_base_oid)?;
+    #[test]
+    fn test_force_with_lease() {
+        let config = config_factory();
+        assert_eq!(
+            force_with_lease(
+                &config.new_github_branch("spr/foo/title"),
+                Oid::from_bytes(&[1; 20]).unwrap()
+            ),
+            "--force-with-lease=refs/heads/spr/foo/title:\
+             0101010101010101010101010101010101010101"
+        );
+    }
 
-        (cherry_pick_tree, master_tree)
-    };
+    #[test]
+    fn test_explain_push_rejection() {
+        let config = config_factory();
 
-    if let Some(number) = local_commit.pull_request_number {
-        output(
-            "#️⃣ ",
-            &format!(
-                "Pull Request #{}: {}",
-                number,
-                config.pull_request_url(number)
+        let explanation = explain_push_rejection(
+            "remote: error: GH006: Protected branch update failed for \
+             refs/heads/spr/foo/master.bar.\n\
+             ! [remote rejected] abc -> spr/foo/master.bar (protected branch \
+             hook declined)\n",
+            &config,
+        )
+        .unwrap();
+        assert!(explanation.contains("'spr/foo/...'"));
+        assert!(explanation.contains("spr.branchPrefix"));
+
+        // Somebody else pushed to the Pull Request branch
+        let explanation = explain_push_rejection(
+            "To github.com:acme/codez.git\n \
+             ! [rejected]        abc -> spr/foo/bar (non-fast-forward)\n\
+             error: failed to push some refs to 'github.com:acme/codez.git'\n",
+            &config,
+        )
+        .unwrap();
+        assert!(explanation.contains("the branch 'spr/foo/bar' has commits"));
+        assert!(explanation.contains("run `spr diff` again"));
+        assert!(explain_push_rejection(
+            " ! [rejected] abc -> spr/foo/bar (fetch first)\n",
+            &config,
+        )
+        .is_some());
+
+        assert_eq!(
+            explain_push_rejection(
+                "fatal: unable to access 'https://github.com/': Could not \
+                 resolve host\n",
+                &config,
             ),
-        )?;
+            None
+        );
     }
 
-    if local_commit.pull_request_number.is_none() || opts.update_message {
-        validate_commit_message(message, config)?;
+    #[test]
+    fn test_push_to_default_branch_is_refused() {
+        let config = config_factory();
+        let oid = "1111111111111111111111111111111111111111";
+
+        assert!(check_push_targets(
+            &config,
+            &[
+                &format!("{oid}:refs/heads/spr/foo/add-feature"),
+                &format!("+{oid}:refs/heads/spr/foo/master.add-feature"),
+            ]
+        )
+        .is_ok());
+
+        // Together with a legitimate one, as in an atomic push
+        let error = check_push_targets(
+            &config,
+            &[
+                &format!("{oid}:refs/heads/spr/foo/add-feature"),
+                &format!("{oid}:refs/heads/master"),
+            ],
+        )
+        .unwrap_err();
+        assert!(error.messages()[0].contains("Refusing to push to 'master'"));
+
+        // Nor to a branch that is the default in most repositories
+        assert!(check_push_targets(
+            &config,
+            &[&format!("+{oid}:refs/heads/main")]
+        )
+        .is_err());
     }
 
-    if let Some(ref pull_request) = pull_request {
-        if pull_request.state == PullRequestState::Closed {
-            return Err(Error::new(formatdoc!(
-                "Pull request is closed. If you want to open a new one, \
-                 remove the 'Pull Request' section from the commit message."
-            )));
-        }
+    #[test]
+    fn test_render_body_template() {
+        let message = crate::message::parse_message(
+            "Add the frobnicator\n\nIt frobnicates.\n\nTest Plan: cargo test",
+            MessageSection::Title,
+        );
+        let template = "## {title}\n\n{summary}\n\nTesting: {test_plan}\n\n\
+                        PR {pr} ({url}), owned by {team}";
 
-        if !opts.update_message {
-            let mut pull_request_updates: PullRequestUpdate =
-                Default::default();
-            pull_request_updates.update_message(pull_request, message);
-        }
+        let (body, unknown) = render_body_template(
+            template,
+            &message,
+            Some((7, "https://github.com/acme/codez/pull/7")),
+        );
+        assert_eq!(
+            body,
+            "## Add the frobnicator\n\nIt frobnicates.\n\n\
+             Testing: cargo test\n\n\
+             PR 7 (https://github.com/acme/codez/pull/7), owned by "
+        );
+        assert_eq!(unknown, vec!["team"]);
+
+        // Before the Pull Request exists, its number and URL render empty
+        let (body, unknown) =
+            render_body_template("{title} #{pr}", &message, None);
+        assert_eq!(body, "Add the frobnicator #");
+        assert!(unknown.is_empty());
     }
 
-    // Parse "Reviewers" section, if this is a new Pull Request
-    let mut requested_reviewers = PullRequestRequestReviewers::default();
+    #[test]
+    fn test_render_message_template() {
+        assert_eq!(
+            render_message_template("{reason} #{pr}: {title}", 7, "Foo", true),
+            "rebase #7: Foo"
+        );
+        assert_eq!(
+            render_message_template("{reason} #{pr}: {title}", 7, "Foo", false),
+            "amend #7: Foo"
+        );
+        assert_eq!(
+            render_message_template("review comments", 7, "Foo", false),
+            "review comments"
+        );
+    }
 
-    if local_commit.pull_request_number.is_none() {
-        if let Some(reviewers) = message.get(&MessageSection::Reviewers) {
-            let reviewers = parse_name_list(reviewers);
-            let mut checked_reviewers = Vec::new();
-
-            for reviewer in reviewers {
-                // Teams are indicated with a leading #
-                if let Some(slug) = reviewer.strip_prefix('#') {
-                    if let Ok(team) = GitHub::get_github_team(
-                        (&config.owner).into(),
-                        slug.into(),
-                    )
-                    .await
-                    {
-                        requested_reviewers
-                            .team_reviewers
-                            .push(team.slug.to_string());
+    #[tokio::test]
+    async fn test_check_reviewers() {
+        use clap::Parser;
 
-                        checked_reviewers.push(reviewer);
-                    } else {
-                        return Err(Error::new(format!(
-                            "Reviewers field contains unknown team '{}'",
-                            reviewer
-                        )));
-                    }
-                } else if let Ok(user) =
-                    GitHub::get_github_user(reviewer.clone()).await
-                {
-                    requested_reviewers.reviewers.push(user.login);
-                    if let Some(name) = user.name {
-                        checked_reviewers.push(format!(
-                            "{} ({})",
-                            reviewer.clone(),
-                            remove_all_parens(&name)
-                        ));
-                    } else {
-                        checked_reviewers.push(reviewer);
-                    }
-                } else {
-                    return Err(Error::new(format!(
-                        "Reviewers field contains unknown user '{}'",
-                        reviewer
-                    )));
+        /// Knows the user alice and the team team
+        struct Known;
+        impl ReviewerLookup for Known {
+            async fn user(&self, login: String) -> Result<UserWithName> {
+                match &login[..] {
+                    "alice" => Ok(UserWithName {
+                        login,
+                        name: Some("Alice (A.) Smith".into()),
+                        is_collaborator: true,
+                    }),
+                    _ => Err(Error::new("Not Found")),
+                }
+            }
+            async fn team(&self, owner: &str, slug: &str) -> Result<String> {
+                assert_eq!(owner, "acme");
+                match slug {
+                    "team" => Ok(slug.to_string()),
+                    _ => Err(Error::new("Not Found")),
                 }
             }
-
-            message.insert(
-                MessageSection::Reviewers,
-                checked_reviewers.join(", "),
-            );
         }
+        let config = config_factory();
+        let message = || {
+            crate::message::parse_message(
+                "Title\n\nReviewers:  alice,#team",
+                MessageSection::Title,
+            )
+        };
+
+        let opts = DiffOptions::parse_from(["diff"]);
+        let mut written_back = message();
+        let requested =
+            check_reviewers(&Known, &opts, &config, &mut written_back)
+                .await
+                .unwrap();
+        assert_eq!(requested.reviewers, vec!["alice".to_string()]);
+        assert_eq!(requested.team_reviewers, vec!["team".to_string()]);
+        assert_eq!(
+            written_back.get(&MessageSection::Reviewers).unwrap(),
+            "alice (Alice A. Smith), #team"
+        );
+
+        // The same reviewers are requested, but the message stays as it was
+        let opts =
+            DiffOptions::parse_from(["diff", "--no-reviewers-writeback"]);
+        let mut unchanged = message();
+        let requested = check_reviewers(&Known, &opts, &config, &mut unchanged)
+            .await
+            .unwrap();
+        assert_eq!(requested.reviewers, vec!["alice".to_string()]);
+        assert_eq!(unchanged, message());
+
+        let mut unknown = crate::message::parse_message(
+            "Title\n\nReviewers: bob",
+            MessageSection::Title,
+        );
+        let error = check_reviewers(&Known, &opts, &config, &mut unknown)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.messages(),
+            &vec!["Reviewers field contains unknown user 'bob'".to_string()]
+        );
     }
 
-    // Get the name of the existing Pull Request branch, or constuct one if
-    // there is none yet.
+    #[tokio::test]
+    async fn test_draft_if_failing() {
+        use CheckStatus::*;
 
-    let title = message
-        .get(&MessageSection::Title)
-        .map(|t| &t[..])
-        .unwrap_or("");
+        let checks =
+            |is_draft, status: Option<CheckStatus>| PullRequestChecks {
+                id: "PR_1".into(),
+                is_draft,
+                required: vec!["ci".into()],
+                checks: status
+                    .map(|status| vec![("ci".to_string(), status)])
+                    .unwrap_or_default(),
+            };
+        let wait = |statuses: Vec<Option<CheckStatus>>, attempts| async move {
+            let mut fetched = statuses.into_iter();
+            let mut fetches = 0;
+            let checks = wait_for_required_checks(
+                || {
+                    fetches += 1;
+                    let status = fetched.next().unwrap();
+                    async move { Ok(checks(false, status)) }
+                },
+                1,
+                std::time::Duration::from_millis(1),
+                attempts,
+            )
+            .await
+            .unwrap();
+            (checks.required_status(), fetches)
+        };
 
-    let pull_request_branch = match &pull_request {
-        Some(pr) => pr.head.clone(),
-        None => config.new_github_branch(
-            &config.get_new_branch_name(&git.get_all_ref_names()?, title),
-        ),
-    };
+        // Red: the checks that had not been reported right after the push
+        // are waited for, then the Pull Request becomes a draft
+        let (status, fetches) =
+            wait(vec![None, Some(Pending), Some(Failure)], 5).await;
+        assert_eq!((status, fetches), (Some(Failure), 3));
+        assert_eq!(draft_state_change(status, false), Some(true));
+        assert_eq!(draft_state_change(status, true), None);
 
-    // Get the tree ids of the current head of the Pull Request, as well as the
-    // base, and the commit id of the master commit this PR is currently based
-    // on.
-    // If there is no pre-existing Pull Request, we fill in the equivalent
-    // values.
-    let (pr_head_oid, pr_head_tree, pr_base_oid, pr_base_tree, pr_master_base) =
-        if let Some(pr) = &pull_request {
-            let pr_head_tree = git.get_tree_oid_for_commit(pr.head_oid)?;
+        // Green: a draft becomes ready for review
+        let (status, fetches) = wait(vec![Some(Success)], 5).await;
+        assert_eq!((status, fetches), (Some(Success), 1));
+        assert_eq!(draft_state_change(status, true), Some(false));
+        assert_eq!(draft_state_change(status, false), None);
 
-            let current_master_oid = git.resolve_reference(base_ref.local())?;
-            let pr_base_oid =
-                git.repo().merge_base(pr.head_oid, pr.base_oid)?;
-            let pr_base_tree = git.get_tree_oid_for_commit(pr_base_oid)?;
+        // Pending for too long: nothing changes
+        let (status, fetches) = wait(vec![None, Some(Pending)], 2).await;
+        assert_eq!((status, fetches), (Some(Pending), 2));
+        assert_eq!(draft_state_change(status, false), None);
+        assert_eq!(draft_state_change(status, true), None);
 
-            let pr_master_base =
-                git.repo().merge_base(pr.head_oid, current_master_oid)?;
+        // Without required checks, there is nothing to wait for
+        assert_eq!(draft_state_change(None, true), None);
+    }
 
-            (
-                pr.head_oid,
-                pr_head_tree,
-                pr_base_oid,
-                pr_base_tree,
-                pr_master_base,
+    #[test]
+    fn test_is_significant_update() {
+        assert!(!is_significant_update(false, 0));
+        assert!(!is_significant_update(false, 9));
+        assert!(is_significant_update(false, 10));
+        assert!(is_significant_update(true, 0));
+    }
+
+    #[test]
+    fn test_draft_state_from_keyword() {
+        let mut config = config_factory();
+        assert_eq!(draft_state_from_keyword(&config, Some("ready")), None);
+
+        config.message_keywords = true;
+        assert_eq!(
+            draft_state_from_keyword(&config, Some("Ready ")),
+            Some(false)
+        );
+        assert_eq!(
+            draft_state_from_keyword(&config, Some("draft")),
+            Some(true)
+        );
+        assert_eq!(
+            draft_state_from_keyword(&config, Some("ready for review")),
+            None
+        );
+        assert_eq!(draft_state_from_keyword(&config, None), None);
+
+        config.ready_keyword = "lgtm".into();
+        assert_eq!(draft_state_from_keyword(&config, Some("ready")), None);
+        assert_eq!(
+            draft_state_from_keyword(&config, Some("LGTM")),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_find_base_cycle() {
+        let mut bases = [
+            ("spr/foo/one", "master"),
+            ("spr/foo/two", "spr/foo/one"),
+            ("spr/foo/three", "spr/foo/two"),
+        ]
+        .into_iter()
+        .map(|(head, base)| (head.to_string(), base.to_string()))
+        .collect::<std::collections::BTreeMap<_, _>>();
+        assert_eq!(find_base_cycle(&bases), None);
+
+        // After reordering, one was restacked onto three
+        bases.insert("spr/foo/one".into(), "spr/foo/three".into());
+        assert_eq!(
+            find_base_cycle(&bases).unwrap(),
+            vec!["spr/foo/one", "spr/foo/three", "spr/foo/two", "spr/foo/one"]
+        );
+
+        bases.insert("spr/foo/one".into(), "spr/foo/one".into());
+        assert_eq!(
+            find_base_cycle(&bases).unwrap(),
+            vec!["spr/foo/one", "spr/foo/one"]
+        );
+    }
+
+    #[test]
+    fn test_all_up_to_date() {
+        use DiffOutcome::*;
+
+        assert!(all_up_to_date(&[(0, UpToDate), (1, UpToDate)]));
+        assert!(all_up_to_date(&[(0, UpToDate), (1, Empty)]));
+        // Any real update counts as a change
+        assert!(!all_up_to_date(&[(0, UpToDate), (1, Succeeded)]));
+        assert!(!all_up_to_date(&[]));
+    }
+
+    #[tokio::test]
+    async fn test_keep_going() {
+        use DiffOutcome::*;
+
+        /// The results of diff for a stack of four commits, where updating
+        /// the second Pull Request fails
+        struct FailingSecond {
+            completed: Vec<usize>,
+        }
+        impl DiffStep for FailingSecond {
+            async fn diff(&mut self, index: usize) -> Result<DiffOutcome> {
+                match index {
+                    1 => Err(Error::new("GitHub said no to #2")),
+                    3 => Ok(UpToDate),
+                    _ => Ok(Succeeded),
+                }
+            }
+            fn completed(&mut self, index: usize) -> Result<()> {
+                self.completed.push(index);
+                Ok(())
+            }
+        }
+        let run = |keep_going| async move {
+            let mut step = FailingSecond { completed: vec![] };
+            let mut result = Ok(());
+            let outcomes = diff_selected(
+                &mut step,
+                &[0, 1, 2, 3],
+                keep_going,
+                &mut result,
             )
-        } else {
-            let master_base_tree =
-                git.get_tree_oid_for_commit(master_base_oid)?;
+            .await
+            .unwrap();
             (
-                master_base_oid,
-                master_base_tree,
-                master_base_oid,
-                master_base_tree,
-                master_base_oid,
+                result,
+                outcomes.into_iter().map(|(_, outcome)| outcome).collect(),
+                step.completed,
             )
         };
-    let needs_merging_master = pr_master_base != master_base_oid;
 
-    // At this point we can check if we can exit early because no update to the
-    // existing Pull Request is necessary
-    if let Some(ref pull_request) = pull_request {
-        // So there is an existing Pull Request...
-        if !needs_merging_master
-            && pr_head_tree == new_head_tree
-            && pr_base_tree == new_base_tree
-        {
-            // ...and it does not need a rebase, and the trees of both Pull
-            // Request branch and base are all the right ones.
-            output("✅", "No update necessary")?;
+        let (result, outcomes, completed): (_, Vec<_>, _) = run(false).await;
+        assert_eq!(outcomes, vec![Succeeded, Failed, Skipped, Skipped]);
+        assert_eq!(completed, vec![0]);
+        assert!(result.is_err());
 
-            if opts.update_message {
-                // However, the user requested to update the commit message on
-                // GitHub
+        let (result, outcomes, completed): (_, Vec<_>, _) = run(true).await;
+        assert_eq!(outcomes, vec![Succeeded, Failed, Succeeded, UpToDate]);
+        assert_eq!(completed, vec![0, 2, 3]);
+        assert_eq!(result.unwrap_err().messages(), &["GitHub said no to #2"]);
 
-                let mut pull_request_updates: PullRequestUpdate =
-                    Default::default();
-                pull_request_updates.update_message(pull_request, message);
+        // All failures are reported
+        let mut result = Ok(());
+        record_result(&mut result, Err(Error::new("first")));
+        assert!(!stop_after_failure(&result, true));
+        record_result(&mut result, Err(Error::new("second")));
+        assert_eq!(result.unwrap_err().messages(), &["first", "second"]);
+    }
 
-                if !pull_request_updates.is_empty() {
-                    // ...and there are actual changes to the message
-                    gh.update_pull_request(
-                        pull_request.number,
-                        pull_request_updates,
-                    )
-                    .await?;
-                    output("✍", "Updated commit message on GitHub")?;
-                }
-            }
+    #[test]
+    fn test_confirm_plan() {
+        let plan = Plan {
+            commits: vec![PlannedCommit {
+                commit: "1111111111111111111111111111111111111111".into(),
+                title: "Add feature".into(),
+                action: PlannedAction::Create,
+                pull_request: None,
+                base_branch: "master".into(),
+                head_branch: "spr/foo/add-feature".into(),
+            }],
+        };
 
-            return Ok(());
-        }
+        // Declining aborts, before diff has talked to GitHub for anything
+        // but reading the Pull Requests
+        let error = confirm_plan(&plan, |_| Ok(false)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Aborted);
+        assert!(confirm_plan(&plan, |prompt| {
+            assert_eq!(prompt, "Go ahead?");
+            Ok(true)
+        })
+        .is_ok());
     }
 
-    // Check if there is a intermediate base branch on GitHub already. That's the case when
-    // there is an existing Pull Request, and its base is not the master branch or other PR's branch.
-    let base_branch = if let Some(ref pr) = pull_request {
-        if pr.base.is_master_branch()
-            || opts.base.is_some()
-            || !opts.no_cherry_pick
+    #[tokio::test]
+    async fn test_compute_plan() {
+        let git = init_test_repo("compute-plan");
+        let master = commit_files(&git, &[("m", "m")], "Master", &[]);
+        let first =
+            commit_files(&git, &[("m", "m"), ("a", "a")], "First", &[master]);
+        let second = commit_files(
+            &git,
+            &[("m", "m"), ("a", "a"), ("b", "b")],
+            "Second",
+            &[first],
+        );
+        // Changes a file master does not have, so it can't be cherry-picked
+        let third = commit_files(
+            &git,
+            &[("m", "m"), ("a", "a2"), ("b", "b")],
+            "Third",
+            &[second],
+        );
         {
-            None
-        } else {
-            Some(pr.base.clone())
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/master", master, true, "test")
+                .unwrap();
+            repo.reference("refs/heads/master", third, true, "test")
+                .unwrap();
         }
-    } else {
-        None
-    };
-
-    // We are going to construct `pr_base_parent: Option<Oid>`.
-    // The value will be the commit we have to merge into the new Pull Request
-    // commit to reflect changes in the parent of the local commit (by rebasing
-    // or changing commits between master and this one, although technically
-    // that's also rebasing).
-    // If it's `None`, then we will not merge anything into the new Pull Request
-    // commit.
-    // If we are updating an existing PR, then there are three cases here:
-    // (1) the parent tree of this commit is unchanged and we do not need to
-    //     merge in master, which means that the local commit was amended, but
-    //     not rebased. We don't need to merge anything into the Pull Request
-    //     branch.
-    // (2) the parent tree has changed, but the parent of the local commit is on
-    //     master (or we are cherry-picking) and we are not already using a base
-    //     branch: in this case we can merge the master commit we are based on
-    //     into the PR branch, without going via a base branch. Thus, we don't
-    //     introduce a base branch here and the PR continues to target the
-    //     master branch.
-    // (3) the parent tree has changed, and we need to use a base branch (either
-    //     because one was already created earlier, or we find that we are not
-    //     directly based on master now): we need to construct a new commit for
-    //     the base branch. That new commit's tree is always that of that local
-    //     commit's parent (thus making sure that the difference between base
-    //     branch and pull request branch are exactly the changes made by the
-    //     local commit, thus the changes we want to have reviewed). The new
-    //     commit may have one or two parents. The previous base is always a
-    //     parent (that's either the current commit on an existing base branch,
-    //     or the previous master commit the PR was based on if there isn't a
-    //     base branch already). In addition, if the master commit this commit
-    //     is based on has changed, (i.e. the local commit got rebased on newer
-    //     master in the meantime) then we have to merge in that master commit,
-    //     which will be the second parent.
-    // If we are creating a new pull request then `pr_base_tree` (the current
-    // base of the PR) was set above to be the tree of the master commit the
-    // local commit is based one, whereas `new_base_tree` is the tree of the
-    // parent of the local commit. So if the local commit for this new PR is on
-    // master, those two are the same (and we want to apply case 1). If the
-    // commit is not directly based on master, we have to create this new PR
-    // with a base branch, so that is case 3.
-
-    let (pr_base_parent, base_branch) = if pr_base_tree == new_base_tree
-        && !needs_merging_master
-    {
-        // Case 1
-        (None, base_branch)
-    } else if base_branch.is_none() && !opts.no_cherry_pick {
-        // Case 2
-        (Some(master_base_oid), None)
-    } else {
-        // Case 3
+        let config = config_factory();
+        let mut prepared_commits =
+            git.get_prepared_commits(&config, None).unwrap();
+        async fn plan(
+            git: &crate::git::Git,
+            config: &crate::config::Config,
+            prepared_commits: &mut [PreparedCommit],
+            options: PlanOptions,
+        ) -> Vec<(PlannedAction, String, String)> {
+            compute_plan(git, config, prepared_commits, &options)
+                .await
+                .unwrap()
+                .commits
+                .into_iter()
+                .map(|commit| {
+                    (commit.action, commit.base_branch, commit.head_branch)
+                })
+                .collect()
+        }
+        let planned = |action, base: &str, head: &str| {
+            (action, base.to_string(), head.to_string())
+        };
 
-        // We are constructing a base branch commit.
-        // One parent of the new base branch commit will be the current base
-        // commit, that could be either the top commit of an existing base
-        // branch, or a commit on master.
-        let mut parents = vec![pr_base_oid];
+        assert_eq!(
+            plan(&git, &config, &mut prepared_commits, Default::default())
+                .await,
+            vec![
+                planned(PlannedAction::Create, "master", "spr/foo/first"),
+                planned(PlannedAction::Create, "master", "spr/foo/second"),
+                planned(PlannedAction::Conflict, "master", "spr/foo/third"),
+            ]
+        );
 
-        // If we need to rebase on master, make the master commit also a
-        // parent (except if the first parent is that same commit, we don't
-        // want duplicates in `parents`).
-        if needs_merging_master && pr_base_oid != master_base_oid {
-            parents.push(master_base_oid);
-        }
+        // Stacked, each onto the Pull Request the commit below creates
+        assert_eq!(
+            plan(
+                &git,
+                &config,
+                &mut prepared_commits,
+                PlanOptions {
+                    base: Some("HEAD^".into()),
+                    ..Default::default()
+                },
+            )
+            .await,
+            vec![
+                planned(PlannedAction::Create, "master", "spr/foo/first"),
+                planned(
+                    PlannedAction::Create,
+                    "spr/foo/first",
+                    "spr/foo/second"
+                ),
+                planned(
+                    PlannedAction::Conflict,
+                    "spr/foo/second",
+                    "spr/foo/third"
+                ),
+            ]
+        );
 
-        let new_base_branch_commit = git.create_derived_commit(
-            local_commit.parent_oid,
-            &format!(
-                "[𝘀𝗽𝗿] {}\n\nCreated using spr {}\n\n[skip ci]",
-                if pull_request.is_some() {
-                    "changes introduced through rebase".to_string()
-                } else {
-                    format!(
-                        "changes to {} this commit is based on",
-                        config.master_ref.branch_name()
-                    )
+        // Without cherry-picking, the commits above the first need a base
+        // branch
+        assert_eq!(
+            plan(
+                &git,
+                &config,
+                &mut prepared_commits,
+                PlanOptions {
+                    no_cherry_pick: true,
+                    ..Default::default()
                 },
-                env!("CARGO_PKG_VERSION"),
-            ),
-            new_base_tree,
-            &parents[..],
-        )?;
+            )
+            .await,
+            vec![
+                planned(PlannedAction::Create, "master", "spr/foo/first"),
+                planned(
+                    PlannedAction::Create,
+                    "spr/foo/master.second",
+                    "spr/foo/second"
+                ),
+                planned(
+                    PlannedAction::Create,
+                    "spr/foo/master.third",
+                    "spr/foo/third"
+                ),
+            ]
+        );
 
-        // If `base_branch` is `None` (which means a base branch does not exist
-        // yet), then make a `GitHubBranch` with a new name for a base branch
-        let base_branch = if let Some(base_branch) = base_branch {
-            base_branch
-        } else {
-            config.new_github_branch(
-                &config.get_base_branch_name(&git.get_all_ref_names()?, title),
+        // Planning takes no branch names
+        compute_plan(
+            &git,
+            &config,
+            &mut prepared_commits,
+            &PlanOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            new_pull_request_branch(
+                &config,
+                &git.get_all_ref_names().unwrap(),
+                None,
+                "First"
             )
-        };
+            .unwrap()
+            .branch_name(),
+            "spr/foo/first"
+        );
+    }
 
-        (Some(new_base_branch_commit), Some(base_branch))
-    };
+    #[tokio::test]
+    async fn test_confirm_selection() {
+        use clap::Parser;
 
-    let mut github_commit_message = opts.message.clone();
-    if pull_request.is_some() && github_commit_message.is_none() {
-        let input = {
-            let message_on_prompt = message_on_prompt.clone();
+        let git = init_test_repo("confirm-selection");
+        let master = commit_files(&git, &[("m", "m")], "Master", &[]);
+        let first =
+            commit_files(&git, &[("m", "m"), ("a", "a")], "First", &[master]);
+        let second = commit_files(
+            &git,
+            &[("m", "m"), ("a", "a"), ("b", "b")],
+            "Second",
+            &[first],
+        );
+        {
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/master", master, true, "test")
+                .unwrap();
+            repo.reference("refs/heads/master", second, true, "test")
+                .unwrap();
+        }
+        let mut config = config_factory();
+        let mut prepared_commits =
+            git.get_prepared_commits(&config, None).unwrap();
 
-            tokio::task::spawn_blocking(move || {
-                dialoguer::Input::<String>::new()
-                    .with_prompt("Message (leave empty to abort)")
-                    .with_initial_text(message_on_prompt)
-                    .allow_empty(true)
-                    .interact_text()
-            })
-            .await??
-        };
+        // Declining the squashed stack (the Pull Request of the top commit)
+        // aborts the run
+        let opts =
+            DiffOptions::parse_from(["diff", "--squash-stack", "--confirm"]);
+        let mut asked = false;
+        let error = confirm_selection(
+            &opts,
+            &git,
+            &config,
+            &mut prepared_commits,
+            &[1],
+            |_| {
+                asked = true;
+                Ok(false)
+            },
+        )
+        .await
+        .unwrap_err();
+        assert!(asked);
+        assert_eq!(error.kind(), ErrorKind::Aborted);
 
-        if input.is_empty() {
-            return Err(Error::new("Aborted as per user request".to_string()));
-        }
+        // Without --confirm or spr.confirm, or with --assume-yes, nobody is
+        // asked
+        let not_asked = |_: &str| -> Result<bool> { panic!("asked") };
+        let opts = DiffOptions::parse_from(["diff"]);
+        confirm_selection(
+            &opts,
+            &git,
+            &config,
+            &mut prepared_commits,
+            &[1],
+            not_asked,
+        )
+        .await
+        .unwrap();
+        config.confirm = true;
+        let opts = DiffOptions::parse_from(["diff", "-y"]);
+        confirm_selection(
+            &opts,
+            &git,
+            &config,
+            &mut prepared_commits,
+            &[1],
+            not_asked,
+        )
+        .await
+        .unwrap();
 
-        *message_on_prompt = input.clone();
-        github_commit_message = Some(input);
+        // Going ahead with spr.confirm
+        let opts = DiffOptions::parse_from(["diff"]);
+        confirm_selection(
+            &opts,
+            &git,
+            &config,
+            &mut prepared_commits,
+            &[0, 1],
+            |_| Ok(true),
+        )
+        .await
+        .unwrap();
     }
 
-    // Construct the new commit for the Pull Request branch. First parent is the
-    // current head commit of the Pull Request (we set this to the master base
-    // commit earlier if the Pull Request does not yet exist)
-    let mut pr_commit_parents = vec![pr_head_oid];
+    #[test]
+    fn test_choose_update_case() {
+        use UpdateCase::*;
 
-    // If we prepared a commit earlier that needs merging into the Pull Request
-    // branch, then that commit is a parent of the new Pull Request commit.
-    if let Some(oid) = pr_base_parent {
-        // ...unless if that's the same commit as the one we added to
-        // pr_commit_parents first.
-        if pr_commit_parents.get(0) != Some(&oid) {
-            pr_commit_parents.push(oid);
-        }
+        assert_eq!(choose_update_case(true, false, false, false), Unchanged);
+        // Amended, but not rebased: even with a base branch
+        assert_eq!(choose_update_case(true, false, true, true), Unchanged);
+        assert_eq!(choose_update_case(false, false, false, false), MergeMaster);
+        assert_eq!(choose_update_case(true, true, false, false), MergeMaster);
+        assert_eq!(choose_update_case(false, true, true, false), BaseBranch);
+        assert_eq!(choose_update_case(false, false, false, true), BaseBranch);
     }
 
-    // Create the new commit
-    let pr_commit = git.create_derived_commit(
-        local_commit.oid,
-        &format!(
-            "{}\n\nCreated using spr {}",
-            github_commit_message
-                .as_ref()
-                .map(|s| &s[..])
-                .unwrap_or("[𝘀𝗽𝗿] initial version"),
-            env!("CARGO_PKG_VERSION"),
-        ),
-        new_head_tree,
-        &pr_commit_parents[..],
-    )?;
+    #[test]
+    fn test_explain_update_case() {
+        assert_eq!(
+            explain_update_case(false, true, Some("spr/foo/base"), true, false),
+            "Case 3: the parent tree changed or master needs merging in, and \
+             a base branch exists already, so a new commit is created on the \
+             base branch\n  \
+             needs_merging_master: true\n  \
+             pr_base_tree == new_base_tree: false\n  \
+             existing base branch: spr/foo/base\n  \
+             no_cherry_pick: true"
+        );
+        assert!(explain_update_case(true, false, None, false, true)
+            .starts_with("Case 1: "));
+        assert!(explain_update_case(true, false, None, false, true)
+            .ends_with("\nThe Pull Request is up to date, though"));
+    }
 
-    let mut cmd = tokio::process::Command::new("git");
-    cmd.arg("push").arg("--atomic");
+    #[test]
+    fn test_existing_base_branch_keeps_stacked_target() {
+        let config = config_factory();
+        let master = config.master_ref.clone();
+        let intermediate = config.new_github_branch("spr/foo/master.my-title");
+        let other_pr = config.new_github_branch("spr/foo/other-feature");
 
-    if opts.no_verify {
-        cmd.arg("--no-verify");
+        assert!(existing_base_branch(&config, &master, false, true).is_none());
+        assert_eq!(
+            existing_base_branch(&config, &intermediate, false, true)
+                .map(|b| b.branch_name().to_string()),
+            Some("spr/foo/master.my-title".to_string())
+        );
+        assert!(existing_base_branch(&config, &intermediate, false, false)
+            .is_none());
+
+        // A Pull Request stacked onto another one keeps targeting it, whether
+        // or not `--base` is given again
+        assert!(existing_base_branch(&config, &other_pr, true, true).is_none());
+        assert!(existing_base_branch(&config, &other_pr, false, true).is_none());
     }
 
-    cmd.arg("--").arg(&config.remote_name).arg(format!(
-        "{}:{}",
-        pr_commit,
-        pull_request_branch.on_github()
-    ));
+    #[test]
+    fn test_prefer_existing_base() {
+        let config = config_factory();
+        let master = config.master_ref.clone();
+        let intermediate = config.new_github_branch("spr/foo/master.my-title");
+        let other_pr = config.new_github_branch("spr/foo/other-feature");
 
-    if let Some(pull_request) = pull_request {
-        // We are updating an existing Pull Request
+        // Without --no-cherry-pick, the base branch would be dropped
+        assert!(existing_base_branch(&config, &intermediate, false, false)
+            .is_none());
 
-        if needs_merging_master {
-            output(
-                "⚾",
-                &format!(
-                    "Commit was rebased - updating Pull Request #{}",
-                    pull_request.number
-                ),
-            )?;
-        } else {
-            output(
-                "🔁",
-                &format!(
-                    "Commit was changed - updating Pull Request #{}",
-                    pull_request.number
-                ),
-            )?;
-        }
+        // Only the head changed: the base branch is kept, and as it's case 1
+        // there is no new base branch commit to push
+        let base_branch =
+            reusable_base_branch(&config, &intermediate, true, true, false);
+        assert_eq!(
+            base_branch.as_ref().map(|b| b.branch_name()),
+            Some("spr/foo/master.my-title")
+        );
+        assert_eq!(
+            choose_update_case(true, false, base_branch.is_some(), false),
+            UpdateCase::Unchanged
+        );
 
-        // Things we want to update in the Pull Request on GitHub
-        let mut pull_request_updates: PullRequestUpdate = Default::default();
+        // Not without the flag, nor when the base changed as well
+        assert!(reusable_base_branch(
+            &config,
+            &intermediate,
+            false,
+            true,
+            false
+        )
+        .is_none());
+        assert!(reusable_base_branch(
+            &config,
+            &intermediate,
+            true,
+            false,
+            false
+        )
+        .is_none());
+        assert!(
+            reusable_base_branch(&config, &intermediate, true, true, true)
+                .is_none()
+        );
 
-        if opts.update_message {
-            pull_request_updates.update_message(&pull_request, message);
-        }
+        // Only intermediate base branches are reused
+        assert!(
+            reusable_base_branch(&config, &master, true, true, false).is_none()
+        );
+        assert!(reusable_base_branch(&config, &other_pr, true, true, false)
+            .is_none());
+    }
 
-        if let Some(base_branch) = base_branch {
-            // We are using a base branch.
+    #[test]
+    fn test_diff_summary() {
+        let config = config_factory();
+        let commit = |short_id: &str, message: &str| PreparedCommit {
+            oid: Oid::zero(),
+            short_id: short_id.into(),
+            parent_oid: Oid::zero(),
+            message: crate::message::parse_message(
+                message,
+                MessageSection::Title,
+            ),
+            pull_request_number: None,
+            pull_request_task: None,
+        };
+        let prepared_commits = vec![
+            commit("aaaaaaa", "First\n\nPull Request: #1"),
+            commit("bbbbbbb", "Second"),
+            commit("ccccccc", "Third"),
+        ];
 
-            if let Some(base_branch_commit) = pr_base_parent {
-                // ...and we prepared a new commit for it, so we need to push an
-                // update of the base branch.
-                cmd.arg(format!(
-                    "{}:{}",
-                    base_branch_commit,
-                    base_branch.on_github()
-                ));
-            }
+        assert_eq!(
+            diff_summary(
+                &config,
+                &prepared_commits,
+                &[
+                    (0, DiffOutcome::Succeeded),
+                    (1, DiffOutcome::Failed),
+                    (2, DiffOutcome::Skipped),
+                ],
+            ),
+            vec![
+                ("✅", "aaaaaaa First (#1): succeeded".to_string()),
+                ("❌", "bbbbbbb Second: failed".to_string()),
+                ("⏭️", "ccccccc Third: skipped".to_string()),
+            ]
+        );
+    }
 
-            // Push the new commit onto the Pull Request branch (and also the
-            // new base commit, if we added that to cmd above).
-            run_command_with_live_output(&mut cmd)
-                .await
-                .reword("git push failed".to_string())?;
+    #[test]
+    fn test_base_branch_commit_author() {
+        let git = init_test_repo("base-author");
+        let commit = |author: &str, content: &str, parents: &[Oid]| {
+            commit_files_as(
+                &git,
+                author,
+                &[("file.txt", content)],
+                content,
+                parents,
+            )
+        };
+        let master = commit("someone", "master", &[]);
+        let parent = commit("colleague", "parent", &[master]);
+        let local = commit("me", "local", &[parent]);
 
-            // If the Pull Request's base is not set to the base branch yet,
-            // change that now.
-            if pull_request.base.branch_name() != base_branch.branch_name() {
-                pull_request_updates.base =
-                    Some(base_branch.branch_name().to_string());
-            }
-        } else {
-            if opts.base.is_some()
-                && pull_request.base.branch_name() != base_ref.branch_name()
-            {
-                pull_request_updates.base =
-                    Some(base_ref.branch_name().to_string());
-            }
+        let mut config = config_factory();
+        config.derived_commit_committer = Some(crate::config::CommitIdentity {
+            name: "me".into(),
+            email: "me@example.com".into(),
+        });
+        config.signoff = true;
+        let base_commit = create_base_branch_commit(
+            &git,
+            &config,
+            local,
+            git.get_tree_oid_for_commit(parent).unwrap(),
+            &[master],
+            false,
+        )
+        .unwrap();
 
-            // The Pull Request is against the master branch. In that case we
-            // only need to push the update to the Pull Request branch.
-            run_command_with_live_output(&mut cmd)
-                .await
-                .reword("git push failed".to_string())?;
-        }
+        let repo = git.repo();
+        let base_commit = repo.find_commit(base_commit).unwrap();
+        assert_eq!(base_commit.author().name(), Some("me"));
+        assert_eq!(base_commit.author().email(), Some("me@example.com"));
+        assert!(base_commit
+            .message()
+            .unwrap()
+            .contains("Signed-off-by: me <me@example.com>"));
+    }
 
-        if !pull_request_updates.is_empty() {
-            gh.update_pull_request(pull_request.number, pull_request_updates)
-                .await?;
+    #[test]
+    fn test_no_push_creates_local_branches() {
+        use clap::Parser;
+
+        let git = init_test_repo("no-push");
+        let master = commit_files(&git, &[("m", "m")], "Master", &[]);
+        let first = commit_files(
+            &git,
+            &[("m", "m"), ("a", "a")],
+            "First\n\nSummary",
+            &[master],
+        );
+        let second = commit_files(
+            &git,
+            &[("m", "m"), ("a", "a"), ("b", "b")],
+            "Second",
+            &[first],
+        );
+        {
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/master", master, true, "test")
+                .unwrap();
+            repo.reference("refs/heads/master", second, true, "test")
+                .unwrap();
         }
-    } else {
-        // We are creating a new Pull Request.
 
-        // If there's a base branch, add it to the push
-        if let (Some(base_branch), Some(base_branch_commit)) =
-            (&base_branch, pr_base_parent)
+        // There is no GitHub client at all, so nothing can be pushed or asked
+        // of GitHub
+        let opts = DiffOptions::parse_from([
+            "diff",
+            "--no-push",
+            "--all",
+            "--no-cherry-pick",
+        ]);
+        diff_no_push(&opts, &git, &config_factory()).unwrap();
+
+        // The parents and the tree of the commit of a local branch
+        let branch = |name: &str| {
+            let repo = git.repo();
+            let commit = repo
+                .find_reference(&format!("refs/heads/{name}"))
+                .and_then(|reference| reference.peel_to_commit())
+                .unwrap();
+            (commit.id(), commit.parent_ids().collect::<Vec<_>>(), {
+                commit.tree_id()
+            })
+        };
+        let spr_branches = || {
+            let repo = git.repo();
+            let mut names = repo
+                .references_glob("refs/heads/spr/*")
+                .unwrap()
+                .names()
+                .map(|name| name.unwrap().to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        let (_, first_parents, _) = branch("spr/foo/first");
+        assert_eq!(first_parents, [master]);
+        // As the new Pull Request on GitHub would, the commit merges the base
+        // branch into master
+        let (second_base, second_base_parents, second_base_tree) =
+            branch("spr/foo/master.second");
+        let (_, second_parents, _) = branch("spr/foo/second");
+        assert_eq!(second_parents, [master, second_base]);
+        assert_eq!(second_base_parents, [master]);
+        assert_eq!(
+            second_base_tree,
+            git.get_tree_oid_for_commit(first).unwrap()
+        );
+        assert!(git
+            .repo()
+            .references_glob("refs/remotes/origin/spr/*")
+            .unwrap()
+            .next()
+            .is_none());
+
+        // The local commit messages were rewritten (normalised) all the same
         {
-            cmd.arg(format!(
-                "{}:{}",
-                base_branch_commit,
-                base_branch.on_github()
-            ));
+            let repo = git.repo();
+            let head = repo.head().unwrap().peel_to_commit().unwrap();
+            assert_ne!(head.id(), second);
+            assert_eq!(head.message(), Some("Second\n"));
+            assert_eq!(
+                head.parent(0).unwrap().message(),
+                Some("First\n\nSummary\n")
+            );
         }
-        // Push the pull request branch and the base branch if present
-        run_command_with_live_output(&mut cmd)
-            .await
-            .reword("git push failed".to_string())?;
 
-        // Then call GitHub to create the Pull Request.
-        let pull_request_number = gh
-            .create_pull_request(
-                message,
-                base_branch
-                    .as_ref()
-                    .unwrap_or(&base_ref)
-                    .branch_name()
-                    .to_string(),
-                pull_request_branch.branch_name().to_string(),
-                opts.draft,
-            )
-            .await?;
+        // Running again updates the same branches, rather than creating more
+        let branches = spr_branches();
+        diff_no_push(&opts, &git, &config_factory()).unwrap();
+        assert_eq!(spr_branches(), branches);
 
-        let pull_request_url = config.pull_request_url(pull_request_number);
+        // Stacked on the Pull Request below, no base branch is needed
+        let stacked = DiffOptions::parse_from([
+            "diff",
+            "--no-push",
+            "--all",
+            "--no-cherry-pick",
+            "--base",
+            "HEAD^",
+        ]);
+        diff_no_push(&stacked, &git, &config_factory()).unwrap();
+        let (first_pr, _, _) = branch("spr/foo/first");
+        let (_, second_parents, _) = branch("spr/foo/second");
+        assert_eq!(second_parents, [first_pr]);
+        assert_eq!(spr_branches(), branches);
 
-        output(
-            "✨",
-            &format!(
-                "Created new Pull Request #{}: {}",
-                pull_request_number, &pull_request_url,
-            ),
-        )?;
+        // A branch of the same name that someone else moved is left alone
+        git.repo()
+            .reference("refs/heads/spr/foo/first", master, true, "reset")
+            .unwrap();
+        let error = diff_no_push(&opts, &git, &config_factory()).unwrap_err();
+        assert!(error.messages()[0].contains("spr/foo/first exists already"));
+        assert_eq!(branch("spr/foo/first").0, master);
+    }
 
-        message.insert(MessageSection::PullRequest, pull_request_url);
+    #[tokio::test]
+    async fn test_no_push_checks_the_branch() {
+        use crate::github::test_utils::offline_github;
+        use clap::Parser;
 
-        // If current commit is not the last selected commit, update pull request number and task
-        // so that it can be used as a base PR for the subsequent commits.
-        if Some(&index) != selected_indexes.last() {
-            local_commit.pull_request_number = Some(pull_request_number);
-            local_commit.pull_request_task = Some(tokio::spawn(
-                gh.clone().get_pull_request(pull_request_number),
-            ));
+        let git = init_test_repo("no-push-checks");
+        let master = commit_files(&git, &[("m", "m")], "Master", &[]);
+        let first =
+            commit_files(&git, &[("m", "m"), ("a", "a")], "First", &[master]);
+        {
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/master", master, true, "test")
+                .unwrap();
+            repo.reference("refs/heads/master", first, true, "test")
+                .unwrap();
         }
+        let (mut gh, _) = offline_github(&git);
+        let config = config_factory();
+        let opts = || DiffOptions::parse_from(["diff", "--no-push"]);
+        let spr_branches = || {
+            git.repo()
+                .references_glob("refs/heads/spr/*")
+                .unwrap()
+                .count()
+        };
 
-        let result = gh
-            .request_reviewers(pull_request_number, requested_reviewers)
-            .await;
-        match result {
-            Ok(()) => (),
-            Err(error) => {
-                output("⚠️", "Requesting reviewers failed")?;
-                for message in error.messages() {
-                    output("  ", message)?;
-                }
-            }
-        }
+        // Nothing was checked out yet, so the files look deleted
+        let error = diff(opts(), &git, &mut gh, &config).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::DirtyWorkingTree);
+        assert_eq!(spr_branches(), 0);
+
+        git.repo()
+            .checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+            .unwrap();
+        git.repo().set_head_detached(first).unwrap();
+        let error = diff(opts(), &git, &mut gh, &config).await.unwrap_err();
+        assert!(error.messages().iter().any(|m| m.contains("--branch")));
+        assert_eq!(spr_branches(), 0);
+
+        git.repo().set_head("refs/heads/master").unwrap();
+        diff(opts(), &git, &mut gh, &config).await.unwrap();
+        assert_eq!(spr_branches(), 1);
     }
 
-    Ok(())
-}
+    #[test]
+    fn test_pinned_master_base() {
+        let git = init_test_repo("pinned-base");
+        let commit = |content: &str, parents: &[Oid]| {
+            commit_files(&git, &[("file.txt", content)], content, parents)
+        };
+        let old_master = commit("old", &[]);
+        let master = commit("new", &[old_master]);
+        let off_master = commit("other", &[old_master]);
 
-async fn get_pull_request_for_index(
-    prepared_commits: &mut [PreparedCommit],
-    choice_index: isize,
-) -> Result<crate::github::PullRequest> {
-    let pull_request = if let Some(task) = &mut prepared_commits
-        .get_mut(choice_index as usize)
-        .unwrap()
-        .pull_request_task
-    {
-        Some(task.await??)
-    } else {
-        None
-    };
-    Ok(match pull_request {
-        Some(pull_request) => pull_request,
-        None => {
-            return Err(Error::new(
-                "Could not find a PR for the base".to_string(),
-            ));
-        }
-    })
-}
+        assert_eq!(
+            pinned_master_base(&git, &old_master.to_string(), master).unwrap(),
+            old_master
+        );
+        assert_eq!(
+            pinned_master_base(&git, &master.to_string(), master).unwrap(),
+            master
+        );
+        assert!(
+            pinned_master_base(&git, &off_master.to_string(), master).is_err()
+        );
+        assert!(pinned_master_base(&git, "no-such-commit", master).is_err());
+    }
 
-fn parse_parent_or_zero(s: &str) -> isize {
-    if s == "HEAD~" || s == "HEAD^" {
-        1
-    } else if s.starts_with("HEAD^") || s.starts_with("HEAD^") {
-        if let Ok(n) = s[5..].parse::<isize>() {
-            n
-        } else {
-            0
-        }
-    } else {
-        0
+    #[test]
+    fn test_reviewers_required() {
+        let mut config = config_factory();
+        let mut requested_reviewers = PullRequestRequestReviewers::default();
+        assert!(check_reviewers_required(&config, &requested_reviewers).is_ok());
+
+        config.require_reviewers = true;
+        assert!(
+            check_reviewers_required(&config, &requested_reviewers).is_err()
+        );
+
+        requested_reviewers.team_reviewers.push("core".into());
+        assert!(check_reviewers_required(&config, &requested_reviewers).is_ok());
+    }
+
+    #[test]
+    fn test_parse_cross_repo_reference() {
+        let expected = CrossRepoReference {
+            owner: "acme".into(),
+            repo: "widgets.rs".into(),
+            number: 42,
+        };
+        assert_eq!(expected.to_string(), "acme/widgets.rs#42");
+        assert_eq!(
+            parse_cross_repo_reference("acme/widgets.rs#42"),
+            Some(expected)
+        );
+        assert_eq!(
+            parse_cross_repo_reference("#acme/widgets.rs#42")
+                .map(|reference| reference.to_string())
+                .as_deref(),
+            Some("acme/widgets.rs#42")
+        );
+
+        // Branches and revisions are not cross-repository references
+        assert_eq!(parse_cross_repo_reference("main"), None);
+        assert_eq!(parse_cross_repo_reference("spr/foo/title"), None);
+        assert_eq!(parse_cross_repo_reference("HEAD^"), None);
+        assert_eq!(parse_cross_repo_reference("acme/widgets#abc"), None);
+        assert_eq!(parse_cross_repo_reference("a/b/c#1"), None);
+    }
+
+    #[test]
+    fn test_new_only_skips_linked_commits() {
+        let commit = |pull_request_number| PreparedCommit {
+            oid: Oid::zero(),
+            short_id: "aaaaaaa".into(),
+            parent_oid: Oid::zero(),
+            message: Default::default(),
+            pull_request_number,
+            pull_request_task: None,
+        };
+        let prepared_commits =
+            vec![commit(Some(1)), commit(None), commit(Some(3)), commit(None)];
+
+        assert_eq!(
+            split_linked_commits(&prepared_commits, vec![0, 1, 2, 3]),
+            (vec![1, 3], vec![0, 2])
+        );
+        assert_eq!(
+            split_linked_commits(&prepared_commits, vec![2]),
+            (vec![], vec![2])
+        );
+    }
+
+    #[test]
+    fn test_draft_comment() {
+        let text = Some("Not ready, still testing #{pr} on staging");
+        assert_eq!(
+            draft_comment(true, text, 12).as_deref(),
+            Some("Not ready, still testing #12 on staging")
+        );
+        // Only drafts get the comment
+        assert_eq!(draft_comment(false, text, 12), None);
+        assert_eq!(draft_comment(true, None, 12), None);
+    }
+
+    #[test]
+    fn test_render_comment() {
+        assert_eq!(
+            render_comment("Addressed feedback on #{pr}", 42),
+            "Addressed feedback on #42"
+        );
+        assert_eq!(render_comment("Done", 42), "Done");
     }
 }
@@ -41,8 +41,9 @@ pub async fn format(
     let mut failure = false;
 
     for commit in slice.iter() {
-        write_commit_title(commit)?;
-        failure = validate_commit_message(&commit.message, config).is_err()
+        write_commit_title(commit, None)?;
+        failure = validate_commit_message(&commit.message, config, false)
+            .is_err()
             || failure;
     }
     git.rewrite_commit_messages(slice, None)?;
@@ -9,6 +9,7 @@ use indoc::formatdoc;
 use lazy_regex::regex;
 
 use crate::{
+    config::parse_github_remote_url,
     error::{Error, Result, ResultExt},
     output::output,
 };
@@ -121,18 +122,11 @@ pub async fn init() -> Result<()> {
     )?;
 
     let url = repo.find_remote(&remote)?.url().map(String::from);
-    let regex =
-        lazy_regex::regex!(r#"github\.com[/:]([\w\-\.]+/[\w\-\.]+?)(.git)?$"#);
     let github_repo = config
         .get_string("spr.githubRepository")
         .ok()
         .and_then(|value| if value.is_empty() { None } else { Some(value) })
-        .or_else(|| {
-            url.as_ref()
-                .and_then(|url| regex.captures(url))
-                .and_then(|caps| caps.get(1))
-                .map(|m| m.as_str().to_string())
-        })
+        .or_else(|| url.as_deref().and_then(parse_github_remote_url))
         .unwrap_or_default();
 
     let github_repo = dialoguer::Input::<String>::new()
@@ -9,7 +9,7 @@ use indoc::formatdoc;
 use std::{io::Write, process::Stdio, time::Duration};
 
 use crate::{
-    error::{Error, Result, ResultExt},
+    error::{Error, ErrorKind, Result, ResultExt},
     github::{PullRequestState, PullRequestUpdate, ReviewStatus},
     message::build_github_body_for_merging,
     output::{output, write_commit_title},
@@ -53,7 +53,7 @@ pub async fn land(
         }
     };
 
-    write_commit_title(prepared_commit)?;
+    write_commit_title(prepared_commit, None)?;
 
     let pull_request_number =
         if let Some(number) = prepared_commit.pull_request_number {
@@ -112,7 +112,8 @@ pub async fn land(
             } else {
                 ""
             },
-        )));
+        ))
+        .with_kind(ErrorKind::Conflict));
     }
 
     // This is the tree we are getting from cherrypicking the local commit
@@ -204,12 +205,17 @@ pub async fn land(
 
             pr_head_oid = git.create_derived_commit(
                 pr_head_oid,
-                &format!(
-                    "[𝘀𝗽𝗿] landed version\n\nCreated using spr {}",
-                    env!("CARGO_PKG_VERSION"),
-                ),
+                &git.derived_commit_message(
+                    config,
+                    format!(
+                        "[𝘀𝗽𝗿] landed version\n\nCreated using spr {}",
+                        env!("CARGO_PKG_VERSION"),
+                    ),
+                )?,
                 our_tree_oid,
                 &[pr_head_oid, current_master],
+                None,
+                None,
             )?;
 
             let mut cmd = tokio::process::Command::new("git");
@@ -312,7 +318,7 @@ pub async fn land(
                 .sha(format!("{}", pr_head_oid))
                 .send()
                 .await
-                .convert()
+                .with_kind(ErrorKind::GitHub)
                 .and_then(|merge| {
                     if merge.merged {
                         Ok(merge)
@@ -6,7 +6,9 @@
  */
 
 use crate::error::Error;
+use crate::error::ErrorKind;
 use crate::error::Result;
+use crate::error::ResultExt;
 use graphql_client::{GraphQLQuery, Response};
 use reqwest;
 
@@ -20,7 +22,17 @@ type URI = String;
 )]
 pub struct SearchQuery;
 
+#[derive(Debug, clap::Parser)]
+pub struct ListOptions {
+    /// Show the output through a pager ($SPR_PAGER or $PAGER, defaulting to
+    /// less) if it is written to a terminal. Setting $SPR_PAGER enables this
+    /// by default
+    #[clap(long)]
+    pager: bool,
+}
+
 pub async fn list(
+    opts: ListOptions,
     graphql_client: reqwest::Client,
     config: &crate::config::Config,
 ) -> Result<()> {
@@ -35,17 +47,20 @@ pub async fn list(
         .post("https://api.github.com/graphql")
         .json(&request_body)
         .send()
-        .await?;
+        .await
+        .with_kind(ErrorKind::GitHub)?;
     let response_body: Response<search_query::ResponseData> =
-        res.json().await?;
+        res.json().await.with_kind(ErrorKind::GitHub)?;
 
-    print_pr_info(response_body).ok_or_else(|| Error::new("unexpected error"))
+    let text = format_pr_info(response_body)
+        .ok_or_else(|| Error::new("unexpected error"))?;
+    crate::output::write_paged(&text, opts.pager)
 }
 
-fn print_pr_info(
+fn format_pr_info(
     response_body: Response<search_query::ResponseData>,
-) -> Option<()> {
-    let term = console::Term::stdout();
+) -> Option<String> {
+    let mut text = String::new();
     for pr in response_body.data?.search.nodes? {
         let pr = match pr {
             Some(crate::commands::list::search_query::SearchQuerySearchNodes::PullRequest(pr)) => pr,
@@ -68,13 +83,12 @@ fn print_pr_info(
                 console::style(dummy.as_str())
             }
         };
-        term.write_line(&format!(
-            "{} {} {}",
+        text.push_str(&format!(
+            "{} {} {}\n",
             decision,
             console::style(&pr.title).bold(),
             console::style(&pr.url).dim(),
-        ))
-        .ok()?;
+        ));
     }
-    Some(())
+    Some(text)
 }
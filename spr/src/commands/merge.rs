@@ -6,11 +6,10 @@
  */
 
 use crate::{
-    error::{Error, Result, ResultExt},
-    git::CommitOption,
+    error::{add_error, Error, ErrorKind, Result, ResultExt},
+    git::{CommitOption, PreparedCommit},
     message::MessageSection,
     output::output,
-    utils::run_command,
 };
 use inquire::MultiSelect;
 
@@ -20,20 +19,89 @@ pub struct MergeOptions {
     /// merge pull requests, not just the HEAD commit
     #[clap(long, short = 'a')]
     all: bool,
+
+    /// Merge the pull requests of the commits in the given revision range
+    /// (e.g. `HEAD~3..HEAD`, or a single commit) without an interactive
+    /// selection
+    #[clap(long, value_name = "RANGE", conflicts_with = "all")]
+    commits: Option<String>,
+
+    /// Enable GitHub's auto-merge on the Pull Requests, so that GitHub merges
+    /// them once all requirements (reviews, checks) are met. This requires
+    /// auto-merge to be allowed in the repository settings.
+    #[clap(long)]
+    auto: bool,
+
+    /// The merge method GitHub uses when auto-merging
+    #[clap(long, value_enum, default_value = "squash", requires = "auto")]
+    method: crate::github::MergeMethod,
+
+    /// Record the commit the Pull Request was merged as in a 'Landed-as'
+    /// section of the local commit message
+    #[clap(long, conflicts_with_all = &["auto", "label"])]
+    landed_as: bool,
+
+    /// Instead of merging, add a label to the Pull Requests, for a bot that
+    /// merges labelled Pull Requests. Without a value, the label is taken
+    /// from git config spr.mergeLabel, defaulting to 'mergeme'
+    #[clap(
+        long,
+        value_name = "NAME",
+        min_values = 0,
+        require_equals = true,
+        conflicts_with = "auto"
+    )]
+    label: Option<Option<String>>,
+
+    /// Once the Pull Requests are merged, fetch master and rebase the local
+    /// branch onto it, dropping the merged commits
+    #[clap(long, conflicts_with_all = &["auto", "label", "landed-as"])]
+    cleanup: bool,
+
+    /// Post this comment on each Pull Request once it is merged (default
+    /// from git config spr.mergeComment). The placeholders {pr} and {title}
+    /// are replaced with the number and title of the Pull Request
+    #[clap(long, value_name = "TEXT", conflicts_with_all = &["auto", "label"])]
+    comment: Option<String>,
+}
+
+/// The label to add to the Pull Requests, if the user asked for labelling
+/// instead of merging
+fn merge_label(
+    opts: &MergeOptions,
+    config: &crate::config::Config,
+) -> Option<String> {
+    opts.label.as_ref().map(|label| {
+        label.clone().unwrap_or_else(|| config.merge_label.clone())
+    })
 }
 
 pub async fn merge(
     opts: MergeOptions,
     git: &crate::git::Git,
+    gh: &crate::github::GitHub,
     config: &crate::config::Config,
 ) -> Result<()> {
     let mut result = Ok(());
 
+    git.check_head_is_attached(None)?;
+    git.check_master_ref(config)?;
+
+    // The local commit messages are rewritten, or the branch rebased, at the
+    // end
+    if opts.landed_as || opts.cleanup {
+        git.check_no_uncommitted_changes()?;
+    }
+
+    let label = merge_label(&opts, config);
+
+    git.check_stack_depth(config)?;
+
     // Look up the commits on the local branch
-    let prepared_commits = git.get_prepared_commits(config, None)?;
+    let mut prepared_commits = git.get_prepared_commits(config, None)?;
     let length = prepared_commits.len();
 
-    if prepared_commits.get(0).is_none() {
+    if prepared_commits.is_empty() {
         output("👋", "Branch is empty - nothing to do. Good bye!")?;
         return result;
     };
@@ -63,14 +131,20 @@ pub async fn merge(
             .rev()
             .collect::<Vec<CommitOption>>();
 
-        let ans =
-            MultiSelect::new("Select commits to merge:", options).prompt()?;
+        let ans = MultiSelect::new("Select commits to merge:", options)
+            .prompt()
+            .with_kind(ErrorKind::Aborted)?;
 
         ans.iter().map(|x| x.index as usize).rev().collect()
+    } else if let Some(range) = &opts.commits {
+        git.select_commit_range(&prepared_commits, range)?
     } else {
         vec![length - 1]
     };
 
+    let mut landed_indexes = Vec::new();
+    let mut merged = Vec::new();
+
     // selected_indexes is sorted from lower commits to higher commits
     for index in selected_indexes {
         if result.is_err() {
@@ -80,27 +154,75 @@ pub async fn merge(
         let pull_request_number = prepared_commits[index].pull_request_number;
 
         if let Some(pull_request_number) = pull_request_number {
-            // This could be refactored to use the GitHub API directly
-            // but this is a quick and easy way to get the job done
-            // `spr label` and git config frequent labels could be added
-            run_command(
-                tokio::process::Command::new("gh")
-                    .arg("pr")
-                    .arg("merge")
-                    .arg(pull_request_number.to_string())
-            )
-            .await
-            .reword("enabling auto-merge failed".to_string())?;
+            // An error ends the loop, but what has been merged so far is
+            // still followed up on below
+            if let Some(label) = &label {
+                if add_error(
+                    &mut result,
+                    gh.add_label(pull_request_number, label).await,
+                )
+                .is_none()
+                {
+                    break;
+                }
+                output(
+                    "🏷️",
+                    &format!(
+                        "Added label '{}' to Pull Request #{}: {}",
+                        label,
+                        pull_request_number,
+                        &config.pull_request_url(pull_request_number),
+                    ),
+                )?;
+                continue;
+            }
+
+            if opts.auto {
+                if add_error(
+                    &mut result,
+                    gh.enable_auto_merge(pull_request_number, opts.method)
+                        .await,
+                )
+                .is_none()
+                {
+                    break;
+                }
+                output(
+                    "✅",
+                    &format!(
+                        "Enabled auto-merge on Pull Request #{}: {}",
+                        pull_request_number,
+                        &config.pull_request_url(pull_request_number),
+                    ),
+                )?;
+                continue;
+            }
 
-            let pull_request_url = config.pull_request_url(pull_request_number);
+            let merge_commit = match add_error(
+                &mut result,
+                gh.merge_pull_request(pull_request_number).await,
+            ) {
+                Some(merge_commit) => merge_commit,
+                None => break,
+            };
 
             output(
                 "✅",
                 &format!(
-                    "Enabled auto-merge on Pull Request #{}: {}",
-                    pull_request_number, &pull_request_url,
+                    "Merged Pull Request #{} as {}: {}",
+                    pull_request_number,
+                    merge_commit,
+                    &config.pull_request_url(pull_request_number),
                 ),
             )?;
+
+            merged.push((index, merge_commit));
+            if opts.landed_as {
+                prepared_commits[index]
+                    .message
+                    .insert(MessageSection::LandedAs, merge_commit.to_string());
+                landed_indexes.push(index);
+            }
         } else {
             result = Err(Error::new(
                 "Your selection obviously has no PR created yet".to_string(),
@@ -108,5 +230,316 @@ pub async fn merge(
         }
     }
 
+    if let Some(template) =
+        opts.comment.as_ref().or(config.merge_comment.as_ref())
+    {
+        for (number, text) in
+            merge_comments(template, &prepared_commits, &merged)
+        {
+            let posted = gh.create_comment(number, &text).await;
+            if posted.is_ok() {
+                output("💬", &format!("Commented on #{number}"))?;
+            }
+            add_error(&mut result, posted);
+        }
+    }
+
+    if !landed_indexes.is_empty() {
+        add_error(
+            &mut result,
+            git.rewrite_commit_messages(&mut prepared_commits, None),
+        );
+    }
+
+    if opts.cleanup && !merged.is_empty() {
+        add_error(
+            &mut result,
+            cleanup(git, config, prepared_commits, &merged).await,
+        );
+    }
+
     result
 }
+
+/// The comments to post on the merged Pull Requests (given by the index of
+/// their commit): one for each
+fn merge_comments(
+    template: &str,
+    prepared_commits: &[PreparedCommit],
+    merged: &[(usize, git2::Oid)],
+) -> Vec<(u64, String)> {
+    merged
+        .iter()
+        .filter_map(|(index, _)| {
+            let commit = &prepared_commits[*index];
+            let number = commit.pull_request_number?;
+            let title = commit
+                .message
+                .get(&MessageSection::Title)
+                .map(|t| &t[..])
+                .unwrap_or_default();
+            Some((
+                number,
+                template
+                    .replace("{pr}", &number.to_string())
+                    .replace("{title}", title),
+            ))
+        })
+        .collect()
+}
+
+/// Drop the merged commits (given by index, with the commit they were merged
+/// as) from the local branch, once master on GitHub contains them
+async fn cleanup(
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+    prepared_commits: Vec<PreparedCommit>,
+    merged: &[(usize, git2::Oid)],
+) -> Result<()> {
+    crate::git::Git::fetch_from_remote(
+        &[&config.master_ref],
+        &config.remote_name,
+    )
+    .await?;
+    let master_oid = git.resolve_reference(config.master_ref.local())?;
+
+    for (_, merge_commit) in merged {
+        let on_master = *merge_commit == master_oid
+            || git.repo().graph_descendant_of(master_oid, *merge_commit)?;
+        if !on_master {
+            output(
+                "⚠️",
+                &format!(
+                    "{} does not contain {} yet - not cleaning up the local \
+                     branch",
+                    config.master_ref.branch_name(),
+                    merge_commit
+                ),
+            )?;
+            return Ok(());
+        }
+    }
+
+    let merged_indexes =
+        merged.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+    drop_merged_commits(git, prepared_commits, &merged_indexes, master_oid)?;
+    output(
+        "🧹",
+        &format!(
+            "Dropped {} merged commit(s) from the local branch, which is \
+             now based on {}",
+            merged_indexes.len(),
+            config.master_ref.branch_name()
+        ),
+    )?;
+
+    Ok(())
+}
+
+/// Rebase the commits that are not among the given merged ones onto the
+/// given master commit. On conflicts, the branch is left as it is.
+fn drop_merged_commits(
+    git: &crate::git::Git,
+    prepared_commits: Vec<PreparedCommit>,
+    merged_indexes: &[usize],
+    master_oid: git2::Oid,
+) -> Result<()> {
+    let mut remaining = prepared_commits
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !merged_indexes.contains(index))
+        .map(|(_, prepared_commit)| prepared_commit)
+        .collect::<Vec<_>>();
+
+    git.rebase_commits(&mut remaining, master_oid).context(
+        "The local branch has not been changed - please rebase manually"
+            .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use clap::Parser;
+
+    use crate::config::test_utils::config_factory;
+    use crate::git::test_utils::{commit_files, init_test_repo};
+    use git2::Oid;
+
+    #[derive(Debug, clap::Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        opts: MergeOptions,
+    }
+
+    fn parse(args: &[&str]) -> MergeOptions {
+        Cli::parse_from(std::iter::once("merge").chain(args.iter().copied()))
+            .opts
+    }
+
+    #[tokio::test]
+    async fn test_max_stack_depth() {
+        use crate::git::test_utils::commit;
+        use crate::github::test_utils::{offline_github, sent_requests};
+
+        let git = init_test_repo("merge-max-stack-depth");
+        let root = commit(&git, "root", &[]);
+        let mut head = root;
+        for number in 1..=3 {
+            head = commit(
+                &git,
+                &format!(
+                    "Commit {number}\n\nPull Request: \
+                     https://github.com/acme/codez/pull/{number}"
+                ),
+                &[head],
+            );
+        }
+        {
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/master", root, true, "test")
+                .unwrap();
+            repo.reference("refs/heads/master", head, true, "test")
+                .unwrap();
+        }
+        let (gh, listener) = offline_github(&git);
+        let mut config = config_factory();
+        config.max_stack_depth = 2;
+
+        let error = merge(parse(&["--all"]), &git, &gh, &config)
+            .await
+            .unwrap_err();
+        assert!(error.messages()[0].contains("maximum of 2"));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(sent_requests(&listener), 0);
+    }
+
+    #[test]
+    fn test_drop_merged_commit() {
+        let git = init_test_repo("drop-merged");
+        let commit = |message: &str, files: &[(&str, &str)], parent: Oid| {
+            commit_files(&git, files, message, &[parent])
+        };
+
+        // The local branch has commits A (merged now) and B on master
+        let master = commit_files(&git, &[("m", "m")], "M", &[]);
+        let a = commit("A", &[("m", "m"), ("a", "a")], master);
+        let b = commit("B", &[("m", "m"), ("a", "a"), ("b", "b")], a);
+        {
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/master", master, false, "test")
+                .unwrap();
+            repo.reference("refs/heads/master", b, true, "test")
+                .unwrap();
+            repo.checkout_head(Some(
+                git2::build::CheckoutBuilder::new().force(),
+            ))
+            .unwrap();
+        }
+        // A was squash-merged, and someone else changed master since
+        let merged = commit("A (#1)", &[("m", "m"), ("a", "a")], master);
+        let new_master =
+            commit("N", &[("m", "m"), ("a", "a"), ("n", "n")], merged);
+        let conflicting_master =
+            commit("C", &[("m", "m"), ("a", "a"), ("b", "conflict")], merged);
+
+        let config = config_factory();
+
+        // On conflict, the branch stays as it is
+        let prepared_commits = git.get_prepared_commits(&config, None).unwrap();
+        assert_eq!(prepared_commits.len(), 2);
+        let error = drop_merged_commits(
+            &git,
+            prepared_commits,
+            &[0],
+            conflicting_master,
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Conflict);
+        assert_eq!(git.head().unwrap(), b);
+
+        let prepared_commits = git.get_prepared_commits(&config, None).unwrap();
+        drop_merged_commits(&git, prepared_commits, &[0], new_master).unwrap();
+        let head_oid = git.head().unwrap();
+        let repo = git.repo();
+        let head = repo.find_commit(head_oid).unwrap();
+        assert_eq!(head.message(), Some("B"));
+        assert_eq!(head.parent_ids().collect::<Vec<_>>(), vec![new_master]);
+        let tree = head.tree().unwrap();
+        for name in ["m", "a", "n", "b"] {
+            assert!(tree.get_name(name).is_some(), "{name} missing");
+        }
+    }
+
+    #[test]
+    fn test_merge_label() {
+        let mut config = config_factory();
+
+        assert_eq!(merge_label(&parse(&[]), &config), None);
+        assert_eq!(
+            merge_label(&parse(&["--label"]), &config),
+            Some("mergeme".to_string())
+        );
+        assert_eq!(
+            merge_label(&parse(&["--label=automerge"]), &config),
+            Some("automerge".to_string())
+        );
+
+        config.merge_label = "ship-it".into();
+        assert_eq!(
+            merge_label(&parse(&["--label"]), &config),
+            Some("ship-it".to_string())
+        );
+        assert_eq!(
+            merge_label(&parse(&["--label=automerge"]), &config),
+            Some("automerge".to_string())
+        );
+    }
+
+    #[test]
+    fn test_merge_comments() {
+        let commit = |number: Option<u64>, title: &str| PreparedCommit {
+            oid: git2::Oid::zero(),
+            short_id: "aaaaaaa".into(),
+            parent_oid: git2::Oid::zero(),
+            message: [(MessageSection::Title, title.to_string())].into(),
+            pull_request_number: number,
+            pull_request_task: None,
+        };
+        let prepared_commits = vec![
+            commit(Some(1), "Already merged"),
+            commit(Some(2), "Add feature"),
+            commit(Some(3), "Fix bug"),
+        ];
+        let merged = [(1, git2::Oid::zero()), (2, git2::Oid::zero())];
+
+        // Only the Pull Requests that were merged get a comment, once each
+        assert_eq!(
+            merge_comments(
+                "Landed #{pr} ({title}), thanks!",
+                &prepared_commits,
+                &merged
+            ),
+            vec![
+                (2, "Landed #2 (Add feature), thanks!".to_string()),
+                (3, "Landed #3 (Fix bug), thanks!".to_string()),
+            ]
+        );
+        assert!(merge_comments("Landed", &prepared_commits, &[]).is_empty());
+
+        // A comment can't be given together with --auto or --label
+        assert!(
+            Cli::try_parse_from(["merge", "--auto", "--comment", "x"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_merge_method_only_with_auto() {
+        assert!(Cli::try_parse_from(["merge", "--method", "rebase"]).is_err());
+        assert_eq!(
+            parse(&["--auto", "--method", "rebase"]).method,
+            crate::github::MergeMethod::Rebase
+        );
+    }
+}
@@ -0,0 +1,172 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::error::ResultExt;
+use graphql_client::{GraphQLQuery, Response};
+use serde::Serialize;
+
+#[allow(clippy::upper_case_acronyms)]
+type URI = String;
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/schema.docs.graphql",
+    query_path = "src/gql/my_pull_requests.graphql",
+    response_derives = "Debug"
+)]
+pub struct MyPullRequestsQuery;
+
+#[derive(Debug, clap::Parser)]
+pub struct MineOptions {
+    /// Print the Pull Requests as JSON
+    #[clap(long)]
+    json: bool,
+
+    /// Show the output through a pager ($SPR_PAGER or $PAGER, defaulting to
+    /// less) if it is written to a terminal. Setting $SPR_PAGER enables this
+    /// by default
+    #[clap(long)]
+    pager: bool,
+}
+
+/// An open Pull Request of the current user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MyPullRequest {
+    number: u64,
+    title: String,
+    url: String,
+    base_branch: String,
+    head_branch: String,
+}
+
+pub async fn mine(
+    opts: MineOptions,
+    graphql_client: reqwest::Client,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let variables = my_pull_requests_query::Variables {
+        query: format!(
+            "repo:{}/{} is:open is:pr author:@me archived:false",
+            config.owner, config.repo
+        ),
+    };
+    let request_body = MyPullRequestsQuery::build_query(variables);
+    let res = graphql_client
+        .post("https://api.github.com/graphql")
+        .json(&request_body)
+        .send()
+        .await
+        .with_kind(ErrorKind::GitHub)?;
+    let response_body: Response<my_pull_requests_query::ResponseData> =
+        res.json().await.with_kind(ErrorKind::GitHub)?;
+
+    let pull_requests = response_body
+        .data
+        .and_then(|data| data.search.nodes)
+        .ok_or_else(|| Error::new("unexpected error"))?
+        .into_iter()
+        .filter_map(|node| match node {
+            Some(my_pull_requests_query::MyPullRequestsQuerySearchNodes::PullRequest(pr)) => {
+                Some(MyPullRequest {
+                    number: pr.number as u64,
+                    title: pr.title,
+                    url: pr.url,
+                    base_branch: pr.base_ref_name,
+                    head_branch: pr.head_ref_name,
+                })
+            }
+            _ => None,
+        });
+    let pull_requests = spr_pull_requests(pull_requests, &config.branch_prefix);
+
+    let text = if opts.json {
+        format!("{}\n", serde_json::to_string_pretty(&pull_requests)?)
+    } else {
+        format_pull_requests(&pull_requests)
+    };
+    crate::output::write_paged(&text, opts.pager)
+}
+
+/// The Pull Requests whose head branch was created by spr (i.e. is under the
+/// branch prefix), ordered by number
+fn spr_pull_requests(
+    pull_requests: impl Iterator<Item = MyPullRequest>,
+    branch_prefix: &str,
+) -> Vec<MyPullRequest> {
+    let mut result: Vec<_> = pull_requests
+        .filter(|pr| pr.head_branch.starts_with(branch_prefix))
+        .collect();
+    result.sort_by_key(|pr| pr.number);
+    result
+}
+
+fn format_pull_requests(pull_requests: &[MyPullRequest]) -> String {
+    let mut text = String::new();
+    for pr in pull_requests {
+        text.push_str(&format!(
+            "#{} {} {} → {} {}\n",
+            pr.number,
+            console::style(&pr.title).bold(),
+            pr.head_branch,
+            pr.base_branch,
+            console::style(&pr.url).dim(),
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn pull_request_factory(number: u64, head_branch: &str) -> MyPullRequest {
+        MyPullRequest {
+            number,
+            title: format!("Title {number}"),
+            url: format!("https://github.com/acme/codez/pull/{number}"),
+            base_branch: "master".into(),
+            head_branch: head_branch.into(),
+        }
+    }
+
+    #[test]
+    fn test_spr_pull_requests() {
+        let pull_requests = vec![
+            pull_request_factory(12, "spr/foo/newer"),
+            pull_request_factory(11, "feature/by-hand"),
+            pull_request_factory(3, "spr/foo/stranded"),
+            pull_request_factory(5, "spr/bar/someone-elses-prefix"),
+        ];
+
+        assert_eq!(
+            spr_pull_requests(pull_requests.into_iter(), "spr/foo/"),
+            vec![
+                pull_request_factory(3, "spr/foo/stranded"),
+                pull_request_factory(12, "spr/foo/newer"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_my_pull_request_json() {
+        assert_eq!(
+            serde_json::to_value(pull_request_factory(3, "spr/foo/title"))
+                .unwrap(),
+            serde_json::json!({
+                "number": 3,
+                "title": "Title 3",
+                "url": "https://github.com/acme/codez/pull/3",
+                "baseBranch": "master",
+                "headBranch": "spr/foo/title",
+            })
+        );
+    }
+}
@@ -6,11 +6,20 @@
  */
 
 pub mod amend;
+pub mod branches;
 pub mod close;
+pub mod describe;
 pub mod diff;
 pub mod format;
 pub mod init;
 pub mod land;
 pub mod list;
 pub mod merge;
+pub mod mine;
 pub mod patch;
+pub mod ready;
+pub mod rebase;
+pub mod split;
+pub mod status;
+pub mod sync_messages;
+pub mod verify;
@@ -96,6 +96,8 @@ pub async fn patch(
                     &format!("[𝘀𝗽𝗿] Base of Pull Request #{}", pr.number),
                     pr_base_tree,
                     &[pr_master_oid],
+                    None,
+                    None,
                 )?;
             }
         }
@@ -108,6 +110,8 @@ pub async fn patch(
             &build_commit_message(&pr.sections),
             git.get_tree_oid_for_commit(pr.head_oid)?,
             &[pr_master_oid],
+            None,
+            None,
         )?
     };
 
@@ -0,0 +1,190 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use crate::{
+    error::{Error, Result},
+    git::PreparedCommit,
+    github::{PullRequest, PullRequestRequestReviewers, PullRequestState},
+    message::{validate_commit_message, MessageSection},
+    output::{output, write_commit_title},
+    utils::parse_name_list,
+};
+
+#[derive(Debug, clap::Parser)]
+pub struct ReadyOptions {
+    /// Mark the Pull Requests of the whole branch as ready, not just the one
+    /// of the HEAD commit
+    #[clap(long, short = 'a')]
+    all: bool,
+}
+
+pub async fn ready(
+    opts: ReadyOptions,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let mut prepared_commits = git.get_prepared_commits(config, None)?;
+
+    if prepared_commits.is_empty() {
+        output("👋", "Branch is empty - nothing to do. Good bye!")?;
+        return Ok(());
+    };
+
+    if !opts.all {
+        // Only operate on the HEAD commit
+        prepared_commits.drain(0..prepared_commits.len() - 1);
+    }
+
+    for prepared_commit in prepared_commits.iter() {
+        write_commit_title(prepared_commit, None)?;
+        ready_impl(gh, config, prepared_commit).await?;
+    }
+
+    Ok(())
+}
+
+async fn ready_impl(
+    gh: &crate::github::GitHub,
+    config: &crate::config::Config,
+    prepared_commit: &PreparedCommit,
+) -> Result<()> {
+    let number = prepared_commit.pull_request_number.ok_or_else(|| {
+        Error::new("This commit does not refer to a Pull Request.")
+    })?;
+
+    let pull_request = gh.clone().get_pull_request(number).await?;
+    if pull_request.state != PullRequestState::Open {
+        return Err(Error::new(format!("Pull Request #{} is closed", number)));
+    }
+
+    let listed = match prepared_commit.message.get(&MessageSection::Reviewers) {
+        Some(reviewers) => {
+            config.expand_reviewer_aliases(parse_name_list(reviewers))?
+        }
+        None => Vec::new(),
+    };
+    let to_request = reviewers_to_request(&listed, &pull_request);
+
+    if !pull_request.is_draft && to_request == Default::default() {
+        output(
+            "👌",
+            &format!(
+                "#{} is ready for review already, and all its reviewers \
+                 are requested",
+                number
+            ),
+        )?;
+        return Ok(());
+    }
+
+    if pull_request.is_draft {
+        // The checks a draft is exempt from apply now
+        validate_commit_message(&prepared_commit.message, config, false)?;
+        let id = gh.get_pull_request_id(number).await?;
+        gh.set_pull_request_draft(&id, false).await?;
+        output("✅", &format!("Marked #{} as ready for review", number))?;
+    }
+
+    if to_request != Default::default() {
+        let names = to_request
+            .reviewers
+            .iter()
+            .cloned()
+            .chain(to_request.team_reviewers.iter().map(|t| format!("#{t}")))
+            .collect::<Vec<_>>()
+            .join(", ");
+        gh.request_reviewers(number, to_request).await?;
+        output("👀", &format!("Requested reviews from {}", names))?;
+    }
+
+    Ok(())
+}
+
+/// The users and teams listed in the Reviewers section that have neither a
+/// pending review request nor submitted a review on the Pull Request
+fn reviewers_to_request(
+    listed: &[String],
+    pull_request: &PullRequest,
+) -> PullRequestRequestReviewers {
+    let is_requested = |name: &str| {
+        pull_request
+            .requested_reviewers
+            .iter()
+            .any(|requested| requested.eq_ignore_ascii_case(name))
+    };
+    let has_reviewed = |name: &str| {
+        pull_request
+            .reviewers
+            .keys()
+            .any(|reviewer| reviewer.eq_ignore_ascii_case(name))
+    };
+    let mut to_request = PullRequestRequestReviewers::default();
+
+    for name in listed {
+        if is_requested(name) {
+            continue;
+        }
+        if let Some(slug) = name.strip_prefix('#') {
+            to_request.team_reviewers.push(slug.to_string());
+        } else if !has_reviewed(name) {
+            to_request.reviewers.push(name.clone());
+        }
+    }
+
+    to_request
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    use crate::github::{GitHubBranch, ReviewStatus};
+
+    #[test]
+    fn test_reviewers_to_request() {
+        let branch = GitHubBranch::new_from_branch_name(
+            "spr/foo/bar",
+            "origin",
+            "master",
+        );
+        let pull_request = PullRequest {
+            number: 1,
+            state: PullRequestState::Open,
+            is_draft: true,
+            title: "Title".into(),
+            body: None,
+            sections: Default::default(),
+            base: branch.clone(),
+            head: branch,
+            base_oid: git2::Oid::zero(),
+            head_oid: git2::Oid::zero(),
+            merge_commit: None,
+            reviewers: [("carol".to_string(), ReviewStatus::Approved)]
+                .into_iter()
+                .collect(),
+            requested_reviewers: vec!["alice".into(), "#web".into()],
+            review_status: None,
+        };
+
+        let listed = ["Alice", "bob", "carol", "#web", "#core"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(
+            reviewers_to_request(&listed, &pull_request),
+            PullRequestRequestReviewers {
+                reviewers: vec!["bob".into()],
+                team_reviewers: vec!["core".into()],
+            }
+        );
+        assert_eq!(
+            reviewers_to_request(&[], &pull_request),
+            PullRequestRequestReviewers::default()
+        );
+    }
+}
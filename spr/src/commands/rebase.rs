@@ -0,0 +1,65 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use crate::{
+    error::{Result, ResultExt},
+    output::{output, write_commit_title},
+};
+
+#[derive(Debug, clap::Parser)]
+pub struct RebaseOptions {
+    /// The commit or reference (e.g. 'origin/master') to move the stack onto
+    #[clap(long, value_name = "REF")]
+    onto: String,
+}
+
+pub async fn rebase(
+    opts: RebaseOptions,
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+) -> Result<()> {
+    git.check_no_uncommitted_changes()?;
+    let mut prepared_commits = git.get_prepared_commits(config, None)?;
+
+    if prepared_commits.is_empty() {
+        output("👋", "Branch is empty - nothing to do. Good bye!")?;
+        return Ok(());
+    }
+
+    let onto_oid = git
+        .resolve_revision(&opts.onto)
+        .reword(format!("Could not resolve '{}'", &opts.onto))?;
+
+    for prepared_commit in prepared_commits.iter() {
+        write_commit_title(prepared_commit, None)?;
+    }
+
+    output(
+        "🛫",
+        &format!(
+            "Rebasing {} commit(s) onto {}...",
+            prepared_commits.len(),
+            &opts.onto
+        ),
+    )?;
+
+    // The rebase only moves the current branch once all commits have been
+    // cherry-picked successfully, so on conflicts the repository is left
+    // untouched.
+    git.rebase_commits(&mut prepared_commits[..], onto_oid)
+        .context(
+            "Your branch has not been changed - please rebase manually"
+                .to_string(),
+        )?;
+
+    output(
+        "✅",
+        "Rebased! Run `spr diff --all` to update the Pull Requests.",
+    )?;
+
+    Ok(())
+}
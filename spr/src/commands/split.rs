@@ -0,0 +1,92 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use crate::{
+    error::{Error, Result},
+    message::{build_commit_message, MessageSection},
+    output::{output, write_commit_title},
+};
+
+#[derive(Debug, clap::Parser)]
+pub struct SplitOptions {
+    /// Patterns (e.g. 'docs/*' or '*.md') for the files to move into separate
+    /// commits. Each pattern gets a commit of its own, in the order given,
+    /// and the files not matched by any pattern remain in the last commit
+    #[clap(required = true, value_name = "GLOB")]
+    patterns: Vec<String>,
+}
+
+pub async fn split(
+    opts: SplitOptions,
+    git: &crate::git::Git,
+    config: &crate::config::Config,
+) -> Result<()> {
+    git.check_no_uncommitted_changes()?;
+    let prepared_commits = git.get_prepared_commits(config, None)?;
+
+    let head = match prepared_commits.last() {
+        Some(head) => head,
+        None => {
+            output("👋", "Branch is empty - nothing to do. Good bye!")?;
+            return Ok(());
+        }
+    };
+    write_commit_title(head, None)?;
+
+    let trees = git.split_commit_trees(head.oid, &opts.patterns)?;
+    if trees.len() < 2 {
+        return Err(Error::new(
+            "All changes of the commit match the first pattern - nothing to \
+             split",
+        ));
+    }
+
+    let title = head
+        .message
+        .get(&MessageSection::Title)
+        .map(|t| &t[..])
+        .unwrap_or("(untitled)");
+
+    let mut parent_oid = head.parent_oid;
+    for (index, tree_oid) in trees.iter().enumerate() {
+        let mut message = head.message.clone();
+        message.insert(
+            MessageSection::Title,
+            format!("{} ({}/{})", title, index + 1, trees.len()),
+        );
+        // Only the last commit, which keeps the remaining changes, stays
+        // associated with the Pull Request of the original commit.
+        if index + 1 < trees.len() {
+            message.remove(&MessageSection::PullRequest);
+            message.remove(&MessageSection::ReviewedBy);
+        }
+
+        parent_oid = git.create_derived_commit(
+            head.oid,
+            &build_commit_message(&message),
+            *tree_oid,
+            &[parent_oid],
+            None,
+            None,
+        )?;
+    }
+
+    // The last of the new commits has the same tree as the original one, so
+    // the working tree stays as it is.
+    git.set_head_target(parent_oid, "spr split")?;
+
+    output(
+        "✂️",
+        &format!(
+            "Split into {} commits. Run `spr diff --all` to create Pull \
+             Requests for them.",
+            trees.len()
+        ),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use crate::{
+    commands::diff::compute_plan,
+    error::Result,
+    output::{output, write_paged},
+    plan::{render_json, render_text, PlanOptions},
+};
+
+#[derive(Debug, clap::Parser)]
+pub struct StatusOptions {
+    /// Print the status as JSON (the same format as `spr diff --dry-run
+    /// --output json`)
+    #[clap(long)]
+    json: bool,
+
+    /// Show the output through a pager ($SPR_PAGER or $PAGER, defaulting to
+    /// less) if it is written to a terminal. Setting $SPR_PAGER enables this
+    /// by default
+    #[clap(long)]
+    pager: bool,
+}
+
+pub async fn status(
+    opts: StatusOptions,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let mut prepared_commits = git.get_prepared_commits(config, Some(gh))?;
+    let plan = compute_plan(
+        git,
+        config,
+        &mut prepared_commits,
+        &PlanOptions::default(),
+    )
+    .await?;
+
+    if opts.json {
+        write_paged(&format!("{}\n", render_json(&plan)?), opts.pager)?;
+    } else if plan.commits.is_empty() {
+        output("👋", "Branch is empty - nothing to do. Good bye!")?;
+    } else {
+        write_paged(&format!("{}\n", render_text(&plan)), opts.pager)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,206 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use crate::{
+    error::{Error, Result},
+    github::{PullRequest, PullRequestState, PullRequestUpdate},
+    message::{
+        diffstat_of, message_section_label, validate_commit_message,
+        MessageSection, MessageSectionsMap,
+    },
+    output::{output, write_commit_title},
+};
+
+#[derive(Debug, clap::Parser)]
+pub struct SyncMessagesOptions {
+    /// Sync the messages of all commits in branch, not just HEAD
+    #[clap(long, short = 'a')]
+    all: bool,
+}
+
+pub async fn sync_messages(
+    opts: SyncMessagesOptions,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let mut prepared_commits = git.get_prepared_commits(config, None)?;
+
+    let len = prepared_commits.len();
+    if len == 0 {
+        output("👋", "Branch is empty - nothing to do. Good bye!")?;
+        return Ok(());
+    }
+
+    let slice = if opts.all {
+        &mut prepared_commits[..]
+    } else {
+        &mut prepared_commits[len - 1..]
+    };
+
+    // Request the Pull Requests of all the commits first, so that GitHub can
+    // work on them in parallel
+    for prepared_commit in slice.iter_mut() {
+        prepared_commit.pull_request_task = prepared_commit
+            .pull_request_number
+            .map(|number| tokio::spawn(gh.clone().get_pull_request(number)));
+    }
+
+    let mut failure = false;
+
+    for prepared_commit in slice.iter_mut() {
+        write_commit_title(prepared_commit, None)?;
+        let pull_request = match prepared_commit.pull_request().await? {
+            Some(pull_request) => pull_request,
+            None => {
+                output("🤷", "No Pull Request yet - run `spr diff` first")?;
+                continue;
+            }
+        };
+        if pull_request.state == PullRequestState::Closed {
+            output("⚠️", "Pull Request is closed - skipping")?;
+            continue;
+        }
+        if validate_commit_message(
+            &prepared_commit.message,
+            config,
+            pull_request.is_draft,
+        )
+        .is_err()
+        {
+            failure = true;
+            continue;
+        }
+
+        let updates = message_updates(&pull_request, &prepared_commit.message);
+        if updates.is_empty() {
+            output("✅", "Message on GitHub is up to date")?;
+        } else {
+            gh.update_pull_request(pull_request.number, updates).await?;
+            output("✍", "Updated commit message on GitHub")?;
+        }
+    }
+
+    if failure {
+        Err(Error::empty())
+    } else {
+        Ok(())
+    }
+}
+
+/// The changes to the title and description of the Pull Request that bring
+/// them in line with the given commit message. Nothing else of the Pull
+/// Request (base, branches, state) is touched. The "Depends On" part of the
+/// description, which `spr diff` adds for stacked Pull Requests, is kept,
+/// and so is the diffstat on top of it.
+fn message_updates(
+    pull_request: &PullRequest,
+    message: &MessageSectionsMap,
+) -> PullRequestUpdate {
+    let mut message = message.clone();
+    if let Some(base_pr) = pull_request.body.as_deref().and_then(base_pr_text) {
+        message.insert(MessageSection::BasePR, base_pr);
+    }
+
+    let mut updates = PullRequestUpdate::default();
+    updates.update_title_and_description(
+        pull_request,
+        Some(&message),
+        None,
+        pull_request.body.as_deref().and_then(diffstat_of),
+    );
+    updates
+}
+
+/// The text of the "Depends On" section in the given Pull Request
+/// description, as `spr diff` put it there
+fn base_pr_text(body: &str) -> Option<String> {
+    let label =
+        format!("{}:\n", message_section_label(&MessageSection::BasePR));
+    let start = body.find(&label)? + label.len();
+    let text = body[start..]
+        .trim_start_matches('\n')
+        .split("\n\n")
+        .next()?;
+    Some(format!("\n{text}"))
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    use crate::{
+        github::test_utils::pull_request_factory,
+        message::{build_github_body, parse_message, with_diffstat},
+    };
+
+    #[test]
+    fn test_sync_messages_only_touches_title_and_body() {
+        let mut on_github = parse_message(
+            "Title\n\nOld summary\n\nTest Plan: none",
+            MessageSection::Title,
+        );
+        on_github.insert(
+            MessageSection::BasePR,
+            "\n- #6 (powered by [spr-enhanced](https://go/spr-enhanced))"
+                .into(),
+        );
+        let pull_request = PullRequest {
+            title: on_github[&MessageSection::Title].clone(),
+            body: Some(build_github_body(&on_github)),
+            sections: on_github.clone(),
+            ..pull_request_factory(PullRequestState::Open)
+        };
+
+        // Nothing to do if the local message matches
+        let local = parse_message(
+            "Title\n\nOld summary\n\nTest Plan: none",
+            MessageSection::Title,
+        );
+        assert!(message_updates(&pull_request, &local).is_empty());
+
+        // Only the description changes. The base branch stays, and so does
+        // the link to the base Pull Request.
+        let local = parse_message(
+            "Title\n\nNew summary\n\nTest Plan: none",
+            MessageSection::Title,
+        );
+        let updates = message_updates(&pull_request, &local);
+        assert_eq!(updates.title, None);
+        assert_eq!(updates.base, None);
+        assert_eq!(updates.state, None);
+        assert_eq!(
+            updates.body.as_deref(),
+            Some(
+                "New summary\n\n\
+                 <hr>Depends On:\n\n\
+                 - #6 (powered by [spr-enhanced](https://go/spr-enhanced))\n\n\
+                 Test Plan: none\n"
+            )
+        );
+
+        // The diffstat `spr diff` put on top of the description stays
+        let diffstat = " src/lib.rs | 2 +-\n 1 file changed\n";
+        let pull_request = PullRequest {
+            body: Some(with_diffstat(&build_github_body(&on_github), diffstat)),
+            ..pull_request
+        };
+        let local = parse_message(
+            "Title\n\nOld summary\n\nTest Plan: none",
+            MessageSection::Title,
+        );
+        assert!(message_updates(&pull_request, &local).is_empty());
+        let local = parse_message(
+            "Title\n\nNew summary\n\nTest Plan: none",
+            MessageSection::Title,
+        );
+        let body = message_updates(&pull_request, &local).body.unwrap();
+        assert_eq!(diffstat_of(&body), Some(diffstat));
+        assert!(body.contains("New summary"));
+    }
+}
@@ -0,0 +1,229 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::collections::HashSet;
+
+use crate::{
+    error::{Error, Result},
+    git::PreparedCommit,
+    github::{is_not_found_error, PullRequest, PullRequestState},
+    message::MessageSection,
+    output::{output, write_commit_title},
+};
+
+#[derive(Debug, clap::Parser)]
+pub struct VerifyOptions {
+    /// Remove the broken Pull Request links from the local commit messages,
+    /// so that the next `spr diff` creates new Pull Requests for them
+    #[clap(long)]
+    fix: bool,
+}
+
+/// Something wrong with the Pull Request a commit links to
+#[derive(Debug, PartialEq, Eq)]
+enum LinkProblem {
+    /// There is no Pull Request with this number
+    Missing,
+    /// The Pull Request was closed without being merged
+    Closed,
+    /// The Pull Request was merged, so the commit should be landed
+    Merged,
+    /// The head branch of the Pull Request is not one created by spr
+    ForeignBranch(String),
+    /// An earlier commit links to the same Pull Request
+    Duplicate,
+}
+
+impl LinkProblem {
+    /// Whether the link is broken, and should be removed. A merged Pull
+    /// Request is not broken: the commit is just waiting to be landed. Nor
+    /// is one on a branch spr did not create, which the user may well have
+    /// linked on purpose.
+    fn is_broken(&self) -> bool {
+        !matches!(self, LinkProblem::Merged | LinkProblem::ForeignBranch(_))
+    }
+
+    fn describe(&self, number: u64) -> String {
+        match self {
+            LinkProblem::Missing => {
+                format!("Pull Request #{number} does not exist")
+            }
+            LinkProblem::Closed => {
+                format!("Pull Request #{number} was closed without merging")
+            }
+            LinkProblem::Merged => format!(
+                "Pull Request #{number} is merged already - use `spr land` \
+                 or rebase onto master"
+            ),
+            LinkProblem::ForeignBranch(branch) => format!(
+                "Pull Request #{number} has the head branch '{branch}', which \
+                 was not created by spr"
+            ),
+            LinkProblem::Duplicate => format!(
+                "Pull Request #{number} is linked by an earlier commit as well"
+            ),
+        }
+    }
+}
+
+pub async fn verify(
+    opts: VerifyOptions,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let mut prepared_commits = git.get_prepared_commits(config, Some(gh))?;
+
+    if prepared_commits.is_empty() {
+        output("👋", "Branch is empty - nothing to do. Good bye!")?;
+        return Ok(());
+    };
+
+    let mut seen = HashSet::new();
+    let mut broken = 0;
+
+    for prepared_commit in prepared_commits.iter_mut() {
+        let number = match prepared_commit.pull_request_number {
+            Some(number) => number,
+            None => continue,
+        };
+        write_commit_title(prepared_commit, None)?;
+
+        let problem = if !seen.insert(number) {
+            Some(LinkProblem::Duplicate)
+        } else {
+            let pull_request = match prepared_commit.pull_request().await {
+                Ok(pull_request) => pull_request,
+                Err(error) if is_not_found_error(&error) => None,
+                Err(error) => return Err(error),
+            };
+            check_link(config, pull_request.as_ref())
+        };
+
+        match problem {
+            None => output("✅", &format!("#{number} is fine"))?,
+            Some(problem) if !problem.is_broken() => {
+                output("⚠️", &problem.describe(number))?
+            }
+            Some(problem) => {
+                output("❗", &problem.describe(number))?;
+                broken += 1;
+                if opts.fix {
+                    remove_link(prepared_commit);
+                    output("🧹", "Removed the link from the commit message")?;
+                }
+            }
+        }
+    }
+
+    if opts.fix {
+        git.rewrite_commit_messages(prepared_commits.as_mut_slice(), None)?;
+    } else if broken > 0 {
+        return Err(Error::new(format!(
+            "Found {broken} broken Pull Request link(s). Run `spr verify \
+             --fix` to remove them from the commit messages."
+        )));
+    }
+
+    Ok(())
+}
+
+/// Check the Pull Request a commit links to (`None` if it doesn't exist)
+fn check_link(
+    config: &crate::config::Config,
+    pull_request: Option<&PullRequest>,
+) -> Option<LinkProblem> {
+    let pull_request = match pull_request {
+        Some(pull_request) => pull_request,
+        None => return Some(LinkProblem::Missing),
+    };
+
+    if pull_request.state == PullRequestState::Closed {
+        Some(if pull_request.merge_commit.is_some() {
+            LinkProblem::Merged
+        } else {
+            LinkProblem::Closed
+        })
+    } else if !pull_request
+        .head
+        .branch_name()
+        .starts_with(&config.branch_prefix)
+    {
+        Some(LinkProblem::ForeignBranch(
+            pull_request.head.branch_name().to_string(),
+        ))
+    } else {
+        None
+    }
+}
+
+fn remove_link(prepared_commit: &mut PreparedCommit) {
+    prepared_commit.message.remove(&MessageSection::PullRequest);
+    prepared_commit.pull_request_number = None;
+    prepared_commit.pull_request_task = None;
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    use crate::{
+        config::test_utils::config_factory,
+        github::test_utils::pull_request_factory,
+        message::{build_commit_message, parse_message},
+    };
+
+    #[test]
+    fn test_dangling_pull_request_link() {
+        let config = config_factory();
+
+        // The commit links to a Pull Request that doesn't exist (any more)
+        assert_eq!(check_link(&config, None), Some(LinkProblem::Missing));
+        assert!(LinkProblem::Missing.is_broken());
+
+        let mut prepared_commit = PreparedCommit {
+            oid: git2::Oid::zero(),
+            short_id: "aaaaaaa".into(),
+            parent_oid: git2::Oid::zero(),
+            message: parse_message(
+                "Title\n\nSummary\n\n\
+                 Pull Request: https://github.com/acme/codez/pull/404\n",
+                MessageSection::Title,
+            ),
+            pull_request_number: Some(404),
+            pull_request_task: None,
+        };
+        remove_link(&mut prepared_commit);
+        assert_eq!(prepared_commit.pull_request_number, None);
+        assert_eq!(
+            build_commit_message(&prepared_commit.message),
+            "Title\n\nSummary\n"
+        );
+
+        use PullRequestState::{Closed, Open};
+        let pr = pull_request_factory(Open);
+        assert_eq!(check_link(&config, Some(&pr)), None);
+        let pr = pull_request_factory(Closed);
+        assert_eq!(check_link(&config, Some(&pr)), Some(LinkProblem::Closed));
+        let pr = PullRequest {
+            merge_commit: Some(git2::Oid::zero()),
+            ..pull_request_factory(Closed)
+        };
+        assert_eq!(check_link(&config, Some(&pr)), Some(LinkProblem::Merged));
+        assert!(!LinkProblem::Merged.is_broken());
+        let pr = PullRequest {
+            head: config.new_github_branch("feature/other"),
+            ..pull_request_factory(Open)
+        };
+        assert_eq!(
+            check_link(&config, Some(&pr)),
+            Some(LinkProblem::ForeignBranch("feature/other".into()))
+        );
+        assert!(!LinkProblem::ForeignBranch("feature/other".into()).is_broken());
+    }
+}
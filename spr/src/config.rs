@@ -7,7 +7,204 @@
 
 use std::collections::HashSet;
 
-use crate::{error::Result, github::GitHubBranch, utils::slugify};
+use crate::{
+    error::{Error, Result, ResultExt},
+    github::GitHubBranch,
+    utils::slugify,
+};
+
+/// Name of the optional config file in the root of the working tree. Values
+/// in this file can be shared with the team by checking the file in. They are
+/// overridden by Git config values, which in turn are overridden by command
+/// line options.
+pub const CONFIG_FILE_NAME: &str = ".spr.toml";
+
+/// The contents of the config file. The keys are the same as the ones used
+/// in the `spr` section of Git config.
+#[derive(Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ConfigFile {
+    pub github_repository: Option<String>,
+    pub github_remote_name: Option<String>,
+    pub github_master_branch: Option<String>,
+    pub branch_prefix: Option<String>,
+    pub require_approval: Option<bool>,
+    pub require_test_plan: Option<bool>,
+    pub derived_commit_author: Option<String>,
+    pub derived_commit_committer: Option<String>,
+    pub max_stack_depth: Option<usize>,
+    pub max_concurrent_api: Option<usize>,
+    pub run_commit_hooks: Option<bool>,
+    pub warn_shared_commits: Option<bool>,
+    pub merge_label: Option<String>,
+    pub merge_comment: Option<String>,
+    pub base_branch_strategy: Option<String>,
+    pub base_branch_skip_ci: Option<bool>,
+    pub auto_no_cherry_pick: Option<bool>,
+    pub diffstat: Option<bool>,
+    pub first_parent: Option<bool>,
+    pub require_reviewers: Option<bool>,
+    pub reviewer_aliases: Option<std::collections::BTreeMap<String, String>>,
+    pub size_reviewers: Option<std::collections::BTreeMap<String, String>>,
+    pub signoff: Option<bool>,
+    pub require_signoff: Option<bool>,
+    pub confirm: Option<bool>,
+    pub reattach: Option<bool>,
+    pub message_keywords: Option<bool>,
+    pub ready_keyword: Option<String>,
+    pub draft_keyword: Option<String>,
+    pub extra_headers: Option<Vec<String>>,
+    pub rebase_message: Option<String>,
+    pub commit_date: Option<CommitDateValue>,
+}
+
+impl ConfigFile {
+    /// Load the config file from the working tree of the given repository.
+    /// Returns the empty default if there is no config file.
+    pub fn load(repo: &git2::Repository) -> Result<Self> {
+        let path = match repo.workdir() {
+            Some(workdir) => workdir.join(CONFIG_FILE_NAME),
+            None => return Ok(Default::default()),
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text)
+                .context(format!("Could not read {}", path.display())),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Default::default())
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        toml::from_str(text).convert()
+    }
+}
+
+/// A Git remote that points to a repository on GitHub
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GitHubRemote {
+    pub name: String,
+    /// The GitHub repository in 'OWNER/REPO' format
+    pub repository: String,
+}
+
+/// Extract the GitHub repository (in 'OWNER/REPO' format) from the URL of a
+/// Git remote, if it points to GitHub.
+pub fn parse_github_remote_url(url: &str) -> Option<String> {
+    lazy_regex::regex!(r#"github\.com[/:]([\w\-\.]+/[\w\-\.]+?)(.git)?/?$"#)
+        .captures(url)
+        .and_then(|caps| caps.get(1))
+        .map(|m| m.as_str().to_string())
+}
+
+/// List the remotes of the given repository that point to GitHub
+pub fn get_github_remotes(
+    repo: &git2::Repository,
+) -> Result<Vec<GitHubRemote>> {
+    let mut result = Vec::new();
+
+    for name in repo.remotes()?.iter().flatten() {
+        let remote = repo.find_remote(name)?;
+        if let Some(repository) = remote.url().and_then(parse_github_remote_url)
+        {
+            result.push(GitHubRemote {
+                name: name.to_string(),
+                repository,
+            });
+        }
+    }
+
+    Ok(result)
+}
+
+/// Choose the remote to use among the ones pointing to GitHub. If the GitHub
+/// repository is known, only remotes pointing to it are considered. If there
+/// are several candidates, 'origin' is preferred, and the second return value
+/// is true to indicate that the choice was ambiguous.
+pub fn choose_github_remote<'a>(
+    remotes: &'a [GitHubRemote],
+    github_repository: Option<&str>,
+) -> (Option<&'a GitHubRemote>, bool) {
+    let candidates: Vec<_> = remotes
+        .iter()
+        .filter(|remote| {
+            github_repository.is_none_or(|repository| {
+                remote.repository.eq_ignore_ascii_case(repository)
+            })
+        })
+        .collect();
+
+    let choice = candidates
+        .iter()
+        .find(|remote| remote.name == "origin")
+        .or_else(|| candidates.first())
+        .copied();
+
+    (choice, candidates.len() > 1)
+}
+
+/// Default for the `maxStackDepth` config value. Stacks deeper than that are
+/// most likely the result of a branch based on the wrong commit.
+pub const DEFAULT_MAX_STACK_DEPTH: usize = 100;
+
+/// Default for the `maxConcurrentApi` config value, which keeps large stacks
+/// clear of GitHub's secondary rate limits
+pub const DEFAULT_MAX_CONCURRENT_API: usize = 4;
+
+/// Default for the `mergeLabel` config value
+pub const DEFAULT_MERGE_LABEL: &str = "mergeme";
+
+/// How spr updates the intermediate base branch of a Pull Request that is not
+/// based directly on master
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BaseBranchStrategy {
+    /// Add a commit on top of the previous base, merging in master if needed.
+    /// Reviewers can follow every change of the base.
+    #[default]
+    Merge,
+    /// Replace the base branch with a single fresh commit on master, keeping
+    /// its history linear. This force-pushes the base branch.
+    Rebase,
+}
+
+impl BaseBranchStrategy {
+    pub fn parse(text: &str) -> Result<Self> {
+        <Self as clap::ValueEnum>::from_str(text, true).map_err(|_| {
+            Error::new(format!(
+                "Base branch strategy must be 'merge' or 'rebase', but given \
+                 value was '{text}'"
+            ))
+        })
+    }
+}
+
+/// A name and email address to be used in the signature of commits created
+/// by spr
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitIdentity {
+    pub name: String,
+    pub email: String,
+}
+
+impl CommitIdentity {
+    /// Parse an identity given in the 'Name <email>' format
+    pub fn parse(text: &str) -> Result<Self> {
+        let captures = lazy_regex::regex!(r#"^\s*([^<>]*?)\s*<([^<>]+)>\s*$"#)
+            .captures(text)
+            .ok_or_else(|| {
+                Error::new(format!(
+                    "Identity must be given as 'Name <email>', but given \
+                     value was '{text}'"
+                ))
+            })?;
+        Ok(Self {
+            name: captures.get(1).unwrap().as_str().to_string(),
+            email: captures.get(2).unwrap().as_str().to_string(),
+        })
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -18,6 +215,134 @@ pub struct Config {
     pub branch_prefix: String,
     pub require_approval: bool,
     pub require_test_plan: bool,
+    /// Author of the commits spr creates for GitHub branches. If not set, the
+    /// author of the local commit is used.
+    pub derived_commit_author: Option<CommitIdentity>,
+    /// Committer of the commits spr creates for GitHub branches. If not set,
+    /// the current Git user is used.
+    pub derived_commit_committer: Option<CommitIdentity>,
+    /// The maximum number of commits between master and HEAD that `spr diff`
+    /// and `spr merge` will operate on. Zero means no limit.
+    pub max_stack_depth: usize,
+    /// The maximum number of lookups on GitHub (Pull Requests, users, teams)
+    /// in flight at the same time. Zero means no limit.
+    pub max_concurrent_api: usize,
+    /// Whether to print more details of what spr does (`--verbose`)
+    pub verbose: bool,
+    /// Whether to run the `prepare-commit-msg` and `commit-msg` hooks on the
+    /// messages of the commits spr creates for GitHub branches
+    pub run_commit_hooks: bool,
+    /// Whether to warn before force-updating a Pull Request branch that other
+    /// branches on GitHub are based on
+    pub warn_shared_commits: bool,
+    /// The label `spr merge --label` adds to Pull Requests, for bots that
+    /// merge labelled Pull Requests
+    pub merge_label: String,
+    /// The comment `spr merge` posts on the Pull Requests it merged, unless
+    /// `--comment` is given
+    pub merge_comment: Option<String>,
+    /// How intermediate base branches are updated
+    pub base_branch_strategy: BaseBranchStrategy,
+    /// Whether the commits spr creates for intermediate base branches carry
+    /// `[skip ci]`, so that CI doesn't run on them
+    pub base_branch_skip_ci: bool,
+    /// Whether `spr diff` submits a commit as with `--no-cherry-pick` when
+    /// it can't be cherry-picked onto master, instead of failing
+    pub auto_no_cherry_pick: bool,
+    /// Whether `spr diff` puts the diffstat of the change at the top of the
+    /// Pull Request description
+    pub diffstat: bool,
+    /// Whether merge commits between master and HEAD are followed along
+    /// their first parent, instead of being refused
+    pub first_parent: bool,
+    /// Whether `spr diff` refuses to create a Pull Request without any
+    /// reviewers to request
+    pub require_reviewers: bool,
+    /// Aliases that can be used in the Reviewers section as `@name`, mapped
+    /// to comma-separated lists of users and `#teams`. Names are lowercase.
+    pub reviewer_aliases: std::collections::BTreeMap<String, String>,
+    /// Reviewers to request for new Pull Requests by the size of the change:
+    /// the entry with the largest number of changed lines not above that of
+    /// the commit applies. Values are lists like in the Reviewers section.
+    pub size_reviewers: std::collections::BTreeMap<usize, String>,
+    /// Whether to add a `Signed-off-by:` trailer for the committer to the
+    /// messages of the commits spr creates for GitHub branches
+    pub signoff: bool,
+    /// Whether local commits must have a `Signed-off-by:` trailer
+    pub require_signoff: bool,
+    /// Whether `spr diff` asks for confirmation before changing anything
+    pub confirm: bool,
+    /// Whether `spr diff` offers to link a commit without a Pull Request
+    /// section to the open Pull Request on the branch spr would create for it
+    pub reattach: bool,
+    /// Whether an update message of just `ready_keyword` or `draft_keyword`
+    /// marks the Pull Request ready for review, or converts it to a draft
+    pub message_keywords: bool,
+    pub ready_keyword: String,
+    pub draft_keyword: String,
+    /// Headers (name and value) to send with every request to GitHub, e.g.
+    /// for an authenticating gateway
+    pub extra_headers: Vec<(String, String)>,
+    /// Message to use, instead of prompting for one, when a Pull Request is
+    /// updated only to rebase it, without any change to its diff
+    pub rebase_message: Option<String>,
+}
+
+/// A `commitDate` in the config file, where it can be given as a number
+#[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(untagged)]
+pub enum CommitDateValue {
+    Seconds(i64),
+    Text(String),
+}
+
+impl CommitDateValue {
+    pub fn into_text(self) -> String {
+        match self {
+            CommitDateValue::Seconds(seconds) => seconds.to_string(),
+            CommitDateValue::Text(text) => text,
+        }
+    }
+}
+
+/// Parse the `commitDate` config value: seconds since the epoch, or
+/// `SOURCE_DATE_EPOCH` to take them from that environment variable (whose
+/// value is given as `source_date_epoch`), as set for reproducible builds. If
+/// the variable is not set, commits get the current time.
+pub fn parse_commit_date(
+    text: &str,
+    source_date_epoch: Option<&str>,
+) -> Result<Option<i64>> {
+    if !text.trim().eq_ignore_ascii_case("SOURCE_DATE_EPOCH") {
+        return text.trim().parse().map(Some).map_err(|_| {
+            Error::new(format!(
+                "Commit date must be seconds since the epoch or \
+                 'SOURCE_DATE_EPOCH', but given value was '{text}'"
+            ))
+        });
+    }
+
+    match source_date_epoch {
+        Some(epoch) => epoch.trim().parse().map(Some).map_err(|_| {
+            Error::new(format!(
+                "Invalid SOURCE_DATE_EPOCH '{epoch}': expected seconds since \
+                 the epoch"
+            ))
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Parse an extra HTTP header given as `Name: value`
+pub fn parse_extra_header(text: &str) -> Result<(String, String)> {
+    match text.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(Error::new(format!(
+            "Invalid extra header '{text}': expected 'Name: value'"
+        ))),
+    }
 }
 
 impl Config {
@@ -43,6 +368,80 @@ impl Config {
             branch_prefix,
             require_approval,
             require_test_plan,
+            derived_commit_author: None,
+            derived_commit_committer: None,
+            max_stack_depth: DEFAULT_MAX_STACK_DEPTH,
+            max_concurrent_api: DEFAULT_MAX_CONCURRENT_API,
+            verbose: false,
+            run_commit_hooks: false,
+            warn_shared_commits: false,
+            merge_label: DEFAULT_MERGE_LABEL.to_string(),
+            merge_comment: None,
+            base_branch_strategy: BaseBranchStrategy::default(),
+            base_branch_skip_ci: true,
+            auto_no_cherry_pick: false,
+            diffstat: false,
+            first_parent: false,
+            require_reviewers: false,
+            reviewer_aliases: Default::default(),
+            size_reviewers: Default::default(),
+            signoff: false,
+            require_signoff: false,
+            confirm: false,
+            reattach: false,
+            message_keywords: false,
+            ready_keyword: "ready".to_string(),
+            draft_keyword: "draft".to_string(),
+            extra_headers: Vec::new(),
+            rebase_message: None,
+        }
+    }
+
+    /// Replace the aliases (`@name`) in the given list of reviewers by the
+    /// users and teams they stand for. Duplicates are removed, keeping the
+    /// first occurrence.
+    pub fn expand_reviewer_aliases(
+        &self,
+        reviewers: Vec<String>,
+    ) -> Result<Vec<String>> {
+        let mut expanded = Vec::<String>::new();
+        for reviewer in reviewers {
+            let names = if let Some(alias) = reviewer.strip_prefix('@') {
+                let names = self
+                    .reviewer_aliases
+                    .get(&alias.to_lowercase())
+                    .ok_or_else(|| {
+                        Error::new(format!(
+                            "Reviewers field contains unknown alias '{}' \
+                             (aliases are configured with \
+                             spr.reviewerAlias.<name>)",
+                            reviewer
+                        ))
+                    })?;
+                crate::utils::parse_name_list(names)
+            } else {
+                vec![reviewer]
+            };
+            for name in names {
+                if !expanded.iter().any(|n| n.eq_ignore_ascii_case(&name)) {
+                    expanded.push(name);
+                }
+            }
+        }
+
+        Ok(expanded)
+    }
+
+    /// The reviewers `size_reviewers` lists for a change of the given number
+    /// of lines, with aliases expanded
+    pub fn reviewers_for_size(
+        &self,
+        changed_lines: usize,
+    ) -> Result<Vec<String>> {
+        match self.size_reviewers.range(..=changed_lines).next_back() {
+            Some((_, names)) => self
+                .expand_reviewer_aliases(crate::utils::parse_name_list(names)),
+            None => Ok(Vec::new()),
         }
     }
 
@@ -65,13 +464,21 @@ impl Config {
             return Some(caps.get(1).unwrap().as_str().parse().unwrap());
         }
 
+        // URLs as pasted from the browser may carry a path (`/files`), a
+        // query string or an anchor (`#issuecomment-...`), or be wrapped in
+        // angle brackets. GitHub owner and repository names are not case
+        // sensitive.
         let regex = lazy_regex::regex!(
-            r#"^\s*https?://github.com/([\w\-]+)/([\w\-]+)/pull/(\d+)([/?#].*)?\s*$"#
+            r#"^\s*<?https?://(?:www\.)?github\.com/([\w\-]+)/([\w.\-]+?)(?:\.git)?/pull/(\d+)(?:[/?#][^\s>]*)?>?\s*$"#
         );
         let m = regex.captures(text);
         if let Some(caps) = m {
-            if self.owner == caps.get(1).unwrap().as_str()
-                && self.repo == caps.get(2).unwrap().as_str()
+            if self
+                .owner
+                .eq_ignore_ascii_case(caps.get(1).unwrap().as_str())
+                && self
+                    .repo
+                    .eq_ignore_ascii_case(caps.get(2).unwrap().as_str())
             {
                 return Some(caps.get(3).unwrap().as_str().parse().unwrap());
             }
@@ -99,6 +506,15 @@ impl Config {
         )
     }
 
+    /// Whether the given branch name is one that `get_base_branch_name` may
+    /// have generated for an intermediate base branch
+    pub fn is_base_branch_name(&self, branch_name: &str) -> bool {
+        branch_name
+            .strip_prefix(&self.branch_prefix)
+            .and_then(|name| name.strip_prefix(self.master_ref.branch_name()))
+            .is_some_and(|name| name.starts_with('.'))
+    }
+
     fn find_unused_branch_name(
         &self,
         existing_ref_names: &HashSet<String>,
@@ -142,13 +558,15 @@ impl Config {
     }
 }
 
+/// Configuration for tests in all modules
 #[cfg(test)]
-mod tests {
-    // Note this useful idiom: importing names from outer (for mod tests) scope.
+pub(crate) mod test_utils {
     use super::*;
 
-    fn config_factory() -> Config {
-        crate::config::Config::new(
+    /// The configuration of the `acme/codez` repository, with `spr/foo/` as
+    /// the branch prefix
+    pub(crate) fn config_factory() -> Config {
+        Config::new(
             "acme".into(),
             "codez".into(),
             "origin".into(),
@@ -158,6 +576,197 @@ mod tests {
             true,
         )
     }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    use super::test_utils::config_factory;
+
+    #[test]
+    fn test_parse_config_file() {
+        assert_eq!(ConfigFile::parse("").unwrap(), ConfigFile::default());
+        assert_eq!(
+            ConfigFile::parse(
+                r#"
+                githubRepository = "acme/codez"
+                githubMasterBranch = "main"
+                branchPrefix = "spr/"
+                requireTestPlan = false
+                "#
+            )
+            .unwrap(),
+            ConfigFile {
+                github_repository: Some("acme/codez".into()),
+                github_master_branch: Some("main".into()),
+                branch_prefix: Some("spr/".into()),
+                require_test_plan: Some(false),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_config_file_rejects_unknown_keys() {
+        assert!(ConfigFile::parse(r#"githubAuthToken = "secret""#).is_err());
+        assert!(ConfigFile::parse(r#"requireTestPlan = "yes""#).is_err());
+    }
+
+    fn remote(name: &str, repository: &str) -> GitHubRemote {
+        GitHubRemote {
+            name: name.into(),
+            repository: repository.into(),
+        }
+    }
+
+    #[test]
+    fn test_parse_commit_identity() {
+        assert_eq!(
+            CommitIdentity::parse("spr bot <bot@example.com>").unwrap(),
+            CommitIdentity {
+                name: "spr bot".into(),
+                email: "bot@example.com".into(),
+            }
+        );
+        assert!(CommitIdentity::parse("bot@example.com").is_err());
+    }
+
+    #[test]
+    fn test_reviewers_for_size() {
+        let mut config = config_factory();
+        config
+            .reviewer_aliases
+            .insert("leads".into(), "carol, dave".into());
+        config.size_reviewers.insert(1, "alice".into());
+        config.size_reviewers.insert(100, "alice, @leads".into());
+        config.size_reviewers.insert(500, "#core".into());
+
+        assert!(config.reviewers_for_size(0).unwrap().is_empty());
+        assert_eq!(config.reviewers_for_size(12).unwrap(), vec!["alice"]);
+        assert_eq!(
+            config.reviewers_for_size(100).unwrap(),
+            vec!["alice", "carol", "dave"]
+        );
+        // A large diff goes to the whole team
+        assert_eq!(config.reviewers_for_size(2345).unwrap(), vec!["#core"]);
+    }
+
+    #[test]
+    fn test_expand_reviewer_aliases() {
+        let mut config = config_factory();
+        config
+            .reviewer_aliases
+            .insert("frontend".into(), "alice, #web-team, bob".into());
+
+        assert_eq!(
+            config
+                .expand_reviewer_aliases(vec![
+                    "carol".into(),
+                    "@Frontend".into(),
+                    "Alice".into(),
+                    "#core".into(),
+                ])
+                .unwrap(),
+            vec!["carol", "alice", "#web-team", "bob", "#core"]
+        );
+
+        let error = config
+            .expand_reviewer_aliases(vec!["@backend".into()])
+            .unwrap_err();
+        assert!(error.messages()[0].contains("unknown alias '@backend'"));
+    }
+
+    #[test]
+    fn test_parse_commit_date() {
+        assert_eq!(
+            parse_commit_date("1000", Some("2000")).unwrap(),
+            Some(1000)
+        );
+        assert_eq!(
+            parse_commit_date("SOURCE_DATE_EPOCH", Some("2000")).unwrap(),
+            Some(2000)
+        );
+        assert_eq!(parse_commit_date("SOURCE_DATE_EPOCH", None).unwrap(), None);
+        assert!(parse_commit_date("yesterday", None).is_err());
+        assert!(parse_commit_date("SOURCE_DATE_EPOCH", Some("x")).is_err());
+
+        assert_eq!(
+            ConfigFile::parse("commitDate = 1000").unwrap().commit_date,
+            Some(CommitDateValue::Seconds(1000))
+        );
+        assert_eq!(
+            ConfigFile::parse(r#"commitDate = "SOURCE_DATE_EPOCH""#)
+                .unwrap()
+                .commit_date
+                .map(CommitDateValue::into_text),
+            Some("SOURCE_DATE_EPOCH".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_base_branch_strategy() {
+        assert_eq!(
+            BaseBranchStrategy::parse("rebase").unwrap(),
+            BaseBranchStrategy::Rebase
+        );
+        assert_eq!(
+            BaseBranchStrategy::parse("Merge").unwrap(),
+            BaseBranchStrategy::Merge
+        );
+        assert!(BaseBranchStrategy::parse("squash").is_err());
+    }
+
+    #[test]
+    fn test_parse_github_remote_url() {
+        assert_eq!(
+            parse_github_remote_url("git@github.com:acme/codez.git"),
+            Some("acme/codez".into())
+        );
+        assert_eq!(
+            parse_github_remote_url("https://github.com/acme/codez"),
+            Some("acme/codez".into())
+        );
+        assert_eq!(
+            parse_github_remote_url("https://github.com/acme/co.dez.git"),
+            Some("acme/co.dez".into())
+        );
+        assert_eq!(
+            parse_github_remote_url("https://gitlab.com/acme/codez.git"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_choose_github_remote() {
+        let remotes =
+            vec![remote("fork", "me/codez"), remote("upstream", "acme/codez")];
+
+        assert_eq!(
+            choose_github_remote(&remotes, Some("acme/codez")),
+            (Some(&remotes[1]), false)
+        );
+        assert_eq!(
+            choose_github_remote(&remotes, None),
+            (Some(&remotes[0]), true)
+        );
+        assert_eq!(choose_github_remote(&remotes, Some("x/y")), (None, false));
+        assert_eq!(choose_github_remote(&[], None), (None, false));
+    }
+
+    #[test]
+    fn test_choose_github_remote_prefers_origin() {
+        let remotes = vec![
+            remote("upstream", "acme/codez"),
+            remote("origin", "me/codez"),
+        ];
+
+        assert_eq!(
+            choose_github_remote(&remotes, None),
+            (Some(&remotes[1]), true)
+        );
+    }
 
     #[test]
     fn test_pull_request_url() {
@@ -229,4 +838,43 @@ mod tests {
             Some(123)
         );
     }
+
+    #[test]
+    fn test_parse_pull_request_field_messy_url() {
+        let gh = config_factory();
+
+        for url in [
+            "https://github.com/acme/codez/pull/123#issuecomment-1234567",
+            "https://github.com/acme/codez/pull/123/files?w=1#diff-abc",
+            "https://github.com/acme/codez/pull/123/?notification_referrer_id=x",
+            "https://github.com/Acme/CODEZ/pull/123",
+            "https://www.github.com/acme/codez/pull/123",
+            "<https://github.com/acme/codez/pull/123>",
+            "http://github.com/acme/codez/pull/123//",
+        ] {
+            assert_eq!(gh.parse_pull_request_field(url), Some(123), "{url}");
+        }
+
+        let mut dotted = config_factory();
+        dotted.repo = "co.dez".into();
+        assert_eq!(
+            dotted.parse_pull_request_field(
+                "https://github.com/acme/co.dez/pull/7#discussion_r1"
+            ),
+            Some(7)
+        );
+
+        // Pull Requests of other repositories, or not on GitHub at all
+        for url in [
+            "https://github.com/acme/other/pull/123",
+            "https://github.com/someone/codez/pull/123",
+            "https://github.com/acme/codez-fork/pull/123",
+            "https://github.com.evil.example/acme/codez/pull/123",
+            "https://gitlab.com/acme/codez/pull/123",
+            "https://github.com/acme/codez/issues/123",
+            "https://github.com/acme/codez/pull/123 and more",
+        ] {
+            assert_eq!(gh.parse_pull_request_field(url), None, "{url}");
+        }
+    }
 }
@@ -5,9 +5,43 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+/// The kind of an error determines the exit code of spr, so that scripts can
+/// tell different failures apart.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorKind {
+    #[default]
+    Other,
+    /// Commits could not be cherry-picked or rebased due to conflicts
+    Conflict,
+    /// The GitHub API could not be reached or returned an error (this
+    /// includes authentication failures)
+    GitHub,
+    /// The working tree has uncommitted changes
+    DirtyWorkingTree,
+    /// The user aborted the operation
+    Aborted,
+    /// Not a failure: there was nothing to update (only reported if asked
+    /// for, e.g. with `spr diff --no-op-exit-code`)
+    UpToDate,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::Conflict => 2,
+            ErrorKind::GitHub => 3,
+            ErrorKind::DirtyWorkingTree => 4,
+            ErrorKind::Aborted => 5,
+            ErrorKind::UpToDate => 6,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Error {
     messages: Vec<String>,
+    kind: ErrorKind,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -19,15 +53,26 @@ impl Error {
     {
         Self {
             messages: vec![message.into()],
+            kind: Default::default(),
         }
     }
 
     pub fn empty() -> Self {
         Self {
             messages: Default::default(),
+            kind: Default::default(),
         }
     }
 
+    pub fn with_kind(mut self, kind: ErrorKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
     pub fn is_empty(&self) -> bool {
         self.messages.is_empty()
     }
@@ -48,6 +93,7 @@ where
     fn from(error: E) -> Self {
         Self {
             messages: vec![format!("{}", error)],
+            kind: Default::default(),
         }
     }
 }
@@ -69,6 +115,7 @@ pub trait ResultExt {
     fn convert(self) -> Self::Output;
     fn context(self, message: String) -> Self::Output;
     fn reword(self, message: String) -> Self::Output;
+    fn with_kind(self, kind: ErrorKind) -> Self::Output;
 }
 impl<T> ResultExt for Result<T> {
     type Output = Self;
@@ -93,6 +140,10 @@ impl<T> ResultExt for Result<T> {
 
         self
     }
+
+    fn with_kind(self, kind: ErrorKind) -> Self {
+        self.map_err(|error| error.with_kind(kind))
+    }
 }
 
 impl<T, E> ResultExt for std::result::Result<T, E>
@@ -115,6 +166,10 @@ where
     fn reword(self, message: String) -> Result<T> {
         self.convert().reword(message)
     }
+
+    fn with_kind(self, kind: ErrorKind) -> Result<T> {
+        self.convert().with_kind(kind)
+    }
 }
 
 pub struct Terminator {
@@ -153,7 +208,7 @@ pub fn add_error<T, U>(result: &mut Result<T>, other: Result<U>) -> Option<U> {
         Ok(result) => Some(result),
         Err(error) => {
             if let Err(e) = result {
-                e.messages.extend(error.messages.into_iter());
+                e.messages.extend(error.messages);
             } else {
                 *result = Err(error);
             }
@@ -161,3 +216,41 @@ pub fn add_error<T, U>(result: &mut Result<T>, other: Result<U>) -> Option<U> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_error_kind() {
+        assert_eq!(Error::new("foo").kind(), ErrorKind::Other);
+        assert_eq!(
+            Error::new("foo").with_kind(ErrorKind::Conflict).kind(),
+            ErrorKind::Conflict
+        );
+
+        let result: Result<()> = Err(Error::new("foo"));
+        let result = result
+            .with_kind(ErrorKind::GitHub)
+            .context("bar".to_string());
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::GitHub);
+    }
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let codes = [
+            ErrorKind::Other,
+            ErrorKind::Conflict,
+            ErrorKind::GitHub,
+            ErrorKind::DirtyWorkingTree,
+            ErrorKind::Aborted,
+        ]
+        .map(ErrorKind::exit_code);
+
+        assert!(codes.iter().all(|code| *code > 0));
+        for (i, code) in codes.iter().enumerate() {
+            assert!(!codes[i + 1..].contains(code));
+        }
+    }
+}
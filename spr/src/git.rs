@@ -5,11 +5,14 @@
  * LICENSE file in the root directory of this source tree.
  */
 
-use std::collections::{HashSet, VecDeque};
+use std::{
+    collections::{HashSet, VecDeque},
+    io::Write,
+};
 
 use crate::{
-    config::Config,
-    error::{Error, Result, ResultExt},
+    config::{CommitIdentity, Config},
+    error::{Error, ErrorKind, Result, ResultExt},
     github::{GitHubBranch, PullRequest},
     message::{
         build_commit_message, parse_message, MessageSection, MessageSectionsMap,
@@ -41,39 +44,234 @@ pub struct PreparedCommit {
     pub pull_request_task: Option<JoinHandle<Result<PullRequest>>>,
 }
 
+/// Lock held for the duration of a mutating spr command. The lock file is
+/// removed again when this is dropped.
+#[derive(Debug)]
+pub struct SprLock {
+    path: std::path::PathBuf,
+}
+
+impl Drop for SprLock {
+    fn drop(&mut self) {
+        // Ignore the result: there is nothing sensible we could do about a
+        // failure to remove the lock file at this point.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl PreparedCommit {
+    /// Whether this is a commit created by `git commit --fixup` or
+    /// `--squash`, meant to be squashed into another commit eventually.
+    pub fn is_fixup(&self) -> bool {
+        self.message
+            .get(&MessageSection::Title)
+            .map(|title| {
+                title.starts_with("fixup!")
+                    || title.starts_with("squash!")
+                    || title.starts_with("amend!")
+            })
+            .unwrap_or(false)
+    }
+
+    /// The Pull Request of this commit, if it has one. This awaits the Pull
+    /// Request task, and replaces it with a finished one (with the same
+    /// result), so that it can be awaited again.
+    pub async fn pull_request(&mut self) -> Result<Option<PullRequest>> {
+        match &mut self.pull_request_task {
+            Some(task) => {
+                let result = task.await.map_err(Error::from).and_then(|r| r);
+                self.pull_request_task =
+                    Some(tokio::spawn(std::future::ready(result.clone())));
+                result.map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Git {
     repo: std::sync::Arc<std::sync::Mutex<git2::Repository>>,
+    /// The local branch (full reference name) to work on instead of HEAD
+    branch: Option<String>,
+    /// The names of all references, once read (see `get_all_ref_names`)
+    ref_names: std::sync::Arc<
+        std::sync::Mutex<Option<std::sync::Arc<HashSet<String>>>>,
+    >,
+    /// The author and committer date (seconds since the epoch) of the commits
+    /// spr creates, instead of now
+    commit_date: Option<i64>,
 }
 
 impl Git {
     pub fn new(repo: git2::Repository) -> Self {
         Self {
             repo: std::sync::Arc::new(std::sync::Mutex::new(repo)),
+            branch: None,
+            ref_names: Default::default(),
+            commit_date: None,
+        }
+    }
+
+    /// A `Git` that gives the commits it creates for GitHub the given author
+    /// and committer date, so that running spr again on the same commits
+    /// creates the very same commits
+    pub fn with_commit_date(self, commit_date: Option<i64>) -> Self {
+        Self {
+            commit_date,
+            ..self
         }
     }
 
-    pub fn repo(&self) -> std::sync::MutexGuard<git2::Repository> {
+    /// A `Git` that reads the stack of commits from, and rewrites, the given
+    /// local branch instead of HEAD, so that the branch doesn't have to be
+    /// checked out.
+    pub fn with_branch(&self, branch_name: &str) -> Result<Self> {
+        let reference_name = self
+            .repo()
+            .find_branch(branch_name, git2::BranchType::Local)
+            .map_err(|_| {
+                Error::new(format!("There is no local branch '{branch_name}'"))
+            })?
+            .get()
+            .name()
+            .map(String::from)
+            .ok_or_else(|| {
+                Error::new(format!("Invalid branch name '{branch_name}'"))
+            })?;
+
+        Ok(Self {
+            repo: self.repo.clone(),
+            branch: Some(reference_name),
+            ref_names: self.ref_names.clone(),
+            commit_date: self.commit_date,
+        })
+    }
+
+    pub fn repo(&self) -> std::sync::MutexGuard<'_, git2::Repository> {
         self.repo.lock().expect("poisoned mutex")
     }
 
-    pub fn get_commit_oids(&self, master_ref: &str) -> Result<Vec<Oid>> {
+    /// The reference spr works on: the branch given to `with_branch`, or
+    /// else whatever HEAD refers to (a branch, or HEAD itself if detached)
+    fn head_reference<'r>(
+        &self,
+        repo: &'r git2::Repository,
+    ) -> Result<git2::Reference<'r>> {
+        Ok(match &self.branch {
+            Some(name) => repo.find_reference(name)?,
+            None => repo.head()?.resolve()?,
+        })
+    }
+
+    /// Acquire the spr lock for this repository. This fails if another spr
+    /// operation currently holds the lock. A lock left behind by an spr
+    /// process that is gone (e.g. because it was killed) is replaced. The
+    /// lock is released when the returned value goes out of scope.
+    pub fn lock(&self) -> Result<SprLock> {
+        let path = self.repo().path().join("spr.lock");
+
+        let mut replaced_stale_lock = false;
+        loop {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    // Record who holds the lock, so that a stale lock file
+                    // can be recognized as such.
+                    let _ = writeln!(file, "{}", std::process::id());
+                    return Ok(SprLock { path });
+                }
+                Err(error)
+                    if error.kind() == std::io::ErrorKind::AlreadyExists =>
+                {
+                    if !replaced_stale_lock && is_stale_lock(&path) {
+                        crate::output::output(
+                            "🧹",
+                            &format!(
+                                "Removing {}, left behind by an spr process \
+                                 that is no longer running",
+                                path.display()
+                            ),
+                        )?;
+                        // Another spr process may have got there first, then
+                        // creating the lock fails again below
+                        let _ = std::fs::remove_file(&path);
+                        replaced_stale_lock = true;
+                        continue;
+                    }
+                    return Err(Error::new(format!(
+                        "Another spr operation is in progress. If that is not \
+                         the case, remove {} and try again.",
+                        path.display()
+                    )));
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+
+    /// The commits between master and HEAD, oldest first. With
+    /// `first_parent`, only the first parent of merge commits is followed,
+    /// so the commits they merge in are left out.
+    pub fn get_commit_oids(
+        &self,
+        master_ref: &str,
+        first_parent: bool,
+    ) -> Result<Vec<Oid>> {
         let repo = self.repo();
         let mut walk = repo.revwalk()?;
         walk.set_sorting(git2::Sort::TOPOLOGICAL.union(git2::Sort::REVERSE))?;
-        walk.push_head()?;
+        if first_parent {
+            walk.simplify_first_parent()?;
+        }
+        walk.push(
+            self.head_reference(&repo)?
+                .target()
+                .ok_or_else(|| Error::new("Cannot resolve HEAD"))?,
+        )?;
         walk.hide_ref(master_ref)?;
 
         Ok(walk.collect::<std::result::Result<Vec<Oid>, _>>()?)
     }
 
+    /// Refuse to work on a suspiciously long stack, so that we don't flood
+    /// GitHub with Pull Requests after e.g. a bad rebase. This only looks at
+    /// the local branch, so that it can run before anything is requested
+    /// from GitHub.
+    pub fn check_stack_depth(&self, config: &Config) -> Result<()> {
+        if config.max_stack_depth == 0 {
+            return Ok(());
+        }
+        let length = self
+            .get_commit_oids(config.master_ref.local(), config.first_parent)?
+            .len();
+        if length > config.max_stack_depth {
+            return Err(Error::new(format!(
+                "There are {} commits between {} and HEAD, which is more than \
+                 the maximum of {} (spr.maxStackDepth). Is your branch based \
+                 on the right commit? Use `spr rebase --onto` to move it, or \
+                 raise spr.maxStackDepth if this is intended.",
+                length,
+                config.master_ref.local(),
+                config.max_stack_depth,
+            )));
+        }
+
+        Ok(())
+    }
+
     pub fn get_prepared_commits(
         &self,
         config: &Config,
         gh: Option<&crate::github::GitHub>,
     ) -> Result<Vec<PreparedCommit>> {
-        self.get_commit_oids(config.master_ref.local())?
-            .into_iter()
+        let oids = self
+            .get_commit_oids(config.master_ref.local(), config.first_parent)?;
+
+        oids.into_iter()
             .map(|oid| self.prepare_commit(config, oid, gh))
             .collect()
     }
@@ -127,8 +325,7 @@ impl Git {
 
         if updating {
             if let Some(oid) = parent_oid {
-                repo.find_reference("HEAD")?
-                    .resolve()?
+                self.head_reference(&repo)?
                     .set_target(oid, "spr updated commit messages")?;
             }
         }
@@ -136,24 +333,32 @@ impl Git {
         Ok(())
     }
 
+    /// Rebase the given commits onto the given new parent, and move the
+    /// current branch to the result. Commits that become empty are dropped.
+    /// With no commits at all, the branch is moved to the new parent.
     pub fn rebase_commits(
         &self,
         commits: &mut [PreparedCommit],
         mut new_parent_oid: git2::Oid,
     ) -> Result<()> {
-        if commits.is_empty() {
-            return Ok(());
-        }
         let repo = self.repo();
 
         for prepared_commit in commits.iter_mut() {
             let new_parent_commit = repo.find_commit(new_parent_oid)?;
             let commit = repo.find_commit(prepared_commit.oid)?;
 
-            let mut index =
-                repo.cherrypick_commit(&commit, &new_parent_commit, 0, None)?;
+            let mut index = repo.cherrypick_commit(
+                &commit,
+                &new_parent_commit,
+                mainline(&commit),
+                None,
+            )?;
             if index.has_conflicts() {
-                return Err(Error::new("Rebase failed due to merge conflicts"));
+                return Err(Error::new(format!(
+                    "Rebase failed due to merge conflicts in commit {}",
+                    prepared_commit.short_id
+                ))
+                .with_kind(ErrorKind::Conflict));
             }
 
             let tree_oid = index.write_tree_to(&repo)?;
@@ -179,7 +384,7 @@ impl Git {
 
         // Get and resolve the HEAD reference. This will be either a reference
         // to a branch ('refs/heads/...') or 'HEAD' if the head is detached.
-        let mut reference = repo.head()?.resolve()?;
+        let mut reference = self.head_reference(&repo)?;
 
         // Checkout the tree of the top commit of the rebased branch. This can
         // fail if there are local changes in the worktree that collide with
@@ -206,11 +411,111 @@ impl Git {
         Ok(())
     }
 
+    /// Point HEAD (or the branch it refers to) at the given commit, without
+    /// touching the working tree. Only use this if the new commit has the same
+    /// tree as the current HEAD.
+    pub fn set_head_target(&self, oid: Oid, log_message: &str) -> Result<()> {
+        let repo = self.repo();
+        let mut reference = self.head_reference(&repo)?;
+        reference.set_target(oid, log_message)?;
+
+        Ok(())
+    }
+
+    /// Partition the changes made by the given commit into several trees.
+    /// Each pattern (a glob, as understood by Git pathspecs) claims the changed
+    /// files it matches that no earlier pattern claimed; any remaining changes
+    /// form a final partition. The returned trees each build on the previous
+    /// one, starting from the commit's parent, and the last one is the tree of
+    /// the commit itself.
+    pub fn split_commit_trees(
+        &self,
+        commit_oid: Oid,
+        patterns: &[String],
+    ) -> Result<Vec<Oid>> {
+        let repo = self.repo();
+        let commit = repo.find_commit(commit_oid)?;
+        if commit.parent_count() != 1 {
+            return Err(Error::new(
+                "Only commits with exactly one parent can be split",
+            ));
+        }
+        let parent_tree = commit.parent(0)?.tree()?;
+        let diff = repo.diff_tree_to_tree(
+            Some(&parent_tree),
+            Some(&commit.tree()?),
+            None,
+        )?;
+
+        let pathspecs = patterns
+            .iter()
+            .map(|pattern| git2::Pathspec::new(std::iter::once(pattern)))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        // Each partition is a list of (path, new blob and mode or None if the
+        // file was deleted).
+        type Change = (std::path::PathBuf, Option<(Oid, git2::FileMode)>);
+        let mut partitions: Vec<Vec<Change>> =
+            vec![Vec::new(); patterns.len() + 1];
+        for delta in diff.deltas() {
+            let new_file = delta.new_file();
+            let path = new_file
+                .path()
+                .or_else(|| delta.old_file().path())
+                .ok_or_else(|| Error::new("Changed file without a path"))?
+                .to_path_buf();
+            let change = if delta.status() == git2::Delta::Deleted {
+                None
+            } else {
+                Some((new_file.id(), new_file.mode()))
+            };
+            let index = pathspecs
+                .iter()
+                .position(|pathspec| {
+                    pathspec.matches_path(&path, git2::PathspecFlags::DEFAULT)
+                })
+                .unwrap_or(patterns.len());
+            partitions[index].push((path, change));
+        }
+
+        if let Some(index) = partitions[..patterns.len()]
+            .iter()
+            .position(|changes| changes.is_empty())
+        {
+            return Err(Error::new(format!(
+                "'{}' does not match any file changed by the commit",
+                patterns[index]
+            )));
+        }
+
+        let mut trees = Vec::new();
+        let mut tree_oid = parent_tree.id();
+        for changes in partitions.iter().filter(|changes| !changes.is_empty()) {
+            let mut builder = git2::build::TreeUpdateBuilder::new();
+            for (path, change) in changes {
+                match change {
+                    Some((oid, mode)) => builder.upsert(path, *oid, *mode),
+                    None => builder.remove(path),
+                };
+            }
+            tree_oid =
+                builder.create_updated(&repo, &repo.find_tree(tree_oid)?)?;
+            trees.push(tree_oid);
+        }
+
+        if tree_oid != commit.tree_id() {
+            return Err(Error::new(
+                "The split commits do not add up to the original commit",
+            ));
+        }
+
+        Ok(trees)
+    }
+
     pub fn head(&self) -> Result<Oid> {
+        let repo = self.repo();
         let oid = self
-            .repo()
-            .head()?
-            .resolve()?
+            .head_reference(&repo)?
             .target()
             .ok_or_else(|| Error::new("Cannot resolve HEAD"))?;
 
@@ -227,6 +532,249 @@ impl Git {
         Ok(result)
     }
 
+    pub fn resolve_revision(&self, revision: &str) -> Result<Oid> {
+        let result = self
+            .repo()
+            .revparse_single(revision)?
+            .peel_to_commit()?
+            .id();
+
+        Ok(result)
+    }
+
+    /// Select commits of the stack by a revision range like `HEAD~3..HEAD`
+    /// (or a single revision, for just that commit). Returns the indexes of
+    /// the selected commits in `prepared_commits`, from the bottom of the
+    /// stack to the top. It is an error for the range to include commits that
+    /// are not part of the stack.
+    pub fn select_commit_range(
+        &self,
+        prepared_commits: &[PreparedCommit],
+        range: &str,
+    ) -> Result<Vec<usize>> {
+        let oids = {
+            let repo = self.repo();
+            let revspec = repo.revparse(range).map_err(|e| {
+                Error::new(format!("Invalid commit range '{range}': {e}"))
+            })?;
+
+            if revspec.mode().contains(git2::RevparseMode::SINGLE) {
+                let from = revspec.from().unwrap().peel_to_commit()?;
+                vec![from.id()]
+            } else if revspec.mode().contains(git2::RevparseMode::RANGE)
+                && !revspec.mode().contains(git2::RevparseMode::MERGE_BASE)
+            {
+                let mut walk = repo.revwalk()?;
+                if let Some(to) = revspec.to() {
+                    walk.push(to.peel_to_commit()?.id())?;
+                }
+                if let Some(from) = revspec.from() {
+                    walk.hide(from.peel_to_commit()?.id())?;
+                }
+                walk.collect::<std::result::Result<Vec<Oid>, _>>()?
+            } else {
+                return Err(Error::new(format!(
+                    "Invalid commit range '{range}': use either a single \
+                     commit or a range like 'HEAD~3..HEAD'"
+                )));
+            }
+        };
+
+        let mut indexes = oids
+            .into_iter()
+            .map(|oid| {
+                prepared_commits
+                    .iter()
+                    .position(|commit| commit.oid == oid)
+                    .ok_or_else(|| {
+                        Error::new(format!(
+                            "Commit range '{range}' includes commit {oid}, \
+                             which is not on the local stack"
+                        ))
+                    })
+            })
+            .collect::<Result<Vec<usize>>>()?;
+        indexes.sort_unstable();
+
+        if indexes.is_empty() {
+            return Err(Error::new(format!(
+                "Commit range '{range}' does not include any commits"
+            )));
+        }
+
+        Ok(indexes)
+    }
+
+    /// The names of the branches of the GitHub remote (other than
+    /// `own_branch` and master) that have commits on top of the given commit.
+    /// This is a heuristic to spot others' work based on a Pull Request
+    /// branch, which a force-push would leave behind. It only knows about
+    /// branches fetched from GitHub.
+    pub fn branches_based_on(
+        &self,
+        config: &Config,
+        oid: Oid,
+        own_branch: &str,
+    ) -> Result<Vec<String>> {
+        let repo = self.repo();
+        let prefix = format!("refs/remotes/{}/", config.remote_name);
+        let mut branches = Vec::new();
+
+        for reference in repo.references_glob(&format!("{prefix}*"))? {
+            let reference = reference?;
+            let branch_name = match reference
+                .name()
+                .and_then(|name| name.strip_prefix(&prefix))
+            {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            if branch_name == own_branch
+                || branch_name == "HEAD"
+                || branch_name == config.master_ref.branch_name()
+            {
+                continue;
+            }
+            let branch_oid = match reference.peel_to_commit() {
+                Ok(commit) => commit.id(),
+                Err(_) => continue,
+            };
+            if repo.graph_descendant_of(branch_oid, oid)? {
+                branches.push(branch_name);
+            }
+        }
+
+        branches.sort();
+        Ok(branches)
+    }
+
+    /// Find the branch on GitHub that the given revision refers to. Remote
+    /// tracking branches of the GitHub remote (e.g. `origin/main`) refer to
+    /// the respective branch. A local branch refers to the branch of the
+    /// same name on GitHub if there is one, and otherwise to the GitHub
+    /// branch it tracks (so does `@{upstream}`), or failing that, to a
+    /// branch of its name. Returns `None` if the revision cannot be resolved
+    /// at all, and an error if it resolves to a commit that is not a branch.
+    pub fn resolve_github_branch_name(
+        &self,
+        config: &Config,
+        revision: &str,
+    ) -> Result<Option<String>> {
+        let repo = self.repo();
+
+        // libgit2 does not understand the short form `@{...}` for the current
+        // branch, so spell out the branch name.
+        let revision = match revision.strip_prefix('@') {
+            Some(suffix) if suffix.starts_with('{') => {
+                match repo
+                    .head()
+                    .ok()
+                    .and_then(|head| head.shorthand().map(String::from))
+                {
+                    Some(branch_name) => format!("{branch_name}@{suffix}"),
+                    None => revision.to_string(),
+                }
+            }
+            _ => revision.to_string(),
+        };
+
+        let (object, reference) = match repo.revparse_ext(&revision) {
+            Ok(result) => result,
+            Err(_) => return Ok(None),
+        };
+
+        let remote_prefix = format!("refs/remotes/{}/", config.remote_name);
+        let branch_name = reference.as_ref().and_then(|reference| {
+            let name = reference.name()?;
+            if let Some(branch_name) = name.strip_prefix(&remote_prefix) {
+                return Some(branch_name.to_string());
+            }
+            let branch_name = name.strip_prefix("refs/heads/")?;
+            if repo
+                .find_reference(&format!("{remote_prefix}{branch_name}"))
+                .is_ok()
+            {
+                return Some(branch_name.to_string());
+            }
+            let upstream = git2::Branch::wrap(repo.find_reference(name).ok()?)
+                .upstream()
+                .ok()
+                .and_then(|upstream| {
+                    upstream
+                        .get()
+                        .name()?
+                        .strip_prefix(&remote_prefix)
+                        .map(String::from)
+                });
+            Some(upstream.unwrap_or_else(|| branch_name.to_string()))
+        });
+
+        match branch_name {
+            Some(branch_name) => Ok(Some(branch_name)),
+            None => Err(Error::new(format!(
+                "'{}' resolves to commit {}, which does not correspond to a \
+                 branch on {}. Use a branch that has been pushed to GitHub \
+                 as the base.",
+                revision,
+                object.short_id()?.as_str().unwrap_or_default(),
+                config.remote_name,
+            ))),
+        }
+    }
+
+    /// If `name` is a tag, return the commit it points to, together with the
+    /// name of the branch on GitHub to target instead: of the branches that
+    /// contain the tagged commit, the one with the fewest commits on top of
+    /// it. Returns `None` if there is no such tag.
+    pub fn resolve_tag_base(
+        &self,
+        config: &Config,
+        name: &str,
+    ) -> Result<Option<(Oid, String)>> {
+        let repo = self.repo();
+        let tag_oid = match repo.find_reference(&format!("refs/tags/{name}")) {
+            Ok(reference) => reference.peel_to_commit()?.id(),
+            Err(_) => return Ok(None),
+        };
+
+        let remote_prefix = format!("refs/remotes/{}/", config.remote_name);
+        let mut best: Option<(usize, String)> = None;
+        for reference in repo.references_glob(&format!("{remote_prefix}*"))? {
+            let reference = reference?;
+            let (Some(ref_name), Ok(commit)) =
+                (reference.name(), reference.peel_to_commit())
+            else {
+                continue;
+            };
+            let branch_name = &ref_name[remote_prefix.len()..];
+            if branch_name == "HEAD" {
+                continue;
+            }
+            if commit.id() != tag_oid
+                && !repo.graph_descendant_of(commit.id(), tag_oid)?
+            {
+                continue;
+            }
+            let (ahead, _) = repo.graph_ahead_behind(commit.id(), tag_oid)?;
+            if best
+                .as_ref()
+                .is_none_or(|(best_ahead, _)| ahead < *best_ahead)
+            {
+                best = Some((ahead, branch_name.to_string()));
+            }
+        }
+
+        match best {
+            Some((_, branch_name)) => Ok(Some((tag_oid, branch_name))),
+            None => Err(Error::new(format!(
+                "Tag '{}' is not contained in any branch on {}. Pull Requests \
+                 must target a branch: push a branch containing the tag, or \
+                 give that branch as the base.",
+                name, config.remote_name
+            ))),
+        }
+    }
+
     pub async fn fetch_commits_from_remote(
         &self,
         commit_oids: &[git2::Oid],
@@ -294,17 +842,42 @@ impl Git {
         let repo = self.repo();
         let commit = repo.find_commit(oid)?;
 
-        if commit.parent_count() != 1 {
-            return Err(Error::new("Parent commit count != 1"));
+        let short_id =
+            commit.as_object().short_id()?.as_str().unwrap().to_string();
+
+        if commit.parent_count() == 0 {
+            return Err(Error::new(format!(
+                "Commit {short_id} has no parent. Is the branch based on \
+                 {}?",
+                config.master_ref.branch_name()
+            )));
+        }
+        if commit.parent_count() > 1 {
+            if !config.first_parent {
+                return Err(Error::new(format!(
+                    "Commit {short_id} ({}) is a merge commit. spr works on \
+                     a linear stack of commits: rebase the branch instead of \
+                     merging (e.g. `git rebase {}`), or use --first-parent \
+                     to treat merge commits like single commits with all \
+                     the changes they merge in.",
+                    commit.summary().unwrap_or_default(),
+                    config.master_ref.branch_name(),
+                )));
+            }
+            crate::output::output(
+                "⚠️",
+                &format!(
+                    "Following the first parent of merge commit {short_id}: \
+                     it is treated like a single commit with all the \
+                     changes it merges in"
+                ),
+            )?;
         }
 
         let parent_oid = commit.parent_id(0)?;
 
         let message =
             String::from_utf8_lossy(commit.message_bytes()).into_owned();
-
-        let short_id =
-            commit.as_object().short_id()?.as_str().unwrap().to_string();
         drop(commit);
         drop(repo);
 
@@ -340,15 +913,40 @@ impl Git {
         })
     }
 
-    pub fn get_all_ref_names(&self) -> Result<HashSet<String>> {
+    /// The names of all references in the repository. Walking them is slow
+    /// in repositories with many thousands of references, so they are read
+    /// only once, and remembered until `forget_ref_names` is called.
+    /// References spr is about to create should be added with
+    /// `add_ref_name` in the meantime.
+    pub fn get_all_ref_names(&self) -> Result<std::sync::Arc<HashSet<String>>> {
+        let mut ref_names = self.ref_names.lock().expect("poisoned mutex");
+        if let Some(ref_names) = &*ref_names {
+            return Ok(ref_names.clone());
+        }
+
         let result: std::result::Result<HashSet<_>, _> = self
             .repo()
             .references()?
             .names()
             .map(|r| r.map(String::from))
             .collect();
+        let result = std::sync::Arc::new(result?);
+        *ref_names = Some(result.clone());
 
-        Ok(result?)
+        Ok(result)
+    }
+
+    /// Add a reference name to the ones `get_all_ref_names` returns
+    pub fn add_ref_name(&self, name: &str) {
+        let mut ref_names = self.ref_names.lock().expect("poisoned mutex");
+        if let Some(ref_names) = &mut *ref_names {
+            std::sync::Arc::make_mut(ref_names).insert(name.to_string());
+        }
+    }
+
+    /// Read the reference names afresh the next time they are needed
+    pub fn forget_ref_names(&self) {
+        *self.ref_names.lock().expect("poisoned mutex") = None;
     }
 
     pub fn get_pr_patch_branch_name(&self, pr_number: u64) -> Result<String> {
@@ -368,18 +966,40 @@ impl Git {
         }
     }
 
+    /// Cherry-pick the given commit onto the given base commit, in memory.
+    /// Submodules are gitlink entries (the commit id of the submodule) in the
+    /// resulting index, just like in the trees of the commits, so a commit
+    /// that bumps a submodule bumps it in the result too.
     pub fn cherrypick(&self, oid: Oid, base_oid: Oid) -> Result<git2::Index> {
         let repo = self.repo();
         let commit = repo.find_commit(oid)?;
         let base_commit = repo.find_commit(base_oid)?;
 
-        Ok(repo.cherrypick_commit(&commit, &base_commit, 0, None)?)
+        Ok(repo.cherrypick_commit(
+            &commit,
+            &base_commit,
+            mainline(&commit),
+            None,
+        )?)
     }
 
     pub fn write_index(&self, mut index: git2::Index) -> Result<Oid> {
         Ok(index.write_tree_to(&self.repo())?)
     }
 
+    /// Whether the given commit has no changes compared to its (first)
+    /// parent, as can happen e.g. after a rebase
+    pub fn is_empty_commit(&self, oid: Oid) -> Result<bool> {
+        let repo = self.repo();
+        let commit = repo.find_commit(oid)?;
+        let is_empty = match commit.parent(0) {
+            Ok(parent) => parent.tree_id() == commit.tree_id(),
+            Err(_) => false,
+        };
+
+        Ok(is_empty)
+    }
+
     pub fn get_tree_oid_for_commit(&self, oid: Oid) -> Result<Oid> {
         let tree_oid = self.repo().find_commit(oid)?.tree_id();
 
@@ -429,12 +1049,43 @@ impl Git {
         Ok(None)
     }
 
+    /// Find a commit that the Pull Request branch at `head_oid` contains, but
+    /// which is not created by spr and not reachable from `master_oid`.
+    /// Pull Request branches only consist of commits created by spr on top of
+    /// master commits, so such a commit must have been removed from master,
+    /// most likely by a force-push.
+    pub fn find_lost_master_base(
+        &self,
+        head_oid: Oid,
+        master_oid: Oid,
+    ) -> Result<Option<Oid>> {
+        let repo = self.repo();
+        let mut walk = repo.revwalk()?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+        walk.push(head_oid)?;
+        walk.hide(master_oid)?;
+
+        for oid in walk {
+            let oid = oid?;
+            let commit = repo.find_commit(oid)?;
+            if !String::from_utf8_lossy(commit.message_bytes())
+                .contains("Created using spr")
+            {
+                return Ok(Some(oid));
+            }
+        }
+
+        Ok(None)
+    }
+
     pub fn create_derived_commit(
         &self,
         original_commit_oid: Oid,
         message: &str,
         tree_oid: Oid,
         parent_oids: &[Oid],
+        author: Option<&CommitIdentity>,
+        committer: Option<&CommitIdentity>,
     ) -> Result<Oid> {
         let repo = self.repo();
         let original_commit = repo.find_commit(original_commit_oid)?;
@@ -446,34 +1097,52 @@ impl Git {
         let parent_refs = parents.iter().collect::<Vec<_>>();
         let message = git2::message_prettify(message, None)?;
 
-        // The committer signature should be the default signature (i.e. the
-        // current user - as configured in Git as `user.name` and `user.email` -
-        // and the timestamp set to now). If the default signature can't be
-        // obtained (no user configured), then take the user/email from the
-        // existing commit but make a new signature which has a timestamp of
-        // now.
-        let committer = repo.signature().or_else(|_| {
-            git2::Signature::now(
-                String::from_utf8_lossy(
+        // Signatures get the timestamp now, unless a commit date is set
+        let signature = |name: &[u8], email: &[u8]| {
+            let name = String::from_utf8_lossy(name);
+            let email = String::from_utf8_lossy(email);
+            match self.commit_date {
+                Some(seconds) => git2::Signature::new(
+                    &name,
+                    &email,
+                    &git2::Time::new(seconds, 0),
+                ),
+                None => git2::Signature::now(&name, &email),
+            }
+        };
+
+        // The committer signature should be the configured identity, if any,
+        // or else the current user (as configured in Git as `user.name` and
+        // `user.email`). If no user is configured, then take the user/email
+        // from the existing commit.
+        let committer = match committer {
+            Some(identity) => {
+                signature(identity.name.as_bytes(), identity.email.as_bytes())
+            }
+            None => match repo.signature() {
+                Ok(default) => {
+                    signature(default.name_bytes(), default.email_bytes())
+                }
+                Err(_) => signature(
                     original_commit.committer().name_bytes(),
-                )
-                .as_ref(),
-                String::from_utf8_lossy(
                     original_commit.committer().email_bytes(),
-                )
-                .as_ref(),
-            )
-        })?;
-
-        // The author signature should reference the same user as the original
-        // commit, but we set the timestamp to now, so this commit shows up in
-        // GitHub's timeline in the right place.
-        let author = git2::Signature::now(
-            String::from_utf8_lossy(original_commit.author().name_bytes())
-                .as_ref(),
-            String::from_utf8_lossy(original_commit.author().email_bytes())
-                .as_ref(),
-        )?;
+                ),
+            },
+        }?;
+
+        // The author signature should be the configured identity, if any, or
+        // else reference the same user as the original commit. In both cases
+        // the timestamp is now (unless a commit date is set), so this commit
+        // shows up in GitHub's timeline in the right place.
+        let author = match author {
+            Some(identity) => {
+                signature(identity.name.as_bytes(), identity.email.as_bytes())
+            }
+            None => signature(
+                original_commit.author().name_bytes(),
+                original_commit.author().email_bytes(),
+            ),
+        }?;
 
         let oid = repo.commit(
             None,
@@ -487,6 +1156,251 @@ impl Git {
         Ok(oid)
     }
 
+    /// The paths of the files the given commit changes, compared to its
+    /// (first) parent. Renamed files are listed with both paths.
+    pub fn changed_paths(&self, commit_oid: Oid) -> Result<Vec<String>> {
+        let repo = self.repo();
+        let commit = repo.find_commit(commit_oid)?;
+        let parent_tree = match commit.parent(0) {
+            Ok(parent) => Some(parent.tree()?),
+            Err(_) => None,
+        };
+        let diff = repo.diff_tree_to_tree(
+            parent_tree.as_ref(),
+            Some(&commit.tree()?),
+            None,
+        )?;
+
+        Ok(diff_paths(&diff))
+    }
+
+    /// The paths of the files that differ between the two given trees, as
+    /// with `changed_paths`
+    pub fn changed_paths_between(
+        &self,
+        old_tree_oid: Oid,
+        new_tree_oid: Oid,
+    ) -> Result<Vec<String>> {
+        let repo = self.repo();
+        let old_tree = repo.find_tree(old_tree_oid)?;
+        let new_tree = repo.find_tree(new_tree_oid)?;
+        let diff =
+            repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+
+        Ok(diff_paths(&diff))
+    }
+
+    /// The number of lines the given commit adds or removes, compared to its
+    /// (first) parent
+    pub fn count_changed_lines(&self, commit_oid: Oid) -> Result<usize> {
+        let repo = self.repo();
+        let commit = repo.find_commit(commit_oid)?;
+        let parent_tree = commit.parent(0)?.tree()?;
+        let diff = repo.diff_tree_to_tree(
+            Some(&parent_tree),
+            Some(&commit.tree()?),
+            None,
+        )?;
+        let stats = diff.stats()?;
+
+        Ok(stats.insertions() + stats.deletions())
+    }
+
+    /// Count the files that differ between the two given trees
+    pub fn count_changed_files(
+        &self,
+        old_tree_oid: Oid,
+        new_tree_oid: Oid,
+    ) -> Result<usize> {
+        let repo = self.repo();
+        let old_tree = repo.find_tree(old_tree_oid)?;
+        let new_tree = repo.find_tree(new_tree_oid)?;
+        let diff =
+            repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+
+        Ok(diff.deltas().len())
+    }
+
+    /// Whether the changes from the old base tree to the old head tree are
+    /// the same as those from the new base tree to the new head tree, i.e.
+    /// the new trees are just a rebase of the old ones. Like `git cherry`,
+    /// this compares patch ids, so the line numbers may differ.
+    pub fn same_changes(
+        &self,
+        old_base_tree: Oid,
+        old_head_tree: Oid,
+        new_base_tree: Oid,
+        new_head_tree: Oid,
+    ) -> Result<bool> {
+        let repo = self.repo();
+        let patch_id = |base_tree: Oid, head_tree: Oid| -> Result<Oid> {
+            let diff = repo.diff_tree_to_tree(
+                Some(&repo.find_tree(base_tree)?),
+                Some(&repo.find_tree(head_tree)?),
+                None,
+            )?;
+            Ok(diff.patchid(None)?)
+        };
+
+        Ok(patch_id(old_base_tree, old_head_tree)?
+            == patch_id(new_base_tree, new_head_tree)?)
+    }
+
+    /// The diffstat (as `git diff --stat` shows it) of the changes from the
+    /// base tree to the head tree
+    pub fn diffstat(&self, base_tree: Oid, head_tree: Oid) -> Result<String> {
+        let repo = self.repo();
+        let diff = repo.diff_tree_to_tree(
+            Some(&repo.find_tree(base_tree)?),
+            Some(&repo.find_tree(head_tree)?),
+            None,
+        )?;
+        let stats = diff.stats()?.to_buf(git2::DiffStatsFormat::FULL, 72)?;
+
+        Ok(stats.as_str().unwrap_or_default().to_string())
+    }
+
+    /// Create a parentless commit whose tree contains the given files (name
+    /// and content) at the top level.
+    pub fn create_commit_with_files(
+        &self,
+        files: &[(String, Vec<u8>)],
+        message: &str,
+    ) -> Result<Oid> {
+        let repo = self.repo();
+        let mut builder = repo.treebuilder(None)?;
+        for (name, content) in files {
+            let blob = repo.blob(content)?;
+            builder.insert(name, blob, 0o100644)?;
+        }
+        let tree = repo.find_tree(builder.write()?)?;
+        let signature = repo.signature()?;
+
+        Ok(repo.commit(None, &signature, &signature, message, &tree, &[])?)
+    }
+
+    /// The message for a commit spr creates for GitHub: the given message,
+    /// signed off and passed through the commit message hooks if so
+    /// configured.
+    pub fn derived_commit_message(
+        &self,
+        config: &Config,
+        message: String,
+    ) -> Result<String> {
+        let message = if config.signoff {
+            add_trailer(&message, &self.signoff_trailer(config)?)
+        } else {
+            message
+        };
+
+        if config.run_commit_hooks {
+            self.run_commit_message_hooks(&message)
+        } else {
+            Ok(message)
+        }
+    }
+
+    /// The `Signed-off-by:` trailer for the committer of the commits spr
+    /// creates, like `git commit --signoff` would add
+    fn signoff_trailer(&self, config: &Config) -> Result<String> {
+        let (name, email) = match &config.derived_commit_committer {
+            Some(identity) => (identity.name.clone(), identity.email.clone()),
+            None => {
+                let signature = self.repo().signature().map_err(|_| {
+                    Error::new(
+                        "Cannot sign off commits: no Git user configured \
+                         (user.name and user.email)",
+                    )
+                })?;
+                (
+                    String::from_utf8_lossy(signature.name_bytes()).into(),
+                    String::from_utf8_lossy(signature.email_bytes()).into(),
+                )
+            }
+        };
+
+        Ok(format!("Signed-off-by: {} <{}>", name, email))
+    }
+
+    /// Run the `prepare-commit-msg` and `commit-msg` hooks of the repository
+    /// (honouring `core.hooksPath`) on the given commit message, the same way
+    /// `git commit` would, and return the message as modified by the hooks.
+    /// Hooks that don't exist or are not executable are skipped.
+    pub fn run_commit_message_hooks(&self, message: &str) -> Result<String> {
+        let (hooks_dir, workdir, message_file) = {
+            let repo = self.repo();
+            let workdir = repo
+                .workdir()
+                .ok_or_else(|| Error::new("Repository has no working tree"))?
+                .to_path_buf();
+            let hooks_dir = match repo.config()?.get_path("core.hooksPath") {
+                Ok(path) => workdir.join(path),
+                Err(_) => repo.path().join("hooks"),
+            };
+            (hooks_dir, workdir, repo.path().join("SPR_COMMIT_EDITMSG"))
+        };
+
+        std::fs::write(&message_file, message)?;
+
+        let result = (|| {
+            for (hook, args) in [
+                ("prepare-commit-msg", &["message"][..]),
+                ("commit-msg", &[][..]),
+            ] {
+                let path = hooks_dir.join(hook);
+                if !is_executable(&path) {
+                    continue;
+                }
+
+                let status = std::process::Command::new(&path)
+                    .arg(&message_file)
+                    .args(args)
+                    .current_dir(&workdir)
+                    .stdin(std::process::Stdio::null())
+                    .status()?;
+                if !status.success() {
+                    return Err(Error::new(format!(
+                        "The {hook} hook failed ({status})"
+                    )));
+                }
+            }
+
+            Ok(std::fs::read_to_string(&message_file)?)
+        })();
+
+        let _ = std::fs::remove_file(&message_file);
+        result
+    }
+
+    /// Make sure a branch is checked out. If HEAD is detached and
+    /// `new_branch` is given, a branch of that name is created at HEAD and
+    /// checked out, and `true` is returned. Otherwise a detached HEAD is an
+    /// error.
+    pub fn check_head_is_attached(
+        &self,
+        new_branch: Option<&str>,
+    ) -> Result<bool> {
+        let repo = self.repo();
+        if !repo.head_detached()? {
+            return Ok(false);
+        }
+
+        match new_branch {
+            Some(name) => {
+                let head = repo.head()?.peel_to_commit()?;
+                let branch = repo
+                    .branch(name, &head, false)
+                    .context(format!("Could not create branch '{name}'"))?;
+                repo.set_head(branch.get().name().unwrap_or_default())?;
+                Ok(true)
+            }
+            None => Err(Error::new(
+                "HEAD is detached. Check out a branch first, e.g. with \
+                 `git switch -c <name>`",
+            )),
+        }
+    }
+
     pub fn check_no_uncommitted_changes(&self) -> Result<()> {
         let mut opts = git2::StatusOptions::new();
         opts.include_ignored(false).include_untracked(false);
@@ -495,7 +1409,1193 @@ impl Git {
         } else {
             Err(Error::new(
                 "There are uncommitted changes. Stash or amend them first",
+            )
+            .with_kind(ErrorKind::DirtyWorkingTree))
+        }
+    }
+
+    /// Make sure the master branch spr is configured with exists on the
+    /// remote (as far as the last fetch knows). If it doesn't, e.g. because
+    /// it was renamed from master to main, suggest the branches it probably
+    /// should be instead.
+    pub fn check_master_ref(
+        &self,
+        config: &crate::config::Config,
+    ) -> Result<()> {
+        let repo = self.repo();
+        if repo.find_reference(config.master_ref.local()).is_ok() {
+            return Ok(());
+        }
+
+        let remote = &config.remote_name;
+        let prefix = format!("refs/remotes/{remote}/");
+        let mut candidates = Vec::new();
+        // The default branch of the remote, as recorded by `git clone` or
+        // `git remote set-head`
+        if let Some(target) = repo
+            .find_reference(&format!("{prefix}HEAD"))
+            .ok()
+            .and_then(|head| head.symbolic_target().map(String::from))
+        {
+            if let Some(name) = target.strip_prefix(&prefix) {
+                candidates.push(name.to_string());
+            }
+        }
+        for name in MASTER_BRANCH_CANDIDATES {
+            if !candidates.iter().any(|c| c == name)
+                && repo.find_reference(&format!("{prefix}{name}")).is_ok()
+            {
+                candidates.push(name.to_string());
+            }
+        }
+
+        let mut message = format!(
+            "The master branch '{}' does not exist on remote '{remote}' \
+             ({} not found). Was it renamed?",
+            config.master_ref.branch_name(),
+            config.master_ref.local(),
+        );
+        match candidates.first() {
+            Some(candidate) => message.push_str(&format!(
+                " Found {}. Set the right one with `git config \
+                 spr.githubMasterBranch {candidate}`",
+                candidates
+                    .iter()
+                    .map(|c| format!("'{c}'"))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )),
+            None => message.push_str(&format!(
+                " Run `git fetch {remote}`, or set the right branch with \
+                 `git config spr.githubMasterBranch <branch>`"
+            )),
+        }
+
+        Err(Error::new(message))
+    }
+}
+
+/// Branch names that are commonly the default branch of a repository
+const MASTER_BRANCH_CANDIDATES: &[&str] =
+    &["main", "master", "trunk", "develop"];
+
+/// Whether the lock file at the given path names a process that is not
+/// running any more. A lock file without a process ID may be one that is
+/// just being written, so it is not stale.
+fn is_stale_lock(path: &std::path::Path) -> bool {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| content.trim().parse::<u32>().ok())
+        .is_some_and(|pid| !process_is_running(pid))
+}
+
+/// Whether a process with the given ID exists. Where that can't be told, it
+/// is assumed to exist.
+fn process_is_running(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        std::process::Command::new("ps")
+            .arg("-p")
+            .arg(pid.to_string())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .map_or(true, |status| status.success())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        true
+    }
+}
+
+fn is_executable(path: &std::path::Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .map(|metadata| {
+                metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+            })
+            .unwrap_or(false)
+    }
+
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+/// Add the given trailer (e.g. `Signed-off-by: Name <email>`) to the end of
+/// the commit message, unless it is there already. If the last paragraph of
+/// the message consists of trailers, the new one is added to it.
+pub fn add_trailer(message: &str, trailer: &str) -> String {
+    let message = message.trim_end();
+    if message.lines().any(|line| line.trim_end() == trailer) {
+        return message.to_string();
+    }
+    if message.is_empty() {
+        return trailer.to_string();
+    }
+
+    let last_paragraph = message.rsplit("\n\n").next().unwrap_or_default();
+    let separator = if last_paragraph.lines().all(is_trailer_line) {
+        "\n"
+    } else {
+        "\n\n"
+    };
+
+    format!("{}{}{}", message, separator, trailer)
+}
+
+fn is_trailer_line(line: &str) -> bool {
+    match line.split_once(": ") {
+        Some((token, _)) => {
+            !token.is_empty()
+                && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        }
+        None => false,
+    }
+}
+
+/// The parent to cherry-pick the given commit relative to: for a merge
+/// commit (followed with `first_parent`), all the changes it merges into its
+/// first parent
+fn mainline(commit: &git2::Commit) -> u32 {
+    if commit.parent_count() > 1 {
+        1
+    } else {
+        0
+    }
+}
+
+/// The paths of the files the given diff touches. Renamed files are listed
+/// with both paths.
+fn diff_paths(diff: &git2::Diff) -> Vec<String> {
+    let mut paths = Vec::<String>::new();
+    for delta in diff.deltas() {
+        for file in [delta.old_file(), delta.new_file()] {
+            if let Some(path) = file.path().and_then(|p| p.to_str()) {
+                if !paths.iter().any(|p| p == path) {
+                    paths.push(path.to_string());
+                }
+            }
+        }
+    }
+
+    paths
+}
+
+/// Repositories and commits for tests in all modules
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use super::*;
+
+    /// A `Git` for a new repository in a temporary directory, which is
+    /// removed again when this is dropped
+    pub(crate) struct TestRepo {
+        git: Git,
+        path: std::path::PathBuf,
+    }
+
+    impl std::ops::Deref for TestRepo {
+        type Target = Git;
+
+        fn deref(&self) -> &Git {
+            &self.git
+        }
+    }
+
+    impl Drop for TestRepo {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    pub(crate) fn init_test_repo(name: &str) -> TestRepo {
+        let path = std::env::temp_dir().join(format!(
+            "spr-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        TestRepo {
+            git: Git::new(git2::Repository::init(&path).unwrap()),
+            path,
+        }
+    }
+
+    /// Commit the given tree, authored and committed by `author` (with the
+    /// email address `<author>@example.com`)
+    pub(crate) fn commit_tree_as(
+        git: &Git,
+        author: &str,
+        tree: Oid,
+        message: &str,
+        parents: &[Oid],
+    ) -> Oid {
+        let repo = git.repo();
+        let signature =
+            git2::Signature::now(author, &format!("{author}@example.com"))
+                .unwrap();
+        let tree = repo.find_tree(tree).unwrap();
+        let parents = parents
+            .iter()
+            .map(|oid| repo.find_commit(*oid).unwrap())
+            .collect::<Vec<_>>();
+        let parent_refs = parents.iter().collect::<Vec<_>>();
+        repo.commit(
+            None,
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parent_refs[..],
+        )
+        .unwrap()
+    }
+
+    pub(crate) fn commit_tree(
+        git: &Git,
+        tree: Oid,
+        message: &str,
+        parents: &[Oid],
+    ) -> Oid {
+        commit_tree_as(git, "Test", tree, message, parents)
+    }
+
+    /// Commit a tree with just the given files (name and content), authored
+    /// and committed by `author`
+    pub(crate) fn commit_files_as(
+        git: &Git,
+        author: &str,
+        files: &[(&str, &str)],
+        message: &str,
+        parents: &[Oid],
+    ) -> Oid {
+        let tree = {
+            let repo = git.repo();
+            let mut builder = repo.treebuilder(None).unwrap();
+            for (name, content) in files {
+                let blob = repo.blob(content.as_bytes()).unwrap();
+                builder.insert(name, blob, 0o100644).unwrap();
+            }
+            builder.write().unwrap()
+        };
+        commit_tree_as(git, author, tree, message, parents)
+    }
+
+    /// Commit a tree with just the given files (name and content)
+    pub(crate) fn commit_files(
+        git: &Git,
+        files: &[(&str, &str)],
+        message: &str,
+        parents: &[Oid],
+    ) -> Oid {
+        commit_files_as(git, "Test", files, message, parents)
+    }
+
+    /// Commit an empty tree
+    pub(crate) fn commit(git: &Git, message: &str, parents: &[Oid]) -> Oid {
+        commit_files(git, &[], message, parents)
+    }
+
+    /// Commit the tree of the parent with the given file added (or changed)
+    pub(crate) fn commit_with_file(
+        git: &Git,
+        path: &str,
+        content: &str,
+        parent: Oid,
+    ) -> Oid {
+        let repo = git.repo();
+        let signature =
+            git2::Signature::now("Test", "test@example.com").unwrap();
+        let parent = repo.find_commit(parent).unwrap();
+        let blob = repo.blob(content.as_bytes()).unwrap();
+        let mut builder =
+            repo.treebuilder(Some(&parent.tree().unwrap())).unwrap();
+        builder.insert(path, blob, 0o100644).unwrap();
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        repo.commit(None, &signature, &signature, path, &tree, &[&parent])
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    use super::test_utils::*;
+    use crate::config::test_utils::config_factory;
+
+    #[test]
+    fn test_check_master_ref() {
+        let git = init_test_repo("master-ref");
+        let config = config_factory();
+
+        // Nothing was fetched yet, so there are no suggestions
+        let error = git.check_master_ref(&config).unwrap_err();
+        assert!(error.messages()[0].starts_with(
+            "The master branch 'master' does not exist on remote 'origin' \
+             (refs/remotes/origin/master not found). Was it renamed? Run \
+             `git fetch origin`"
+        ));
+
+        // The branch was renamed to main
+        let oid = commit(&git, "Initial", &[]);
+        git.repo()
+            .reference("refs/remotes/origin/main", oid, false, "test")
+            .unwrap();
+        let error = git.check_master_ref(&config).unwrap_err();
+        assert!(error.messages()[0].ends_with(
+            "Was it renamed? Found 'main'. Set the right one with `git \
+             config spr.githubMasterBranch main`"
+        ));
+
+        git.repo()
+            .reference("refs/remotes/origin/master", oid, false, "test")
+            .unwrap();
+        assert!(git.check_master_ref(&config).is_ok());
+    }
+
+    #[test]
+    fn test_create_derived_commit_with_commit_date() {
+        let repo = init_test_repo("derived-date");
+        let git = repo.clone().with_commit_date(Some(1_000));
+        let original = commit(&git, "original", &[]);
+        let tree_oid = git.get_tree_oid_for_commit(original).unwrap();
+
+        let derive = || {
+            git.create_derived_commit(
+                original,
+                "derived",
+                tree_oid,
+                &[original],
+                None,
+                None,
+            )
+            .unwrap()
+        };
+        let first = derive();
+        {
+            let repo = git.repo();
+            let derived = repo.find_commit(first).unwrap();
+            assert_eq!(derived.author().when().seconds(), 1_000);
+            assert_eq!(derived.committer().when().seconds(), 1_000);
+        }
+
+        // Without a commit date, the same commit gets the current time
+        let now = repo
+            .create_derived_commit(
+                original,
+                "derived",
+                tree_oid,
+                &[original],
+                None,
+                None,
+            )
+            .unwrap();
+        assert_ne!(now, first);
+    }
+
+    #[test]
+    fn test_create_derived_commit_with_identity() {
+        let git = init_test_repo("derived-identity");
+        let original = commit(&git, "original", &[]);
+        let tree_oid = git.get_tree_oid_for_commit(original).unwrap();
+        let bot = CommitIdentity {
+            name: "spr bot".into(),
+            email: "bot@example.com".into(),
+        };
+
+        let derived = git
+            .create_derived_commit(
+                original,
+                "derived",
+                tree_oid,
+                &[original],
+                Some(&bot),
+                Some(&bot),
+            )
+            .unwrap();
+        let plain = git
+            .create_derived_commit(
+                original,
+                "plain",
+                tree_oid,
+                &[original],
+                None,
+                None,
+            )
+            .unwrap();
+
+        let repo = git.repo();
+        let derived = repo.find_commit(derived).unwrap();
+        assert_eq!(derived.author().name(), Some("spr bot"));
+        assert_eq!(derived.author().email(), Some("bot@example.com"));
+        assert_eq!(derived.committer().name(), Some("spr bot"));
+        assert_eq!(derived.committer().email(), Some("bot@example.com"));
+
+        let plain = repo.find_commit(plain).unwrap();
+        assert_eq!(plain.author().name(), Some("Test"));
+
+        // The original commit is left alone
+        let original = repo.find_commit(original).unwrap();
+        assert_eq!(original.author().name(), Some("Test"));
+        assert_eq!(original.committer().name(), Some("Test"));
+    }
+
+    #[test]
+    fn test_add_trailer() {
+        let trailer = "Signed-off-by: Jane <jane@example.com>";
+        assert_eq!(
+            add_trailer("Title\n\nSummary\n", trailer),
+            "Title\n\nSummary\n\nSigned-off-by: Jane <jane@example.com>"
+        );
+        assert_eq!(
+            add_trailer("Title\n\nCo-authored-by: Bob <bob@b.com>", trailer),
+            "Title\n\nCo-authored-by: Bob <bob@b.com>\n\
+             Signed-off-by: Jane <jane@example.com>"
+        );
+        assert_eq!(
+            add_trailer(
+                "Title\n\nSigned-off-by: Jane <jane@example.com>",
+                trailer
+            ),
+            "Title\n\nSigned-off-by: Jane <jane@example.com>"
+        );
+    }
+
+    #[test]
+    fn test_derived_commit_signoff() {
+        let git = init_test_repo("derived-signoff");
+        let original = commit(&git, "original", &[]);
+        let tree_oid = git.get_tree_oid_for_commit(original).unwrap();
+        let mut config = config_factory();
+        config.derived_commit_committer = Some(CommitIdentity {
+            name: "spr bot".into(),
+            email: "bot@example.com".into(),
+        });
+
+        let message = git
+            .derived_commit_message(&config, "Title\n\nSummary".into())
+            .unwrap();
+        assert_eq!(message, "Title\n\nSummary");
+
+        config.signoff = true;
+        let message = git
+            .derived_commit_message(&config, "Title\n\nSummary".into())
+            .unwrap();
+        let derived = git
+            .create_derived_commit(
+                original,
+                &message,
+                tree_oid,
+                &[original],
+                None,
+                config.derived_commit_committer.as_ref(),
+            )
+            .unwrap();
+
+        let repo = git.repo();
+        assert_eq!(
+            repo.find_commit(derived).unwrap().message(),
+            Some("Title\n\nSummary\n\nSigned-off-by: spr bot <bot@example.com>\n")
+        );
+    }
+
+    #[test]
+    fn test_check_stack_depth() {
+        let git = init_test_repo("max-stack-depth");
+        let root = commit(&git, "root", &[]);
+        let first = commit(&git, "first", &[root]);
+        let second = commit(&git, "second", &[first]);
+        {
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/master", root, true, "test")
+                .unwrap();
+            repo.set_head_detached(second).unwrap();
+        }
+
+        let mut config = config_factory();
+        assert_eq!(
+            config.max_stack_depth,
+            crate::config::DEFAULT_MAX_STACK_DEPTH
+        );
+        config.max_stack_depth = 2;
+        assert!(git.check_stack_depth(&config).is_ok());
+
+        config.max_stack_depth = 1;
+        let error = git.check_stack_depth(&config).unwrap_err();
+        assert!(error.messages()[0].contains("maximum of 1"));
+
+        config.max_stack_depth = 0;
+        assert!(git.check_stack_depth(&config).is_ok());
+    }
+
+    #[test]
+    fn test_get_prepared_commits_with_merge_commit() {
+        let git = init_test_repo("merge-commit");
+        let root = commit(&git, "root", &[]);
+        let first = commit(&git, "first", &[root]);
+        let side = commit(&git, "side", &[root]);
+        let merge = commit(&git, "Merge branch 'side'", &[first, side]);
+        let last = commit(&git, "last", &[merge]);
+        {
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/master", root, true, "test")
+                .unwrap();
+            repo.set_head_detached(last).unwrap();
+        }
+
+        let mut config = config_factory();
+        let error = git.get_prepared_commits(&config, None).unwrap_err();
+        assert!(error.messages()[0]
+            .contains("(Merge branch 'side') is a merge commit"));
+        assert!(error.messages()[0].contains("--first-parent"));
+
+        // The commit from the merged branch is left out, and the merge commit
+        // is based on its first parent
+        config.first_parent = true;
+        let commits = git.get_prepared_commits(&config, None).unwrap();
+        assert_eq!(
+            commits.iter().map(|c| c.oid).collect::<Vec<_>>(),
+            vec![first, merge, last]
+        );
+        assert_eq!(commits[1].parent_oid, first);
+    }
+
+    #[test]
+    fn test_rebase_commits_with_merge_commit() {
+        let git = init_test_repo("rebase-merge-commit");
+        let root = commit_files(&git, &[("r", "r")], "root", &[]);
+        let first = commit_with_file(&git, "first", "1", root);
+        let side = commit_with_file(&git, "side", "s", root);
+        let merge = commit_files(
+            &git,
+            &[("r", "r"), ("first", "1"), ("side", "s")],
+            "Merge branch 'side'",
+            &[first, side],
+        );
+        let last = commit_with_file(&git, "last", "l", merge);
+        let new_master = commit_with_file(&git, "new", "n", root);
+        {
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/master", root, false, "test")
+                .unwrap();
+            repo.reference("refs/heads/master", last, true, "test")
+                .unwrap();
+            repo.checkout_head(Some(
+                git2::build::CheckoutBuilder::new().force(),
             ))
+            .unwrap();
+        }
+        let mut config = config_factory();
+        config.first_parent = true;
+
+        // The merge commit is cherry-picked with all it merges in
+        let index = git.cherrypick(merge, new_master).unwrap();
+        let tree = git.write_index(index).unwrap();
+        {
+            let repo = git.repo();
+            let tree = repo.find_tree(tree).unwrap();
+            assert!(tree.get_name("side").is_some());
+            assert!(tree.get_name("first").is_none());
+        }
+
+        let mut commits = git.get_prepared_commits(&config, None).unwrap();
+        git.rebase_commits(&mut commits, new_master).unwrap();
+
+        let repo = git.repo();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let tree = head.tree().unwrap();
+        let mut names = tree
+            .iter()
+            .map(|entry| entry.name().unwrap().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["first", "last", "new", "r", "side"]);
+
+        // The stack is linear now, with the merge commit a regular commit
+        let merge = head.parent(0).unwrap();
+        assert_eq!(merge.summary(), Some("Merge branch 'side'"));
+        assert_eq!(merge.parent_count(), 1);
+        assert_eq!(merge.parent(0).unwrap().parent_id(0).unwrap(), new_master);
+    }
+
+    #[test]
+    fn test_with_branch() {
+        let git = init_test_repo("with-branch");
+        let root = commit(&git, "root", &[]);
+        let first = commit(&git, "first", &[root]);
+        let second = commit(&git, "second", &[first]);
+        {
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/master", root, true, "test")
+                .unwrap();
+            repo.reference("refs/heads/feature", second, true, "test")
+                .unwrap();
+            repo.set_head_detached(root).unwrap();
+        }
+        let config = config_factory();
+
+        assert!(git.get_prepared_commits(&config, None).unwrap().is_empty());
+        assert!(git.with_branch("nope").is_err());
+
+        let feature = git.with_branch("feature").unwrap();
+        assert_eq!(feature.head().unwrap(), second);
+        let mut commits = feature.get_prepared_commits(&config, None).unwrap();
+        assert_eq!(
+            commits.iter().map(|c| c.oid).collect::<Vec<_>>(),
+            vec![first, second]
+        );
+
+        // Rewriting messages moves the branch, and leaves HEAD alone
+        commits[0]
+            .message
+            .insert(MessageSection::Summary, "Amended".into());
+        feature.rewrite_commit_messages(&mut commits, None).unwrap();
+        assert_eq!(feature.head().unwrap(), commits[1].oid);
+        assert_ne!(commits[1].oid, second);
+        assert_eq!(git.head().unwrap(), root);
+    }
+
+    #[test]
+    fn test_branches_based_on() {
+        let git = init_test_repo("branches-based-on");
+        let master = commit(&git, "master", &[]);
+        let pr_head = commit(&git, "pr", &[master]);
+        let dependent = commit(&git, "dependent", &[pr_head]);
+        let unrelated = commit(&git, "unrelated", &[master]);
+        {
+            let repo = git.repo();
+            for (name, oid) in [
+                ("master", dependent),
+                ("spr/foo/pr", pr_head),
+                ("spr/bar/dependent", dependent),
+                ("unrelated", unrelated),
+            ] {
+                repo.reference(
+                    &format!("refs/remotes/origin/{name}"),
+                    oid,
+                    true,
+                    "test",
+                )
+                .unwrap();
+            }
         }
+        let config = config_factory();
+
+        assert_eq!(
+            git.branches_based_on(&config, pr_head, "spr/foo/pr")
+                .unwrap(),
+            vec!["spr/bar/dependent".to_string()]
+        );
+        assert!(git
+            .branches_based_on(&config, dependent, "spr/bar/dependent")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_select_commit_range() {
+        let git = init_test_repo("select-commit-range");
+        let root = commit(&git, "root", &[]);
+        let first = commit(&git, "first", &[root]);
+        let second = commit(&git, "second", &[first]);
+        let third = commit(&git, "third", &[second]);
+        {
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/master", root, true, "test")
+                .unwrap();
+            repo.set_head_detached(third).unwrap();
+        }
+        let config = config_factory();
+        let prepared_commits = git.get_prepared_commits(&config, None).unwrap();
+
+        assert_eq!(
+            git.select_commit_range(&prepared_commits, "HEAD~2..HEAD")
+                .unwrap(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            git.select_commit_range(&prepared_commits, &format!("{first}"))
+                .unwrap(),
+            vec![0]
+        );
+        assert_eq!(
+            git.select_commit_range(&prepared_commits, "HEAD~3..HEAD")
+                .unwrap(),
+            vec![0, 1, 2]
+        );
+
+        // The root commit is on master, not on the stack
+        let error = git
+            .select_commit_range(&prepared_commits, &format!("{root}"))
+            .unwrap_err();
+        assert!(error.messages()[0].contains("not on the local stack"));
+
+        assert!(git
+            .select_commit_range(&prepared_commits, "HEAD..HEAD")
+            .is_err());
+    }
+
+    fn github_branch_test_setup(
+        name: &str,
+    ) -> (TestRepo, crate::config::Config) {
+        let git = init_test_repo(name);
+        let root = commit(&git, "root", &[]);
+        let main = commit(&git, "main", &[root]);
+        {
+            let repo = git.repo();
+            repo.reference("refs/remotes/origin/main", main, true, "test")
+                .unwrap();
+            repo.reference("refs/heads/feature", root, true, "test")
+                .unwrap();
+            repo.reference("refs/heads/local-only", root, true, "test")
+                .unwrap();
+            repo.remote("origin", "https://github.com/acme/codez.git")
+                .unwrap();
+            let mut config = repo.config().unwrap();
+            config.set_str("branch.feature.remote", "origin").unwrap();
+            config
+                .set_str("branch.feature.merge", "refs/heads/main")
+                .unwrap();
+            repo.set_head("refs/heads/feature").unwrap();
+        }
+        let config = crate::config::Config::new(
+            "acme".into(),
+            "codez".into(),
+            "origin".into(),
+            "main".into(),
+            "spr/foo/".into(),
+            false,
+            false,
+        );
+        (git, config)
+    }
+
+    #[test]
+    fn test_resolve_github_branch_name_remote_branch() {
+        let (git, config) = github_branch_test_setup("base-remote");
+        assert_eq!(
+            git.resolve_github_branch_name(&config, "origin/main")
+                .unwrap(),
+            Some("main".into())
+        );
+        assert_eq!(
+            git.resolve_github_branch_name(&config, "does-not-exist")
+                .unwrap(),
+            None
+        );
+
+        // A commit that is not the tip of any branch
+        let root = git.resolve_revision("HEAD").unwrap();
+        assert!(git
+            .resolve_github_branch_name(&config, &root.to_string())
+            .is_err());
+    }
+
+    #[test]
+    fn test_resolve_github_branch_name_upstream() {
+        let (git, config) = github_branch_test_setup("base-upstream");
+        assert_eq!(
+            git.resolve_github_branch_name(&config, "@{upstream}")
+                .unwrap(),
+            Some("main".into())
+        );
+        assert_eq!(
+            git.resolve_github_branch_name(&config, "feature").unwrap(),
+            Some("main".into())
+        );
+
+        // A local branch without an upstream is taken by its name
+        assert_eq!(
+            git.resolve_github_branch_name(&config, "local-only")
+                .unwrap(),
+            Some("local-only".into())
+        );
+
+        // Once the branch exists on GitHub, the name refers to that branch,
+        // not the upstream of the local branch
+        let root = git.resolve_revision("feature").unwrap();
+        git.repo()
+            .reference("refs/remotes/origin/feature", root, true, "test")
+            .unwrap();
+        assert_eq!(
+            git.resolve_github_branch_name(&config, "feature").unwrap(),
+            Some("feature".into())
+        );
+        assert_eq!(
+            git.resolve_github_branch_name(&config, "@{upstream}")
+                .unwrap(),
+            Some("main".into())
+        );
+    }
+
+    #[test]
+    fn test_is_empty_commit() {
+        let git = init_test_repo("empty-commit");
+        let master = commit(&git, "master", &[]);
+        let first = commit_with_file(&git, "a.txt", "a", master);
+        // What a rebase can leave behind: a commit whose changes are in
+        // master already
+        let empty = {
+            let repo = git.repo();
+            let signature =
+                git2::Signature::now("Test", "test@example.com").unwrap();
+            let parent = repo.find_commit(first).unwrap();
+            let oid = repo
+                .commit(
+                    None,
+                    &signature,
+                    &signature,
+                    "empty",
+                    &parent.tree().unwrap(),
+                    &[&parent],
+                )
+                .unwrap();
+            oid
+        };
+        let second = commit_with_file(&git, "b.txt", "b", empty);
+
+        assert!(!git.is_empty_commit(first).unwrap());
+        assert!(git.is_empty_commit(empty).unwrap());
+        assert!(!git.is_empty_commit(second).unwrap());
+        // A root commit is never empty
+        assert!(!git.is_empty_commit(master).unwrap());
+    }
+
+    #[test]
+    fn test_diffstat() {
+        let git = init_test_repo("diffstat");
+        let base = commit(&git, "Initial", &[]);
+        let first = commit_with_file(&git, "lib.rs", "fn a() {}\n", base);
+        let second =
+            commit_with_file(&git, "README.md", "# Title\n\nText\n", first);
+        let tree = |oid| git.get_tree_oid_for_commit(oid).unwrap();
+
+        assert_eq!(
+            git.diffstat(tree(first), tree(second)).unwrap(),
+            " README.md | 3 +++\n 1 file changed, 3 insertions(+)\n"
+        );
+        assert_eq!(
+            git.diffstat(tree(base), tree(second)).unwrap(),
+            " README.md | 3 +++\n lib.rs    | 1 +\n 2 files changed, 4 \
+             insertions(+)\n"
+        );
+    }
+
+    #[test]
+    fn test_same_changes() {
+        let git = init_test_repo("same-changes");
+        let tree = |content: &str| {
+            let repo = git.repo();
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            let mut builder = repo.treebuilder(None).unwrap();
+            builder.insert("file.txt", blob, 0o100644).unwrap();
+            builder.write().unwrap()
+        };
+        let lines = |changed: &str| {
+            let mut lines = (1..10).map(|n| n.to_string()).collect::<Vec<_>>();
+            lines[7] = changed.to_string();
+            lines.join("\n")
+        };
+        let old_base = tree(&lines("8"));
+        let old_head = tree(&lines("eight"));
+        // Master gained a line at the top in the meantime
+        let new_base = tree(&format!("0\n{}", lines("8")));
+
+        // Only rebased: the same change, at a different line number
+        let rebased = tree(&format!("0\n{}", lines("eight")));
+        assert!(git
+            .same_changes(old_base, old_head, new_base, rebased)
+            .unwrap());
+
+        // Rebased and amended
+        let amended = tree(&format!("0\n{}", lines("EIGHT")));
+        assert!(!git
+            .same_changes(old_base, old_head, new_base, amended)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_cherrypick_submodule_bump() {
+        let git = init_test_repo("submodule-bump");
+        let submodule_v1 =
+            Oid::from_str("1111111111111111111111111111111111111111").unwrap();
+        let submodule_v2 =
+            Oid::from_str("2222222222222222222222222222222222222222").unwrap();
+        // Commit a tree with the given README and submodule commit
+        let commit = |readme: &str, submodule: Oid, parents: &[Oid]| {
+            let tree = {
+                let repo = git.repo();
+                let mut builder = repo.treebuilder(None).unwrap();
+                let blob = repo.blob(readme.as_bytes()).unwrap();
+                builder.insert("README", blob, 0o100644).unwrap();
+                builder.insert("vendor", submodule, 0o160000).unwrap();
+                builder.write().unwrap()
+            };
+            commit_tree(&git, tree, readme, parents)
+        };
+        let base = commit("base", submodule_v1, &[]);
+        // The local commit only bumps the submodule...
+        let bump = commit("base", submodule_v2, &[base]);
+        // ...and master has moved on in the meantime
+        let master = commit("master", submodule_v1, &[base]);
+
+        let index = git.cherrypick(bump, master).unwrap();
+        assert!(!index.has_conflicts());
+        let tree_oid = git.write_index(index).unwrap();
+        assert_eq!(git.changed_paths(bump).unwrap(), vec!["vendor"]);
+        assert_eq!(git.count_changed_lines(bump).unwrap(), 2);
+
+        let repo = git.repo();
+        let tree = repo.find_tree(tree_oid).unwrap();
+        let entry = tree.get_name("vendor").unwrap();
+        assert_eq!(entry.id(), submodule_v2);
+        assert_eq!(entry.filemode(), 0o160000);
+        assert_eq!(entry.kind(), Some(git2::ObjectType::Commit));
+
+        // Compared to master, the only change is the submodule bump
+        let master_tree = repo.find_commit(master).unwrap().tree().unwrap();
+        let diff = repo
+            .diff_tree_to_tree(Some(&master_tree), Some(&tree), None)
+            .unwrap();
+        let deltas = diff.deltas().collect::<Vec<_>>();
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(
+            deltas[0].new_file().path().unwrap().to_str(),
+            Some("vendor")
+        );
+        assert_eq!(deltas[0].old_file().id(), submodule_v1);
+        assert_eq!(deltas[0].new_file().id(), submodule_v2);
+    }
+
+    #[test]
+    fn test_ref_names_are_read_once() {
+        let git = init_test_repo("ref-names");
+        let root = commit(&git, "root", &[]);
+        git.repo()
+            .reference("refs/heads/main", root, false, "test")
+            .unwrap();
+
+        let ref_names = git.get_all_ref_names().unwrap();
+        assert!(ref_names.contains("refs/heads/main"));
+
+        // A reference created behind our back is not seen, as the references
+        // are not walked again...
+        git.repo()
+            .reference("refs/remotes/origin/spr/foo/other", root, false, "test")
+            .unwrap();
+        let ref_names = git.get_all_ref_names().unwrap();
+        assert!(!ref_names.contains("refs/remotes/origin/spr/foo/other"));
+
+        // ...but the ones we add are
+        git.add_ref_name("refs/remotes/origin/spr/foo/new");
+        let ref_names = git.get_all_ref_names().unwrap();
+        assert!(ref_names.contains("refs/remotes/origin/spr/foo/new"));
+
+        git.forget_ref_names();
+        let ref_names = git.get_all_ref_names().unwrap();
+        assert!(ref_names.contains("refs/remotes/origin/spr/foo/other"));
+        assert!(!ref_names.contains("refs/remotes/origin/spr/foo/new"));
+    }
+
+    #[test]
+    fn test_check_head_is_attached() {
+        let git = init_test_repo("detached-head");
+        let root = commit(&git, "root", &[]);
+        git.repo().set_head_detached(root).unwrap();
+
+        assert!(git.check_head_is_attached(None).is_err());
+
+        assert!(git.check_head_is_attached(Some("rescued")).unwrap());
+        assert!(!git.repo().head_detached().unwrap());
+        assert_eq!(git.repo().head().unwrap().shorthand(), Some("rescued"));
+        assert_eq!(git.head().unwrap(), root);
+
+        assert!(!git.check_head_is_attached(None).unwrap());
+    }
+
+    #[test]
+    fn test_split_commit_trees() {
+        let git = init_test_repo("split-commit");
+        let root = commit(&git, "root", &[]);
+        let a = commit_with_file(&git, "a.rs", "a", root);
+        let b = commit_with_file(&git, "b.md", "b", a);
+        let c = commit_with_file(&git, "c.rs", "c", b);
+        let top = {
+            // One commit containing all three files
+            let repo = git.repo();
+            let signature =
+                git2::Signature::now("Test", "test@example.com").unwrap();
+            let tree = repo.find_commit(c).unwrap().tree().unwrap();
+            let root = repo.find_commit(root).unwrap();
+            repo.commit(None, &signature, &signature, "all", &tree, &[&root])
+                .unwrap()
+        };
+
+        let trees = git.split_commit_trees(top, &["*.md".to_string()]).unwrap();
+        assert_eq!(trees.len(), 2);
+        {
+            let repo = git.repo();
+            let first = repo.find_tree(trees[0]).unwrap();
+            assert!(first.get_name("b.md").is_some());
+            assert!(first.get_name("a.rs").is_none());
+            assert_eq!(trees[1], repo.find_commit(top).unwrap().tree_id());
+        }
+
+        // Patterns claiming all files leave no remainder
+        let trees = git
+            .split_commit_trees(top, &["*.md".to_string(), "*.rs".to_string()])
+            .unwrap();
+        assert_eq!(trees.len(), 2);
+
+        assert!(git.split_commit_trees(top, &["*.txt".to_string()]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_commit_message_hooks() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let git = init_test_repo("commit-hooks");
+        let hooks_dir = git.repo().path().join("hooks");
+        std::fs::create_dir_all(&hooks_dir).unwrap();
+
+        // Without hooks, the message is unchanged
+        assert_eq!(git.run_commit_message_hooks("Title\n").unwrap(), "Title\n");
+
+        let hook = hooks_dir.join("commit-msg");
+        std::fs::write(
+            &hook,
+            "#!/bin/sh\nprintf '\\nChange-Id: I123\\n' >> \"$1\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        assert_eq!(
+            git.run_commit_message_hooks("Title\n").unwrap(),
+            "Title\n\nChange-Id: I123\n"
+        );
+
+        std::fs::write(&hook, "#!/bin/sh\nexit 1\n").unwrap();
+        assert!(git.run_commit_message_hooks("Title\n").is_err());
+    }
+
+    #[test]
+    fn test_resolve_tag_base() {
+        let (git, config) = github_branch_test_setup("base-tag");
+        let main = git.resolve_reference("refs/remotes/origin/main").unwrap();
+        let root = git.repo().find_commit(main).unwrap().parent_id(0).unwrap();
+        let orphan = commit(&git, "orphan", &[]);
+        {
+            let repo = git.repo();
+            repo.reference("refs/tags/v1", root, true, "test").unwrap();
+            repo.reference("refs/tags/v2", main, true, "test").unwrap();
+            repo.reference("refs/tags/orphan", orphan, true, "test")
+                .unwrap();
+        }
+
+        assert_eq!(
+            git.resolve_tag_base(&config, "v1").unwrap(),
+            Some((root, "main".to_string()))
+        );
+        assert_eq!(
+            git.resolve_tag_base(&config, "v2").unwrap(),
+            Some((main, "main".to_string()))
+        );
+        assert_eq!(git.resolve_tag_base(&config, "v3").unwrap(), None);
+        assert!(git.resolve_tag_base(&config, "orphan").is_err());
+    }
+
+    fn prepared_commit_factory(message: &str) -> PreparedCommit {
+        PreparedCommit {
+            oid: Oid::zero(),
+            short_id: "0000000".into(),
+            parent_oid: Oid::zero(),
+            message: parse_message(message, MessageSection::Title),
+            pull_request_number: None,
+            pull_request_task: None,
+        }
+    }
+
+    #[test]
+    fn test_is_fixup() {
+        let commits = [
+            prepared_commit_factory("Add feature"),
+            prepared_commit_factory("fixup! Add feature"),
+            prepared_commit_factory("Fix bug\n\nsquash! is mentioned here"),
+            prepared_commit_factory("squash! Fix bug"),
+            prepared_commit_factory("amend! Fix bug"),
+        ];
+
+        assert_eq!(
+            commits.iter().map(|c| c.is_fixup()).collect::<Vec<_>>(),
+            vec![false, true, false, true, true]
+        );
+    }
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let git = init_test_repo("lock-exclusive");
+
+        let lock = git.lock().unwrap();
+        assert!(git.lock().is_err());
+
+        drop(lock);
+        assert!(git.lock().is_ok());
+    }
+
+    #[test]
+    fn test_stale_lock_is_replaced() {
+        let git = init_test_repo("lock-stale");
+        let path = git.repo().path().join("spr.lock");
+
+        // Held by a running process (this one)
+        std::fs::write(&path, format!("{}\n", std::process::id())).unwrap();
+        assert!(git.lock().is_err());
+
+        // Without a process ID, it may be just being written
+        std::fs::write(&path, "").unwrap();
+        assert!(git.lock().is_err());
+
+        // Left behind by a process that is gone. PIDs on Linux are below
+        // 2^22.
+        std::fs::write(&path, "4194304\n").unwrap();
+        let lock = git.lock().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(lock);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_find_lost_master_base() {
+        let git = init_test_repo("lost-master-base");
+
+        let root = commit(&git, "root", &[]);
+        let master = commit(&git, "master", &[root]);
+        let pr_head = commit(&git, "PR\n\nCreated using spr 1.0", &[master]);
+
+        // Master moved on normally
+        let new_master = commit(&git, "new master", &[master]);
+        assert_eq!(
+            git.find_lost_master_base(pr_head, new_master).unwrap(),
+            None
+        );
+
+        // Master was force-pushed, dropping the commit the PR is based on
+        let rewritten_master = commit(&git, "rewritten master", &[root]);
+        assert_eq!(
+            git.find_lost_master_base(pr_head, rewritten_master)
+                .unwrap(),
+            Some(master)
+        );
     }
 }
@@ -9,25 +9,90 @@ use graphql_client::{GraphQLQuery, Response};
 use serde::Deserialize;
 
 use crate::{
-    error::{Error, Result, ResultExt},
+    error::{Error, ErrorKind, Result, ResultExt},
     git::Git,
     message::{
-        build_github_body, parse_message, MessageSection, MessageSectionsMap,
+        build_github_body, parse_message, with_diffstat, MessageSection,
+        MessageSectionsMap,
     },
 };
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+#[cfg(feature = "attachments")]
+use crate::{
+    output::output,
+    utils::{is_image_file_name, run_command},
+};
+
+/// The HTTP client for GitHub's GraphQL API, authenticating with the given
+/// token and sending the given extra headers with every request. Proxies are
+/// taken from the environment (`HTTPS_PROXY`, `NO_PROXY` etc.).
+pub fn graphql_client_builder(
+    token: &str,
+    extra_headers: &[(String, String)],
+) -> Result<reqwest::ClientBuilder> {
+    use reqwest::header;
+
+    let mut headers = header::HeaderMap::new();
+    headers.insert(header::ACCEPT, "application/json".parse()?);
+    headers.insert(
+        header::USER_AGENT,
+        format!("spr/{}", env!("CARGO_PKG_VERSION")).try_into()?,
+    );
+    headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse()?);
+    for (name, value) in extra_headers {
+        headers.append(
+            header::HeaderName::from_bytes(name.as_bytes())?,
+            value.parse()?,
+        );
+    }
+
+    Ok(reqwest::Client::builder().default_headers(headers))
+}
+
+/// Caps the number of lookups on GitHub in flight at the same time, so that
+/// a burst of them (e.g. for the reviewers of a whole stack) doesn't trip
+/// GitHub's secondary rate limits. Clones share the cap.
+#[derive(Clone, Debug)]
+pub struct ApiLimiter {
+    permits: Option<Arc<tokio::sync::Semaphore>>,
+}
+
+impl ApiLimiter {
+    /// Allow at most `max` lookups at a time. Zero means no limit.
+    pub fn new(max: usize) -> Self {
+        Self {
+            permits: (max > 0)
+                .then(|| Arc::new(tokio::sync::Semaphore::new(max))),
+        }
+    }
+
+    /// Run the given lookup once there is room for it
+    pub async fn run<F: std::future::Future>(&self, lookup: F) -> F::Output {
+        let _permit = match &self.permits {
+            Some(permits) => permits.acquire().await.ok(),
+            None => None,
+        };
+        lookup.await
+    }
+}
 
 #[derive(Clone)]
 pub struct GitHub {
     config: crate::config::Config,
     git: crate::git::Git,
     graphql_client: reqwest::Client,
+    api_limiter: ApiLimiter,
 }
 
 #[derive(Debug, Clone)]
 pub struct PullRequest {
     pub number: u64,
     pub state: PullRequestState,
+    pub is_draft: bool,
     pub title: String,
     pub body: Option<String>,
     pub sections: MessageSectionsMap,
@@ -37,6 +102,9 @@ pub struct PullRequest {
     pub head_oid: git2::Oid,
     pub merge_commit: Option<git2::Oid>,
     pub reviewers: HashMap<String, ReviewStatus>,
+    /// Reviewers (users by login, teams as `#slug`) that are requested but
+    /// have not submitted a review yet
+    pub requested_reviewers: Vec<String>,
     pub review_status: Option<ReviewStatus>,
 }
 
@@ -82,9 +150,60 @@ impl PullRequestUpdate {
             self.body = Some(body);
         }
     }
+
+    /// Put the diffstat at the top of the description, on top of the
+    /// description that is to be set, or the current one
+    pub fn update_diffstat(
+        &mut self,
+        pull_request: &PullRequest,
+        diffstat: &str,
+    ) {
+        let body = self
+            .body
+            .as_deref()
+            .or(pull_request.body.as_deref())
+            .unwrap_or_default();
+        let body = with_diffstat(body, diffstat);
+        if pull_request.body.as_ref() != Some(&body) {
+            self.body = Some(body);
+        } else {
+            self.body = None;
+        }
+    }
+
+    /// Set the title of the Pull Request, independently of the commit message
+    pub fn update_title(&mut self, pull_request: &PullRequest, title: &str) {
+        if title != pull_request.title {
+            self.title = Some(title.to_string());
+        } else {
+            self.title = None;
+        }
+    }
+
+    /// Bring the title and description in line with the given commit
+    /// message, title and diffstat, each if given. This is the order the
+    /// updates must happen in: the title overrides the one of the message,
+    /// and the diffstat goes on top of the new description.
+    pub fn update_title_and_description(
+        &mut self,
+        pull_request: &PullRequest,
+        message: Option<&MessageSectionsMap>,
+        title: Option<&str>,
+        diffstat: Option<&str>,
+    ) {
+        if let Some(message) = message {
+            self.update_message(pull_request, message);
+        }
+        if let Some(title) = title {
+            self.update_title(pull_request, title);
+        }
+        if let Some(diffstat) = diffstat {
+            self.update_diffstat(pull_request, diffstat);
+        }
+    }
 }
 
-#[derive(serde::Serialize, Default, Debug)]
+#[derive(serde::Serialize, Default, Debug, PartialEq, Eq)]
 pub struct PullRequestRequestReviewers {
     pub reviewers: Vec<String>,
     pub team_reviewers: Vec<String>,
@@ -105,6 +224,79 @@ pub struct UserWithName {
     pub is_collaborator: bool,
 }
 
+/// The parts of the branch protection settings of a branch that spr looks at
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct BranchProtection {
+    pub required_pull_request_reviews: Option<RequiredPullRequestReviews>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct RequiredPullRequestReviews {
+    #[serde(default)]
+    pub required_approving_review_count: u64,
+}
+
+impl BranchProtection {
+    /// The number of approvals a Pull Request needs before it can be merged
+    pub fn required_approvals(&self) -> u64 {
+        self.required_pull_request_reviews
+            .as_ref()
+            .map(|reviews| reviews.required_approving_review_count)
+            .unwrap_or(0)
+    }
+}
+
+/// State of a check (or commit status) on a commit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pending,
+    Success,
+    Failure,
+}
+
+/// The checks on a commit pushed for a Pull Request
+#[derive(Debug, Clone)]
+pub struct PullRequestChecks {
+    pub id: String,
+    pub is_draft: bool,
+    /// The names of the checks the protection rule of the base branch
+    /// requires
+    pub required: Vec<String>,
+    /// The checks reported on the commit so far, by name
+    pub checks: Vec<(String, CheckStatus)>,
+}
+
+impl PullRequestChecks {
+    /// The combined state of the required checks: failing as soon as one of
+    /// them fails, and passing once all of them have passed. Checks that have
+    /// not been reported yet are pending. `None` if no checks are required.
+    pub fn required_status(&self) -> Option<CheckStatus> {
+        if self.required.is_empty() {
+            return None;
+        }
+
+        let mut status = CheckStatus::Success;
+        for name in &self.required {
+            match self
+                .checks
+                .iter()
+                .rev()
+                .find(|(check, _)| check == name)
+                .map(|(_, status)| *status)
+            {
+                Some(CheckStatus::Failure) => {
+                    return Some(CheckStatus::Failure)
+                }
+                Some(CheckStatus::Success) => (),
+                Some(CheckStatus::Pending) | None => {
+                    status = CheckStatus::Pending
+                }
+            }
+        }
+        Some(status)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PullRequestMergeability {
     pub base: GitHubBranch,
@@ -122,6 +314,14 @@ pub struct PullRequestMergeability {
 pub struct PullRequestQuery;
 type GitObjectID = String;
 
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/schema.docs.graphql",
+    query_path = "src/gql/pullrequest_checks_query.graphql",
+    response_derives = "Debug"
+)]
+pub struct PullRequestChecksQuery;
+
 #[derive(GraphQLQuery)]
 #[graphql(
     schema_path = "src/gql/schema.docs.graphql",
@@ -130,73 +330,321 @@ type GitObjectID = String;
 )]
 pub struct PullRequestMergeabilityQuery;
 
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/schema.docs.graphql",
+    query_path = "src/gql/pullrequest_draft_mutations.graphql",
+    response_derives = "Debug"
+)]
+pub struct ConvertPullRequestToDraftMutation;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/schema.docs.graphql",
+    query_path = "src/gql/pullrequest_draft_mutations.graphql",
+    response_derives = "Debug"
+)]
+pub struct MarkPullRequestReadyForReviewMutation;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/schema.docs.graphql",
+    query_path = "src/gql/pullrequest_reviews_dismiss.graphql",
+    response_derives = "Debug"
+)]
+pub struct PullRequestApprovedReviewsQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/schema.docs.graphql",
+    query_path = "src/gql/pullrequest_reviews_dismiss.graphql",
+    response_derives = "Debug"
+)]
+pub struct DismissPullRequestReviewMutation;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/schema.docs.graphql",
+    query_path = "src/gql/pullrequest_auto_merge.graphql",
+    response_derives = "Debug"
+)]
+pub struct PullRequestIdQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/schema.docs.graphql",
+    query_path = "src/gql/pullrequest_auto_merge.graphql",
+    response_derives = "Debug"
+)]
+pub struct EnablePullRequestAutoMergeMutation;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/schema.docs.graphql",
+    query_path = "src/gql/project_add_item.graphql",
+    response_derives = "Debug"
+)]
+pub struct ProjectsQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/schema.docs.graphql",
+    query_path = "src/gql/project_add_item.graphql",
+    response_derives = "Debug"
+)]
+pub struct AddProjectItemMutation;
+
+/// How GitHub merges a Pull Request once auto-merge kicks in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MergeMethod {
+    Merge,
+    Squash,
+    Rebase,
+}
+
+/// The commit a Pull Request was merged as, from GitHub's response to the
+/// merge request
+pub fn merged_commit_oid(
+    merge: octocrab::models::pulls::Merge,
+) -> Result<git2::Oid> {
+    if !merge.merged {
+        return Err(Error::new(format!(
+            "GitHub Pull Request merge failed: {}",
+            merge.message.unwrap_or_default()
+        ))
+        .with_kind(ErrorKind::GitHub));
+    }
+
+    let sha = merge.sha.ok_or_else(|| {
+        Error::new("GitHub did not report the merge commit")
+            .with_kind(ErrorKind::GitHub)
+    })?;
+    Ok(git2::Oid::from_str(&sha)?)
+}
+
+impl MergeMethod {
+    fn to_graphql(
+        self,
+    ) -> enable_pull_request_auto_merge_mutation::PullRequestMergeMethod {
+        use enable_pull_request_auto_merge_mutation::PullRequestMergeMethod;
+        match self {
+            MergeMethod::Merge => PullRequestMergeMethod::MERGE,
+            MergeMethod::Squash => PullRequestMergeMethod::SQUASH,
+            MergeMethod::Rebase => PullRequestMergeMethod::REBASE,
+        }
+    }
+}
+
+/// Whether the given error is GitHub telling us that the requested Pull
+/// Request does not exist
+pub fn is_not_found_error(error: &Error) -> bool {
+    error
+        .messages()
+        .iter()
+        .any(|message| message.contains("Could not resolve to a PullRequest"))
+}
+
+/// If the given GitHub error message says that auto-merge is not available
+/// on the repository, return an explanation of how to fix that.
+pub fn explain_auto_merge_error(message: &str) -> Option<String> {
+    let lower = message.to_ascii_lowercase();
+    if lower.contains("auto merge is not allowed")
+        || lower.contains("auto-merge is not allowed")
+        || lower.contains("auto merge is not enabled")
+        || lower.contains("auto-merge is not enabled")
+    {
+        Some(
+            "Auto-merge is not enabled for this repository. A repository \
+             admin can turn it on under Settings > General > \
+             'Allow auto-merge'."
+                .to_string(),
+        )
+    } else {
+        None
+    }
+}
+
 impl GitHub {
     pub fn new(
         config: crate::config::Config,
         git: crate::git::Git,
         graphql_client: reqwest::Client,
     ) -> Self {
+        let api_limiter = ApiLimiter::new(config.max_concurrent_api);
         Self {
             config,
             git,
             graphql_client,
+            api_limiter,
         }
     }
 
-    pub async fn get_github_user(login: String) -> Result<UserWithName> {
-        octocrab::instance()
-            .get::<UserWithName, _, _>(format!("users/{}", login), None::<&()>)
+    /// Send the GraphQL query (or mutation) with the given variables to
+    /// GitHub, once the API limiter has room for it
+    async fn graphql_response<Q: GraphQLQuery>(
+        &self,
+        variables: Q::Variables,
+    ) -> Result<Response<Q::ResponseData>> {
+        let request_body = Q::build_query(variables);
+        self.api_limiter
+            .run(async {
+                self.graphql_client
+                    .post("https://api.github.com/graphql")
+                    .json(&request_body)
+                    .send()
+                    .await?
+                    .json()
+                    .await
+            })
+            .await
+            .with_kind(ErrorKind::GitHub)
+    }
+
+    /// Send the GraphQL query (or mutation) with the given variables to
+    /// GitHub, and return the data of the response. Errors in the response
+    /// make this fail, with their messages.
+    async fn graphql<Q: GraphQLQuery>(
+        &self,
+        variables: Q::Variables,
+    ) -> Result<Q::ResponseData> {
+        graphql_data(self.graphql_response::<Q>(variables).await?)
+    }
+
+    pub async fn get_github_user(&self, login: String) -> Result<UserWithName> {
+        self.api_limiter
+            .run(octocrab::instance().get::<UserWithName, _, _>(
+                format!("users/{}", login),
+                None::<&()>,
+            ))
+            .await
+            .with_kind(ErrorKind::GitHub)
+    }
+
+    /// The name of the default branch of the repository on GitHub
+    pub async fn get_default_branch(&self) -> Result<Option<String>> {
+        let repository = self
+            .api_limiter
+            .run(
+                octocrab::instance()
+                    .repos(&self.config.owner, &self.config.repo)
+                    .get(),
+            )
+            .await
+            .with_kind(ErrorKind::GitHub)?;
+
+        Ok(repository.default_branch)
+    }
+
+    /// The branch protection settings of the given branch, or `None` if the
+    /// branch is not protected. Reading them needs admin access to the
+    /// repository, so expect this to fail for many users.
+    pub async fn get_branch_protection(
+        &self,
+        branch_name: &str,
+    ) -> Result<Option<BranchProtection>> {
+        let result = self
+            .api_limiter
+            .run(octocrab::instance().get::<BranchProtection, _, _>(
+                format!(
+                    "repos/{}/{}/branches/{}/protection",
+                    self.config.owner, self.config.repo, branch_name
+                ),
+                None::<&()>,
+            ))
+            .await;
+        match result {
+            Ok(protection) => Ok(Some(protection)),
+            Err(octocrab::Error::GitHub { source, .. })
+                if source.message == "Branch not protected" =>
+            {
+                Ok(None)
+            }
+            Err(error) => Err(error).with_kind(ErrorKind::GitHub),
+        }
+    }
+
+    /// The title of a Pull Request in another repository (e.g. a Pull
+    /// Request that one in this repository depends on)
+    pub async fn get_pull_request_title_in(
+        &self,
+        owner: &str,
+        repo: &str,
+        number: u64,
+    ) -> Result<String> {
+        let pull_request = self
+            .api_limiter
+            .run(octocrab::instance().pulls(owner, repo).get(number))
+            .await
+            .with_kind(ErrorKind::GitHub)?;
+
+        Ok(pull_request.title.unwrap_or_default())
+    }
+
+    /// Whether the Pull Request with the given number exists in the
+    /// repository
+    pub async fn pull_request_exists(&self, number: u64) -> Result<bool> {
+        let result = self
+            .api_limiter
+            .run(
+                octocrab::instance()
+                    .pulls(&self.config.owner, &self.config.repo)
+                    .get(number),
+            )
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(octocrab::Error::GitHub { source, .. })
+                if source.message == "Not Found" =>
+            {
+                Ok(false)
+            }
+            Err(error) => Err(error).with_kind(ErrorKind::GitHub),
+        }
+    }
+
+    /// The login of the user spr acts as
+    pub async fn get_current_user_login() -> Result<String> {
+        Ok(octocrab::instance()
+            .current()
+            .user()
             .await
-            .map_err(Error::from)
+            .with_kind(ErrorKind::GitHub)?
+            .login)
     }
 
     pub async fn get_github_team(
+        &self,
         owner: String,
         team: String,
     ) -> Result<octocrab::models::teams::Team> {
-        octocrab::instance()
-            .teams(owner)
-            .get(team)
+        self.api_limiter
+            .run(octocrab::instance().teams(owner).get(team))
             .await
-            .map_err(Error::from)
+            .with_kind(ErrorKind::GitHub)
     }
 
     pub async fn get_pull_request(self, number: u64) -> Result<PullRequest> {
-        let GitHub {
-            config,
-            git,
-            graphql_client,
-        } = self;
-
         let variables = pull_request_query::Variables {
-            name: config.repo.clone(),
-            owner: config.owner.clone(),
+            name: self.config.repo.clone(),
+            owner: self.config.owner.clone(),
             number: number as i64,
         };
-        let request_body = PullRequestQuery::build_query(variables);
-        let res = graphql_client
-            .post("https://api.github.com/graphql")
-            .json(&request_body)
-            .send()
-            .await?;
-        let response_body: Response<pull_request_query::ResponseData> =
-            res.json().await?;
-
-        if let Some(errors) = response_body.errors {
-            let error =
-                Err(Error::new(format!("fetching PR #{number} failed")));
-            return errors
-                .into_iter()
-                .fold(error, |err, e| err.context(e.to_string()));
-        }
+        let data = self
+            .graphql::<PullRequestQuery>(variables)
+            .await
+            .context(format!("fetching PR #{number} failed"))?;
 
-        let pr = response_body
-            .data
-            .ok_or_else(|| Error::new("failed to fetch PR"))?
+        let GitHub { config, git, .. } = self;
+        let pr = data
             .repository
-            .ok_or_else(|| Error::new("failed to find repository"))?
+            .ok_or_else(|| {
+                Error::new("failed to find repository")
+                    .with_kind(ErrorKind::GitHub)
+            })?
             .pull_request
-            .ok_or_else(|| Error::new("failed to find PR"))?;
+            .ok_or_else(|| {
+                Error::new("failed to find PR").with_kind(ErrorKind::GitHub)
+            })?;
 
         let base = config.new_github_branch_from_ref(&pr.base_ref_name)?;
         let head = config.new_github_branch_from_ref(&pr.head_ref_name)?;
@@ -247,7 +695,7 @@ impl GitHub {
             _ => None,
         };
 
-        let requested_reviewers: Vec<String> = pr.review_requests
+        let pending_reviewers: Vec<String> = pr.review_requests
             .iter()
             .flat_map(|x| &x.nodes)
             .flatten()
@@ -261,6 +709,11 @@ impl GitHub {
                 _ => None,
               }
             })
+            .collect();
+
+        let requested_reviewers: Vec<String> = pending_reviewers
+            .iter()
+            .cloned()
             .chain(reviewers.keys().cloned())
             .collect::<HashSet<String>>() // de-duplicate
             .into_iter()
@@ -307,6 +760,7 @@ impl GitHub {
                 }
                 _ => PullRequestState::Closed,
             },
+            is_draft: pr.is_draft,
             title: pr.title,
             body: Some(pr.body),
             sections,
@@ -315,6 +769,7 @@ impl GitHub {
             base_oid,
             head_oid,
             reviewers,
+            requested_reviewers: pending_reviewers,
             review_status,
             merge_commit: pr
                 .merge_commit
@@ -322,9 +777,12 @@ impl GitHub {
         })
     }
 
+    /// Create a Pull Request with the title of the given message and the
+    /// given description
     pub async fn create_pull_request(
         &self,
         message: &MessageSectionsMap,
+        body: String,
         base_ref_name: String,
         head_ref_name: String,
         draft: bool,
@@ -338,10 +796,11 @@ impl GitHub {
                 head_ref_name,
                 base_ref_name,
             )
-            .body(build_github_body(message))
+            .body(body)
             .draft(Some(draft))
             .send()
-            .await?
+            .await
+            .with_kind(ErrorKind::GitHub)?
             .number;
 
         Ok(number)
@@ -360,7 +819,8 @@ impl GitHub {
                 ),
                 Some(&updates),
             )
-            .await?;
+            .await
+            .with_kind(ErrorKind::GitHub)?;
 
         Ok(())
     }
@@ -375,7 +835,8 @@ impl GitHub {
             .state(octocrab::params::State::Open)
             .head(format!("{}:{}", self.config.owner.clone(), head_ref_name))
             .send()
-            .await?;
+            .await
+            .with_kind(ErrorKind::GitHub)?;
 
         let pull_request = pull_requests.into_iter().next();
 
@@ -403,11 +864,180 @@ impl GitHub {
                 ),
                 Some(&reviewers),
             )
-            .await?;
+            .await
+            .with_kind(ErrorKind::GitHub)?;
+
+        Ok(())
+    }
+
+    /// Remove the given users and teams from the requested reviewers of the
+    /// given Pull Request.
+    pub async fn remove_requested_reviewers(
+        &self,
+        number: u64,
+        reviewers: PullRequestRequestReviewers,
+    ) -> Result<()> {
+        // The endpoint takes a JSON body, which `Octocrab::delete` does not
+        // support, so build the request ourselves.
+        let octocrab = octocrab::instance();
+        let url = octocrab
+            .absolute_url(format!(
+                "repos/{}/{}/pulls/{}/requested_reviewers",
+                self.config.owner, self.config.repo, number
+            ))
+            .with_kind(ErrorKind::GitHub)?;
+        let request = octocrab
+            .request_builder(url, reqwest::Method::DELETE)
+            .json(&reviewers);
+        let response = octocrab
+            .execute(request)
+            .await
+            .with_kind(ErrorKind::GitHub)?;
+        octocrab::map_github_error(response)
+            .await
+            .with_kind(ErrorKind::GitHub)?;
+
+        Ok(())
+    }
+
+    /// Upload the given files (paths relative to the root of the working
+    /// tree) and reference them in a comment on the given Pull Request. GitHub
+    /// has no API for its own attachment uploads, so the files are pushed as
+    /// a commit to a separate branch and linked from there. Files that cannot
+    /// be read are skipped with a warning.
+    #[cfg(feature = "attachments")]
+    pub async fn upload_and_comment(
+        &self,
+        number: u64,
+        paths: &[&str],
+    ) -> Result<()> {
+        let workdir = self
+            .git
+            .repo()
+            .workdir()
+            .ok_or_else(|| Error::new("Repository has no working tree"))?
+            .to_path_buf();
+
+        let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+        for path in paths {
+            let content = match read_attachment(&workdir, path) {
+                Ok(content) => content,
+                Err(error) => {
+                    output(
+                        "⚠️",
+                        &format!("Skipping attachment '{path}': {error}"),
+                    )?;
+                    continue;
+                }
+            };
+            let file_name = std::path::Path::new(path)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.to_string());
+            let file_name = if files.iter().any(|(name, _)| *name == file_name)
+            {
+                format!("{}-{}", files.len(), file_name)
+            } else {
+                file_name
+            };
+            files.push((file_name, content));
+        }
+
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        let commit_oid = self.git.create_commit_with_files(
+            &files,
+            &format!("[𝘀𝗽𝗿] attachments for Pull Request #{number}"),
+        )?;
+        let branch = self.config.new_github_branch(&format!(
+            "{}attachments/pr-{}",
+            self.config.branch_prefix, number
+        ));
+
+        let mut cmd = tokio::process::Command::new("git");
+        cmd.arg("push")
+            .arg("--force")
+            .arg("--")
+            .arg(&self.config.remote_name)
+            .arg(format!("{}:{}", commit_oid, branch.on_github()));
+        run_command(&mut cmd)
+            .await
+            .reword("git push of attachments failed".to_string())?;
+
+        let body = std::iter::once("Attachments:\n".to_string())
+            .chain(files.iter().map(|(name, _)| {
+                let url = format!(
+                    "https://github.com/{}/{}/blob/{}/{}?raw=true",
+                    self.config.owner, self.config.repo, commit_oid, name
+                );
+                if is_image_file_name(name) {
+                    format!("![{name}]({url})")
+                } else {
+                    format!("- [{name}]({url})")
+                }
+            }))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        self.create_comment(number, &body).await
+    }
+
+    pub async fn create_comment(&self, number: u64, text: &str) -> Result<()> {
+        octocrab::instance()
+            .issues(self.config.owner.clone(), self.config.repo.clone())
+            .create_comment(number, text)
+            .await
+            .with_kind(ErrorKind::GitHub)?;
 
         Ok(())
     }
 
+    /// Merge the given Pull Request right away, with the repository's default
+    /// merge method (a merge commit), returning the commit it was merged as
+    pub async fn merge_pull_request(&self, number: u64) -> Result<git2::Oid> {
+        let merge = octocrab::instance()
+            .pulls(&self.config.owner, &self.config.repo)
+            .merge(number)
+            .send()
+            .await
+            .with_kind(ErrorKind::GitHub)?;
+
+        merged_commit_oid(merge)
+    }
+
+    pub async fn add_label(&self, number: u64, label: &str) -> Result<()> {
+        self.add_labels(number, &[label.to_string()]).await
+    }
+
+    pub async fn add_labels(
+        &self,
+        number: u64,
+        labels: &[String],
+    ) -> Result<()> {
+        octocrab::instance()
+            .issues(self.config.owner.clone(), self.config.repo.clone())
+            .add_labels(number, labels)
+            .await
+            .with_kind(ErrorKind::GitHub)?;
+
+        Ok(())
+    }
+
+    /// The names of the labels of the given Pull Request
+    pub async fn get_labels(&self, number: u64) -> Result<Vec<String>> {
+        let labels = octocrab::instance()
+            .issues(self.config.owner.clone(), self.config.repo.clone())
+            .list_labels_for_issue(number)
+            .per_page(100)
+            .send()
+            .await
+            .with_kind(ErrorKind::GitHub)?;
+
+        Ok(labels.items.into_iter().map(|label| label.name).collect())
+    }
+
     pub async fn get_pull_request_mergeability(
         &self,
         number: u64,
@@ -417,33 +1047,19 @@ impl GitHub {
             owner: self.config.owner.clone(),
             number: number as i64,
         };
-        let request_body = PullRequestMergeabilityQuery::build_query(variables);
-        let res = self
-            .graphql_client
-            .post("https://api.github.com/graphql")
-            .json(&request_body)
-            .send()
-            .await?;
-        let response_body: Response<
-            pull_request_mergeability_query::ResponseData,
-        > = res.json().await?;
-
-        if let Some(errors) = response_body.errors {
-            let error = Err(Error::new(format!(
-                "querying PR #{number} mergeability failed"
-            )));
-            return errors
-                .into_iter()
-                .fold(error, |err, e| err.context(e.to_string()));
-        }
-
-        let pr = response_body
-            .data
-            .ok_or_else(|| Error::new("failed to fetch PR"))?
+        let pr = self
+            .graphql::<PullRequestMergeabilityQuery>(variables)
+            .await
+            .context(format!("querying PR #{number} mergeability failed"))?
             .repository
-            .ok_or_else(|| Error::new("failed to find repository"))?
+            .ok_or_else(|| {
+                Error::new("failed to find repository")
+                    .with_kind(ErrorKind::GitHub)
+            })?
             .pull_request
-            .ok_or_else(|| Error::new("failed to find PR"))?;
+            .ok_or_else(|| {
+                Error::new("failed to find PR").with_kind(ErrorKind::GitHub)
+            })?;
 
         Ok::<_, Error>(PullRequestMergeability {
             base: self.config.new_github_branch_from_ref(&pr.base_ref_name)?,
@@ -461,6 +1077,328 @@ impl GitHub {
     }
 }
 
+impl GitHub {
+    /// The checks on the given commit, which was pushed for the given Pull
+    /// Request, and the checks required by the base branch of the Pull Request
+    pub async fn get_pull_request_checks(
+        &self,
+        number: u64,
+        head_oid: git2::Oid,
+    ) -> Result<PullRequestChecks> {
+        use pull_request_checks_query::{
+            CheckConclusionState, CheckStatusState,
+            PullRequestChecksQueryRepositoryObject as Object,
+            PullRequestChecksQueryRepositoryObjectOnCommitStatusCheckRollupContextsNodes as Context,
+            StatusState,
+        };
+
+        let variables = pull_request_checks_query::Variables {
+            name: self.config.repo.clone(),
+            owner: self.config.owner.clone(),
+            number: number as i64,
+            oid: head_oid.to_string(),
+        };
+        let repository = self
+            .graphql::<PullRequestChecksQuery>(variables)
+            .await
+            .context(format!("querying PR #{number} checks failed"))?
+            .repository
+            .ok_or_else(|| {
+                Error::new("failed to find repository")
+                    .with_kind(ErrorKind::GitHub)
+            })?;
+        let pr = repository.pull_request.ok_or_else(|| {
+            Error::new("failed to find PR").with_kind(ErrorKind::GitHub)
+        })?;
+
+        let required = pr
+            .base_ref
+            .and_then(|base_ref| base_ref.branch_protection_rule)
+            .and_then(|rule| rule.required_status_check_contexts)
+            .unwrap_or_default()
+            .into_iter()
+            .flatten()
+            .collect();
+
+        // GitHub may not know the commit yet, right after the push
+        let contexts = match repository.object {
+            Some(Object::Commit(commit)) => commit
+                .status_check_rollup
+                .and_then(|rollup| rollup.contexts.nodes)
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        let checks = contexts
+            .into_iter()
+            .flatten()
+            .map(|context| match context {
+                Context::CheckRun(run) => {
+                    let status = match (run.status, run.conclusion) {
+                        (CheckStatusState::COMPLETED, Some(conclusion)) => {
+                            match conclusion {
+                                CheckConclusionState::SUCCESS
+                                | CheckConclusionState::NEUTRAL
+                                | CheckConclusionState::SKIPPED => {
+                                    CheckStatus::Success
+                                }
+                                _ => CheckStatus::Failure,
+                            }
+                        }
+                        _ => CheckStatus::Pending,
+                    };
+                    (run.name, status)
+                }
+                Context::StatusContext(context) => {
+                    let status = match context.state {
+                        StatusState::SUCCESS => CheckStatus::Success,
+                        StatusState::ERROR | StatusState::FAILURE => {
+                            CheckStatus::Failure
+                        }
+                        _ => CheckStatus::Pending,
+                    };
+                    (context.context, status)
+                }
+            })
+            .collect();
+
+        Ok(PullRequestChecks {
+            id: pr.id,
+            is_draft: pr.is_draft,
+            required,
+            checks,
+        })
+    }
+
+    /// Convert the Pull Request with the given GraphQL node id to a draft, or
+    /// mark it as ready for review.
+    pub async fn set_pull_request_draft(
+        &self,
+        id: &str,
+        draft: bool,
+    ) -> Result<()> {
+        let result = if draft {
+            self.graphql::<ConvertPullRequestToDraftMutation>(
+                convert_pull_request_to_draft_mutation::Variables {
+                    id: id.to_string(),
+                },
+            )
+            .await
+            .map(|_| ())
+        } else {
+            self.graphql::<MarkPullRequestReadyForReviewMutation>(
+                mark_pull_request_ready_for_review_mutation::Variables {
+                    id: id.to_string(),
+                },
+            )
+            .await
+            .map(|_| ())
+        };
+
+        result.context("changing PR draft state failed".to_string())
+    }
+
+    /// Dismiss all approving reviews of the given Pull Request with the given
+    /// message. Returns the number of reviews dismissed.
+    pub async fn dismiss_reviews(
+        &self,
+        number: u64,
+        message: &str,
+    ) -> Result<usize> {
+        let variables = pull_request_approved_reviews_query::Variables {
+            name: self.config.repo.clone(),
+            owner: self.config.owner.clone(),
+            number: number as i64,
+        };
+        let review_ids = self
+            .graphql::<PullRequestApprovedReviewsQuery>(variables)
+            .await
+            .context(format!("querying PR #{number} reviews failed"))?
+            .repository
+            .and_then(|repo| repo.pull_request)
+            .and_then(|pr| pr.reviews)
+            .and_then(|reviews| reviews.nodes)
+            .unwrap_or_default()
+            .into_iter()
+            .flatten()
+            .map(|review| review.id)
+            .collect::<Vec<_>>();
+
+        for id in &review_ids {
+            self.graphql::<DismissPullRequestReviewMutation>(
+                dismiss_pull_request_review_mutation::Variables {
+                    id: id.clone(),
+                    message: message.to_string(),
+                },
+            )
+            .await
+            .context(format!("dismissing review on PR #{number} failed"))?;
+        }
+
+        Ok(review_ids.len())
+    }
+
+    /// Ask GitHub to merge the given Pull Request with the given method as
+    /// soon as all its requirements (reviews, checks) are met.
+    pub async fn enable_auto_merge(
+        &self,
+        number: u64,
+        method: MergeMethod,
+    ) -> Result<()> {
+        let id = self.get_pull_request_id(number).await?;
+
+        let result = self
+            .graphql::<EnablePullRequestAutoMergeMutation>(
+                enable_pull_request_auto_merge_mutation::Variables {
+                    id,
+                    method: method.to_graphql(),
+                },
+            )
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(error) => match error
+                .messages()
+                .iter()
+                .find_map(|message| explain_auto_merge_error(message))
+            {
+                Some(explanation) => {
+                    Err(Error::new(explanation).with_kind(ErrorKind::GitHub))
+                }
+                None => Err(error).context(format!(
+                    "enabling auto-merge on PR #{number} failed"
+                )),
+            },
+        }
+    }
+
+    /// Add the given Pull Request to the project (v2) of the repository owner
+    /// with the given title.
+    pub async fn add_to_project(
+        &self,
+        number: u64,
+        project_name: &str,
+    ) -> Result<()> {
+        let not_found = || {
+            Error::new(format!(
+                "Project '{}' not found for {}, or not accessible to you. \
+                 Check the name in the Project section of the commit \
+                 message, and that your GitHub token has the 'project' \
+                 scope.",
+                project_name, self.config.owner
+            ))
+            .with_kind(ErrorKind::GitHub)
+        };
+
+        let variables = projects_query::Variables {
+            owner: self.config.owner.clone(),
+            name: project_name.to_string(),
+        };
+        let response_body =
+            self.graphql_response::<ProjectsQuery>(variables).await?;
+
+        // The owner is either an organization or a user, so one of the two
+        // lookups always fails. Errors only matter if we find no project.
+        let errors = response_body.errors.unwrap_or_default();
+        let candidates = response_body
+            .data
+            .map(|data| {
+                let organization_projects = data
+                    .organization
+                    .and_then(|org| org.projects_v2.nodes)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .flatten()
+                    .map(|p| (p.id, p.title, p.closed));
+                let user_projects = data
+                    .user
+                    .and_then(|user| user.projects_v2.nodes)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .flatten()
+                    .map(|p| (p.id, p.title, p.closed));
+                organization_projects.chain(user_projects).collect()
+            })
+            .unwrap_or_default();
+
+        let project_id = match choose_project(project_name, candidates) {
+            Some(id) => id,
+            None => {
+                let error = Err(not_found());
+                return errors
+                    .into_iter()
+                    .filter(|e| !e.message.starts_with("Could not resolve to"))
+                    .fold(error, |err, e| err.context(e.to_string()));
+            }
+        };
+
+        let content_id = self.get_pull_request_id(number).await?;
+        self.graphql::<AddProjectItemMutation>(
+            add_project_item_mutation::Variables {
+                project: project_id,
+                content: content_id,
+            },
+        )
+        .await
+        .context(format!(
+            "adding PR #{number} to project '{project_name}' failed"
+        ))?;
+
+        Ok(())
+    }
+
+    /// Look up the GraphQL node id of the given Pull Request
+    pub async fn get_pull_request_id(&self, number: u64) -> Result<String> {
+        let variables = pull_request_id_query::Variables {
+            name: self.config.repo.clone(),
+            owner: self.config.owner.clone(),
+            number: number as i64,
+        };
+        let id = self
+            .graphql::<PullRequestIdQuery>(variables)
+            .await
+            .context(format!("querying PR #{number} failed"))?
+            .repository
+            .and_then(|repo| repo.pull_request)
+            .map(|pr| pr.id)
+            .ok_or_else(|| {
+                Error::new(format!("Pull Request #{number} not found"))
+                    .with_kind(ErrorKind::GitHub)
+            })?;
+
+        Ok(id)
+    }
+}
+
+/// The data of the given GraphQL response, or an error with the messages of
+/// the errors in it
+fn graphql_data<T>(response: Response<T>) -> Result<T> {
+    if let Some(errors) = response.errors {
+        let mut error = Error::empty().with_kind(ErrorKind::GitHub);
+        for e in errors {
+            error.push(e.to_string());
+        }
+        return Err(error);
+    }
+
+    response.data.ok_or_else(|| {
+        Error::new("GitHub sent no data").with_kind(ErrorKind::GitHub)
+    })
+}
+
+/// Pick the project with the given title (ignoring case) from the candidates
+/// given as `(id, title, closed)`, preferring open projects over closed ones.
+fn choose_project(
+    name: &str,
+    candidates: Vec<(String, String, bool)>,
+) -> Option<String> {
+    candidates
+        .into_iter()
+        .filter(|(_, title, _)| title.eq_ignore_ascii_case(name.trim()))
+        .min_by_key(|(_, _, closed)| *closed)
+        .map(|(id, _, _)| id)
+}
+
 #[derive(Debug, Clone)]
 pub struct GitHubBranch {
     ref_on_github: String,
@@ -528,11 +1466,277 @@ impl GitHubBranch {
     }
 }
 
+/// Read a file to attach to a Pull Request. The path is relative to the
+/// working tree, and files outside of it are refused.
+#[cfg(feature = "attachments")]
+fn read_attachment(workdir: &std::path::Path, path: &str) -> Result<Vec<u8>> {
+    let workdir = workdir.canonicalize()?;
+    let file = workdir.join(path).canonicalize()?;
+    if !file.starts_with(&workdir) {
+        return Err(Error::new("the file is outside of the working tree"));
+    }
+    Ok(std::fs::read(file)?)
+}
+
+/// Pull Requests for tests in all modules
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use super::*;
+
+    use crate::config::test_utils::config_factory;
+
+    /// A `GitHub` that sends its GraphQL requests to the returned listener
+    /// instead, which never answers them. Whether any were sent can be seen
+    /// with `sent_requests`.
+    pub(crate) fn offline_github(
+        git: &crate::git::Git,
+    ) -> (GitHub, std::net::TcpListener) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all(proxy).unwrap())
+            .build()
+            .unwrap();
+
+        (GitHub::new(config_factory(), git.clone(), client), listener)
+    }
+
+    /// The number of connections made to the listener of `offline_github`
+    /// so far
+    pub(crate) fn sent_requests(listener: &std::net::TcpListener) -> usize {
+        std::iter::from_fn(|| listener.accept().ok()).count()
+    }
+
+    /// Pull Request #42 from `spr/foo/base` into `master`
+    pub(crate) fn pull_request_factory(state: PullRequestState) -> PullRequest {
+        let config = config_factory();
+        PullRequest {
+            number: 42,
+            state,
+            is_draft: false,
+            title: "Base".into(),
+            body: None,
+            sections: Default::default(),
+            base: config.master_ref.clone(),
+            head: config.new_github_branch("spr/foo/base"),
+            base_oid: git2::Oid::zero(),
+            head_oid: git2::Oid::zero(),
+            merge_commit: None,
+            reviewers: Default::default(),
+            requested_reviewers: Default::default(),
+            review_status: None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
 
+    #[cfg(feature = "attachments")]
+    #[test]
+    fn test_read_attachment_within_working_tree() {
+        let repo = crate::git::test_utils::init_test_repo("attachments");
+        let workdir = repo.repo().workdir().unwrap().to_path_buf();
+        std::fs::create_dir(workdir.join("docs")).unwrap();
+        std::fs::write(workdir.join("docs/shot.png"), b"png").unwrap();
+
+        assert_eq!(read_attachment(&workdir, "docs/shot.png").unwrap(), b"png");
+        assert_eq!(
+            read_attachment(&workdir, "docs/../docs/shot.png").unwrap(),
+            b"png"
+        );
+
+        // Paths leaving the working tree are refused, even if the file
+        // exists
+        let outside = format!("../x-{}", std::process::id());
+        std::fs::write(workdir.join(&outside), b"outside").unwrap();
+        let error = read_attachment(&workdir, &outside).unwrap_err();
+        std::fs::remove_file(workdir.join(&outside)).unwrap();
+        let refused =
+            vec!["the file is outside of the working tree".to_string()];
+        assert_eq!(error.messages(), &refused);
+        let error = read_attachment(&workdir, "/etc/passwd").unwrap_err();
+        assert_eq!(error.messages(), &refused);
+    }
+
+    #[tokio::test]
+    async fn test_graphql_client_sends_extra_headers() {
+        use std::io::{Read, Write};
+
+        // A mock server that answers a single request and hands back what
+        // it received
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buffer).unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..n]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\
+                      Connection: close\r\n\r\n",
+                )
+                .unwrap();
+            String::from_utf8(request).unwrap().to_lowercase()
+        });
+
+        let client = graphql_client_builder(
+            "secret-token",
+            &[
+                ("X-Gateway-Auth".into(), "opensesame".into()),
+                ("X-Team".into(), "platform".into()),
+            ],
+        )
+        .unwrap()
+        .no_proxy()
+        .build()
+        .unwrap();
+        let response = client
+            .get(format!("http://{address}/graphql"))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        let request = server.join().unwrap();
+        assert!(request.contains("\r\nx-gateway-auth: opensesame\r\n"));
+        assert!(request.contains("\r\nx-team: platform\r\n"));
+        assert!(request.contains("\r\nauthorization: bearer secret-token\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_api_limiter_caps_requests_in_flight() {
+        use std::io::{Read, Write};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        const REQUESTS: usize = 8;
+        const MAX_IN_FLIGHT: usize = 3;
+
+        // A mock server that answers every request after a little while,
+        // keeping track of how many it is handling at the same time
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_seen = Arc::new(AtomicUsize::new(0));
+        let server = {
+            let in_flight = in_flight.clone();
+            let max_seen = max_seen.clone();
+            std::thread::spawn(move || {
+                let handlers = listener
+                    .incoming()
+                    .take(REQUESTS)
+                    .map(|stream| {
+                        let mut stream = stream.unwrap();
+                        let in_flight = in_flight.clone();
+                        let max_seen = max_seen.clone();
+                        std::thread::spawn(move || {
+                            let mut request = Vec::new();
+                            let mut buffer = [0; 1024];
+                            while !request.windows(4).any(|w| w == b"\r\n\r\n")
+                            {
+                                let n = stream.read(&mut buffer).unwrap();
+                                if n == 0 {
+                                    break;
+                                }
+                                request.extend_from_slice(&buffer[..n]);
+                            }
+                            let now =
+                                in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                            max_seen.fetch_max(now, Ordering::SeqCst);
+                            std::thread::sleep(
+                                std::time::Duration::from_millis(50),
+                            );
+                            in_flight.fetch_sub(1, Ordering::SeqCst);
+                            stream
+                                .write_all(
+                                    b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\
+                                      Connection: close\r\n\r\n",
+                                )
+                                .unwrap();
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for handler in handlers {
+                    handler.join().unwrap();
+                }
+            })
+        };
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let limiter = ApiLimiter::new(MAX_IN_FLIGHT);
+        let lookups = (0..REQUESTS).map(|i| {
+            let client = client.clone();
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+                limiter
+                    .run(
+                        client
+                            .get(format!("http://{address}/users/{i}"))
+                            .send(),
+                    )
+                    .await
+            })
+        });
+        for result in futures::future::join_all(lookups).await {
+            assert!(result.unwrap().unwrap().status().is_success());
+        }
+        server.join().unwrap();
+
+        assert!(max_seen.load(Ordering::SeqCst) <= MAX_IN_FLIGHT);
+        // The lookups did overlap, up to the cap
+        assert!(max_seen.load(Ordering::SeqCst) > 1);
+    }
+
+    #[test]
+    fn test_graphql_data() {
+        let response = |value| {
+            serde_json::from_value::<Response<serde_json::Value>>(value)
+                .unwrap()
+        };
+
+        assert_eq!(
+            graphql_data(response(serde_json::json!({"data": {"id": 1}})))
+                .unwrap(),
+            serde_json::json!({"id": 1})
+        );
+
+        let error = graphql_data(response(serde_json::json!({
+            "data": null,
+            "errors": [{"message": "Could not resolve to a PullRequest"}],
+        })))
+        .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::GitHub);
+        assert!(is_not_found_error(&error));
+        // Callers say what failed
+        let error = Err::<(), _>(error)
+            .context("fetching PR #42 failed".to_string())
+            .unwrap_err();
+        assert_eq!(error.to_string(), "fetching PR #42 failed");
+
+        assert!(
+            graphql_data(response(serde_json::json!({"data": null}))).is_err()
+        );
+    }
+
+    #[test]
+    fn test_graphql_client_rejects_invalid_header_name() {
+        assert!(graphql_client_builder(
+            "token",
+            &[("Bad Header".into(), "x".into())]
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_new_from_ref_with_branch_name() {
         let r =
@@ -638,4 +1842,128 @@ mod tests {
         assert_eq!(r.branch_name(), "refs/heads/foo");
         assert!(!r.is_master_branch());
     }
+
+    #[test]
+    fn test_explain_auto_merge_error() {
+        assert!(explain_auto_merge_error(
+            "Pull request Auto merge is not allowed for this repository"
+        )
+        .is_some());
+        assert!(explain_auto_merge_error("Pull request is in clean status")
+            .is_none());
+    }
+
+    #[test]
+    fn test_choose_project() {
+        let candidates = vec![
+            ("1".to_string(), "Roadmap 2022".to_string(), false),
+            ("2".to_string(), "Roadmap".to_string(), true),
+            ("3".to_string(), "roadmap".to_string(), false),
+        ];
+        assert_eq!(
+            choose_project("Roadmap", candidates.clone()),
+            Some("3".to_string())
+        );
+        assert_eq!(choose_project("Backlog", candidates), None);
+    }
+
+    #[test]
+    fn test_merged_commit_oid() {
+        let merge: octocrab::models::pulls::Merge = serde_json::from_str(
+            r#"{
+                "sha": "6dcb09b5b57875f334f61aebed695e2e4193db5e",
+                "merged": true,
+                "message": "Pull Request successfully merged"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            merged_commit_oid(merge).unwrap().to_string(),
+            "6dcb09b5b57875f334f61aebed695e2e4193db5e"
+        );
+
+        let merge: octocrab::models::pulls::Merge = serde_json::from_str(
+            r#"{"sha": null, "merged": false, "message": "Not mergeable"}"#,
+        )
+        .unwrap();
+        let error = merged_commit_oid(merge).unwrap_err();
+        assert!(error.messages()[0].contains("Not mergeable"));
+    }
+
+    #[test]
+    fn test_required_check_status() {
+        use CheckStatus::*;
+
+        let checks = |checks: &[(&str, CheckStatus)]| PullRequestChecks {
+            id: "PR_1".into(),
+            is_draft: false,
+            required: vec!["build".into(), "test".into()],
+            checks: checks
+                .iter()
+                .map(|(name, status)| (name.to_string(), *status))
+                .collect(),
+        };
+
+        // Red, even if another required check is still pending
+        assert_eq!(
+            checks(&[("build", Failure), ("test", Pending)]).required_status(),
+            Some(Failure)
+        );
+        // Green, whatever checks that are not required say
+        assert_eq!(
+            checks(&[("build", Success), ("test", Success), ("lint", Failure)])
+                .required_status(),
+            Some(Success)
+        );
+        // Pending until all required checks have been reported
+        assert_eq!(checks(&[]).required_status(), Some(Pending));
+        assert_eq!(
+            checks(&[("build", Success)]).required_status(),
+            Some(Pending)
+        );
+        // A check that was run again counts with its latest result
+        assert_eq!(
+            checks(&[
+                ("build", Failure),
+                ("build", Success),
+                ("test", Success)
+            ])
+            .required_status(),
+            Some(Success)
+        );
+
+        let unprotected = PullRequestChecks {
+            required: vec![],
+            ..checks(&[("build", Failure)])
+        };
+        assert_eq!(unprotected.required_status(), None);
+    }
+
+    #[test]
+    fn test_branch_protection_required_approvals() {
+        // Shortened response of GET /repos/{owner}/{repo}/branches/{branch}/protection
+        let protection: BranchProtection = serde_json::from_str(
+            r#"{
+                "url": "https://api.github.com/repos/acme/codez/branches/master/protection",
+                "required_status_checks": {
+                    "strict": true,
+                    "contexts": ["ci"]
+                },
+                "required_pull_request_reviews": {
+                    "dismiss_stale_reviews": true,
+                    "require_code_owner_reviews": false,
+                    "required_approving_review_count": 2
+                },
+                "enforce_admins": {"enabled": false}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(protection.required_approvals(), 2);
+
+        // Protected, but without required reviews
+        let protection: BranchProtection =
+            serde_json::from_str(r#"{"required_status_checks": null}"#)
+                .unwrap();
+        assert_eq!(protection.required_approvals(), 0);
+    }
 }
@@ -5,6 +5,7 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+pub mod codeowners;
 pub mod commands;
 pub mod config;
 pub mod error;
@@ -12,4 +13,6 @@ pub mod git;
 pub mod github;
 pub mod message;
 pub mod output;
+pub mod plan;
+pub mod state;
 pub mod utils;
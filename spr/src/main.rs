@@ -13,7 +13,7 @@ use clap::{Parser, Subcommand};
 use reqwest::{self, header};
 use spr::{
     commands,
-    error::{Error, Result},
+    error::{Error, ErrorKind, Result, ResultExt},
     output::output,
 };
 
@@ -21,7 +21,14 @@ use spr::{
 #[clap(
     name = "spr",
     version,
-    about = "Submit pull requests for individual, amendable, rebaseable commits to GitHub"
+    about = "Submit pull requests for individual, amendable, rebaseable commits to GitHub",
+    after_help = "EXIT CODES:
+    0    Success
+    1    Other errors
+    2    Commits could not be cherry-picked or rebased due to conflicts
+    3    GitHub API error (including authentication failure)
+    4    The working tree has uncommitted changes
+    5    Aborted by the user"
 )]
 pub struct Cli {
     /// Change to DIR before performing any operations
@@ -44,10 +51,22 @@ pub struct Cli {
     #[clap(long)]
     branch_prefix: Option<String>,
 
+    /// maximum number of lookups on GitHub in flight at the same time, 0 for
+    /// no limit (if not given taken from git config spr.maxConcurrentApi,
+    /// defaulting to 4)
+    #[clap(long, value_name = "N")]
+    max_concurrent_api: Option<usize>,
+
+    /// Print more details of what spr does, such as the refspecs it pushes
+    #[clap(long, short = 'v')]
+    verbose: bool,
+
     #[clap(subcommand)]
     command: Commands,
 }
 
+// Parsed once per run, so the size of the diff options doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Interactive assistant for configuring spr in a local GitHub-backed Git
@@ -72,13 +91,46 @@ enum Commands {
     Amend(commands::amend::AmendOptions),
 
     /// List open Pull Requests on GitHub and their review decision
-    List,
+    List(commands::list::ListOptions),
+
+    /// List all open Pull Requests of the current user whose branch was
+    /// created by spr, whether or not they are in the local stack
+    Mine(commands::mine::MineOptions),
+
+    /// List the branches on GitHub that spr manages for the Pull Requests
+    /// of the commits on this branch
+    Branches(commands::branches::BranchesOptions),
 
     /// Create a new branch with the contents of an existing Pull Request
     Patch(commands::patch::PatchOptions),
 
     /// Close a Pull request
     Close(commands::close::CloseOptions),
+
+    /// Replace the description of the Pull Request of the HEAD commit with
+    /// the contents of a file, leaving the commit message alone
+    Describe(commands::describe::DescribeOptions),
+
+    /// Mark a draft Pull Request as ready for review and request reviews
+    /// from the reviewers listed in the commit message
+    Ready(commands::ready::ReadyOptions),
+
+    /// Move the whole stack of local commits onto another commit
+    Rebase(commands::rebase::RebaseOptions),
+
+    /// Split the HEAD commit into several commits by file path
+    Split(commands::split::SplitOptions),
+
+    /// Show the state of the Pull Requests for the commits on this branch
+    Status(commands::status::StatusOptions),
+
+    /// Update the titles and descriptions of the Pull Requests on GitHub
+    /// from the local commit messages, without pushing anything
+    SyncMessages(commands::sync_messages::SyncMessagesOptions),
+
+    /// Check that the Pull Requests linked from the commits on this branch
+    /// exist and belong to them
+    Verify(commands::verify::VerifyOptions),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -92,6 +144,11 @@ pub enum OptionsError {
 pub async fn spr() -> Result<()> {
     let cli = Cli::parse();
 
+    // See https://no-color.org/
+    if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+        console::set_colors_enabled(false);
+    }
+
     if let Some(path) = &cli.cd {
         if let Err(err) = std::env::set_current_dir(path) {
             eprintln!("Could not change directory to {:?}", &path);
@@ -107,10 +164,57 @@ pub async fn spr() -> Result<()> {
 
     let git_config = repo.config()?;
 
+    // Values are taken from the command line, falling back to Git config,
+    // falling back to the config file in the repository.
+    let config_file = spr::config::ConfigFile::load(&repo)?;
+
     let github_repository = match cli.github_repository {
         Some(v) => Ok(v),
-        None => git_config.get_string("spr.githubRepository"),
-    }?;
+        None => git_config
+            .get_string("spr.githubRepository")
+            .or_else(|err| config_file.github_repository.clone().ok_or(err)),
+    };
+
+    // Unless configured explicitly, use the remote that points to the GitHub
+    // repository. This also gives us the repository, if not configured.
+    let github_remote_name = git_config
+        .get_string("spr.githubRemoteName")
+        .ok()
+        .or(config_file.github_remote_name);
+    let (github_remote_name, github_repository) = match github_remote_name {
+        Some(name) => (name, github_repository?),
+        None => {
+            let github_remotes = spr::config::get_github_remotes(&repo)?;
+            let (remote, ambiguous) = spr::config::choose_github_remote(
+                &github_remotes,
+                github_repository.as_ref().ok().map(|r| &r[..]),
+            );
+            if ambiguous {
+                output(
+                    "⚠️",
+                    &format!(
+                        "Several remotes point to GitHub ({}). Using '{}'. \
+                         Set spr.githubRemoteName to choose a different one.",
+                        github_remotes
+                            .iter()
+                            .map(|r| &r.name[..])
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        remote.map(|r| &r.name[..]).unwrap_or("origin"),
+                    ),
+                )?;
+            }
+            match (remote, github_repository) {
+                (Some(remote), Ok(repository)) => {
+                    (remote.name.clone(), repository)
+                }
+                (Some(remote), Err(_)) => {
+                    (remote.name.clone(), remote.repository.clone())
+                }
+                (None, repository) => ("origin".to_string(), repository?),
+            }
+        }
+    };
 
     let (github_owner, github_repo) = {
         let captures = lazy_regex::regex!(r#"^([\w\-\.]+)/([\w\-\.]+)$"#)
@@ -124,23 +228,42 @@ pub async fn spr() -> Result<()> {
         )
     };
 
-    let github_remote_name = git_config
-        .get_string("spr.githubRemoteName")
-        .unwrap_or_else(|_| "origin".to_string());
     let github_master_branch = git_config
         .get_string("spr.githubMasterBranch")
-        .unwrap_or_else(|_| "master".to_string());
-    let branch_prefix = git_config.get_string("spr.branchPrefix")?;
+        .ok()
+        .or(config_file.github_master_branch)
+        .unwrap_or_else(|| "master".to_string());
+    let branch_prefix = match cli.branch_prefix {
+        Some(v) => Ok(v),
+        None => git_config
+            .get_string("spr.branchPrefix")
+            .or_else(|err| config_file.branch_prefix.ok_or(err)),
+    }?;
     let require_approval = git_config
         .get_bool("spr.requireApproval")
         .ok()
+        .or(config_file.require_approval)
         .unwrap_or(false);
     let require_test_plan = git_config
         .get_bool("spr.requireTestPlan")
         .ok()
+        .or(config_file.require_test_plan)
         .unwrap_or(false);
 
-    let config = spr::config::Config::new(
+    let derived_commit_author = git_config
+        .get_string("spr.derivedCommitAuthor")
+        .ok()
+        .or(config_file.derived_commit_author)
+        .map(|v| spr::config::CommitIdentity::parse(&v))
+        .transpose()?;
+    let derived_commit_committer = git_config
+        .get_string("spr.derivedCommitCommitter")
+        .ok()
+        .or(config_file.derived_commit_committer)
+        .map(|v| spr::config::CommitIdentity::parse(&v))
+        .transpose()?;
+
+    let mut config = spr::config::Config::new(
         github_owner,
         github_repo,
         github_remote_name,
@@ -149,36 +272,225 @@ pub async fn spr() -> Result<()> {
         require_approval,
         require_test_plan,
     );
+    config.derived_commit_author = derived_commit_author;
+    config.derived_commit_committer = derived_commit_committer;
+    config.run_commit_hooks = git_config
+        .get_bool("spr.runCommitHooks")
+        .ok()
+        .or(config_file.run_commit_hooks)
+        .unwrap_or(false);
+    config.warn_shared_commits = git_config
+        .get_bool("spr.warnSharedCommits")
+        .ok()
+        .or(config_file.warn_shared_commits)
+        .unwrap_or(false);
+    if let Some(merge_label) = git_config
+        .get_string("spr.mergeLabel")
+        .ok()
+        .or(config_file.merge_label)
+    {
+        config.merge_label = merge_label;
+    }
+    config.merge_comment = git_config
+        .get_string("spr.mergeComment")
+        .ok()
+        .or(config_file.merge_comment);
+    if let Some(strategy) = git_config
+        .get_string("spr.baseBranchStrategy")
+        .ok()
+        .or(config_file.base_branch_strategy)
+    {
+        config.base_branch_strategy =
+            spr::config::BaseBranchStrategy::parse(&strategy)?;
+    }
+    config.base_branch_skip_ci = git_config
+        .get_bool("spr.baseBranchSkipCi")
+        .ok()
+        .or(config_file.base_branch_skip_ci)
+        .unwrap_or(true);
+    config.auto_no_cherry_pick = git_config
+        .get_bool("spr.autoNoCherryPick")
+        .ok()
+        .or(config_file.auto_no_cherry_pick)
+        .unwrap_or(false);
+    config.diffstat = git_config
+        .get_bool("spr.diffstat")
+        .ok()
+        .or(config_file.diffstat)
+        .unwrap_or(false);
+    config.first_parent = git_config
+        .get_bool("spr.firstParent")
+        .ok()
+        .or(config_file.first_parent)
+        .unwrap_or(false);
+    config.require_reviewers = git_config
+        .get_bool("spr.requireReviewers")
+        .ok()
+        .or(config_file.require_reviewers)
+        .unwrap_or(false);
+    config.signoff = git_config
+        .get_bool("spr.signoff")
+        .ok()
+        .or(config_file.signoff)
+        .unwrap_or(false);
+    config.require_signoff = git_config
+        .get_bool("spr.requireSignoff")
+        .ok()
+        .or(config_file.require_signoff)
+        .unwrap_or(false);
+    config.confirm = git_config
+        .get_bool("spr.confirm")
+        .ok()
+        .or(config_file.confirm)
+        .unwrap_or(false);
+    config.reattach = git_config
+        .get_bool("spr.reattach")
+        .ok()
+        .or(config_file.reattach)
+        .unwrap_or(false);
+    config.rebase_message = git_config
+        .get_string("spr.rebaseMessage")
+        .ok()
+        .or(config_file.rebase_message);
+    config.message_keywords = git_config
+        .get_bool("spr.messageKeywords")
+        .ok()
+        .or(config_file.message_keywords)
+        .unwrap_or(false);
+    if let Some(keyword) = git_config
+        .get_string("spr.readyKeyword")
+        .ok()
+        .or(config_file.ready_keyword)
+    {
+        config.ready_keyword = keyword;
+    }
+    if let Some(keyword) = git_config
+        .get_string("spr.draftKeyword")
+        .ok()
+        .or(config_file.draft_keyword)
+    {
+        config.draft_keyword = keyword;
+    }
+    // Like git's http.extraHeader, spr.extraHeader may be given several times
+    let mut extra_headers = Vec::new();
+    let mut entries = git_config.multivar("spr.extraHeader", None)?;
+    while let Some(entry) = entries.next() {
+        if let Some(value) = entry?.value() {
+            extra_headers.push(value.to_string());
+        }
+    }
+    if extra_headers.is_empty() {
+        extra_headers = config_file.extra_headers.unwrap_or_default();
+    }
+    config.extra_headers = extra_headers
+        .iter()
+        .map(|header| spr::config::parse_extra_header(header))
+        .collect::<Result<_>>()?;
+    if let Some(max_stack_depth) = git_config
+        .get_i64("spr.maxStackDepth")
+        .ok()
+        .map(|v| v.max(0) as usize)
+        .or(config_file.max_stack_depth)
+    {
+        config.max_stack_depth = max_stack_depth;
+    }
+    if let Some(max_concurrent_api) = cli.max_concurrent_api.or_else(|| {
+        git_config
+            .get_i64("spr.maxConcurrentApi")
+            .ok()
+            .map(|v| v.max(0) as usize)
+            .or(config_file.max_concurrent_api)
+    }) {
+        config.max_concurrent_api = max_concurrent_api;
+    }
+    config.verbose = cli.verbose;
+
+    // Reviewer aliases from the config file, overridden by the ones in Git
+    // config (`spr.reviewerAlias.<name>`)
+    config.reviewer_aliases = config_file
+        .reviewer_aliases
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, reviewers)| (name.to_lowercase(), reviewers))
+        .collect();
+    let mut size_reviewers = config_file.size_reviewers.unwrap_or_default();
+    let mut entries = git_config.entries(None)?;
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        if let (Some(name), Some(value)) = (entry.name(), entry.value()) {
+            let lowercase_name = name.to_lowercase();
+            if let Some(alias) =
+                lowercase_name.strip_prefix("spr.revieweralias.")
+            {
+                config
+                    .reviewer_aliases
+                    .insert(alias.to_string(), value.to_string());
+            } else if let Some(threshold) =
+                lowercase_name.strip_prefix("spr.sizereviewers.")
+            {
+                size_reviewers.insert(threshold.to_string(), value.to_string());
+            }
+        }
+    }
+    for (threshold, reviewers) in size_reviewers {
+        let threshold = threshold.parse().map_err(|_| {
+            Error::new(format!(
+                "Invalid spr.sizeReviewers entry '{threshold}': expected a \
+                 number of changed lines"
+            ))
+        })?;
+        config.size_reviewers.insert(threshold, reviewers);
+    }
 
-    let git = spr::git::Git::new(repo);
+    let commit_date =
+        match git_config.get_string("spr.commitDate").ok().or_else(|| {
+            config_file
+                .commit_date
+                .map(spr::config::CommitDateValue::into_text)
+        }) {
+            Some(text) => spr::config::parse_commit_date(
+                &text,
+                std::env::var("SOURCE_DATE_EPOCH").ok().as_deref(),
+            )?,
+            None => None,
+        };
+
+    let git = spr::git::Git::new(repo).with_commit_date(commit_date);
 
     if let Commands::Format(opts) = cli.command {
         return commands::format::format(opts, &git, &config).await;
     }
 
+    if let Commands::Rebase(opts) = cli.command {
+        let _lock = git.lock()?;
+        return commands::rebase::rebase(opts, &git, &config).await;
+    }
+
+    if let Commands::Split(opts) = cli.command {
+        let _lock = git.lock()?;
+        return commands::split::split(opts, &git, &config).await;
+    }
+
     let github_auth_token = match cli.github_auth_token {
         Some(v) => Ok(v),
         None => git_config.get_string("spr.githubAuthToken"),
     }?;
 
-    octocrab::initialise(
-        octocrab::Octocrab::builder().personal_token(github_auth_token.clone()),
-    )?;
-
-    let mut headers = header::HeaderMap::new();
-    headers.insert(header::ACCEPT, "application/json".parse()?);
-    headers.insert(
-        header::USER_AGENT,
-        format!("spr/{}", env!("CARGO_PKG_VERSION")).try_into()?,
-    );
-    headers.insert(
-        header::AUTHORIZATION,
-        format!("Bearer {}", github_auth_token).parse()?,
-    );
+    let mut octocrab_builder =
+        octocrab::Octocrab::builder().personal_token(github_auth_token.clone());
+    for (name, value) in &config.extra_headers {
+        octocrab_builder = octocrab_builder.add_header(
+            header::HeaderName::from_bytes(name.as_bytes())?,
+            value.clone(),
+        );
+    }
+    octocrab::initialise(octocrab_builder).with_kind(ErrorKind::GitHub)?;
 
-    let graphql_client = reqwest::Client::builder()
-        .default_headers(headers)
-        .build()?;
+    let graphql_client = spr::github::graphql_client_builder(
+        &github_auth_token,
+        &config.extra_headers,
+    )?
+    .build()?;
 
     let mut gh = spr::github::GitHub::new(
         config.clone(),
@@ -187,11 +499,14 @@ pub async fn spr() -> Result<()> {
     );
 
     match cli.command {
+        // The diff command takes the lock itself, as in watch mode it must
+        // not hold it while waiting
         Commands::Diff(opts) => {
             commands::diff::diff(opts, &git, &mut gh, &config).await?
         }
         Commands::Merge(opts) => {
-            commands::merge::merge(opts, &git, &config).await?
+            let _lock = git.lock()?;
+            commands::merge::merge(opts, &git, &gh, &config).await?
         }
         Commands::Land(opts) => {
             commands::land::land(opts, &git, &mut gh, &config).await?
@@ -199,16 +514,44 @@ pub async fn spr() -> Result<()> {
         Commands::Amend(opts) => {
             commands::amend::amend(opts, &git, &mut gh, &config).await?
         }
-        Commands::List => commands::list::list(graphql_client, &config).await?,
+        Commands::List(opts) => {
+            commands::list::list(opts, graphql_client, &config).await?
+        }
+        Commands::Mine(opts) => {
+            commands::mine::mine(opts, graphql_client, &config).await?
+        }
+        Commands::Branches(opts) => {
+            commands::branches::branches(opts, &git, &mut gh, &config).await?
+        }
         Commands::Patch(opts) => {
             commands::patch::patch(opts, &git, &mut gh, &config).await?
         }
         Commands::Close(opts) => {
             commands::close::close(opts, &git, &mut gh, &config).await?
         }
+        Commands::Describe(opts) => {
+            commands::describe::describe(opts, &git, &mut gh, &config).await?
+        }
+        Commands::Ready(opts) => {
+            commands::ready::ready(opts, &git, &mut gh, &config).await?
+        }
+        Commands::Status(opts) => {
+            commands::status::status(opts, &git, &mut gh, &config).await?
+        }
+        Commands::SyncMessages(opts) => {
+            commands::sync_messages::sync_messages(opts, &git, &mut gh, &config)
+                .await?
+        }
+        Commands::Verify(opts) => {
+            let _lock = git.lock()?;
+            commands::verify::verify(opts, &git, &mut gh, &config).await?
+        }
         // The following commands are executed above and return from this
         // function before it reaches this match.
-        Commands::Init | Commands::Format(_) => (),
+        Commands::Init
+        | Commands::Format(_)
+        | Commands::Rebase(_)
+        | Commands::Split(_) => (),
     };
 
     Ok::<_, Error>(())
@@ -220,7 +563,7 @@ async fn main() -> Result<()> {
         for message in error.messages() {
             output("🛑", message)?;
         }
-        std::process::exit(1);
+        std::process::exit(error.kind().exit_code());
     }
 
     Ok(())
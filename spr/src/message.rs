@@ -18,10 +18,17 @@ pub enum MessageSection {
     Title,
     Summary,
     TestPlan,
+    PRBody,
+    Attachments,
+    Project,
+    Closes,
     BasePR,
+    DependsOn,
+    Base,
     Reviewers,
     ReviewedBy,
     PullRequest,
+    LandedAs,
 }
 
 pub fn message_section_label(section: &MessageSection) -> &'static str {
@@ -31,10 +38,17 @@ pub fn message_section_label(section: &MessageSection) -> &'static str {
         Title => "Title",
         Summary => "Summary",
         TestPlan => "Test Plan",
+        PRBody => "PR-Body",
+        Attachments => "Attachments",
+        Project => "Project",
+        Closes => "Closes",
         BasePR => "<hr>Depends On",
+        DependsOn => "Depends-on",
+        Base => "Base",
         Reviewers => "Reviewers",
         ReviewedBy => "Reviewed By",
         PullRequest => "Pull Request",
+        LandedAs => "Landed-as",
     }
 }
 
@@ -45,32 +59,69 @@ pub fn message_section_by_label(label: &str) -> Option<MessageSection> {
         "title" => Some(Title),
         "summary" => Some(Summary),
         "test plan" => Some(TestPlan),
+        "pr-body" => Some(PRBody),
+        "pr body" => Some(PRBody),
+        "attachments" => Some(Attachments),
+        "project" => Some(Project),
+        "closes" => Some(Closes),
         "depends on" => Some(BasePR),
+        "depends-on" => Some(DependsOn),
+        "base" => Some(Base),
         "reviewer" => Some(Reviewers),
         "reviewers" => Some(Reviewers),
         "reviewed by" => Some(ReviewedBy),
         "pull request" => Some(PullRequest),
+        "landed-as" => Some(LandedAs),
+        "landed as" => Some(LandedAs),
         _ => None,
     }
 }
 
+/// Remove the "Created using spr" footer of the commits spr creates for
+/// GitHub branches. It can end up in a local message that was copied from
+/// such a commit, and must not be added a second time.
+pub fn strip_spr_footer(msg: &str) -> String {
+    lazy_regex::regex!(r#"(?m)^Created using spr \S+\n?"#)
+        .replace_all(msg, "")
+        .trim_end()
+        .to_string()
+}
+
+/// The message for a commit spr creates for a GitHub branch: the given
+/// message with the "Created using spr" footer
+pub fn with_spr_footer(msg: &str) -> String {
+    format!(
+        "{}\n\nCreated using spr {}",
+        strip_spr_footer(msg),
+        env!("CARGO_PKG_VERSION"),
+    )
+}
+
+/// Normalise line endings to LF and remove trailing whitespace from every
+/// line, so that messages written on any platform or pasted from any editor
+/// parse the same way.
+pub fn normalize_message(msg: &str) -> String {
+    msg.replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .split('\n')
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 pub fn parse_message(
     msg: &str,
     top_section: MessageSection,
 ) -> MessageSectionsMap {
-    let regex = lazy_regex::regex!(r#"^\s*([\w\s]+?)\s*:\s*(.*)$"#);
+    let regex = lazy_regex::regex!(r#"^\s*([\w\s\-]+?)\s*:\s*(.*)$"#);
+    let msg = strip_spr_footer(&normalize_message(msg));
 
     let mut section = top_section;
     let mut lines_in_section = Vec::<&str>::new();
     let mut sections =
         std::collections::BTreeMap::<MessageSection, String>::new();
 
-    for (lineno, line) in msg
-        .trim()
-        .split('\n')
-        .map(|line| line.trim_end())
-        .enumerate()
-    {
+    for (lineno, line) in msg.trim().split('\n').enumerate() {
         if let Some(caps) = regex.captures(line) {
             let label = caps.get(1).unwrap().as_str();
             let payload = caps.get(2).unwrap().as_str();
@@ -139,22 +190,46 @@ pub fn build_message(
 
             if section != &MessageSection::Title
                 && section != &MessageSection::Summary
+                && !(section == &MessageSection::PRBody && result.is_empty())
             {
-                // Once we encounter a section that's neither Title nor Summary,
-                // we start displaying the labels.
+                // Once we encounter a section that's neither Title nor Summary
+                // (nor a PR-Body standing in for the Summary), we start
+                // displaying the labels.
                 display_label = true;
             }
 
+            let references = match section {
+                MessageSection::Closes => Some(parse_issue_references(text)),
+                MessageSection::DependsOn => {
+                    Some(parse_dependency_references(text))
+                }
+                _ => None,
+            };
+            if let Some(references) = references {
+                // Each issue gets its own line, as GitHub only links the
+                // first issue after a closing keyword. Dependencies follow
+                // suit, so that tools can find them line by line.
+                for reference in references {
+                    result.push_str(message_section_label(section));
+                    result.push_str(": ");
+                    result.push_str(&reference);
+                    result.push('\n');
+                }
+                continue;
+            }
+
             if display_label {
                 let label = message_section_label(section);
                 result.push_str(label);
-                result.push_str(
-                    if label.len() + text.len() > 76 || text.contains('\n') {
-                        ":\n"
-                    } else {
-                        ": "
-                    },
-                );
+                result.push_str(if text.is_empty() {
+                    // No trailing whitespace after the label of an empty
+                    // section
+                    ":"
+                } else if label.len() + text.len() > 76 || text.contains('\n') {
+                    ":\n"
+                } else {
+                    ": "
+                });
             }
 
             result.push_str(text);
@@ -172,25 +247,106 @@ pub fn build_commit_message(section_texts: &MessageSectionsMap) -> String {
             MessageSection::Title,
             MessageSection::Summary,
             MessageSection::BasePR,
+            MessageSection::DependsOn,
             MessageSection::TestPlan,
+            MessageSection::PRBody,
+            MessageSection::Attachments,
+            MessageSection::Project,
+            MessageSection::Closes,
             MessageSection::Reviewers,
             MessageSection::ReviewedBy,
+            MessageSection::Base,
             MessageSection::PullRequest,
+            MessageSection::LandedAs,
         ],
     )
 }
 
 pub fn build_github_body(section_texts: &MessageSectionsMap) -> String {
+    // A `PR-Body` section, if given, replaces the summary in the Pull Request
+    // description, so the commit message itself can stay concise.
+    let description = if section_texts.contains_key(&MessageSection::PRBody) {
+        MessageSection::PRBody
+    } else {
+        MessageSection::Summary
+    };
+
+    // Issue-closing keywords (`Fixes #42`) in a summary that the PR-Body
+    // replaces would be lost, and with them GitHub's linking of the issues,
+    // so they are carried over to the Closes section.
+    let mut section_texts = section_texts.clone();
+    if description == MessageSection::PRBody {
+        let summary = section_texts
+            .get(&MessageSection::Summary)
+            .map(|text| find_closed_issues(text))
+            .unwrap_or_default();
+        let already_closed = section_texts
+            .get(&MessageSection::PRBody)
+            .map(|text| find_closed_issues(text))
+            .unwrap_or_default();
+        let mut closes = section_texts
+            .get(&MessageSection::Closes)
+            .map(|text| parse_issue_references(text))
+            .unwrap_or_default();
+        for reference in summary {
+            if !already_closed.contains(&reference)
+                && !closes.contains(&reference)
+            {
+                closes.push(reference);
+            }
+        }
+        if !closes.is_empty() {
+            section_texts.insert(MessageSection::Closes, closes.join(", "));
+        }
+    }
+
     build_message(
-        section_texts,
+        &section_texts,
         &[
-            MessageSection::Summary,
+            description,
             MessageSection::BasePR,
+            MessageSection::DependsOn,
             MessageSection::TestPlan,
+            MessageSection::Closes,
         ],
     )
 }
 
+/// Split the text of a Closes section into issue references (`#42`,
+/// `owner/repo#42` or issue URLs)
+pub fn parse_issue_references(text: &str) -> Vec<String> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|reference| !reference.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Split the text of a Depends-on section into Pull Request references.
+/// Bare numbers become `#42`, so that GitHub links them.
+pub fn parse_dependency_references(text: &str) -> Vec<String> {
+    parse_issue_references(text)
+        .into_iter()
+        .map(|reference| {
+            if reference.chars().all(|c| c.is_ascii_digit()) {
+                format!("#{reference}")
+            } else {
+                reference
+            }
+        })
+        .collect()
+}
+
+/// The issues referenced with one of GitHub's issue-closing keywords (e.g.
+/// `Fixes #42`, `closes: owner/repo#7`) in the given text
+fn find_closed_issues(text: &str) -> Vec<String> {
+    lazy_regex::regex!(
+        r#"(?i)\b(?:close[sd]?|fix(?:e[sd])?|resolve[sd]?):?\s+((?:[\w.-]+/[\w.-]+)?#\d+|https://github\.com/[\w.-]+/[\w.-]+/issues/\d+)"#
+    )
+    .captures_iter(text)
+    .map(|caps| caps[1].to_string())
+    .collect()
+}
+
 pub fn build_github_body_for_merging(
     section_texts: &MessageSectionsMap,
 ) -> String {
@@ -206,11 +362,16 @@ pub fn build_github_body_for_merging(
     )
 }
 
+/// Check that the commit message has the sections required by the config.
+/// Draft Pull Requests are work in progress, so they don't need a Test Plan
+/// yet; the title is always required.
 pub fn validate_commit_message(
     message: &MessageSectionsMap,
     config: &crate::config::Config,
+    is_draft: bool,
 ) -> Result<()> {
     if config.require_test_plan
+        && !is_draft
         && !message.contains_key(&MessageSection::TestPlan)
     {
         output("💔", "Commit message does not have a Test Plan!")?;
@@ -226,14 +387,52 @@ pub fn validate_commit_message(
         return Err(Error::empty());
     }
 
+    if config.require_signoff
+        && !message.values().any(|text| {
+            text.lines().any(|line| line.starts_with("Signed-off-by: "))
+        })
+    {
+        output("💔", "Commit message is not signed off (Signed-off-by:)!")?;
+        return Err(Error::empty());
+    }
+
     Ok(())
 }
 
+const DIFFSTAT_START: &str = "<!-- spr-diffstat -->";
+const DIFFSTAT_END: &str = "<!-- /spr-diffstat -->";
+
+/// Put the diffstat at the top of the Pull Request description, replacing
+/// the one added earlier, if any
+pub fn with_diffstat(body: &str, diffstat: &str) -> String {
+    let body = match (body.find(DIFFSTAT_START), body.find(DIFFSTAT_END)) {
+        (Some(start), Some(end)) if start < end => format!(
+            "{}{}",
+            &body[..start],
+            body[end + DIFFSTAT_END.len()..].trim_start_matches('\n')
+        ),
+        _ => body.to_string(),
+    };
+
+    format!("{DIFFSTAT_START}\n```\n{diffstat}```\n{DIFFSTAT_END}\n\n{body}")
+}
+
+/// The diffstat `with_diffstat` put at the top of the given description
+pub fn diffstat_of(body: &str) -> Option<&str> {
+    let start = body.find(DIFFSTAT_START)? + DIFFSTAT_START.len();
+    let end = start + body[start..].find(DIFFSTAT_END)?;
+    body[start..end]
+        .strip_prefix("\n```\n")?
+        .strip_suffix("```\n")
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
 
+    use crate::config::test_utils::config_factory;
+
     #[test]
     fn test_parse_empty() {
         assert_eq!(
@@ -323,4 +522,306 @@ Reviewer:    a, b, c"#,
             .into()
         );
     }
+
+    #[test]
+    fn test_parse_pr_body() {
+        assert_eq!(
+            parse_message(
+                "Hello\n\nTerse.\n\nPR-Body:\nMuch more\ndetail",
+                MessageSection::Title
+            ),
+            [
+                (MessageSection::Title, "Hello".to_string()),
+                (MessageSection::Summary, "Terse.".to_string()),
+                (MessageSection::PRBody, "Much more\ndetail".to_string()),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_github_body_uses_pr_body() {
+        let message = parse_message(
+            "Hello\n\nTerse.\n\nTest Plan: run it\n\nPR-Body:\nMuch more",
+            MessageSection::Title,
+        );
+
+        assert_eq!(
+            build_github_body(&message),
+            "Much more\n\nTest Plan: run it\n"
+        );
+        assert_eq!(
+            build_commit_message(&message),
+            "Hello\n\nTerse.\n\nTest Plan: run it\n\nPR-Body: Much more\n"
+        );
+    }
+
+    #[test]
+    fn test_github_body_without_pr_body() {
+        let message = parse_message("Hello\n\nTerse.", MessageSection::Title);
+
+        assert_eq!(build_github_body(&message), "Terse.\n");
+    }
+
+    #[test]
+    fn test_attachments_not_in_github_body() {
+        let message = parse_message(
+            "Hello\n\nTerse.\n\nAttachments: before.png, after.png",
+            MessageSection::Title,
+        );
+
+        assert_eq!(
+            message.get(&MessageSection::Attachments).map(|s| &s[..]),
+            Some("before.png, after.png")
+        );
+        assert_eq!(build_github_body(&message), "Terse.\n");
+        assert_eq!(
+            build_commit_message(&message),
+            "Hello\n\nTerse.\n\nAttachments: before.png, after.png\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_crlf_and_trailing_whitespace() {
+        let lf =
+            "Hello  \n\nSummary\ntext\n\nTest Plan: run it\n\nReviewers: a";
+        let crlf = "Hello\r\n\r\nSummary \r\ntext\t\r\n\r\nTest Plan: run it  \r\n\r\nReviewers: a\r\n";
+
+        let expected: MessageSectionsMap = [
+            (MessageSection::Title, "Hello".to_string()),
+            (MessageSection::Summary, "Summary\ntext".to_string()),
+            (MessageSection::TestPlan, "run it".to_string()),
+            (MessageSection::Reviewers, "a".to_string()),
+        ]
+        .into();
+        assert_eq!(parse_message(lf, MessageSection::Title), expected);
+        assert_eq!(parse_message(crlf, MessageSection::Title), expected);
+    }
+
+    #[test]
+    fn test_normalization_is_idempotent() {
+        let message = "Hello \r\n\r\nSome\r\nsummary  \r\n\r\nTest Plan:\r\n";
+        let once = build_commit_message(&parse_message(
+            message,
+            MessageSection::Title,
+        ));
+        let twice =
+            build_commit_message(&parse_message(&once, MessageSection::Title));
+
+        assert_eq!(once, "Hello\n\nSome\nsummary\n\nTest Plan:\n");
+        assert_eq!(once, twice);
+        assert_eq!(normalize_message(&once), once);
+    }
+
+    #[test]
+    fn test_project_not_in_github_body() {
+        let message = parse_message(
+            "Hello\n\nTerse.\n\nProject: Roadmap",
+            MessageSection::Title,
+        );
+
+        assert_eq!(
+            message.get(&MessageSection::Project).map(|s| &s[..]),
+            Some("Roadmap")
+        );
+        assert_eq!(build_github_body(&message), "Terse.\n");
+        assert_eq!(
+            build_commit_message(&message),
+            "Hello\n\nTerse.\n\nProject: Roadmap\n"
+        );
+    }
+
+    #[test]
+    fn test_closing_keywords_in_github_body() {
+        // Without a PR-Body, the summary is the description
+        let message = parse_message(
+            "Hello\n\nFix the thing.\n\nFixes #42",
+            MessageSection::Title,
+        );
+        assert_eq!(
+            build_github_body(&message),
+            "Fix the thing.\n\nFixes #42\n"
+        );
+
+        // A PR-Body replaces the summary, but the issue stays linked
+        let message = parse_message(
+            "Hello\n\nFixes #42, resolves acme/other#7\n\n\
+             PR-Body: Much more detail, which closes #43",
+            MessageSection::Title,
+        );
+        assert_eq!(
+            build_github_body(&message),
+            "Much more detail, which closes #43\n\n\
+             Closes: #42\n\
+             Closes: acme/other#7\n"
+        );
+    }
+
+    #[test]
+    fn test_closes_section() {
+        let message = parse_message(
+            "Hello\n\nSummary\n\nCloses: #42, #43",
+            MessageSection::Title,
+        );
+        assert_eq!(
+            message.get(&MessageSection::Closes),
+            Some(&"#42, #43".to_string())
+        );
+
+        let body = build_github_body(&message);
+        assert_eq!(body, "Summary\n\nCloses: #42\nCloses: #43\n");
+        assert_eq!(
+            build_commit_message(&message),
+            "Hello\n\nSummary\n\nCloses: #42\nCloses: #43\n"
+        );
+
+        // The Pull Request description parses back to the same section
+        assert_eq!(
+            parse_issue_references(
+                &parse_message(&body, MessageSection::Summary)
+                    [&MessageSection::Closes]
+            ),
+            vec!["#42", "#43"]
+        );
+    }
+
+    #[test]
+    fn test_depends_on_section() {
+        let message = parse_message(
+            "Hello\n\nSummary\n\n\
+             Depends-on: 12, #13 https://github.com/acme/other/pull/7\n\n\
+             Test Plan: none",
+            MessageSection::Title,
+        );
+        assert_eq!(
+            parse_dependency_references(&message[&MessageSection::DependsOn]),
+            vec!["#12", "#13", "https://github.com/acme/other/pull/7"]
+        );
+
+        let body = build_github_body(&message);
+        assert_eq!(
+            body,
+            "Summary\n\n\
+             Depends-on: #12\n\
+             Depends-on: #13\n\
+             Depends-on: https://github.com/acme/other/pull/7\n\n\
+             Test Plan: none\n"
+        );
+        // Not to be confused with the Pull Request this one is based on
+        assert!(!message.contains_key(&MessageSection::BasePR));
+
+        // The Pull Request description parses back to the same list
+        assert_eq!(
+            parse_dependency_references(
+                &parse_message(&body, MessageSection::Summary)
+                    [&MessageSection::DependsOn]
+            ),
+            vec!["#12", "#13", "https://github.com/acme/other/pull/7"]
+        );
+    }
+
+    #[test]
+    fn test_validate_draft_without_test_plan() {
+        let config = config_factory();
+        let message = parse_message("Hello\n\nWIP", MessageSection::Title);
+
+        assert!(validate_commit_message(&message, &config, true).is_ok());
+    }
+
+    #[test]
+    fn test_validate_ready_without_test_plan() {
+        let config = config_factory();
+        let message = parse_message("Hello\n\nDone", MessageSection::Title);
+        let with_test_plan = parse_message(
+            "Hello\n\nDone\n\nTest Plan: ran it",
+            MessageSection::Title,
+        );
+
+        assert!(validate_commit_message(&message, &config, false).is_err());
+        assert!(
+            validate_commit_message(&with_test_plan, &config, false).is_ok()
+        );
+        // Drafts still need a title
+        assert!(validate_commit_message(
+            &parse_message("", MessageSection::Title),
+            &config,
+            true
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_validate_require_signoff() {
+        let mut config = config_factory();
+        config.require_test_plan = false;
+        config.require_signoff = true;
+
+        assert!(validate_commit_message(
+            &parse_message("Hello\n\nDone", MessageSection::Title),
+            &config,
+            false
+        )
+        .is_err());
+        assert!(validate_commit_message(
+            &parse_message(
+                "Hello\n\nDone\n\nSigned-off-by: Jane <jane@example.com>",
+                MessageSection::Title
+            ),
+            &config,
+            false
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_with_diffstat() {
+        let diffstat = " src/lib.rs | 2 +-\n 1 file changed, 1 insertion(+), \
+                        1 deletion(-)\n";
+        let body = with_diffstat("Summary\n", diffstat);
+        assert_eq!(
+            body,
+            "<!-- spr-diffstat -->\n```\n src/lib.rs | 2 +-\n 1 file changed, \
+             1 insertion(+), 1 deletion(-)\n```\n<!-- /spr-diffstat -->\n\n\
+             Summary\n"
+        );
+
+        // Updating it replaces the old diffstat
+        assert_eq!(with_diffstat(&body, diffstat), body);
+        let updated =
+            with_diffstat(&body, " src/lib.rs | 1 +\n 1 file changed\n");
+        assert_eq!(updated.matches("spr-diffstat").count(), 2);
+        assert!(
+            updated.contains("```\n src/lib.rs | 1 +\n 1 file changed\n```")
+        );
+        assert!(updated.ends_with("-->\n\nSummary\n"));
+
+        assert_eq!(diffstat_of(&body), Some(diffstat));
+        assert_eq!(diffstat_of("Summary\n"), None);
+    }
+
+    #[test]
+    fn test_spr_footer_is_not_duplicated() {
+        let version = env!("CARGO_PKG_VERSION");
+        // A local message copied from a commit spr created
+        let local =
+            "Add feature\n\nSummary text\n\nCreated using spr 1.3.4\n\n\
+                     Test Plan: cargo test\n\nCreated using spr 1.3.4\n";
+
+        let message = parse_message(local, MessageSection::Title);
+        assert_eq!(message[&MessageSection::Summary], "Summary text");
+        assert_eq!(message[&MessageSection::TestPlan], "cargo test");
+        assert_eq!(
+            build_commit_message(&message),
+            "Add feature\n\nSummary text\n\nTest Plan: cargo test\n"
+        );
+
+        assert_eq!(
+            with_spr_footer("Review comments\n\nCreated using spr 1.3.4"),
+            format!("Review comments\n\nCreated using spr {version}")
+        );
+        assert_eq!(
+            with_spr_footer(&with_spr_footer("Rebase")),
+            with_spr_footer("Rebase")
+        );
+    }
 }
@@ -5,7 +5,12 @@
  * LICENSE file in the root directory of this source tree.
  */
 
-use crate::{error::Result, git::PreparedCommit, message::MessageSection};
+use crate::{
+    error::Result,
+    git::PreparedCommit,
+    github::{PullRequest, PullRequestState},
+    message::MessageSection,
+};
 
 pub fn output(icon: &str, text: &str) -> Result<()> {
     let term = console::Term::stdout();
@@ -24,9 +29,92 @@ pub fn output(icon: &str, text: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn write_commit_title(prepared_commit: &PreparedCommit) -> Result<()> {
+/// The line that starts the output for one of several commits, so that the
+/// output for successive commits doesn't run together, e.g.
+/// `── 2/5 ─────────`. It fills the given width.
+pub fn commit_separator(position: usize, total: usize, width: usize) -> String {
+    let label = format!("── {position}/{total} ");
+    let rest = width.saturating_sub(console::measure_text_width(&label));
+    format!("{label}{}", "─".repeat(rest))
+}
+
+/// Write the separator for the commit at the given (1-based) position of
+/// the ones being worked on
+pub fn write_commit_separator(position: usize, total: usize) -> Result<()> {
     let term = console::Term::stdout();
-    term.write_line(&format!(
+    let width = (term.size().1 as usize).min(80);
+    term.write_line(
+        &console::style(commit_separator(position, total, width))
+            .dim()
+            .to_string(),
+    )?;
+    Ok(())
+}
+
+/// The pager to use for long output: `$SPR_PAGER`, falling back to `$PAGER`,
+/// falling back to `less`. An empty value, or `cat`, means no pager.
+pub fn pager_command(
+    spr_pager: Option<String>,
+    pager: Option<String>,
+) -> Option<String> {
+    let command = spr_pager.or(pager).unwrap_or_else(|| "less".to_string());
+    let command = command.trim();
+    if command.is_empty() || command == "cat" {
+        None
+    } else {
+        Some(command.to_string())
+    }
+}
+
+/// Write the output of a read-only command (which must end in a newline),
+/// through the pager if `use_pager` is set or `$SPR_PAGER` is set. Like Git,
+/// we only page when writing to a terminal, and otherwise pass the output
+/// through unchanged.
+pub fn write_paged(text: &str, use_pager: bool) -> Result<()> {
+    use std::io::Write;
+
+    let spr_pager = std::env::var("SPR_PAGER").ok();
+    let use_pager = use_pager || spr_pager.is_some();
+    let command = pager_command(spr_pager, std::env::var("PAGER").ok());
+
+    if let (true, true, Some(command)) =
+        (use_pager, console::Term::stdout().is_term(), command)
+    {
+        let mut child = std::process::Command::new("sh");
+        child
+            .arg("-c")
+            .arg(&command)
+            .stdin(std::process::Stdio::piped());
+        if std::env::var_os("LESS").is_none() {
+            // Quit if the output fits on one screen, keep colours, and
+            // don't clear the screen - the same defaults as Git's
+            child.env("LESS", "FRX");
+        }
+        if let Ok(mut child) = child.spawn() {
+            if let Some(mut stdin) = child.stdin.take() {
+                // The user quitting the pager early closes the pipe, which
+                // is not an error
+                let _ = stdin.write_all(text.as_bytes());
+            }
+            child.wait()?;
+            return Ok(());
+        }
+    }
+
+    let mut stdout = std::io::stdout();
+    stdout.write_all(text.as_bytes())?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Write the short id and title of the commit, followed by a badge with the
+/// state of its Pull Request, if given
+pub fn write_commit_title(
+    prepared_commit: &PreparedCommit,
+    badge: Option<PullRequestBadge>,
+) -> Result<()> {
+    let term = console::Term::stdout();
+    let mut line = format!(
         "{} {}",
         console::style(&prepared_commit.short_id).italic(),
         console::style(
@@ -37,6 +125,126 @@ pub fn write_commit_title(prepared_commit: &PreparedCommit) -> Result<()> {
                 .unwrap_or("(untitled)"),
         )
         .yellow()
-    ))?;
+    );
+    if let Some(badge) = badge {
+        line.push_str(&format!(" {}", badge.styled()));
+    }
+    term.write_line(&line)?;
     Ok(())
 }
+
+/// The state of a commit's Pull Request, as shown next to the commit title
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PullRequestBadge {
+    None,
+    Open,
+    Draft,
+    Merged,
+    Closed,
+}
+
+impl PullRequestBadge {
+    pub fn new(pull_request: Option<&PullRequest>) -> Self {
+        match pull_request {
+            None => PullRequestBadge::None,
+            Some(pr) => match pr.state {
+                PullRequestState::Open if pr.is_draft => {
+                    PullRequestBadge::Draft
+                }
+                PullRequestState::Open => PullRequestBadge::Open,
+                PullRequestState::Closed if pr.merge_commit.is_some() => {
+                    PullRequestBadge::Merged
+                }
+                PullRequestState::Closed => PullRequestBadge::Closed,
+            },
+        }
+    }
+
+    pub fn text(&self) -> &'static str {
+        match self {
+            PullRequestBadge::None => "no PR",
+            PullRequestBadge::Open => "open",
+            PullRequestBadge::Draft => "draft",
+            PullRequestBadge::Merged => "merged",
+            PullRequestBadge::Closed => "closed",
+        }
+    }
+
+    fn styled(&self) -> console::StyledObject<String> {
+        let badge = console::style(format!("[{}]", self.text()));
+        match self {
+            PullRequestBadge::None => badge.dim(),
+            PullRequestBadge::Open => badge.green(),
+            PullRequestBadge::Draft => badge.white(),
+            PullRequestBadge::Merged => badge.magenta(),
+            PullRequestBadge::Closed => badge.red(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    use crate::github::test_utils::pull_request_factory;
+
+    #[test]
+    fn test_commit_separator() {
+        let grouped = (1..=3)
+            .map(|position| commit_separator(position, 3, 20))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(
+            grouped,
+            "── 1/3 ─────────────\n\
+             ── 2/3 ─────────────\n\
+             ── 3/3 ─────────────"
+        );
+        // Labels wider than the terminal are not cut off
+        assert_eq!(commit_separator(10, 12, 4), "── 10/12 ");
+    }
+
+    #[test]
+    fn test_pager_command() {
+        assert_eq!(pager_command(None, None), Some("less".to_string()));
+        assert_eq!(
+            pager_command(None, Some("more".into())),
+            Some("more".to_string())
+        );
+        assert_eq!(
+            pager_command(Some("less -S".into()), Some("more".into())),
+            Some("less -S".to_string())
+        );
+        assert_eq!(pager_command(Some("".into()), Some("more".into())), None);
+        assert_eq!(pager_command(None, Some("cat".into())), None);
+    }
+
+    #[test]
+    fn test_pull_request_badge() {
+        use PullRequestState::*;
+
+        let badge = |state, is_draft, merged: bool| {
+            PullRequestBadge::new(Some(&PullRequest {
+                is_draft,
+                merge_commit: merged.then(git2::Oid::zero),
+                ..pull_request_factory(state)
+            }))
+            .text()
+        };
+
+        assert_eq!(PullRequestBadge::new(None).text(), "no PR");
+        assert_eq!(badge(Open, false, false), "open");
+        assert_eq!(badge(Open, true, false), "draft");
+        assert_eq!(badge(Closed, false, true), "merged");
+        assert_eq!(badge(Closed, false, false), "closed");
+
+        assert_eq!(
+            PullRequestBadge::Draft
+                .styled()
+                .force_styling(false)
+                .to_string(),
+            "[draft]".to_string()
+        );
+    }
+}
@@ -0,0 +1,170 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! What `spr diff` would do (or has done) for each commit of the stack. This
+//! is shared by `spr diff --dry-run` and `spr status`, so that both can emit
+//! the same JSON for tooling to consume. The plan is worked out by
+//! `commands::diff::compute_plan`, with the functions `spr diff` itself
+//! decides with.
+
+use serde::Serialize;
+
+use crate::error::Result;
+
+/// The options of `spr diff` that change what it does for a commit. `spr
+/// status` plans with the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct PlanOptions {
+    pub base: Option<String>,
+    pub master_base: Option<String>,
+    pub branch_name: Option<String>,
+    pub no_cherry_pick: bool,
+    pub prefer_master_base: bool,
+    pub prefer_existing_base: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Plan {
+    /// The commits of the stack, from the bottom (oldest) to the top
+    pub commits: Vec<PlannedCommit>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedCommit {
+    pub commit: String,
+    pub title: String,
+    pub action: PlannedAction,
+    pub pull_request: Option<u64>,
+    /// The branch the Pull Request is (to be) based on
+    pub base_branch: String,
+    /// The branch of the Pull Request, or the one it will be created on
+    pub head_branch: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlannedAction {
+    /// There is no Pull Request yet, it will be created
+    Create,
+    /// The Pull Request will be updated with the changes of the commit
+    Update,
+    /// The Pull Request is up to date
+    NoOp,
+    /// The commit was rebased, and the Pull Request needs the new master
+    /// commit merged in
+    MergeMaster,
+    /// The commit was rebased, and the changes of its parent go onto the
+    /// intermediate base branch of the Pull Request
+    UpdateBase,
+    /// The commit cannot be cherry-picked onto its base, so `spr diff` would
+    /// fail without `--no-cherry-pick`
+    Conflict,
+}
+
+impl PlannedAction {
+    fn description(&self) -> &'static str {
+        match self {
+            PlannedAction::Create => "create",
+            PlannedAction::Update => "update",
+            PlannedAction::NoOp => "no-op",
+            PlannedAction::MergeMaster => "merge master",
+            PlannedAction::UpdateBase => "update base",
+            PlannedAction::Conflict => "conflict",
+        }
+    }
+}
+
+pub fn render_json(plan: &Plan) -> Result<String> {
+    Ok(serde_json::to_string_pretty(plan)?)
+}
+
+pub fn render_text(plan: &Plan) -> String {
+    plan.commits
+        .iter()
+        .map(|commit| {
+            format!(
+                "{} {} [{}{}] -> {}",
+                &commit.commit[..7.min(commit.commit.len())],
+                commit.title,
+                commit.action.description(),
+                commit
+                    .pull_request
+                    .map(|number| format!(" #{number}"))
+                    .unwrap_or_default(),
+                commit.base_branch,
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn plan_factory() -> Plan {
+        Plan {
+            commits: vec![
+                PlannedCommit {
+                    commit: "1111111111111111111111111111111111111111".into(),
+                    title: "Add feature".into(),
+                    action: PlannedAction::MergeMaster,
+                    pull_request: Some(42),
+                    base_branch: "master".into(),
+                    head_branch: "spr/foo/add-feature".into(),
+                },
+                PlannedCommit {
+                    commit: "2222222222222222222222222222222222222222".into(),
+                    title: "Use feature".into(),
+                    action: PlannedAction::Create,
+                    pull_request: None,
+                    base_branch: "master".into(),
+                    head_branch: "spr/foo/use-feature".into(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_render_json() {
+        assert_eq!(
+            render_json(&plan_factory()).unwrap(),
+            r#"{
+  "commits": [
+    {
+      "commit": "1111111111111111111111111111111111111111",
+      "title": "Add feature",
+      "action": "merge-master",
+      "pullRequest": 42,
+      "baseBranch": "master",
+      "headBranch": "spr/foo/add-feature"
+    },
+    {
+      "commit": "2222222222222222222222222222222222222222",
+      "title": "Use feature",
+      "action": "create",
+      "pullRequest": null,
+      "baseBranch": "master",
+      "headBranch": "spr/foo/use-feature"
+    }
+  ]
+}"#
+        );
+    }
+
+    #[test]
+    fn test_render_text() {
+        assert_eq!(
+            render_text(&plan_factory()),
+            "1111111 Add feature [merge master #42] -> master\n\
+             2222222 Use feature [create] -> master"
+        );
+    }
+}
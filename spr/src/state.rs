@@ -0,0 +1,135 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Progress of a `spr diff --all` run, persisted in `.git/spr-state`, so that
+//! a run that was interrupted (or failed) part way can be resumed instead of
+//! starting from scratch.
+
+use git2::Oid;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Result, git::Git};
+
+const STATE_FILE_NAME: &str = "spr-state";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffState {
+    /// The HEAD commit the recorded indexes refer to
+    pub head: String,
+    /// The number of commits on the stack
+    pub stack_length: usize,
+    /// The indexes of the commits selected for the run, bottom to top
+    pub selected: Vec<usize>,
+    /// The commits that have been handled successfully so far
+    pub completed: Vec<CompletedCommit>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompletedCommit {
+    pub index: usize,
+    /// The URL of the commit's Pull Request. If the run was killed, this may
+    /// not have made it into the local commit message yet.
+    pub pull_request_url: Option<String>,
+}
+
+impl DiffState {
+    pub fn new(head: Oid, stack_length: usize, selected: Vec<usize>) -> Self {
+        Self {
+            head: head.to_string(),
+            stack_length,
+            selected,
+            completed: Vec::new(),
+        }
+    }
+
+    /// Whether the state still applies to the given branch. Any change to
+    /// the commits (amend, rebase, new commits) moves HEAD, and then the
+    /// recorded indexes can no longer be trusted.
+    pub fn is_valid_for(&self, head: Oid, stack_length: usize) -> bool {
+        self.head == head.to_string() && self.stack_length == stack_length
+    }
+
+    /// The selected commits that have not been completed yet
+    pub fn remaining(&self) -> Vec<usize> {
+        self.selected
+            .iter()
+            .copied()
+            .filter(|index| !self.completed.iter().any(|c| c.index == *index))
+            .collect()
+    }
+
+    pub fn complete(&mut self, index: usize, pull_request_url: Option<String>) {
+        self.completed.push(CompletedCommit {
+            index,
+            pull_request_url,
+        });
+    }
+
+    pub fn load(git: &Git) -> Result<Option<Self>> {
+        let path = git.repo().path().join(STATE_FILE_NAME);
+        match std::fs::read_to_string(path) {
+            // A state file we can't make sense of is as good as none
+            Ok(text) => Ok(serde_json::from_str(&text).ok()),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                Ok(None)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub fn save(&self, git: &Git) -> Result<()> {
+        let path = git.repo().path().join(STATE_FILE_NAME);
+        std::fs::write(path, serde_json::to_string(self)?)?;
+
+        Ok(())
+    }
+
+    pub fn clear(git: &Git) -> Result<()> {
+        let path = git.repo().path().join(STATE_FILE_NAME);
+        match std::fs::remove_file(path) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                Err(error.into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    use crate::git::test_utils::init_test_repo;
+
+    #[test]
+    fn test_resume_after_partial_run() {
+        let git = init_test_repo("state");
+        let head =
+            Oid::from_str("1111111111111111111111111111111111111111").unwrap();
+
+        assert_eq!(DiffState::load(&git).unwrap(), None);
+
+        let mut state = DiffState::new(head, 4, vec![0, 2, 3]);
+        state.complete(0, Some("https://github.com/a/b/pull/1".into()));
+        state.save(&git).unwrap();
+
+        let loaded = DiffState::load(&git).unwrap().unwrap();
+        assert_eq!(loaded, state);
+        assert_eq!(loaded.remaining(), vec![2, 3]);
+        assert!(loaded.is_valid_for(head, 4));
+        // The stack changed since the interrupted run
+        assert!(!loaded.is_valid_for(Oid::zero(), 4));
+        assert!(!loaded.is_valid_for(head, 5));
+
+        DiffState::clear(&git).unwrap();
+        assert_eq!(DiffState::load(&git).unwrap(), None);
+        DiffState::clear(&git).unwrap();
+    }
+}
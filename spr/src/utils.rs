@@ -5,9 +5,13 @@
  * LICENSE file in the root directory of this source tree.
  */
 
-use crate::error::{Error, Result};
+use crate::{
+    error::{Error, Result},
+    output::output,
+};
 
 use std::{io::Write, process::Stdio};
+use tokio::io::AsyncReadExt;
 use unicode_normalization::UnicodeNormalization;
 
 pub fn slugify(s: &str) -> String {
@@ -44,23 +48,75 @@ pub fn remove_all_parens(text: &str) -> String {
 
 /// Run command with both stdout and stderr streaming live to the terminal.
 /// This is useful for commands like git push where you want to see hook output in real-time.
-pub async fn run_command_with_live_output(
+/// Stderr is also captured, and if the command fails, passed to `explain`,
+/// which may return an additional message explaining the failure.
+/// As stderr is a pipe, commands may hold back their progress output (e.g.
+/// `git push` needs `--progress` for it).
+pub async fn run_command_with_live_output<F>(
     cmd: &mut tokio::process::Command,
-) -> Result<()> {
-    let status = cmd
+    explain: F,
+) -> Result<()>
+where
+    F: FnOnce(&str) -> Option<String>,
+{
+    let mut child = cmd
         .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()?
-        .wait()
-        .await?;
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut captured_stderr = Vec::new();
+    if let Some(mut stderr) = child.stderr.take() {
+        let mut buffer = [0u8; 4096];
+        loop {
+            let len = stderr.read(&mut buffer).await?;
+            if len == 0 {
+                break;
+            }
+            std::io::stderr().write_all(&buffer[..len])?;
+            captured_stderr.extend_from_slice(&buffer[..len]);
+        }
+    }
+
+    let status = child.wait().await?;
 
     if !status.success() {
-        return Err(Error::new("command failed"));
+        let mut error = Error::empty();
+        if let Some(explanation) =
+            explain(&String::from_utf8_lossy(&captured_stderr))
+        {
+            error.push(explanation);
+        }
+        error.push("command failed".to_string());
+        return Err(error);
     }
 
     Ok(())
 }
 
+/// Open the given URL in the default web browser. If that is not possible
+/// (there is no browser, or spr was built without the `browser` feature),
+/// print the URL instead.
+pub fn open_in_browser(url: &str) -> Result<()> {
+    #[cfg(feature = "browser")]
+    if open::that(url).is_ok() {
+        return Ok(());
+    }
+
+    output("🌐", &format!("Could not open a browser: {}", url))
+}
+
+/// Whether the given file name has the extension of an image format that
+/// GitHub displays inline
+pub fn is_image_file_name(name: &str) -> bool {
+    let extension = std::path::Path::new(name)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+    matches!(
+        extension.as_deref(),
+        Some("png" | "jpg" | "jpeg" | "gif" | "svg" | "webp")
+    )
+}
+
 pub async fn run_command(cmd: &mut tokio::process::Command) -> Result<()> {
     let cmd_output = cmd
         .stdout(Stdio::null())
@@ -142,4 +198,12 @@ mod tests {
             expected
         );
     }
+
+    #[test]
+    fn test_is_image_file_name() {
+        assert!(is_image_file_name("screenshot.png"));
+        assert!(is_image_file_name("docs/Before.JPEG"));
+        assert!(!is_image_file_name("trace.json"));
+        assert!(!is_image_file_name("png"));
+    }
 }